@@ -0,0 +1,401 @@
+// Images attached to user messages
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::path::Path;
+use thiserror::Error;
+
+/// Longest edge the model sees an image at; larger images are scaled down
+const MAX_EDGE: u32 = 1568;
+
+/// Most pixels the model sees an image with; larger images are scaled down
+const MAX_PIXELS: u64 = 1_150_000;
+
+/// Largest encoded image Bedrock accepts, in bytes
+const MAX_BYTES: usize = 3_750_000;
+
+/// Quality of JPEG images written when scaling down
+const JPEG_QUALITY: u8 = 85;
+
+/// Pixels the model counts as one token of an image
+const PIXELS_PER_TOKEN: u64 = 750;
+
+/// Longest edge of a thumbnail shown in the journal
+const THUMBNAIL_EDGE: u32 = 160;
+
+/// Tags around an image in the formatted context
+///
+/// Each image is written on one line as JSON, the same way reasoning blocks are.
+const IMAGE_OPEN: &str = "<image>";
+const IMAGE_CLOSE: &str = "</image>";
+
+/// Extensions of the image files that can be attached
+pub const IMAGE_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "webp", "gif"];
+
+/// Why an image could not be attached
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ImageError {
+    /// The file could not be read
+    #[error("Could not read image {path}: {message}")]
+    Read { path: String, message: String },
+
+    /// The data is not a PNG, JPEG, WebP or GIF image
+    #[error("{name} is not a PNG, JPEG, WebP or GIF image")]
+    Unsupported { name: String },
+
+    /// The image could not be decoded or encoded
+    #[error("Could not process image {name}: {message}")]
+    Invalid { name: String, message: String },
+
+    /// The path leads outside the working directory
+    #[error("{path} is outside the working directory")]
+    OutsideWorkspace { path: String },
+}
+
+/// An image attached to a user message, ready to send to the model
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageAttachment {
+    /// File name shown to the user
+    pub name: String,
+
+    /// MIME type of the encoded image
+    pub media_type: String,
+
+    /// Width in pixels
+    pub width: u32,
+
+    /// Height in pixels
+    pub height: u32,
+
+    /// Encoded image, in base64
+    pub data: String,
+}
+
+/// Small preview of an attached image, as a base64 PNG
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Thumbnail {
+    /// File name of the image
+    pub name: String,
+
+    /// Preview image, in base64
+    pub png: String,
+}
+
+impl ImageAttachment {
+    /// Read an image file, scaling it down to what the model accepts
+    pub fn load(path: &Path) -> Result<Self, ImageError> {
+        let bytes = std::fs::read(path).map_err(|e| ImageError::Read {
+            path: path.display().to_string(),
+            message: e.to_string(),
+        })?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.display().to_string());
+        Self::from_bytes(&name, &bytes)
+    }
+
+    /// Read an image file in the working directory, refusing paths that lead outside it
+    ///
+    /// The path is resolved with links followed, so neither `..`, an absolute
+    /// path nor a link can reach an image elsewhere on disk.
+    pub fn load_in(working_dir: &Path, path: &str) -> Result<Self, ImageError> {
+        let unreadable = |e: std::io::Error| ImageError::Read {
+            path: path.to_string(),
+            message: e.to_string(),
+        };
+        let workspace = working_dir.canonicalize().map_err(unreadable)?;
+        let file = workspace.join(path).canonicalize().map_err(unreadable)?;
+        if !file.starts_with(&workspace) {
+            return Err(ImageError::OutsideWorkspace {
+                path: path.to_string(),
+            });
+        }
+        Self::load(&file)
+    }
+
+    /// Attach encoded image data, scaling it down to what the model accepts
+    ///
+    /// Images within the limits are sent as they are; larger ones are
+    /// resized, and written as JPEG if they started as one and PNG otherwise.
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> Result<Self, ImageError> {
+        let unsupported = || ImageError::Unsupported {
+            name: name.to_string(),
+        };
+        let invalid = |message: String| ImageError::Invalid {
+            name: name.to_string(),
+            message,
+        };
+
+        let format = image::guess_format(bytes).map_err(|_| unsupported())?;
+        let media_type = media_type(format).ok_or_else(unsupported)?;
+        let image = image::load_from_memory_with_format(bytes, format)
+            .map_err(|e| invalid(e.to_string()))?;
+
+        let (width, height) = scaled_size(image.width(), image.height());
+        if (width, height) == (image.width(), image.height()) && bytes.len() <= MAX_BYTES {
+            return Ok(Self {
+                name: name.to_string(),
+                media_type: media_type.to_string(),
+                width,
+                height,
+                data: BASE64.encode(bytes),
+            });
+        }
+
+        let resized = image.resize_exact(width, height, FilterType::Lanczos3);
+        let encoded = match format {
+            ImageFormat::Jpeg => encode_jpeg(&resized),
+            _ => encode(&resized, ImageFormat::Png),
+        }
+        .map_err(invalid)?;
+
+        // A detailed PNG can still be too large, but not as a JPEG
+        let (media_type, encoded) = match encoded {
+            encoded if encoded.len() > MAX_BYTES && format != ImageFormat::Jpeg => {
+                ("image/jpeg", encode_jpeg(&resized).map_err(invalid)?)
+            }
+            encoded => (
+                match format {
+                    ImageFormat::Jpeg => "image/jpeg",
+                    _ => "image/png",
+                },
+                encoded,
+            ),
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            media_type: media_type.to_string(),
+            width,
+            height,
+            data: BASE64.encode(encoded),
+        })
+    }
+
+    /// Read an image from a line of the formatted context, if the line holds one
+    pub fn from_markup(line: &str) -> Option<Self> {
+        image_json(line).and_then(|json| serde_json::from_str(json).ok())
+    }
+
+    /// The image as a line of the formatted context
+    pub fn to_markup(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        format!("{}{}{}", IMAGE_OPEN, json, IMAGE_CLOSE)
+    }
+
+    /// Tokens the model counts for the image
+    pub fn estimated_tokens(&self) -> usize {
+        image_tokens(self.width, self.height)
+    }
+
+    /// A small preview of the image for the journal
+    pub fn thumbnail(&self) -> Result<Thumbnail, ImageError> {
+        let invalid = |message: String| ImageError::Invalid {
+            name: self.name.clone(),
+            message,
+        };
+        let bytes = BASE64
+            .decode(&self.data)
+            .map_err(|e| invalid(e.to_string()))?;
+        let image = image::load_from_memory(&bytes).map_err(|e| invalid(e.to_string()))?;
+        let png = encode(
+            &image.thumbnail(THUMBNAIL_EDGE, THUMBNAIL_EDGE),
+            ImageFormat::Png,
+        )
+        .map_err(invalid)?;
+
+        Ok(Thumbnail {
+            name: self.name.clone(),
+            png: BASE64.encode(png),
+        })
+    }
+}
+
+impl Thumbnail {
+    /// Size and RGBA pixels of the preview, for drawing it
+    pub fn pixels(&self) -> Option<([usize; 2], Vec<u8>)> {
+        let bytes = BASE64.decode(&self.png).ok()?;
+        let image = image::load_from_memory_with_format(&bytes, ImageFormat::Png)
+            .ok()?
+            .to_rgba8();
+        let size = [image.width() as usize, image.height() as usize];
+        Some((size, image.into_raw()))
+    }
+}
+
+/// The JSON of the image a line of the formatted context holds, if it holds one
+fn image_json(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix(IMAGE_OPEN)
+        .and_then(|rest| rest.strip_suffix(IMAGE_CLOSE))
+}
+
+/// Whether a line of the formatted context holds an image
+pub fn is_image_line(line: &str) -> bool {
+    image_json(line).is_some()
+}
+
+/// Tokens the model counts for an image of the given size
+pub fn image_tokens(width: u32, height: u32) -> usize {
+    (width as u64 * height as u64).div_ceil(PIXELS_PER_TOKEN) as usize
+}
+
+/// Image files mentioned in a message as `@path`
+pub fn mentioned_images(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|path| path.trim_end_matches(|c: char| ",.;:!?)\"'".contains(c)))
+        .filter(|path| is_image_path(path))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether a path names a file of a kind that can be attached
+pub fn is_image_path(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            IMAGE_EXTENSIONS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(extension))
+        })
+}
+
+/// MIME type the API takes an image format as, if it takes the format at all
+fn media_type(format: ImageFormat) -> Option<&'static str> {
+    match format {
+        ImageFormat::Png => Some("image/png"),
+        ImageFormat::Jpeg => Some("image/jpeg"),
+        ImageFormat::WebP => Some("image/webp"),
+        ImageFormat::Gif => Some("image/gif"),
+        _ => None,
+    }
+}
+
+/// Size an image is scaled down to so it fits the edge and pixel limits
+fn scaled_size(width: u32, height: u32) -> (u32, u32) {
+    let edge_scale = MAX_EDGE as f64 / width.max(height) as f64;
+    let pixel_scale = (MAX_PIXELS as f64 / (width as f64 * height as f64)).sqrt();
+    let scale = edge_scale.min(pixel_scale);
+    if scale >= 1.0 {
+        return (width, height);
+    }
+    (
+        ((width as f64 * scale).floor() as u32).max(1),
+        ((height as f64 * scale).floor() as u32).max(1),
+    )
+}
+
+/// Write an image in a format
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut bytes), format)
+        .map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+/// Write an image as a JPEG, dropping any transparency
+fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    DynamicImage::ImageRgb8(image.to_rgb8())
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY))
+        .map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = DynamicImage::new_rgba8(width, height);
+        encode(&image, ImageFormat::Png).unwrap()
+    }
+
+    #[test]
+    fn small_images_are_sent_unchanged() {
+        let bytes = png(40, 30);
+
+        let image = ImageAttachment::from_bytes("small.png", &bytes).unwrap();
+
+        assert_eq!(image.media_type, "image/png");
+        assert_eq!((image.width, image.height), (40, 30));
+        assert_eq!(BASE64.decode(&image.data).unwrap(), bytes);
+        assert_eq!(image.estimated_tokens(), 2);
+    }
+
+    #[test]
+    fn large_images_are_scaled_to_the_limits() {
+        let image = ImageAttachment::from_bytes("wide.png", &png(4000, 1000)).unwrap();
+
+        assert_eq!((image.width, image.height), (1568, 392));
+
+        let image = ImageAttachment::from_bytes("square.png", &png(1500, 1500)).unwrap();
+
+        assert!(image.width * image.height <= MAX_PIXELS as u32);
+        assert_eq!(image.width, image.height);
+        let decoded = image::load_from_memory(&BASE64.decode(&image.data).unwrap()).unwrap();
+        assert_eq!(decoded.width(), image.width);
+    }
+
+    #[test]
+    fn other_data_is_not_attached() {
+        let error = ImageAttachment::from_bytes("notes.txt", b"just some text").unwrap_err();
+
+        assert_eq!(
+            error,
+            ImageError::Unsupported {
+                name: "notes.txt".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn images_round_trip_through_markup() {
+        let image = ImageAttachment::from_bytes("shot.png", &png(20, 10)).unwrap();
+        let line = image.to_markup();
+
+        assert!(is_image_line(&line));
+        assert_eq!(ImageAttachment::from_markup(&line), Some(image));
+        assert_eq!(ImageAttachment::from_markup("<image>not json</image>"), None);
+    }
+
+    #[test]
+    fn images_outside_the_working_directory_are_refused() {
+        let dir = std::env::temp_dir().join(format!("gamecode-workspace-{}", uuid::Uuid::new_v4()));
+        let workspace = dir.join("workspace");
+        std::fs::create_dir_all(workspace.join("shots")).unwrap();
+        std::fs::write(workspace.join("shots/in.png"), png(4, 4)).unwrap();
+        std::fs::write(dir.join("out.png"), png(4, 4)).unwrap();
+
+        assert!(ImageAttachment::load_in(&workspace, "shots/in.png").is_ok());
+        assert!(ImageAttachment::load_in(&workspace, "shots/../shots/in.png").is_ok());
+        let outside = dir.join("out.png").to_string_lossy().to_string();
+        for path in ["../out.png", "shots/../../out.png", outside.as_str()] {
+            assert_eq!(
+                ImageAttachment::load_in(&workspace, path),
+                Err(ImageError::OutsideWorkspace {
+                    path: path.to_string()
+                })
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn image_mentions_need_an_at_sign_and_an_image_extension() {
+        let mentions = mentioned_images(
+            "Compare @ui/before.PNG with @after.jpg, not @notes.md or diagram.png",
+        );
+
+        assert_eq!(mentions, ["ui/before.PNG", "after.jpg"]);
+    }
+}
@@ -1,12 +1,25 @@
-use crate::agent::backends::{Backend, BackendCore, BackendResponse};
+use crate::agent::attachments::ImageAttachment;
+use crate::agent::backends::cassette::CassetteRecorder;
+use crate::agent::backends::stream::StreamedResponse;
+use crate::agent::backends::{
+    is_reasoning_line, reasoning_block, reasoning_line, Backend, BackendCore, BackendError,
+    BackendResponse, ModelCatalog, ModelSpec, StopReason, TokenUsage,
+};
+use crate::agent::context::{parse_user_line, tool_result_images, UserLine};
 use crate::agent::tools::ExecuteCommandTool;
 use async_trait::async_trait;
 use aws_config::BehaviorVersion;
-use aws_sdk_bedrockruntime::{error::SdkError, operation::invoke_model::InvokeModelError, Client};
+use aws_sdk_bedrockruntime::config::retry::RetryConfig;
+use aws_sdk_bedrockruntime::error::{DisplayErrorContext, ProvideErrorMetadata};
+use aws_sdk_bedrockruntime::operation::invoke_model_with_response_stream::InvokeModelWithResponseStreamError;
+use aws_sdk_bedrockruntime::types::error::ResponseStreamError;
+use aws_sdk_bedrockruntime::types::ResponseStream;
+use aws_sdk_bedrockruntime::{error::SdkError, Client};
 use aws_smithy_types::Blob;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tracing::{debug, error, info, trace, warn};
 use uuid;
@@ -17,20 +30,13 @@ pub struct BedrockBackend {
     config: BedrockConfig,
 
     /// Currently selected model
-    current_model: BedrockModel,
+    current_model: ModelSpec,
 
     /// Bedrock client
     client: Option<Arc<Client>>,
-}
-
-/// Available Bedrock models
-#[derive(Clone, Copy, Debug)]
-pub enum BedrockModel {
-    /// Claude 3.7 Sonnet - for primary interactions
-    Sonnet,
 
-    /// Claude 3.5 Haiku - for context management and summarization
-    Haiku,
+    /// Cassette that successful exchanges are recorded to, if any
+    recorder: Option<CassetteRecorder>,
 }
 
 /// Configuration for the Bedrock backend
@@ -39,25 +45,20 @@ pub struct BedrockConfig {
     /// AWS region to use
     pub region: String,
 
-    /// Maximum token limit for each model
-    pub sonnet_token_limit: usize,
-    pub haiku_token_limit: usize,
-
-    /// Temperature setting for each model
-    pub sonnet_temperature: f32,
-    pub haiku_temperature: f32,
-
-    /// Maximum tokens to generate in a response
-    pub max_tokens: usize,
-
     /// Whether to use AWS profile for authentication
     pub use_profile: bool,
 
     /// AWS profile name to use
     pub profile_name: Option<String>,
 
-    /// Number of retries for API calls
-    pub max_retries: usize,
+    /// Names of the tools offered to the model
+    pub enabled_tools: Vec<String>,
+
+    /// Commands the execute_command tool is allowed to run
+    pub allowed_commands: Vec<String>,
+
+    /// Cassette file to record requests and responses to
+    pub record_cassette: Option<PathBuf>,
 }
 
 impl Default for BedrockConfig {
@@ -65,14 +66,20 @@ impl Default for BedrockConfig {
         Self {
             //region: "us-east-1".to_string(),
             region: "us-west-2".to_string(),
-            sonnet_token_limit: 28000,
-            haiku_token_limit: 28000,
-            sonnet_temperature: 0.7,
-            haiku_temperature: 0.3,
-            max_tokens: 4096,
             use_profile: true,
             profile_name: None,
-            max_retries: 3,
+            enabled_tools: vec![
+                "read_file".to_string(),
+                "write_file".to_string(),
+                "list_directory".to_string(),
+                "view_image".to_string(),
+                "execute_command".to_string(),
+            ],
+            allowed_commands: ExecuteCommandTool::default_allowed_commands()
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect(),
+            record_cassette: None,
         }
     }
 }
@@ -83,9 +90,9 @@ struct ClaudeRequest {
     /// Holds an array of Message objects
     messages: Vec<ClaudeMessage>,
 
-    /// System prompt
+    /// System prompt, as text blocks so it can be cached
     #[serde(skip_serializing_if = "Option::is_none")]
-    system: Option<String>,
+    system: Option<Vec<ClaudeContentBlock>>,
 
     /// Max tokens to generate
     max_tokens: usize,
@@ -101,6 +108,10 @@ struct ClaudeRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     tool_choice: Option<serde_json::Value>,
 
+    /// Extended thinking settings, when the model thinks before answering
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<serde_json::Value>,
+
     // Tool results are now embedded directly in messages as content blocks
     /// Anthropic API version
     anthropic_version: String,
@@ -117,7 +128,7 @@ struct ClaudeMessage {
 }
 
 /// Content block for Claude API
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 enum ClaudeContentBlock {
     /// Text content
@@ -125,6 +136,16 @@ enum ClaudeContentBlock {
         #[serde(rename = "type")]
         content_type: String,
         text: String,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+
+    /// Image attached to a user message
+    Image {
+        #[serde(rename = "type")]
+        content_type: String,
+        source: ClaudeImageSource,
     },
 
     /// Tool use content
@@ -144,7 +165,64 @@ enum ClaudeContentBlock {
         #[serde(rename = "tool_use_id")]
         tool_use_id: String,
         content: Value,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+
+    /// Extended thinking, sent back exactly as the model produced it
+    Thinking {
+        #[serde(rename = "type")]
+        content_type: String,
+        thinking: String,
+        signature: String,
     },
+
+    /// Extended thinking the API returned encrypted
+    RedactedThinking {
+        #[serde(rename = "type")]
+        content_type: String,
+        data: String,
+    },
+}
+
+/// Encoded data of an image block
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ClaudeImageSource {
+    #[serde(rename = "type")]
+    source_type: String,
+    media_type: String,
+    data: String,
+}
+
+/// Prompt cache breakpoint: the request up to and including the block or
+/// tool it is set on is cached, and read back by later requests that start the same way
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CacheControl {
+    #[serde(rename = "type")]
+    cache_type: String,
+}
+
+impl CacheControl {
+    /// A breakpoint kept for a few minutes after its last use
+    fn ephemeral() -> Self {
+        Self {
+            cache_type: "ephemeral".to_string(),
+        }
+    }
+}
+
+impl ClaudeContentBlock {
+    /// Cache the request up to this block, if it is a kind of block that can be cached
+    fn set_cache_breakpoint(&mut self) {
+        match self {
+            ClaudeContentBlock::Text { cache_control, .. }
+            | ClaudeContentBlock::ToolResult { cache_control, .. } => {
+                *cache_control = Some(CacheControl::ephemeral());
+            }
+            _ => {}
+        }
+    }
 }
 
 // Tool results are now embedded directly in messages as content blocks
@@ -161,6 +239,10 @@ struct ClaudeTool {
 
     /// Tool input schema
     input_schema: Value,
+
+    /// Prompt cache breakpoint, set on the last tool
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_control: Option<CacheControl>,
 }
 
 /// Claude API response
@@ -177,6 +259,14 @@ struct ClaudeResponse {
 
     /// Usage information
     usage: ClaudeUsage,
+
+    /// Why the model stopped ("end_turn", "tool_use", "max_tokens", ...)
+    #[serde(default)]
+    stop_reason: Option<String>,
+
+    /// The stop sequence that was reached, if that is why the model stopped
+    #[serde(default)]
+    stop_sequence: Option<String>,
 }
 
 impl ClaudeResponse {}
@@ -203,6 +293,18 @@ struct ClaudeResponseContent {
     /// Tool input (if type is tool_use)
     #[serde(skip_serializing_if = "Option::is_none")]
     input: Option<HashMap<String, Value>>,
+
+    /// Reasoning (if type is thinking)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<String>,
+
+    /// Signature the reasoning is verified with (if type is thinking)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+
+    /// Encrypted reasoning (if type is redacted_thinking)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
 }
 
 /// Usage information in Claude response
@@ -213,10 +315,18 @@ struct ClaudeUsage {
 
     /// Output tokens
     output_tokens: usize,
+
+    /// Input tokens read from the prompt cache
+    #[serde(default)]
+    cache_read_input_tokens: usize,
+
+    /// Input tokens written to the prompt cache
+    #[serde(default)]
+    cache_creation_input_tokens: usize,
 }
 
 /// Tool use structure representing a tool call from the LLM
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolUse {
     /// Tool name
     pub name: String,
@@ -231,19 +341,17 @@ pub struct ToolUse {
 impl BedrockBackend {
     /// Create a new Bedrock backend with default settings
     pub fn new() -> Self {
-        Self {
-            config: BedrockConfig::default(),
-            current_model: BedrockModel::Sonnet,
-            client: None,
-        }
+        Self::with_config(BedrockConfig::default())
     }
 
     /// Create a new Bedrock backend with custom configuration
     pub fn with_config(config: BedrockConfig) -> Self {
+        let recorder = config.record_cassette.clone().map(CassetteRecorder::new);
         Self {
             config,
-            current_model: BedrockModel::Sonnet,
+            current_model: ModelCatalog::builtin().default_model().clone(),
             client: None,
+            recorder,
         }
     }
 
@@ -252,128 +360,235 @@ impl BedrockBackend {
         &self.config
     }
 
-    /// Get the current model
-    pub fn current_model(&self) -> BedrockModel {
-        self.current_model
+    /// Pretty print a serializable value as JSON
+    fn pretty_print_json<T: Serialize>(&self, value: &T) -> Result<String, BackendError> {
+        match serde_json::to_string_pretty(value) {
+            Ok(json) => Ok(json),
+            Err(e) => Err(BackendError::Serialization(format!(
+                "Failed to pretty-print JSON: {}",
+                e
+            ))),
+        }
     }
 
-    /// Initialize the Bedrock client
-    pub async fn init(&mut self) -> Result<(), String> {
-        // Single initialization log with key details
-        let profile_info = if let Some(profile) = &self.config.profile_name {
-            format!("profile '{}' in region '{}'", profile, self.config.region)
-        } else {
-            format!("default profile in region '{}'", self.config.region)
-        };
+    /// Build the JSON request body sent to Bedrock for a prompt
+    ///
+    /// The body goes through its serialized text, so numbers compare equal to
+    /// those in a recorded request.
+    pub fn request_body(&self, prompt: &str) -> Result<Value, BackendError> {
+        self.body_of(self.construct_claude_request(prompt, Some(&self.config.enabled_tools))?)
+    }
 
-        info!("Initializing AWS Bedrock client with {}", profile_info);
+    /// Build the JSON request body for a prompt sent without tools
+    pub fn text_request_body(&self, prompt: &str) -> Result<Value, BackendError> {
+        self.body_of(self.construct_claude_request(prompt, None)?)
+    }
 
-        // Configure AWS client
-        let aws_config = if self.config.use_profile {
-            let mut builder = aws_config::defaults(BehaviorVersion::latest());
+    /// A request as the JSON body sent to Bedrock
+    fn body_of(&self, request: ClaudeRequest) -> Result<Value, BackendError> {
+        let request_json = serde_json::to_string(&request).map_err(|e| {
+            BackendError::Serialization(format!("Failed to serialize request: {}", e))
+        })?;
+        serde_json::from_str(&request_json)
+            .map_err(|e| BackendError::Serialization(format!("Failed to parse request: {}", e)))
+    }
 
-            if let Some(profile) = &self.config.profile_name {
-                builder = builder.profile_name(profile);
+    /// Parse a Claude response body into a backend response
+    pub fn parse_response_body(&self, response_str: &str) -> Result<BackendResponse, BackendError> {
+        // Parse as JSON value first for pretty printing
+        let json_value = match serde_json::from_str::<serde_json::Value>(response_str) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to parse response as JSON: {}", e);
+                return Err(BackendError::Serialization(format!(
+                    "Failed to parse response as JSON: {}",
+                    e
+                )));
             }
+        };
 
-            builder = builder.region(aws_config::Region::new(self.config.region.clone()));
-            builder.load().await
-        } else {
-            aws_config::defaults(BehaviorVersion::latest())
-                .region(aws_config::Region::new(self.config.region.clone()))
-                .load()
-                .await
+        // Print pretty JSON for logging
+        match self.pretty_print_json(&json_value) {
+            Ok(pretty_json) => debug!("RESPONSE JSON:\n{}", pretty_json),
+            Err(e) => {
+                error!("{}", e);
+                // Still continue processing since we have the original response
+            }
         };
 
-        // Create and store client
-        let client = aws_sdk_bedrockruntime::Client::new(&aws_config);
-        self.client = Some(Arc::new(client));
+        // Deserialize response
+        let claude_response: ClaudeResponse = match serde_json::from_str(response_str) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to deserialize response: {}", e);
+                return Err(BackendError::Serialization(format!(
+                    "Failed to deserialize response: {}",
+                    e
+                )));
+            }
+        };
 
-        trace!("AWS Bedrock client initialized successfully");
-        Ok(())
-    }
+        // Extract text content and tool calls from JSON
+        let mut content = String::new();
+        let mut tool_calls = Vec::new();
 
-    /// Switch to a different model
-    pub fn switch_model(&mut self, model: BedrockModel) {
-        self.current_model = model;
-    }
+        // Process each content block from Claude response
+        for block in claude_response.content.iter() {
+            match block.content_type.as_str() {
+                "text" => {
+                    if let Some(text) = &block.text {
+                        content.push_str(text);
+                        content.push('\n');
+                    }
+                }
+                "thinking" | "redacted_thinking" => {
+                    // Reasoning has to go back to the model unchanged, so the block
+                    // is kept whole in the content
+                    let reasoning = match (&block.thinking, &block.signature, &block.data) {
+                        (Some(thinking), Some(signature), _) => ClaudeContentBlock::Thinking {
+                            content_type: "thinking".to_string(),
+                            thinking: thinking.clone(),
+                            signature: signature.clone(),
+                        },
+                        (_, _, Some(data)) => ClaudeContentBlock::RedactedThinking {
+                            content_type: "redacted_thinking".to_string(),
+                            data: data.clone(),
+                        },
+                        _ => {
+                            warn!("Ignoring incomplete {} block", block.content_type);
+                            continue;
+                        }
+                    };
+                    let json = serde_json::to_string(&reasoning).map_err(|e| {
+                        BackendError::Serialization(format!("Failed to serialize reasoning: {}", e))
+                    })?;
+                    content.push_str(&reasoning_line(&json));
+                    content.push('\n');
+                }
+                "tool_use" => {
+                    // Extract tool call directly from JSON
+                    if let (Some(id), Some(name), Some(input)) =
+                        (&block.id, &block.name, &block.input)
+                    {
+                        // Log the exact Claude-provided tool_use ID for tracking
+                        trace!("Received tool_use with ID '{}' from Claude API", id);
 
-    /// Get the current model's token limit
-    pub fn current_model_token_limit(&self) -> usize {
-        match self.current_model {
-            BedrockModel::Sonnet => self.config.sonnet_token_limit,
-            BedrockModel::Haiku => self.config.haiku_token_limit,
+                        tool_calls.push(ToolUse {
+                            name: name.clone(),
+                            args: input.clone(),
+                            id: Some(id.clone()), // Store exactly as received - must not be modified
+                        });
+                    }
+                }
+                _ => {
+                    // Ignore other content types
+                    warn!("Ignoring content block with type: {}", block.content_type);
+                }
+            }
         }
-    }
 
-    /// Get the current model's temperature
-    pub fn current_model_temperature(&self) -> f32 {
-        match self.current_model {
-            BedrockModel::Sonnet => self.config.sonnet_temperature,
-            BedrockModel::Haiku => self.config.haiku_temperature,
-        }
-    }
+        // Add text representation of tool calls for backward compatibility
+        // This will be removed in a future version once transition is complete
+        for tool_call in tool_calls.iter() {
+            let tool_json = self
+                .pretty_print_json(&tool_call.args)
+                .unwrap_or_else(|_| "{}".to_string());
 
-    /// Get the current model's name as a string
-    pub fn current_model_name(&self) -> &'static str {
-        match self.current_model {
-            BedrockModel::Sonnet => "us.anthropic.claude-3-7-sonnet-20250219-v1:0",
-            BedrockModel::Haiku => "anthropic.claude-3-5-haiku-20240307-v1:0",
-        }
-    }
+            // Include the original tool_use_id in the formatted tool call
+            let formatted_tool_call = if let Some(id) = &tool_call.id {
+                trace!(
+                    "Including original tool_use_id '{}' in formatted tool call",
+                    id
+                );
+                format!(
+                    "<tool name=\"{}\" id=\"{}\">\n{}\n</tool>",
+                    tool_call.name,
+                    id, // Include the exact original ID
+                    tool_json
+                )
+            } else {
+                warn!("No ID available for tool call, response validation may fail");
+                format!("<tool name=\"{}\">\n{}\n</tool>", tool_call.name, tool_json)
+            };
 
-    /// Pretty print a serializable value as JSON
-    fn pretty_print_json<T: Serialize>(&self, value: &T) -> Result<String, String> {
-        match serde_json::to_string_pretty(value) {
-            Ok(json) => Ok(json),
-            Err(e) => Err(format!("Failed to pretty-print JSON: {}", e)),
+            content.push_str(&formatted_tool_call);
+            content.push('\n');
         }
+
+        // Log minimal info about processed results
+        trace!(
+            "Processed {} content blocks with {} tool calls",
+            claude_response.content.len(),
+            tool_calls.len()
+        );
+
+        // Build response with tool calls directly included
+        Ok(BackendResponse {
+            content,
+            model: claude_response.model,
+            usage: Some(TokenUsage {
+                input_tokens: claude_response.usage.input_tokens,
+                output_tokens: claude_response.usage.output_tokens,
+                cache_read_tokens: claude_response.usage.cache_read_input_tokens,
+                cache_write_tokens: claude_response.usage.cache_creation_input_tokens,
+            }),
+            tool_calls,
+            stop_reason: StopReason::from_api(
+                claude_response.stop_reason.as_deref(),
+                claude_response.stop_sequence.as_deref(),
+            ),
+        })
     }
 
-    /// Parse error from AWS Bedrock API
-    fn parse_error(&self, err: SdkError<InvokeModelError>) -> String {
+    /// Parse error from AWS Bedrock API, whether it failed the request or its stream
+    fn parse_error<E, R>(&self, err: SdkError<E, R>) -> BackendError
+    where
+        E: ApiError,
+        R: std::fmt::Debug,
+    {
+        // The full chain of causes, which is where credential problems are described
+        let details = DisplayErrorContext(&err).to_string();
+
         match err {
             SdkError::ServiceError(context) => {
                 let err = context.err();
 
-                match err {
-                    InvokeModelError::AccessDeniedException(e) => {
-                        format!("Access denied: {}", e)
-                    }
-                    InvokeModelError::InternalServerException(e) => {
-                        format!("Internal server error: {}", e)
-                    }
-                    InvokeModelError::ModelNotReadyException(e) => {
-                        format!("Model not ready: {}", e)
-                    }
-                    InvokeModelError::ModelTimeoutException(e) => {
-                        format!("Model timeout: {}", e)
-                    }
-                    InvokeModelError::ResourceNotFoundException(e) => {
-                        format!("Resource not found: {}", e)
-                    }
-                    InvokeModelError::ServiceQuotaExceededException(e) => {
-                        format!("Service quota exceeded: {}", e)
-                    }
-                    InvokeModelError::ThrottlingException(e) => {
-                        format!("Throttling error: {}", e)
-                    }
-                    InvokeModelError::ValidationException(e) => {
-                        format!("Validation error: {}", e)
-                    }
-                    _ => format!("Unknown service error: {:?}", err),
+                match err.backend_error() {
+                    Some(error) => error,
+                    None => match err.code() {
+                        Some(
+                            "ExpiredTokenException"
+                            | "UnrecognizedClientException"
+                            | "InvalidSignatureException",
+                        ) => BackendError::Credentials(details),
+                        _ => BackendError::Service(details),
+                    },
                 }
             }
-            SdkError::ConstructionFailure(err) => format!("Construction failure: {:?}", err),
-            SdkError::DispatchFailure(err) => format!("Dispatch failure: {:?}", err),
-            SdkError::ResponseError(err) => format!("Response error: {:?}", err),
-            SdkError::TimeoutError(err) => format!("Timeout error: {:?}", err),
-            _ => format!("Unknown error: {:?}", err),
+            SdkError::ConstructionFailure(_) | SdkError::DispatchFailure(_)
+                if is_credentials_problem(&details) =>
+            {
+                BackendError::Credentials(details)
+            }
+            SdkError::ConstructionFailure(_) => BackendError::InvalidRequest(details),
+            SdkError::DispatchFailure(failure) if failure.is_timeout() => {
+                BackendError::Timeout(details)
+            }
+            SdkError::DispatchFailure(_) => BackendError::Network(details),
+            SdkError::TimeoutError(_) => BackendError::Timeout(details),
+            _ => BackendError::Service(details),
         }
     }
 
     /// Construct a Claude API request from a prompt and optional tool results
-    fn construct_claude_request(&self, prompt: &str) -> Result<ClaudeRequest, String> {
+    ///
+    /// Only the named tools are offered to the model, and none at all with
+    /// `None`, for one-shot requests that must be answered with text.
+    fn construct_claude_request(
+        &self,
+        prompt: &str,
+        tools: Option<&[String]>,
+    ) -> Result<ClaudeRequest, BackendError> {
         // Parse the conversation history from the prompt
         // The prompt comes from the ContextManager as a formatted string that includes:
         // - System messages (<s>...</s>)
@@ -382,7 +597,7 @@ impl BedrockBackend {
         // - Tool results in JSON format ({"type": "tool_result", ...})
 
         // Parse conversation history and extract tool results
-        let (mut messages, tool_results) = self.parse_conversation_history(prompt)?;
+        let (mut messages, tool_results, system_notes) = self.parse_conversation_history(prompt)?;
 
         trace!("Created Claude request with {} messages", messages.len());
         for (i, msg) in messages.iter().enumerate() {
@@ -391,8 +606,11 @@ impl BedrockBackend {
                 .iter()
                 .map(|c| match c {
                     ClaudeContentBlock::Text { .. } => "text",
+                    ClaudeContentBlock::Image { .. } => "image",
                     ClaudeContentBlock::ToolUse { .. } => "tool_use",
                     ClaudeContentBlock::ToolResult { .. } => "tool_result",
+                    ClaudeContentBlock::Thinking { .. } => "thinking",
+                    ClaudeContentBlock::RedactedThinking { .. } => "redacted_thinking",
                 })
                 .collect();
             trace!(
@@ -404,7 +622,8 @@ impl BedrockBackend {
         }
 
         // Create tool schemas for the available tools
-        let tools = Some(vec![
+        let offered = tools;
+        let mut tools = Some(vec![
             ClaudeTool {
                 name: "read_file".to_string(),
                 description: "Read the contents of a file from the filesystem".to_string(),
@@ -418,6 +637,7 @@ impl BedrockBackend {
                     },
                     "required": ["path"]
                 }),
+                cache_control: None,
             },
             ClaudeTool {
                 name: "write_file".to_string(),
@@ -436,6 +656,7 @@ impl BedrockBackend {
                     },
                     "required": ["path", "content"]
                 }),
+                cache_control: None,
             },
             ClaudeTool {
                 name: "list_directory".to_string(),
@@ -449,10 +670,26 @@ impl BedrockBackend {
                         }
                     }
                 }),
+                cache_control: None,
+            },
+            ClaudeTool {
+                name: "view_image".to_string(),
+                description: "Look at a PNG, JPEG, WebP or GIF image file, such as a screenshot, chart or test snapshot".to_string(),
+                input_schema: serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Path to the image file to view"
+                        }
+                    },
+                    "required": ["path"]
+                }),
+                cache_control: None,
             },
             {
                 // Create the execute_command tool with dynamic description based on allowed commands
-                let allowed_cmd_list = ExecuteCommandTool::allowed_commands().join(", ");
+                let allowed_cmd_list = self.config.allowed_commands.join(", ");
                 let description = format!(
                     "Execute a shell command (limited to safe commands: {})",
                     allowed_cmd_list
@@ -500,15 +737,23 @@ impl BedrockBackend {
                     name: "execute_command".to_string(),
                     description,
                     input_schema: serde_json::Value::Object(schema),
+                    cache_control: None,
                 }
             },
-        ]);
+        ])
+        .and_then(|tools| {
+            let offered = offered?;
+            Some(
+                tools
+                    .into_iter()
+                    .filter(|tool| offered.contains(&tool.name))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .filter(|tools| !tools.is_empty() && self.current_model.capabilities.tools);
 
-        // Security-focused system prompt
-        let system_prompt = "You are a helpful AI assistant who has access to the user's computer through tools. \
-        When using tools, prefer relative paths rather than absolute paths for security. \
-        Whenever possible, use the current working directory rather than specifying absolute paths. \
-        Answer questions and help with tasks efficiently and securely.";
+        // The system messages from the context, starting with the rendered system prompt
+        let system_prompt = system_notes.join("\n\n");
 
         // Organize messages to maintain the conversation flow with tool results
 
@@ -598,6 +843,7 @@ impl BedrockBackend {
                                 content_type: "tool_result".to_string(),
                                 tool_use_id: id.clone(),
                                 content: content.clone(),
+                                cache_control: None,
                             });
                         } else {
                             trace!("WARNING: No tool result found for tool_use_id: '{}'", id);
@@ -634,8 +880,11 @@ impl BedrockBackend {
                     .iter()
                     .map(|c| match c {
                         ClaudeContentBlock::Text { .. } => "text",
+                        ClaudeContentBlock::Image { .. } => "image",
                         ClaudeContentBlock::ToolUse { .. } => "tool_use",
                         ClaudeContentBlock::ToolResult { .. } => "tool_result",
+                        ClaudeContentBlock::Thinking { .. } => "thinking",
+                        ClaudeContentBlock::RedactedThinking { .. } => "redacted_thinking",
                     })
                     .collect();
                 trace!(
@@ -655,8 +904,11 @@ impl BedrockBackend {
                 .iter()
                 .map(|c| match c {
                     ClaudeContentBlock::Text { .. } => "text",
+                    ClaudeContentBlock::Image { .. } => "image",
                     ClaudeContentBlock::ToolUse { .. } => "tool_use",
                     ClaudeContentBlock::ToolResult { .. } => "tool_result",
+                    ClaudeContentBlock::Thinking { .. } => "thinking",
+                    ClaudeContentBlock::RedactedThinking { .. } => "redacted_thinking",
                 })
                 .collect();
             trace!(
@@ -694,28 +946,103 @@ impl BedrockBackend {
             }
         }
 
+        // The model thinks before answering unless it is continuing its own
+        // message, which the API does not allow with thinking
+        let continuing = messages
+            .last()
+            .is_some_and(|message| message.role == "assistant");
+        let thinking_budget = self.current_model.thinking().filter(|_| !continuing);
+
+        // Earlier reasoning can only be sent back while thinking is on
+        if thinking_budget.is_none() {
+            for message in &mut messages {
+                message.content.retain(|block| {
+                    !matches!(
+                        block,
+                        ClaudeContentBlock::Thinking { .. }
+                            | ClaudeContentBlock::RedactedThinking { .. }
+                    )
+                });
+            }
+            messages.retain(|message| !message.content.is_empty());
+        }
+
+        // A conversation ending with the model's own message asks it to continue
+        // that message, which may not end with whitespace
+        let continued = messages
+            .last_mut()
+            .filter(|message| message.role == "assistant")
+            .and_then(|message| message.content.last_mut());
+        if let Some(ClaudeContentBlock::Text { text, .. }) = continued {
+            let length = text.trim_end().len();
+            text.truncate(length);
+        }
+
+        // Cache breakpoints after the tool definitions, the system prompt and the
+        // newest user message, so a tool round only pays in full for what it adds
+        let caching = self.current_model.capabilities.prompt_caching;
+        if caching {
+            if let Some(tool) = tools.as_mut().and_then(|tools| tools.last_mut()) {
+                tool.cache_control = Some(CacheControl::ephemeral());
+            }
+            if let Some(block) = messages
+                .iter_mut()
+                .rev()
+                .find(|message| message.role == "user")
+                .and_then(|message| message.content.last_mut())
+            {
+                block.set_cache_breakpoint();
+            }
+        }
+        let system = (!system_prompt.is_empty()).then(|| {
+            vec![ClaudeContentBlock::Text {
+                content_type: "text".to_string(),
+                text: system_prompt,
+                cache_control: caching.then(CacheControl::ephemeral),
+            }]
+        });
+
+        // Tool choice is only valid when tools are offered
+        let tool_choice = tools
+            .as_ref()
+            .map(|_| serde_json::json!({ "type": "auto" }));
+
+        // Thinking counts against the output limit and needs a temperature of 1
+        let (max_tokens, temperature) = match thinking_budget {
+            Some(budget) => (self.current_model.max_output_tokens + budget, 1.0),
+            None => (
+                self.current_model.max_output_tokens,
+                self.current_model.temperature,
+            ),
+        };
+
         Ok(ClaudeRequest {
             messages,
-            system: Some(system_prompt.to_string()),
-            max_tokens: self.config.max_tokens,
-            temperature: self.current_model_temperature(),
+            system,
+            max_tokens,
+            temperature,
             tools,
-            tool_choice: Some(serde_json::json!({ "type": "auto" })),
+            tool_choice,
+            thinking: thinking_budget
+                .map(|budget| serde_json::json!({ "type": "enabled", "budget_tokens": budget })),
             anthropic_version: "bedrock-2023-05-31".to_string(),
         })
     }
 
     /// Parse the conversation history to extract all messages (user, assistant, system, tool) properly formatted
-    /// Returns a tuple of (messages, tool_results) where tool_results is a collection of (id, content) pairs
+    /// Returns a tuple of (messages, tool_results, system_notes) where tool_results is a collection of
+    /// (id, content) pairs and system_notes holds the text of system messages, which Claude takes in
+    /// the request's system prompt rather than as messages
     #[allow(clippy::type_complexity)]
     fn parse_conversation_history(
         &self,
         prompt: &str,
-    ) -> Result<(Vec<ClaudeMessage>, Vec<(String, Value)>), String> {
+    ) -> Result<(Vec<ClaudeMessage>, Vec<(String, Value)>, Vec<String>), BackendError> {
         let mut messages = Vec::new();
+        let mut system_notes = Vec::new();
         let mut current_role = None;
         let mut in_tag = false;
-        let mut tag_lines = Vec::new();
+        let mut tag_lines: Vec<&str> = Vec::new();
         let mut tool_results = Vec::new();
 
         // Split the prompt into lines for processing
@@ -726,7 +1053,7 @@ impl BedrockBackend {
             let line = lines[i].trim();
 
             // Check for opening tags
-            if line == "<s>" {
+            if line == "<system>" || line == "<s>" {
                 // System message start
                 current_role = Some("system");
                 in_tag = true;
@@ -756,7 +1083,20 @@ impl BedrockBackend {
                         // Parse the content into appropriate format for Claude based on tool type
                         trace!("Processing tool result with id: {}, content: {}", id, content);
                         
-                        let parsed_content = if id.contains("read_file") {
+                        let images = tool_result_images(&json);
+                        let parsed_content = if !images.is_empty() {
+                            // Images the tool returned go back as image blocks
+                            trace!("Image tool result: {} images", images.len());
+                            Value::Array(
+                                images
+                                    .into_iter()
+                                    .map(|image| {
+                                        serde_json::to_value(self.image_block(image))
+                                            .unwrap_or_default()
+                                    })
+                                    .collect(),
+                            )
+                        } else if id.contains("read_file") {
                             // For read_file, just pass through the raw content as a single string
                             // No JSON parsing, no line splitting - just the exact file content
                             // IMPORTANT: Claude expects a raw text string for file contents, not a JSON string or array
@@ -844,26 +1184,50 @@ impl BedrockBackend {
                         tool_results.push((id.to_string(), parsed_content));
                     }
                 }
-            } else if line == "</s>" || line == "</user>" || line == "</assistant>" {
+            } else if line == "</system>"
+                || line == "</s>"
+                || line == "</user>"
+                || line == "</assistant>"
+            {
                 // Closing tag - finalize current message if we have a role
                 if let Some(role) = current_role.take() {
                     if !tag_lines.is_empty() {
-                        let content_text = tag_lines.join("\n");
+                        // The model's reasoning and the user's images go first in their message
+                        let mut leading_blocks = Vec::new();
+                        let mut text_lines = Vec::new();
+                        for line in tag_lines.iter().copied() {
+                            match role {
+                                "assistant" if is_reasoning_line(line) => {
+                                    leading_blocks.extend(self.reasoning_content_block(line));
+                                }
+                                "user" => match parse_user_line(line) {
+                                    UserLine::Image(image) => {
+                                        leading_blocks.push(self.image_block(image))
+                                    }
+                                    UserLine::Text(text) => text_lines.push(text),
+                                },
+                                _ => text_lines.push(line),
+                            }
+                        }
+                        let content_text = text_lines.join("\n");
 
-                        // Check for tool calls in assistant messages
-                        if role == "assistant" && content_text.contains("<tool name=") {
+                        // System messages go in the system prompt, not the message list
+                        if role == "system" {
+                            system_notes.push(content_text);
+                        } else if role == "assistant" && content_text.contains("<tool name=") {
                             // Process assistant message with potential tool calls
                             let (text_content, tool_calls) =
                                 self.extract_tool_calls_from_text(&content_text);
 
-                            // Create content blocks - first text, then tool uses
-                            let mut content_blocks = Vec::new();
+                            // Create content blocks - first reasoning and text, then tool uses
+                            let mut content_blocks = leading_blocks;
 
                             // Add text content if not empty
                             if !text_content.trim().is_empty() {
                                 content_blocks.push(ClaudeContentBlock::Text {
                                     content_type: "text".to_string(),
                                     text: text_content,
+                                    cache_control: None,
                                 });
                             }
 
@@ -902,11 +1266,15 @@ impl BedrockBackend {
                                 });
                             }
                         } else {
-                            // Regular text message
-                            let content_blocks = vec![ClaudeContentBlock::Text {
-                                content_type: "text".to_string(),
-                                text: content_text,
-                            }];
+                            // Regular text message, after any reasoning
+                            let mut content_blocks = leading_blocks;
+                            if content_blocks.is_empty() || !content_text.trim().is_empty() {
+                                content_blocks.push(ClaudeContentBlock::Text {
+                                    content_type: "text".to_string(),
+                                    text: content_text,
+                                    cache_control: None,
+                                });
+                            }
 
                             messages.push(ClaudeMessage {
                                 role: role.to_string(),
@@ -931,6 +1299,7 @@ impl BedrockBackend {
             let content_blocks = vec![ClaudeContentBlock::Text {
                 content_type: "text".to_string(),
                 text: prompt.to_string(),
+                cache_control: None,
             }];
 
             messages.push(ClaudeMessage {
@@ -945,7 +1314,41 @@ impl BedrockBackend {
             tool_results.len()
         );
 
-        Ok((messages, tool_results))
+        Ok((messages, tool_results, system_notes))
+    }
+
+    /// The content block for an attached image, or a note if the model cannot take images
+    fn image_block(&self, image: ImageAttachment) -> ClaudeContentBlock {
+        if !self.current_model.capabilities.vision {
+            return ClaudeContentBlock::Text {
+                content_type: "text".to_string(),
+                text: format!(
+                    "[Image {} left out: {} does not accept images]",
+                    image.name, self.current_model.display_name
+                ),
+                cache_control: None,
+            };
+        }
+        ClaudeContentBlock::Image {
+            content_type: "image".to_string(),
+            source: ClaudeImageSource {
+                source_type: "base64".to_string(),
+                media_type: image.media_type,
+                data: image.data,
+            },
+        }
+    }
+
+    /// The content block for a reasoning line at the start of an assistant message
+    fn reasoning_content_block(&self, line: &str) -> Option<ClaudeContentBlock> {
+        let json = reasoning_block(line)?;
+        match serde_json::from_str(json) {
+            Ok(block) => Some(block),
+            Err(e) => {
+                warn!("Dropping reasoning block that does not parse: {}", e);
+                None
+            }
+        }
     }
 
     /// Extract tool calls from formatted assistant text
@@ -1037,42 +1440,32 @@ impl BedrockBackend {
         (text_content, tool_calls)
     }
 
-    // The extract_tool_results function has been replaced by parse_conversation_history
-}
-
-impl BackendCore for BedrockBackend {
-    fn name(&self) -> &'static str {
-        "AWS Bedrock"
-    }
-
-    fn context_window(&self) -> usize {
-        self.current_model_token_limit()
-    }
-}
-
-#[async_trait]
-impl Backend for BedrockBackend {
-    async fn generate_response(&self, prompt: &str) -> Result<BackendResponse, String> {
-        trace!("Generating response with model: {:?}", self.current_model);
+    /// Send a request over a response stream, passing on its text as it arrives
+    async fn send_request(
+        &self,
+        request: ClaudeRequest,
+        on_text: &(dyn Fn(&str) + Send + Sync),
+    ) -> Result<BackendResponse, BackendError> {
+        trace!("Generating response with model: {}", self.current_model.name);
 
         // If client is not initialized, return error
         let client = match &self.client {
             Some(client) => client.clone(),
             None => {
                 error!("Bedrock client not initialized");
-                return Err("Bedrock client not initialized. Call init() first.".to_string());
+                return Err(BackendError::NotInitialized("Bedrock"));
             }
         };
 
-        // Construct Claude request
-        let request = self.construct_claude_request(prompt)?;
-
         // Serialize to pretty-printed JSON for logging
         let pretty_request = match self.pretty_print_json(&request) {
             Ok(json) => json,
             Err(e) => {
                 error!("{}", e);
-                return Err(format!("Failed to serialize request: {}", e));
+                return Err(BackendError::Serialization(format!(
+                    "Failed to serialize request: {}",
+                    e
+                )));
             }
         };
         debug!("REQUEST JSON:\n{}", pretty_request);
@@ -1082,180 +1475,217 @@ impl Backend for BedrockBackend {
             Ok(json) => json,
             Err(e) => {
                 error!("Failed to serialize request: {}", e);
-                return Err(format!("Failed to serialize request: {}", e));
+                return Err(BackendError::Serialization(format!(
+                    "Failed to serialize request: {}",
+                    e
+                )));
             }
         };
 
-        // Set up retry for API calls
-        let mut retries = 0;
-        let mut last_error = None;
-
-        while retries <= self.config.max_retries {
-            if retries > 0 {
-                // Exponential backoff
-                let backoff_ms = 100 * (2u64.pow(retries as u32));
-                warn!(
-                    "Retrying API call ({}/{}) after error. Waiting {}ms before retry.",
-                    retries, self.config.max_retries, backoff_ms
-                );
-                tokio::time::sleep(std::time::Duration::from_millis(backoff_ms)).await;
+        // Call Bedrock API
+        trace!(
+            "Calling AWS Bedrock API with model: {}",
+            self.current_model_name()
+        );
+        let start_time = std::time::Instant::now();
+        let result = client
+            .invoke_model_with_response_stream()
+            .model_id(self.current_model_name())
+            .content_type("application/json")
+            .accept("application/json")
+            .body(Blob::new(request_json.clone().into_bytes()))
+            .send()
+            .await;
+
+        let mut output = match result {
+            Ok(output) => output,
+            Err(err) => {
+                let error = self.parse_error(err);
+                error!("API call failed: {}", error);
+                return Err(error);
             }
+        };
 
-            // Call Bedrock API
-            trace!(
-                "Calling AWS Bedrock API with model: {}",
-                self.current_model_name()
-            );
-            let start_time = std::time::Instant::now();
-            let result = client
-                .invoke_model()
-                .model_id(self.current_model_name())
-                .content_type("application/json")
-                .accept("application/json")
-                .body(Blob::new(request_json.clone().into_bytes()))
-                .send()
-                .await;
-            let elapsed = start_time.elapsed();
-            trace!("API call took {:?}", elapsed);
-
-            match result {
-                Ok(response) => {
-                    // Parse response body
-                    let response_body = response.body.clone();
-                    let response_str = match String::from_utf8(response_body.as_ref().to_vec()) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            error!("Failed to parse response body: {}", e);
-                            return Err(format!("Failed to parse response body: {}", e));
-                        }
-                    };
+        // Rebuild the response from its events, passing on text as it arrives
+        let mut streamed = StreamedResponse::default();
+        loop {
+            let event = match output.body.recv().await {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(err) => {
+                    let error = self.parse_error(err);
+                    error!("Response stream failed: {}", error);
+                    return Err(error);
+                }
+            };
+            let bytes = match event {
+                ResponseStream::Chunk(part) => part.bytes,
+                _ => {
+                    warn!("Ignoring unknown response stream event");
+                    continue;
+                }
+            };
+            let event: Value = match bytes.map(|bytes| serde_json::from_slice(bytes.as_ref())) {
+                Some(Ok(event)) => event,
+                Some(Err(e)) => {
+                    error!("Failed to parse response stream event: {}", e);
+                    return Err(BackendError::Serialization(format!(
+                        "Failed to parse response stream event: {}",
+                        e
+                    )));
+                }
+                None => continue,
+            };
+            if let Some(text) = streamed.apply(&event)? {
+                on_text(&text);
+            }
+        }
+        trace!("API call took {:?}", start_time.elapsed());
 
-                    // Parse as JSON value first for pretty printing
-                    let json_value = match serde_json::from_str::<serde_json::Value>(&response_str)
-                    {
-                        Ok(v) => v,
-                        Err(e) => {
-                            error!("Failed to parse response as JSON: {}", e);
-                            return Err(format!("Failed to parse response as JSON: {}", e));
-                        }
-                    };
+        let response_str = streamed.into_body()?.to_string();
+        let backend_response = self.parse_response_body(&response_str)?;
 
-                    // Print pretty JSON for logging
-                    match self.pretty_print_json(&json_value) {
-                        Ok(pretty_json) => debug!("RESPONSE JSON:\n{}", pretty_json),
-                        Err(e) => {
-                            error!("{}", e);
-                            // Still continue processing since we have the original response
-                        }
-                    };
+        // Save the exchange if a cassette is being recorded
+        if let Some(recorder) = &self.recorder {
+            recorder.record(self.current_model_name(), &request_json, &response_str);
+        }
 
-                    // Deserialize response
-                    let claude_response: ClaudeResponse =
-                        match serde_json::from_str::<ClaudeResponse>(&response_str) {
-                            Ok(r) => r,
-                            Err(e) => {
-                                error!("Failed to deserialize response: {}", e);
-                                return Err(format!("Failed to deserialize response: {}", e));
-                            }
-                        };
+        Ok(backend_response)
+    }
 
-                    // Extract text content and tool calls from JSON
-                    let mut content = String::new();
-                    let mut tool_calls = Vec::new();
-
-                    // Process each content block from Claude response
-                    for block in claude_response.content.iter() {
-                        match block.content_type.as_str() {
-                            "text" => {
-                                if let Some(text) = &block.text {
-                                    content.push_str(text);
-                                    content.push('\n');
-                                }
-                            }
-                            "tool_use" => {
-                                // Extract tool call directly from JSON
-                                if let (Some(id), Some(name), Some(input)) =
-                                    (&block.id, &block.name, &block.input)
-                                {
-                                    // Log the exact Claude-provided tool_use ID for tracking
-                                    trace!("Received tool_use with ID '{}' from Claude API", id);
+    // The extract_tool_results function has been replaced by parse_conversation_history
+}
 
-                                    tool_calls.push(ToolUse {
-                                        name: name.clone(),
-                                        args: input.clone(),
-                                        id: Some(id.clone()), // Store exactly as received - must not be modified
-                                    });
-                                }
-                            }
-                            _ => {
-                                // Ignore other content types
-                                warn!("Ignoring content block with type: {}", block.content_type);
-                            }
-                        }
-                    }
+/// An error the Bedrock API reports for a request or during its response stream
+trait ApiError: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static {
+    /// The backend error this is, if it is one of the errors the API models
+    fn backend_error(&self) -> Option<BackendError>;
+}
 
-                    // Add text representation of tool calls for backward compatibility
-                    // This will be removed in a future version once transition is complete
-                    for tool_call in tool_calls.iter() {
-                        let tool_json = self
-                            .pretty_print_json(&tool_call.args)
-                            .unwrap_or_else(|_| "{}".to_string());
-
-                        // Include the original tool_use_id in the formatted tool call
-                        let formatted_tool_call = if let Some(id) = &tool_call.id {
-                            trace!(
-                                "Including original tool_use_id '{}' in formatted tool call",
-                                id
-                            );
-                            format!(
-                                "<tool name=\"{}\" id=\"{}\">\n{}\n</tool>",
-                                tool_call.name,
-                                id, // Include the exact original ID
-                                tool_json
-                            )
-                        } else {
-                            warn!("No ID available for tool call, response validation may fail");
-                            format!("<tool name=\"{}\">\n{}\n</tool>", tool_call.name, tool_json)
-                        };
+impl ApiError for InvokeModelWithResponseStreamError {
+    fn backend_error(&self) -> Option<BackendError> {
+        let message = self.to_string();
+        Some(match self {
+            Self::AccessDeniedException(_) => BackendError::AccessDenied(message),
+            Self::InternalServerException(_)
+            | Self::ServiceUnavailableException(_)
+            | Self::ModelStreamErrorException(_) => BackendError::Service(message),
+            Self::ModelNotReadyException(_) => BackendError::ModelNotReady(message),
+            Self::ModelTimeoutException(_) => BackendError::Timeout(message),
+            Self::ResourceNotFoundException(_) => BackendError::ModelNotFound(message),
+            Self::ServiceQuotaExceededException(_) => BackendError::QuotaExceeded(message),
+            Self::ThrottlingException(_) => BackendError::Throttled(message),
+            Self::ValidationException(_) => BackendError::InvalidRequest(message),
+            _ => return None,
+        })
+    }
+}
 
-                        content.push_str(&formatted_tool_call);
-                        content.push('\n');
-                    }
+impl ApiError for ResponseStreamError {
+    fn backend_error(&self) -> Option<BackendError> {
+        let message = self.to_string();
+        Some(match self {
+            Self::InternalServerException(_)
+            | Self::ServiceUnavailableException(_)
+            | Self::ModelStreamErrorException(_) => BackendError::Service(message),
+            Self::ModelTimeoutException(_) => BackendError::Timeout(message),
+            Self::ThrottlingException(_) => BackendError::Throttled(message),
+            Self::ValidationException(_) => BackendError::InvalidRequest(message),
+            _ => return None,
+        })
+    }
+}
 
-                    // Log minimal info about processed results
-                    trace!(
-                        "Processed {} content blocks with {} tool calls",
-                        claude_response.content.len(),
-                        tool_calls.len()
-                    );
-
-                    // Build response with tool calls directly included
-                    return Ok(BackendResponse {
-                        content,
-                        model: claude_response.model,
-                        tokens_used: Some(
-                            claude_response.usage.input_tokens
-                                + claude_response.usage.output_tokens,
-                        ),
-                        tool_calls,
-                    });
-                }
-                Err(err) => {
-                    let error_msg = self.parse_error(err);
-                    error!("API call failed: {}", error_msg);
-                    last_error = Some(error_msg);
-                    retries += 1;
-                }
+/// Whether an SDK error was caused by missing, expired or unusable credentials
+fn is_credentials_problem(details: &str) -> bool {
+    let details = details.to_lowercase();
+    ["credential", "sso", "expired", "security token"]
+        .iter()
+        .any(|marker| details.contains(marker))
+}
+
+impl BackendCore for BedrockBackend {
+    fn name(&self) -> &'static str {
+        "AWS Bedrock"
+    }
+
+    fn context_window(&self) -> usize {
+        self.current_model.context_window
+    }
+}
+
+#[async_trait]
+impl Backend for BedrockBackend {
+    async fn init(&mut self) -> Result<(), BackendError> {
+        // Single initialization log with key details
+        let profile_info = if let Some(profile) = &self.config.profile_name {
+            format!("profile '{}' in region '{}'", profile, self.config.region)
+        } else {
+            format!("default profile in region '{}'", self.config.region)
+        };
+
+        info!("Initializing AWS Bedrock client with {}", profile_info);
+
+        // Configure AWS client
+        let aws_config = if self.config.use_profile {
+            let mut builder = aws_config::defaults(BehaviorVersion::latest());
+
+            if let Some(profile) = &self.config.profile_name {
+                builder = builder.profile_name(profile);
             }
-        }
 
-        // If we get here, all retries failed
-        let error_msg =
-            last_error.unwrap_or_else(|| "Unknown error calling Bedrock API".to_string());
-        error!(
-            "Failed to call Bedrock API after {} retries: {}",
-            self.config.max_retries, error_msg
-        );
-        Err(error_msg)
+            builder = builder.region(aws_config::Region::new(self.config.region.clone()));
+            builder.load().await
+        } else {
+            aws_config::defaults(BehaviorVersion::latest())
+                .region(aws_config::Region::new(self.config.region.clone()))
+                .load()
+                .await
+        };
+
+        // Create and store client; failed requests are retried by the agent's
+        // retry policy, so the SDK does not retry them as well
+        let client_config = aws_sdk_bedrockruntime::config::Builder::from(&aws_config)
+            .retry_config(RetryConfig::disabled())
+            .build();
+        let client = aws_sdk_bedrockruntime::Client::from_conf(client_config);
+        self.client = Some(Arc::new(client));
+
+        trace!("AWS Bedrock client initialized successfully");
+        Ok(())
+    }
+
+    async fn generate_response(&self, prompt: &str) -> Result<BackendResponse, BackendError> {
+        self.stream_response(prompt, &|_| {}).await
+    }
+
+    async fn stream_response(
+        &self,
+        prompt: &str,
+        on_text: &(dyn for<'a> Fn(&'a str) + Send + Sync),
+    ) -> Result<BackendResponse, BackendError> {
+        let request = self.construct_claude_request(prompt, Some(&self.config.enabled_tools))?;
+        self.send_request(request, on_text).await
+    }
+
+    async fn generate_text(&self, prompt: &str) -> Result<BackendResponse, BackendError> {
+        let request = self.construct_claude_request(prompt, None)?;
+        self.send_request(request, &|_| {}).await
+    }
+
+    fn current_model(&self) -> &ModelSpec {
+        &self.current_model
+    }
+
+    fn switch_model(&mut self, model: ModelSpec) {
+        self.current_model = model;
+    }
+
+    fn current_model_name(&self) -> &str {
+        &self.current_model.id
+    }
+
+    fn set_enabled_tools(&mut self, tools: Vec<String>) {
+        self.config.enabled_tools = tools;
     }
 }
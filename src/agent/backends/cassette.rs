@@ -0,0 +1,683 @@
+use crate::agent::backends::{
+    Backend, BackendCore, BackendError, BackendResponse, BedrockBackend, BedrockConfig, ModelSpec,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{trace, warn};
+
+/// Recorded Bedrock exchanges, stored as JSON
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    /// Exchanges in the order they happened
+    pub interactions: Vec<Interaction>,
+}
+
+/// A single request sent to Bedrock and the response it returned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    /// Model ID the request was sent to
+    pub model: String,
+
+    /// Request body
+    pub request: Value,
+
+    /// Response body
+    pub response: Value,
+}
+
+impl Cassette {
+    /// Load a cassette from a file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read cassette {}: {}", path.display(), e))?;
+        serde_json::from_str(&text)
+            .map_err(|e| format!("Failed to parse cassette {}: {}", path.display(), e))
+    }
+
+    /// Write the cassette to a file, creating its directory if needed
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        let text = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Failed to serialize cassette: {}", e))?;
+        fs::write(path, text)
+            .map_err(|e| format!("Failed to write cassette {}: {}", path.display(), e))
+    }
+}
+
+/// Appends exchanges to a cassette file as they happen
+pub struct CassetteRecorder {
+    /// File the cassette is written to
+    path: PathBuf,
+
+    /// Exchanges recorded so far
+    cassette: Mutex<Cassette>,
+}
+
+impl CassetteRecorder {
+    /// Start a new recording, replacing any cassette already at `path`
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            cassette: Mutex::new(Cassette::default()),
+        }
+    }
+
+    /// Record an exchange and save the cassette
+    ///
+    /// Recording is best effort: failures are logged and never fail the request.
+    pub fn record(&self, model: &str, request: &str, response: &str) {
+        let (request, response) = match (
+            serde_json::from_str(request),
+            serde_json::from_str(response),
+        ) {
+            (Ok(request), Ok(response)) => (request, response),
+            _ => {
+                warn!("Not recording exchange with a body that is not JSON");
+                return;
+            }
+        };
+
+        let mut cassette = self.cassette.lock().unwrap();
+        cassette.interactions.push(Interaction {
+            model: model.to_string(),
+            request,
+            response,
+        });
+        match cassette.save(&self.path) {
+            Ok(()) => trace!(
+                "Recorded interaction {} to {}",
+                cassette.interactions.len(),
+                self.path.display()
+            ),
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
+
+/// Backend that replays a recorded cassette instead of calling Bedrock
+///
+/// Requests are built exactly as the Bedrock backend builds them and their
+/// messages, tools and thinking settings must match the recorded requests in
+/// order, so a replay checks context formatting and request construction as
+/// well as response handling.
+pub struct ReplayBackend {
+    /// Cassette file to replay
+    path: PathBuf,
+
+    /// Bedrock backend used to build requests and parse responses (never connected)
+    bedrock: BedrockBackend,
+
+    /// Interactions loaded from the cassette
+    cassette: Cassette,
+
+    /// Index of the next interaction to replay
+    next: Mutex<usize>,
+}
+
+impl ReplayBackend {
+    /// Create a backend that replays the cassette at `path` (loaded by `init()`)
+    pub fn new(path: PathBuf, config: BedrockConfig) -> Self {
+        Self {
+            path,
+            bedrock: BedrockBackend::with_config(BedrockConfig {
+                record_cassette: None,
+                ..config
+            }),
+            cassette: Cassette::default(),
+            next: Mutex::new(0),
+        }
+    }
+
+    /// Check a request against the next interaction and return its recorded response
+    fn replay(&self, request: &Value) -> Result<BackendResponse, BackendError> {
+        let request = replayed_fields(request);
+
+        let index = {
+            let mut next = self.next.lock().unwrap();
+            *next += 1;
+            *next - 1
+        };
+        let interaction = self.cassette.interactions.get(index).ok_or_else(|| {
+            BackendError::Replay(format!(
+                "Cassette {} has no interaction {}",
+                self.path.display(),
+                index + 1
+            ))
+        })?;
+
+        if interaction.model != self.bedrock.current_model_name() {
+            return Err(BackendError::Replay(format!(
+                "Request {} was sent to {} but the cassette expects {}",
+                index + 1,
+                self.bedrock.current_model_name(),
+                interaction.model
+            )));
+        }
+        let expected = replayed_fields(&interaction.request);
+        if expected != request {
+            return Err(BackendError::Replay(format!(
+                "Request {} does not match cassette {} (differs in: {})",
+                index + 1,
+                self.path.display(),
+                differing_fields(&expected, &request).join(", ")
+            )));
+        }
+
+        self.bedrock
+            .parse_response_body(&interaction.response.to_string())
+    }
+}
+
+/// Request fields a replay compares
+///
+/// The system prompt, sampling settings and cache markers can change without
+/// changing what a recorded response answers, so cassettes do not have to be
+/// recorded again when they do.
+const REPLAYED_FIELDS: &[&str] = &["messages", "tools", "thinking"];
+
+/// The parts of a request body a replay compares, without cache markers
+fn replayed_fields(request: &Value) -> Value {
+    let mut fields = serde_json::Map::new();
+    for name in REPLAYED_FIELDS {
+        if let Some(value) = request.get(*name) {
+            let mut value = value.clone();
+            remove_cache_markers(&mut value);
+            fields.insert(name.to_string(), value);
+        }
+    }
+    Value::Object(fields)
+}
+
+/// Remove every `cache_control` marker from a request value
+fn remove_cache_markers(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            object.remove("cache_control");
+            object.values_mut().for_each(remove_cache_markers);
+        }
+        Value::Array(items) => items.iter_mut().for_each(remove_cache_markers),
+        _ => {}
+    }
+}
+
+/// Names of the top-level request fields that differ between two requests
+fn differing_fields(expected: &Value, actual: &Value) -> Vec<String> {
+    let (expected, actual) = match (expected.as_object(), actual.as_object()) {
+        (Some(expected), Some(actual)) => (expected, actual),
+        _ => return vec!["<body>".to_string()],
+    };
+
+    let mut fields: Vec<String> = expected
+        .keys()
+        .chain(actual.keys())
+        .filter(|key| expected.get(*key) != actual.get(*key))
+        .cloned()
+        .collect();
+    fields.sort();
+    fields.dedup();
+    fields
+}
+
+impl BackendCore for ReplayBackend {
+    fn name(&self) -> &'static str {
+        "Cassette Replay"
+    }
+
+    fn context_window(&self) -> usize {
+        self.bedrock.context_window()
+    }
+}
+
+#[async_trait]
+impl Backend for ReplayBackend {
+    async fn init(&mut self) -> Result<(), BackendError> {
+        self.cassette = Cassette::load(&self.path).map_err(BackendError::Replay)?;
+        *self.next.lock().unwrap() = 0;
+        trace!(
+            "Loaded cassette {} with {} interactions",
+            self.path.display(),
+            self.cassette.interactions.len()
+        );
+        Ok(())
+    }
+
+    async fn generate_response(&self, prompt: &str) -> Result<BackendResponse, BackendError> {
+        self.replay(&self.bedrock.request_body(prompt)?)
+    }
+
+    async fn generate_text(&self, prompt: &str) -> Result<BackendResponse, BackendError> {
+        self.replay(&self.bedrock.text_request_body(prompt)?)
+    }
+
+    fn current_model(&self) -> &ModelSpec {
+        self.bedrock.current_model()
+    }
+
+    fn switch_model(&mut self, model: ModelSpec) {
+        self.bedrock.switch_model(model);
+    }
+
+    fn current_model_name(&self) -> &str {
+        self.bedrock.current_model_name()
+    }
+
+    fn set_enabled_tools(&mut self, tools: Vec<String>) {
+        self.bedrock.set_enabled_tools(tools);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::backends::{ModelCatalog, reasoning_text};
+    use crate::agent::manager::{AgentConfig, AgentManager, ToolChainConfig};
+    use crate::agent::tools::{ReadFileTool, ViewImageTool};
+    use serde_json::json;
+    use std::collections::VecDeque;
+
+    /// System prompt the tests run with, free of the date and paths of the machine
+    const SYSTEM_PROMPT: &str =
+        "You are a helpful assistant with access to tools that can run on the user's computer.";
+
+    fn fixtures() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+    }
+
+    async fn agent(backend: Box<dyn Backend>) -> AgentManager {
+        agent_with(ModelCatalog::builtin(), SYSTEM_PROMPT, backend).await
+    }
+
+    async fn agent_with(
+        catalog: ModelCatalog,
+        system_prompt: &str,
+        backend: Box<dyn Backend>,
+    ) -> AgentManager {
+        let config = AgentConfig {
+            auto_compress_context: false,
+            tool_chain: ToolChainConfig {
+                max_depth: 5,
+                delay_ms: 0,
+                ..ToolChainConfig::default()
+            },
+            catalog,
+            system_prompt: system_prompt.to_string(),
+            project_instructions: false,
+            ..AgentConfig::default()
+        };
+        let mut agent = AgentManager::with_backend(config, backend);
+        agent.register_tool(Box::new(ReadFileTool));
+        agent.register_tool(Box::new(ViewImageTool));
+        agent.set_working_directory(fixtures().join("workspace").to_str().unwrap());
+        agent.init().await.unwrap();
+        agent
+    }
+
+    fn replay(name: &str) -> Box<dyn Backend> {
+        let path = fixtures().join("cassettes").join(name);
+        Box::new(ReplayBackend::new(path, BedrockConfig::default()))
+    }
+
+    /// The builtin catalog, with the default model thinking before it answers
+    fn thinking_catalog() -> ModelCatalog {
+        let mut catalog = ModelCatalog::builtin();
+        let mut sonnet = catalog.get("sonnet").unwrap().clone();
+        sonnet.thinking_budget = 1024;
+        catalog.insert(sonnet);
+        catalog
+    }
+
+    /// Backend that answers with scripted response bodies and records each exchange
+    ///
+    /// The fixture cassettes are synthetic: their requests are built by the
+    /// Bedrock backend exactly as it would send them, but their responses are
+    /// written in `fixture_cassettes` rather than returned by Bedrock.
+    struct ScriptedBackend {
+        bedrock: BedrockBackend,
+        responses: Mutex<VecDeque<Value>>,
+        recorder: CassetteRecorder,
+    }
+
+    impl ScriptedBackend {
+        /// Answer a request with the next scripted response, recording the exchange
+        fn answer(&self, request: Value) -> Result<BackendResponse, BackendError> {
+            let response = self
+                .responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap()
+                .to_string();
+            self.recorder.record(
+                self.bedrock.current_model_name(),
+                &request.to_string(),
+                &response,
+            );
+            self.bedrock.parse_response_body(&response)
+        }
+    }
+
+    impl BackendCore for ScriptedBackend {
+        fn name(&self) -> &'static str {
+            "Scripted"
+        }
+
+        fn context_window(&self) -> usize {
+            self.bedrock.context_window()
+        }
+    }
+
+    #[async_trait]
+    impl Backend for ScriptedBackend {
+        async fn init(&mut self) -> Result<(), BackendError> {
+            Ok(())
+        }
+
+        async fn generate_response(&self, prompt: &str) -> Result<BackendResponse, BackendError> {
+            self.answer(self.bedrock.request_body(prompt)?)
+        }
+
+        async fn generate_text(&self, prompt: &str) -> Result<BackendResponse, BackendError> {
+            self.answer(self.bedrock.text_request_body(prompt)?)
+        }
+
+        fn current_model(&self) -> &ModelSpec {
+            self.bedrock.current_model()
+        }
+
+        fn switch_model(&mut self, model: ModelSpec) {
+            self.bedrock.switch_model(model);
+        }
+
+        fn current_model_name(&self) -> &str {
+            self.bedrock.current_model_name()
+        }
+
+        fn set_enabled_tools(&mut self, tools: Vec<String>) {
+            self.bedrock.set_enabled_tools(tools);
+        }
+    }
+
+    /// A fixture cassette: the input it answers and the responses it scripts
+    struct Fixture {
+        name: &'static str,
+        catalog: ModelCatalog,
+        input: &'static str,
+        responses: Vec<Value>,
+    }
+
+    /// A scripted response body in the form Bedrock returns
+    fn response(id: &str, content: Value, stop_reason: &str, usage: (usize, usize)) -> Value {
+        json!({
+            "id": id,
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-3-7-sonnet-20250219",
+            "content": content,
+            "stop_reason": stop_reason,
+            "stop_sequence": null,
+            "usage": {"input_tokens": usage.0, "output_tokens": usage.1},
+        })
+    }
+
+    /// The fixture cassettes in tests/fixtures/cassettes
+    fn fixture_cassettes() -> Vec<Fixture> {
+        let read_hello = json!({
+            "type": "tool_use",
+            "id": "toolu_01",
+            "name": "read_file",
+            "input": {"path": "hello.txt"},
+        });
+        vec![
+            Fixture {
+                name: "read_file.json",
+                catalog: ModelCatalog::builtin(),
+                input: "What does hello.txt say?",
+                responses: vec![
+                    response(
+                        "msg_bdrk_01",
+                        json!([{"type": "text", "text": "I'll read the file."}, read_hello]),
+                        "tool_use",
+                        (842, 71),
+                    ),
+                    response(
+                        "msg_bdrk_02",
+                        json!([{"type": "text", "text": "The file says hello."}]),
+                        "end_turn",
+                        (935, 12),
+                    ),
+                ],
+            },
+            Fixture {
+                name: "thinking.json",
+                catalog: thinking_catalog(),
+                input: "What does hello.txt say?",
+                responses: vec![
+                    response(
+                        "msg_bdrk_thinking_01",
+                        json!([
+                            {
+                                "type": "thinking",
+                                "thinking": "The user is asking about hello.txt, so I should read it before answering.",
+                                "signature": "RXhhbXBsZVNpZ25hdHVyZTAx",
+                            },
+                            {"type": "text", "text": "I'll read the file."},
+                            read_hello,
+                        ]),
+                        "tool_use",
+                        (842, 71),
+                    ),
+                    response(
+                        "msg_bdrk_thinking_02",
+                        json!([
+                            {
+                                "type": "thinking",
+                                "thinking": "The tool returned the file. It greets the fixture workspace.",
+                                "signature": "RXhhbXBsZVNpZ25hdHVyZTAy",
+                            },
+                            {"type": "text", "text": "The file says hello."},
+                        ]),
+                        "end_turn",
+                        (935, 12),
+                    ),
+                ],
+            },
+            Fixture {
+                name: "view_image.json",
+                catalog: ModelCatalog::builtin(),
+                input: "What is in red.png?",
+                responses: vec![
+                    response(
+                        "msg_bdrk_11",
+                        json!([
+                            {"type": "text", "text": "I'll look at the image."},
+                            {
+                                "type": "tool_use",
+                                "id": "toolu_11",
+                                "name": "view_image",
+                                "input": {"path": "red.png"},
+                            },
+                        ]),
+                        "tool_use",
+                        (903, 68),
+                    ),
+                    response(
+                        "msg_bdrk_12",
+                        json!([{"type": "text", "text": "The image is a solid red square."}]),
+                        "end_turn",
+                        (990, 12),
+                    ),
+                ],
+            },
+        ]
+    }
+
+    /// Run a fixture's conversation and record it to a cassette at `path`
+    async fn record_fixture(fixture: &Fixture, path: &Path) {
+        let backend = ScriptedBackend {
+            bedrock: BedrockBackend::with_config(BedrockConfig::default()),
+            responses: Mutex::new(fixture.responses.iter().cloned().collect()),
+            recorder: CassetteRecorder::new(path.to_path_buf()),
+        };
+        let mut agent = agent_with(fixture.catalog.clone(), SYSTEM_PROMPT, Box::new(backend)).await;
+        agent.process_input(fixture.input).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "rewrites tests/fixtures/cassettes"]
+    async fn record_fixture_cassettes() {
+        for fixture in fixture_cassettes() {
+            record_fixture(&fixture, &fixtures().join("cassettes").join(fixture.name)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn fixture_cassettes_are_up_to_date() {
+        let dir = std::env::temp_dir().join(format!("gamecode-fixtures-{}", uuid::Uuid::new_v4()));
+        for fixture in fixture_cassettes() {
+            let path = dir.join(fixture.name);
+            record_fixture(&fixture, &path).await;
+
+            let recorded = serde_json::to_value(Cassette::load(&path).unwrap()).unwrap();
+            let committed = Cassette::load(&fixtures().join("cassettes").join(fixture.name));
+            assert_eq!(
+                serde_json::to_value(committed.unwrap()).unwrap(),
+                recorded,
+                "{} is out of date; run `cargo test record_fixture_cassettes -- --ignored`",
+                fixture.name
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_a_tool_turn() {
+        let mut agent = agent(replay("read_file.json")).await;
+
+        let response = agent
+            .process_input("What does hello.txt say?")
+            .await
+            .unwrap();
+
+        assert_eq!(response.rounds, 1);
+        assert_eq!(response.tool_results.len(), 1);
+        assert_eq!(response.tool_results[0].tool_name, "read_file");
+        assert_eq!(
+            response.tool_results[0].result,
+            "Hello from the fixture workspace!\nSecond line.\n"
+        );
+        assert!(response.content.contains("The file says hello."));
+    }
+
+    #[tokio::test]
+    async fn mismatched_requests_are_reported() {
+        let mut agent = agent(replay("read_file.json")).await;
+
+        let error = agent
+            .process_input("Something else")
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error.contains("Request 1 does not match"), "{}", error);
+        assert!(error.contains("messages"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn system_prompt_changes_do_not_affect_replays() {
+        let mut agent = agent_with(
+            ModelCatalog::builtin(),
+            "A prompt the cassette was not recorded with.",
+            replay("read_file.json"),
+        )
+        .await;
+
+        let response = agent
+            .process_input("What does hello.txt say?")
+            .await
+            .unwrap();
+
+        assert!(response.content.contains("The file says hello."));
+    }
+
+    #[tokio::test]
+    async fn running_past_the_cassette_is_an_error() {
+        let mut agent = agent(replay("read_file.json")).await;
+        agent
+            .process_input("What does hello.txt say?")
+            .await
+            .unwrap();
+
+        let error = agent
+            .process_input("And then?")
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error.contains("has no interaction 3"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn viewed_images_go_back_as_image_blocks() {
+        let mut agent = agent(replay("view_image.json")).await;
+
+        let response = agent.process_input("What is in red.png?").await.unwrap();
+
+        assert_eq!(response.tool_results.len(), 1);
+        assert!(response.tool_results[0].image);
+        assert!(response.content.contains("solid red square"));
+    }
+
+    #[tokio::test]
+    async fn reasoning_goes_back_with_tool_results() {
+        let mut agent =
+            agent_with(thinking_catalog(), SYSTEM_PROMPT, replay("thinking.json")).await;
+
+        let response = agent
+            .process_input("What does hello.txt say?")
+            .await
+            .unwrap();
+
+        assert_eq!(response.rounds, 1);
+        assert!(response.content.contains("The file says hello."));
+        assert!(reasoning_text(&response.content).contains("fixture workspace"));
+    }
+
+    #[tokio::test]
+    async fn recordings_replay() {
+        let source = Cassette::load(&fixtures().join("cassettes/read_file.json")).unwrap();
+        let path = std::env::temp_dir()
+            .join(format!("gamecode-cassette-{}", uuid::Uuid::new_v4()))
+            .join("recorded.json");
+
+        let recorder = CassetteRecorder::new(path.clone());
+        for interaction in &source.interactions {
+            recorder.record(
+                &interaction.model,
+                &interaction.request.to_string(),
+                &interaction.response.to_string(),
+            );
+        }
+
+        let mut agent = agent(Box::new(ReplayBackend::new(
+            path.clone(),
+            BedrockConfig::default(),
+        )))
+        .await;
+        let response = agent
+            .process_input("What does hello.txt say?")
+            .await
+            .unwrap();
+        assert!(response.content.contains("The file says hello."));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+// Models the agent can use, described by data rather than code
+
+use crate::agent::usage::ModelPrice;
+use serde::{Deserialize, Serialize};
+
+/// Features a model supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelCapabilities {
+    /// Whether the model can be offered tools
+    pub tools: bool,
+
+    /// Whether the model accepts images
+    pub vision: bool,
+
+    /// Whether the model supports extended thinking
+    pub thinking: bool,
+
+    /// Whether the model supports prompt caching
+    pub prompt_caching: bool,
+}
+
+/// A model in the catalog
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelSpec {
+    /// Name the model is picked by in configuration, sessions and the UI
+    pub name: String,
+
+    /// Name shown to the user
+    pub display_name: String,
+
+    /// Model ID or inference profile requests are sent to
+    pub id: String,
+
+    /// Context window, in tokens
+    pub context_window: usize,
+
+    /// Most tokens requested for a response
+    pub max_output_tokens: usize,
+
+    /// Temperature used unless a request asks for another
+    pub temperature: f32,
+
+    /// Price of the model's tokens
+    pub price: ModelPrice,
+
+    /// Features the model supports
+    pub capabilities: ModelCapabilities,
+
+    /// Model a turn moves to when this one is repeatedly throttled, by name
+    pub fallback: Option<String>,
+
+    /// Output tokens the model may spend reasoning before it answers, if
+    /// it supports extended thinking (zero turns thinking off)
+    pub thinking_budget: usize,
+}
+
+/// The models available to the agent, in the order they are offered
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelCatalog {
+    models: Vec<ModelSpec>,
+}
+
+/// What a model is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelRole {
+    /// Answering the user and running tools
+    Primary,
+
+    /// Summarizing older turns when the context is compacted
+    Summarization,
+
+    /// Naming new sessions
+    Title,
+}
+
+/// Names of the models picked for each role
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelSelection {
+    /// Model used for primary interactions
+    pub primary: String,
+
+    /// Model used to summarize older turns
+    pub summarization: String,
+
+    /// Model used to title sessions
+    pub title: String,
+}
+
+impl Default for ModelCapabilities {
+    fn default() -> Self {
+        Self {
+            tools: true,
+            vision: false,
+            thinking: false,
+            prompt_caching: false,
+        }
+    }
+}
+
+impl ModelSpec {
+    /// Tokens the model may spend thinking before it answers, if it thinks at all
+    pub fn thinking(&self) -> Option<usize> {
+        Some(self.thinking_budget).filter(|&budget| budget > 0 && self.capabilities.thinking)
+    }
+}
+
+impl ModelCatalog {
+    /// The models known without any configuration
+    pub fn builtin() -> Self {
+        Self {
+            models: vec![
+                ModelSpec {
+                    name: "sonnet".to_string(),
+                    display_name: "Claude 3.7 Sonnet".to_string(),
+                    id: "us.anthropic.claude-3-7-sonnet-20250219-v1:0".to_string(),
+                    context_window: 200_000,
+                    max_output_tokens: 4096,
+                    temperature: 0.7,
+                    price: ModelPrice {
+                        input: 3.0,
+                        output: 15.0,
+                        cache_read: 0.3,
+                        cache_write: 3.75,
+                    },
+                    capabilities: ModelCapabilities {
+                        tools: true,
+                        vision: true,
+                        thinking: true,
+                        prompt_caching: true,
+                    },
+                    fallback: None,
+                    thinking_budget: 0,
+                },
+                ModelSpec {
+                    name: "haiku".to_string(),
+                    display_name: "Claude 3.5 Haiku".to_string(),
+                    id: "anthropic.claude-3-5-haiku-20240307-v1:0".to_string(),
+                    context_window: 200_000,
+                    max_output_tokens: 4096,
+                    temperature: 0.3,
+                    price: ModelPrice {
+                        input: 0.8,
+                        output: 4.0,
+                        cache_read: 0.08,
+                        cache_write: 1.0,
+                    },
+                    capabilities: ModelCapabilities {
+                        tools: true,
+                        vision: false,
+                        thinking: false,
+                        prompt_caching: true,
+                    },
+                    fallback: None,
+                    thinking_budget: 0,
+                },
+            ],
+        }
+    }
+
+    /// Every model, in the order they are offered
+    pub fn models(&self) -> &[ModelSpec] {
+        &self.models
+    }
+
+    /// Look up a model by name, ignoring case
+    pub fn get(&self, name: &str) -> Option<&ModelSpec> {
+        self.models
+            .iter()
+            .find(|model| model.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Names of every model, for messages listing the choices
+    pub fn names(&self) -> Vec<&str> {
+        self.models
+            .iter()
+            .map(|model| model.name.as_str())
+            .collect()
+    }
+
+    /// Add a model, replacing any model with the same name
+    pub fn insert(&mut self, model: ModelSpec) {
+        match self.models.iter_mut().find(|m| m.name == model.name) {
+            Some(existing) => *existing = model,
+            None => self.models.push(model),
+        }
+    }
+
+    /// Model used when nothing else is selected
+    pub fn default_model(&self) -> &ModelSpec {
+        &self.models[0]
+    }
+}
+
+impl Default for ModelCatalog {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl ModelRole {
+    /// Every role, in the order they are shown
+    pub const ALL: [ModelRole; 3] = [
+        ModelRole::Primary,
+        ModelRole::Summarization,
+        ModelRole::Title,
+    ];
+
+    /// Name of the role shown to the user
+    pub fn label(&self) -> &'static str {
+        match self {
+            ModelRole::Primary => "Primary",
+            ModelRole::Summarization => "Summarization",
+            ModelRole::Title => "Titles",
+        }
+    }
+}
+
+impl ModelSelection {
+    /// Name of the model picked for a role
+    pub fn get(&self, role: ModelRole) -> &str {
+        match role {
+            ModelRole::Primary => &self.primary,
+            ModelRole::Summarization => &self.summarization,
+            ModelRole::Title => &self.title,
+        }
+    }
+
+    /// Pick the model for a role
+    pub fn set(&mut self, role: ModelRole, name: String) {
+        match role {
+            ModelRole::Primary => self.primary = name,
+            ModelRole::Summarization => self.summarization = name,
+            ModelRole::Title => self.title = name,
+        }
+    }
+}
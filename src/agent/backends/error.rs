@@ -0,0 +1,96 @@
+// Failures of requests to a model backend
+
+use thiserror::Error;
+
+/// Why a request to the backend failed
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum BackendError {
+    /// The backend was used before `init()`
+    #[error("{0} client not initialized. Call init() first.")]
+    NotInitialized(&'static str),
+
+    /// Credentials are missing, expired or were rejected
+    #[error("AWS credentials are missing or expired: {0}")]
+    Credentials(String),
+
+    /// The credentials are valid but may not use the model
+    #[error("Access denied: {0}")]
+    AccessDenied(String),
+
+    /// The model ID or inference profile does not exist in the region
+    #[error("Model not found: {0}")]
+    ModelNotFound(String),
+
+    /// The request was rejected as malformed
+    #[error("Validation error: {0}")]
+    InvalidRequest(String),
+
+    /// Too many requests in a short time
+    #[error("Throttling error: {0}")]
+    Throttled(String),
+
+    /// The account's quota for the model is used up
+    #[error("Service quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    /// The model is still being prepared
+    #[error("Model not ready: {0}")]
+    ModelNotReady(String),
+
+    /// The request or the model took too long
+    #[error("Timeout error: {0}")]
+    Timeout(String),
+
+    /// The service could not be reached
+    #[error("Network error: {0}")]
+    Network(String),
+
+    /// The service failed while handling the request
+    #[error("Service error: {0}")]
+    Service(String),
+
+    /// A request could not be built, or a response could not be read
+    #[error("{0}")]
+    Serialization(String),
+
+    /// A recorded cassette could not be played back
+    #[error("{0}")]
+    Replay(String),
+}
+
+impl BackendError {
+    /// Whether sending the same request again may succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            BackendError::Throttled(_)
+                | BackendError::ModelNotReady(_)
+                | BackendError::Timeout(_)
+                | BackendError::Network(_)
+                | BackendError::Service(_)
+        )
+    }
+
+    /// What the user can do about the failure, if anything
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            BackendError::Credentials(_) => {
+                Some("Refresh your AWS login (for SSO profiles run `aws sso login`) and try again.")
+            }
+            BackendError::AccessDenied(_) => Some(
+                "Check that access to the model is enabled in the Bedrock console and that \
+                your IAM policy allows bedrock:InvokeModelWithResponseStream.",
+            ),
+            BackendError::ModelNotFound(_) => Some(
+                "Check the model's id in the catalog and that it is offered in the configured region.",
+            ),
+            BackendError::QuotaExceeded(_) => {
+                Some("Request a quota increase for the model or switch to another model.")
+            }
+            BackendError::Throttled(_) => {
+                Some("Wait a moment before retrying, or raise tool_chain.delay_ms.")
+            }
+            _ => None,
+        }
+    }
+}
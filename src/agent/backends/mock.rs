@@ -0,0 +1,245 @@
+use crate::agent::backends::{
+    Backend, BackendCore, BackendError, BackendResponse, ModelCatalog, ModelSpec, StopReason,
+    TokenUsage, ToolUse, reasoning_line,
+};
+use crate::agent::manager::display_text;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Backend that replays a fixed script of responses
+///
+/// Each call to `generate_response` takes the next scripted response, and
+/// every prompt received is recorded so tests can inspect what was sent.
+pub struct MockBackend {
+    /// Responses still to be returned, in order
+    script: Mutex<VecDeque<Result<BackendResponse, BackendError>>>,
+
+    /// Prompts received so far
+    prompts: Arc<Mutex<Vec<String>>>,
+
+    /// Output token limit of the model each prompt was sent to
+    output_limits: Arc<Mutex<Vec<usize>>>,
+
+    /// Tools offered to the model
+    enabled_tools: Arc<Mutex<Vec<String>>>,
+
+    /// Whether each prompt was sent with the enabled tools offered
+    tools_offered: Arc<Mutex<Vec<bool>>>,
+
+    /// Currently selected model
+    current_model: ModelSpec,
+}
+
+impl MockBackend {
+    /// Create a mock backend with an empty script
+    pub fn new() -> Self {
+        Self {
+            script: Mutex::new(VecDeque::new()),
+            prompts: Arc::new(Mutex::new(Vec::new())),
+            output_limits: Arc::new(Mutex::new(Vec::new())),
+            enabled_tools: Arc::new(Mutex::new(Vec::new())),
+            tools_offered: Arc::new(Mutex::new(Vec::new())),
+            current_model: ModelCatalog::builtin().default_model().clone(),
+        }
+    }
+
+    /// Script a plain text response
+    pub fn respond_with_text(self, text: &str) -> Self {
+        self.respond_with_tools(text, Vec::new())
+    }
+
+    /// Script a response that asks for the given tool calls
+    ///
+    /// The calls are also written into the content using the `<tool>` markup
+    /// the Bedrock backend produces, so they end up in the context the same way.
+    pub fn respond_with_tools(self, text: &str, tool_calls: Vec<ToolUse>) -> Self {
+        let mut content = String::new();
+        if !text.is_empty() {
+            content.push_str(text);
+            content.push('\n');
+        }
+        for tool_call in &tool_calls {
+            let args =
+                serde_json::to_string_pretty(&tool_call.args).unwrap_or_else(|_| "{}".to_string());
+            match &tool_call.id {
+                Some(id) => content.push_str(&format!(
+                    "<tool name=\"{}\" id=\"{}\">\n{}\n</tool>\n",
+                    tool_call.name, id, args
+                )),
+                None => content.push_str(&format!(
+                    "<tool name=\"{}\">\n{}\n</tool>\n",
+                    tool_call.name, args
+                )),
+            }
+        }
+
+        let stop_reason = if tool_calls.is_empty() {
+            StopReason::EndTurn
+        } else {
+            StopReason::ToolUse
+        };
+        self.push(Ok(BackendResponse {
+            content,
+            model: "mock".to_string(),
+            usage: None,
+            tool_calls,
+            stop_reason,
+        }))
+    }
+
+    /// Report the given token usage with the last scripted response
+    pub fn with_usage(self, input_tokens: usize, output_tokens: usize) -> Self {
+        if let Some(Ok(response)) = self.script.lock().unwrap().back_mut() {
+            response.usage = Some(TokenUsage {
+                input_tokens,
+                output_tokens,
+                ..TokenUsage::default()
+            });
+        }
+        self
+    }
+
+    /// Report the given stop reason with the last scripted response
+    pub fn stopped_by(self, stop_reason: StopReason) -> Self {
+        if let Some(Ok(response)) = self.script.lock().unwrap().back_mut() {
+            response.stop_reason = stop_reason;
+        }
+        self
+    }
+
+    /// Put a reasoning block before the content of the last scripted response
+    pub fn with_reasoning(self, thinking: &str) -> Self {
+        if let Some(Ok(response)) = self.script.lock().unwrap().back_mut() {
+            let block = serde_json::json!({
+                "type": "thinking",
+                "thinking": thinking,
+                "signature": "mock-signature",
+            });
+            response.content = format!(
+                "{}\n{}",
+                reasoning_line(&block.to_string()),
+                response.content
+            );
+        }
+        self
+    }
+
+    /// Script a failed request
+    pub fn fail_with(self, error: BackendError) -> Self {
+        self.push(Err(error))
+    }
+
+    /// Shared handle to the prompts received so far
+    pub fn prompts(&self) -> Arc<Mutex<Vec<String>>> {
+        self.prompts.clone()
+    }
+
+    /// Shared handle to the output token limit each prompt was sent with
+    pub fn output_limits(&self) -> Arc<Mutex<Vec<usize>>> {
+        self.output_limits.clone()
+    }
+
+    /// Shared handle to the tools offered to the model
+    pub fn enabled_tools(&self) -> Arc<Mutex<Vec<String>>> {
+        self.enabled_tools.clone()
+    }
+
+    /// Shared handle to whether each prompt was sent with tools offered
+    pub fn tools_offered(&self) -> Arc<Mutex<Vec<bool>>> {
+        self.tools_offered.clone()
+    }
+
+    /// Record a prompt and take the next scripted response
+    fn respond(&self, prompt: &str, tools: bool) -> Result<BackendResponse, BackendError> {
+        self.prompts.lock().unwrap().push(prompt.to_string());
+        self.output_limits
+            .lock()
+            .unwrap()
+            .push(self.current_model.max_output_tokens);
+        self.tools_offered.lock().unwrap().push(tools);
+        self.script.lock().unwrap().pop_front().unwrap_or_else(|| {
+            Err(BackendError::Replay(
+                "Mock backend has no scripted response left".to_string(),
+            ))
+        })
+    }
+
+    fn push(self, response: Result<BackendResponse, BackendError>) -> Self {
+        self.script.lock().unwrap().push_back(response);
+        self
+    }
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Build a tool call for a scripted response
+pub fn tool_call(id: &str, name: &str, args: &[(&str, Value)]) -> ToolUse {
+    ToolUse {
+        name: name.to_string(),
+        args: args
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect::<HashMap<_, _>>(),
+        id: Some(id.to_string()),
+    }
+}
+
+impl BackendCore for MockBackend {
+    fn name(&self) -> &'static str {
+        "Mock"
+    }
+
+    fn context_window(&self) -> usize {
+        self.current_model.context_window
+    }
+}
+
+#[async_trait]
+impl Backend for MockBackend {
+    async fn init(&mut self) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    async fn generate_response(&self, prompt: &str) -> Result<BackendResponse, BackendError> {
+        self.respond(prompt, true)
+    }
+
+    async fn generate_text(&self, prompt: &str) -> Result<BackendResponse, BackendError> {
+        self.respond(prompt, false)
+    }
+
+    async fn stream_response(
+        &self,
+        prompt: &str,
+        on_text: &(dyn for<'a> Fn(&'a str) + Send + Sync),
+    ) -> Result<BackendResponse, BackendError> {
+        let response = self.generate_response(prompt).await?;
+        // Pass the text on a word at a time, as a streaming backend would
+        for piece in display_text(&response.content).split_inclusive(' ') {
+            on_text(piece);
+        }
+        Ok(response)
+    }
+
+    fn current_model(&self) -> &ModelSpec {
+        &self.current_model
+    }
+
+    fn switch_model(&mut self, model: ModelSpec) {
+        self.current_model = model;
+    }
+
+    fn current_model_name(&self) -> &str {
+        &self.current_model.id
+    }
+
+    fn set_enabled_tools(&mut self, tools: Vec<String>) {
+        *self.enabled_tools.lock().unwrap() = tools;
+    }
+}
@@ -1,8 +1,27 @@
 mod bedrock;
+mod cassette;
+mod catalog;
+mod error;
+#[cfg(test)]
+mod mock;
+mod stream;
 
-pub use bedrock::{BedrockBackend, BedrockConfig, BedrockModel, ToolUse};
+pub use bedrock::{BedrockBackend, BedrockConfig, ToolUse};
+pub use cassette::ReplayBackend;
+pub use catalog::{ModelCapabilities, ModelCatalog, ModelRole, ModelSelection, ModelSpec};
+pub use error::BackendError;
+#[cfg(test)]
+pub use mock::{tool_call, MockBackend};
+use serde::{Deserialize, Serialize};
 use tracing::trace;
 
+/// Tags around a reasoning block in a response's content
+///
+/// Each block is kept on one line as the JSON the backend returned, since the
+/// model has to be sent its reasoning back unchanged while it uses tools.
+const REASONING_OPEN: &str = "<thinking>";
+const REASONING_CLOSE: &str = "</thinking>";
+
 /// Initialize all available backends
 pub fn init() {
     trace!("Initializing agent backends...");
@@ -20,8 +39,126 @@ pub trait BackendCore: Send + Sync {
 /// Trait defining the async operations for the backend
 #[async_trait::async_trait]
 pub trait Backend: BackendCore {
+    /// Prepare the backend for use (clients, credentials)
+    async fn init(&mut self) -> Result<(), BackendError>;
+
     /// Generate a response from the given prompt
-    async fn generate_response(&self, prompt: &str) -> Result<BackendResponse, String>;
+    async fn generate_response(&self, prompt: &str) -> Result<BackendResponse, BackendError>;
+
+    /// Generate a response without offering the model any tools
+    ///
+    /// For one-shot requests, like titles and summaries, that must be
+    /// answered with text.
+    async fn generate_text(&self, prompt: &str) -> Result<BackendResponse, BackendError>;
+
+    /// Generate a response, passing each piece of its text to `on_text` as it arrives
+    ///
+    /// Backends that cannot stream pass no pieces; the whole text is in the
+    /// returned response either way. (The callback's lifetime is spelled out
+    /// so `async_trait` does not tie the text to the call's lifetime.)
+    async fn stream_response(
+        &self,
+        prompt: &str,
+        on_text: &(dyn for<'a> Fn(&'a str) + Send + Sync),
+    ) -> Result<BackendResponse, BackendError> {
+        let _ = on_text;
+        self.generate_response(prompt).await
+    }
+
+    /// Get the current model
+    fn current_model(&self) -> &ModelSpec;
+
+    /// Switch to a different model
+    fn switch_model(&mut self, model: ModelSpec);
+
+    /// Get the ID requests to the current model are sent to
+    fn current_model_name(&self) -> &str;
+
+    /// Offer only the named tools to the model
+    fn set_enabled_tools(&mut self, tools: Vec<String>);
+}
+
+/// Tokens counted by the backend for one request
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TokenUsage {
+    /// Request tokens that were not read from or written to the prompt cache
+    pub input_tokens: usize,
+
+    /// Tokens generated in the response
+    pub output_tokens: usize,
+
+    /// Request tokens read from the prompt cache
+    pub cache_read_tokens: usize,
+
+    /// Request tokens written to the prompt cache
+    pub cache_write_tokens: usize,
+}
+
+impl TokenUsage {
+    /// Tokens in the request: system prompt, tool definitions and messages
+    pub fn prompt_tokens(&self) -> usize {
+        self.input_tokens + self.cache_read_tokens + self.cache_write_tokens
+    }
+
+    /// Tokens in the request and response together
+    pub fn total(&self) -> usize {
+        self.prompt_tokens() + self.output_tokens
+    }
+
+    /// Share of the request tokens read from the prompt cache, if there were any
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        match self.prompt_tokens() {
+            0 => None,
+            prompt_tokens => Some(self.cache_read_tokens as f64 / prompt_tokens as f64),
+        }
+    }
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_read_tokens += other.cache_read_tokens;
+        self.cache_write_tokens += other.cache_write_tokens;
+    }
+}
+
+/// Why the model stopped generating a response
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The model finished its answer
+    #[default]
+    EndTurn,
+
+    /// The model stopped to have tools run
+    ToolUse,
+
+    /// The response reached the output token limit and was cut off
+    MaxTokens,
+
+    /// The response reached a stop sequence, if the backend reports which
+    StopSequence(Option<String>),
+
+    /// The model declined to continue the response
+    Refusal,
+
+    /// A reason this version does not know about
+    Other(String),
+}
+
+impl StopReason {
+    /// Interpret a stop reason reported by the Anthropic Messages API
+    pub fn from_api(reason: Option<&str>, stop_sequence: Option<&str>) -> Self {
+        match reason {
+            None | Some("end_turn") => StopReason::EndTurn,
+            Some("tool_use") => StopReason::ToolUse,
+            Some("max_tokens") => StopReason::MaxTokens,
+            Some("stop_sequence") => StopReason::StopSequence(stop_sequence.map(str::to_string)),
+            Some("refusal") => StopReason::Refusal,
+            Some(other) => StopReason::Other(other.to_string()),
+        }
+    }
 }
 
 /// Structure containing a response from an LLM backend
@@ -33,9 +170,53 @@ pub struct BackendResponse {
     /// Model used for generation
     pub model: String,
 
-    /// Tokens used in this request and response
-    pub tokens_used: Option<usize>,
+    /// Tokens the backend counted for this request and response, if it reports them
+    pub usage: Option<TokenUsage>,
 
     /// Tool calls extracted from the response (if any)
     pub tool_calls: Vec<ToolUse>,
+
+    /// Why the model stopped generating
+    pub stop_reason: StopReason,
+}
+
+/// A reasoning block as a line of a response's content
+pub fn reasoning_line(block_json: &str) -> String {
+    format!("{}{}{}", REASONING_OPEN, block_json, REASONING_CLOSE)
+}
+
+/// The JSON of the reasoning block a content line holds, if it holds one
+pub fn reasoning_block(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix(REASONING_OPEN)
+        .and_then(|rest| rest.strip_suffix(REASONING_CLOSE))
+}
+
+/// Whether a content line holds a reasoning block
+pub fn is_reasoning_line(line: &str) -> bool {
+    reasoning_block(line).is_some()
+}
+
+/// Readable reasoning in a response's content
+///
+/// Blocks the backend returned redacted have no text and are left out.
+pub fn reasoning_text(content: &str) -> String {
+    content
+        .lines()
+        .filter_map(reasoning_block)
+        .filter_map(|json| serde_json::from_str::<serde_json::Value>(json).ok())
+        .filter_map(|block| block.get("thinking")?.as_str().map(str::to_string))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+        .trim()
+        .to_string()
+}
+
+/// A response's content without its reasoning blocks
+pub fn without_reasoning(content: &str) -> String {
+    content
+        .lines()
+        .filter(|line| !is_reasoning_line(line))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
@@ -0,0 +1,244 @@
+// Assembly of streamed Claude responses
+
+use crate::agent::backends::BackendError;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use tracing::trace;
+
+/// A Claude response put back together from the events of a streamed request
+///
+/// Bedrock streams the Messages API events as JSON chunks. Applying them in
+/// order rebuilds the body the request would have returned unstreamed, so the
+/// response is parsed and recorded to cassettes the same way either way.
+#[derive(Default)]
+pub struct StreamedResponse {
+    /// The message from `message_start`, updated by `message_delta`
+    message: Option<Map<String, Value>>,
+
+    /// Content blocks, in the order of their indexes
+    blocks: Vec<Value>,
+
+    /// JSON input of tool_use blocks received so far, by block index
+    partial_inputs: HashMap<usize, String>,
+}
+
+impl StreamedResponse {
+    /// Apply the next event of the stream, returning the response text it adds, if any
+    pub fn apply(&mut self, event: &Value) -> Result<Option<String>, BackendError> {
+        let kind = event
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        match kind {
+            "message_start" => {
+                let message = event
+                    .get("message")
+                    .and_then(Value::as_object)
+                    .ok_or_else(|| malformed(kind))?;
+                self.message = Some(message.clone());
+            }
+            "content_block_start" => {
+                let index = block_index(event)?;
+                let block = event
+                    .get("content_block")
+                    .cloned()
+                    .ok_or_else(|| malformed(kind))?;
+                if self.blocks.len() <= index {
+                    self.blocks.resize(index + 1, Value::Null);
+                }
+                self.blocks[index] = block;
+            }
+            "content_block_delta" => {
+                let index = block_index(event)?;
+                let delta = event.get("delta").ok_or_else(|| malformed(kind))?;
+                return self.apply_delta(index, delta);
+            }
+            "content_block_stop" => {
+                let index = block_index(event)?;
+                if let Some(input) = self.partial_inputs.remove(&index) {
+                    let input: Value = serde_json::from_str(&input).map_err(|e| {
+                        BackendError::Serialization(format!(
+                            "Failed to parse streamed tool input: {}",
+                            e
+                        ))
+                    })?;
+                    self.block(index)?.insert("input".to_string(), input);
+                }
+            }
+            "message_delta" => {
+                let message = self.message.as_mut().ok_or_else(|| malformed(kind))?;
+                if let Some(delta) = event.get("delta").and_then(Value::as_object) {
+                    message.extend(delta.clone());
+                }
+                // The final counts, merged so the input counts from the start are kept
+                if let Some(usage) = event.get("usage").and_then(Value::as_object) {
+                    let counts = message
+                        .entry("usage")
+                        .or_insert_with(|| Value::Object(Map::new()));
+                    if let Some(counts) = counts.as_object_mut() {
+                        counts.extend(usage.clone());
+                    }
+                }
+            }
+            "error" => {
+                let message = event
+                    .pointer("/error/message")
+                    .and_then(Value::as_str)
+                    .unwrap_or("the stream reported an error");
+                return Err(BackendError::Service(message.to_string()));
+            }
+            _ => trace!("Ignoring stream event: {}", kind),
+        }
+        Ok(None)
+    }
+
+    /// The complete response body, once the stream has ended
+    pub fn into_body(self) -> Result<Value, BackendError> {
+        let mut message = self.message.ok_or_else(|| {
+            BackendError::Serialization(
+                "The response stream ended before the message started".to_string(),
+            )
+        })?;
+        let blocks = self.blocks.into_iter().filter(|block| !block.is_null());
+        message.insert("content".to_string(), Value::Array(blocks.collect()));
+        Ok(Value::Object(message))
+    }
+
+    /// Add a delta to the block it belongs to
+    fn apply_delta(&mut self, index: usize, delta: &Value) -> Result<Option<String>, BackendError> {
+        let kind = delta
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let (field, piece) = match kind {
+            "text_delta" => ("text", delta.get("text")),
+            "thinking_delta" => ("thinking", delta.get("thinking")),
+            "signature_delta" => ("signature", delta.get("signature")),
+            "input_json_delta" => {
+                let piece = delta.get("partial_json").and_then(Value::as_str);
+                let input = self.partial_inputs.entry(index).or_default();
+                input.push_str(piece.unwrap_or_default());
+                return Ok(None);
+            }
+            _ => {
+                trace!("Ignoring stream delta: {}", kind);
+                return Ok(None);
+            }
+        };
+        let piece = piece
+            .and_then(Value::as_str)
+            .ok_or_else(|| malformed(kind))?;
+
+        let block = self.block(index)?;
+        let text = block
+            .entry(field)
+            .or_insert_with(|| Value::String(String::new()));
+        if let Value::String(text) = text {
+            text.push_str(piece);
+        }
+        Ok((field == "text").then(|| piece.to_string()))
+    }
+
+    /// The block a delta or stop event refers to
+    fn block(&mut self, index: usize) -> Result<&mut Map<String, Value>, BackendError> {
+        self.blocks
+            .get_mut(index)
+            .and_then(Value::as_object_mut)
+            .ok_or_else(|| {
+                BackendError::Serialization(format!(
+                    "Streamed content block {} was never started",
+                    index
+                ))
+            })
+    }
+}
+
+/// Index of the content block an event refers to
+fn block_index(event: &Value) -> Result<usize, BackendError> {
+    event
+        .get("index")
+        .and_then(Value::as_u64)
+        .map(|index| index as usize)
+        .ok_or_else(|| malformed("content block event"))
+}
+
+/// Error for a stream event that lacks a field it needs
+fn malformed(kind: &str) -> BackendError {
+    BackendError::Serialization(format!("Malformed {} in the response stream", kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn stream_events_rebuild_the_response_body() {
+        let events = [
+            json!({"type": "message_start", "message": {
+                "id": "msg_1", "type": "message", "role": "assistant",
+                "model": "claude", "content": [], "stop_reason": null,
+                "usage": {"input_tokens": 12, "output_tokens": 1}}}),
+            json!({"type": "content_block_start", "index": 0,
+                "content_block": {"type": "thinking", "thinking": ""}}),
+            json!({"type": "content_block_delta", "index": 0,
+                "delta": {"type": "thinking_delta", "thinking": "Look first."}}),
+            json!({"type": "content_block_delta", "index": 0,
+                "delta": {"type": "signature_delta", "signature": "sig"}}),
+            json!({"type": "content_block_stop", "index": 0}),
+            json!({"type": "content_block_start", "index": 1,
+                "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "content_block_delta", "index": 1,
+                "delta": {"type": "text_delta", "text": "Let me "}}),
+            json!({"type": "content_block_delta", "index": 1,
+                "delta": {"type": "text_delta", "text": "check."}}),
+            json!({"type": "content_block_stop", "index": 1}),
+            json!({"type": "content_block_start", "index": 2,
+                "content_block": {"type": "tool_use", "id": "toolu_1", "name": "read_file", "input": {}}}),
+            json!({"type": "content_block_delta", "index": 2,
+                "delta": {"type": "input_json_delta", "partial_json": "{\"path\": "}}),
+            json!({"type": "content_block_delta", "index": 2,
+                "delta": {"type": "input_json_delta", "partial_json": "\"notes.txt\"}"}}),
+            json!({"type": "content_block_stop", "index": 2}),
+            json!({"type": "message_delta",
+                "delta": {"stop_reason": "tool_use", "stop_sequence": null},
+                "usage": {"output_tokens": 40}}),
+            json!({"type": "message_stop"}),
+        ];
+
+        let mut streamed = StreamedResponse::default();
+        let mut texts = Vec::new();
+        for event in &events {
+            texts.extend(streamed.apply(event).unwrap());
+        }
+
+        assert_eq!(texts, ["Let me ", "check."]);
+        assert_eq!(
+            streamed.into_body().unwrap(),
+            json!({
+                "id": "msg_1", "type": "message", "role": "assistant", "model": "claude",
+                "content": [
+                    {"type": "thinking", "thinking": "Look first.", "signature": "sig"},
+                    {"type": "text", "text": "Let me check."},
+                    {"type": "tool_use", "id": "toolu_1", "name": "read_file",
+                        "input": {"path": "notes.txt"}},
+                ],
+                "stop_reason": "tool_use", "stop_sequence": null,
+                "usage": {"input_tokens": 12, "output_tokens": 40},
+            })
+        );
+    }
+
+    #[test]
+    fn stream_errors_fail_the_response() {
+        let mut streamed = StreamedResponse::default();
+        let error = json!({"type": "error",
+            "error": {"type": "overloaded_error", "message": "Overloaded"}});
+
+        assert_eq!(
+            streamed.apply(&error),
+            Err(BackendError::Service("Overloaded".to_string()))
+        );
+        assert!(StreamedResponse::default().into_body().is_err());
+    }
+}
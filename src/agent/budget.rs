@@ -0,0 +1,225 @@
+// Limits on what a turn or a session may spend
+
+use crate::agent::usage::UsageLedger;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Ceilings on tokens, cost, requests and time; limits that are not set are not enforced
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BudgetLimits {
+    /// Most tokens sent and received
+    pub max_tokens: Option<usize>,
+
+    /// Most US dollars spent
+    pub max_cost: Option<f64>,
+
+    /// Most requests made to the model
+    pub max_requests: Option<usize>,
+
+    /// Most seconds the agent may work, including tool runs
+    pub max_seconds: Option<u64>,
+}
+
+/// Which budget a limit belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BudgetScope {
+    Turn,
+    Session,
+}
+
+/// What has been spent so far
+#[derive(Debug, Default, Clone, Copy)]
+struct Spend {
+    tokens: usize,
+    cost: f64,
+    requests: usize,
+    time: Duration,
+}
+
+/// Outcome of checking the spending against the limits
+#[derive(Debug, Default)]
+pub struct BudgetCheck {
+    /// Soft limits crossed for the first time, described for the user
+    pub warnings: Vec<String>,
+
+    /// The first hard limit that was reached, if any
+    pub exceeded: Option<String>,
+}
+
+/// Tracks spending against the turn and session limits
+///
+/// A limit is soft once the spending passes `warn_at` of it, which is
+/// reported once, and hard when the spending reaches it. Confirming a turn
+/// that was paused at a hard limit starts that budget over from the current
+/// spending.
+pub struct BudgetTracker {
+    /// Limits for a single turn
+    turn: BudgetLimits,
+
+    /// Limits for the whole session
+    session: BudgetLimits,
+
+    /// Fraction of a limit at which a warning is given
+    warn_at: f64,
+
+    /// When the current turn started
+    turn_started: Instant,
+
+    /// Session spending when the current turn started
+    turn_baseline: Spend,
+
+    /// Session spending the session limits are counted from
+    session_baseline: Spend,
+
+    /// Soft limits already warned about
+    warned: Vec<(BudgetScope, &'static str)>,
+
+    /// Whether a session limit was reached since the session budget was last confirmed
+    session_exceeded: bool,
+}
+
+impl Spend {
+    /// Spending recorded in a session's usage, plus the time of the running turn
+    fn of(usage: &UsageLedger, running: Duration) -> Self {
+        let totals = usage.totals();
+        Self {
+            tokens: totals.usage.total(),
+            cost: totals.cost,
+            requests: totals.requests,
+            time: usage.working_time() + running,
+        }
+    }
+
+    /// Spending since `baseline`
+    fn since(&self, baseline: &Spend) -> Self {
+        Self {
+            tokens: self.tokens.saturating_sub(baseline.tokens),
+            cost: (self.cost - baseline.cost).max(0.0),
+            requests: self.requests.saturating_sub(baseline.requests),
+            time: self.time.saturating_sub(baseline.time),
+        }
+    }
+}
+
+impl BudgetScope {
+    /// Name used in budget messages
+    fn name(&self) -> &'static str {
+        match self {
+            BudgetScope::Turn => "turn",
+            BudgetScope::Session => "session",
+        }
+    }
+}
+
+impl BudgetTracker {
+    /// Create a tracker for the given limits
+    pub fn new(turn: BudgetLimits, session: BudgetLimits, warn_at: f64) -> Self {
+        Self {
+            turn,
+            session,
+            warn_at,
+            turn_started: Instant::now(),
+            turn_baseline: Spend::default(),
+            session_baseline: Spend::default(),
+            warned: Vec::new(),
+            session_exceeded: false,
+        }
+    }
+
+    /// Start counting a new turn, or a paused turn that was continued
+    pub fn start_turn(&mut self, usage: &UsageLedger) {
+        self.turn_started = Instant::now();
+        self.turn_baseline = Spend::of(usage, Duration::ZERO);
+        self.warned
+            .retain(|(scope, _)| *scope == BudgetScope::Session);
+    }
+
+    /// Accept the spending that paused the last turn, so the turn can go on
+    pub fn confirm(&mut self, usage: &UsageLedger) {
+        if self.session_exceeded {
+            self.session_baseline = Spend::of(usage, Duration::ZERO);
+            self.warned.clear();
+            self.session_exceeded = false;
+        }
+    }
+
+    /// Count the session from nothing, as when it is cleared or replaced
+    pub fn reset_session(&mut self) {
+        self.session_baseline = Spend::default();
+        self.warned.clear();
+        self.session_exceeded = false;
+    }
+
+    /// Time the current turn has been running
+    pub fn turn_elapsed(&self) -> Duration {
+        self.turn_started.elapsed()
+    }
+
+    /// Compare the spending so far with the limits
+    pub fn check(&mut self, usage: &UsageLedger) -> BudgetCheck {
+        let session = Spend::of(usage, self.turn_elapsed());
+        let mut check = BudgetCheck::default();
+
+        for scope in [BudgetScope::Turn, BudgetScope::Session] {
+            let (limits, spent) = match scope {
+                BudgetScope::Turn => (&self.turn, session.since(&self.turn_baseline)),
+                BudgetScope::Session => (&self.session, session.since(&self.session_baseline)),
+            };
+            let measures = [
+                (
+                    "tokens",
+                    spent.tokens as f64,
+                    limits.max_tokens.map(|max| max as f64),
+                ),
+                ("cost", spent.cost, limits.max_cost),
+                (
+                    "requests",
+                    spent.requests as f64,
+                    limits.max_requests.map(|max| max as f64),
+                ),
+                (
+                    "time",
+                    spent.time.as_secs_f64(),
+                    limits.max_seconds.map(|max| max as f64),
+                ),
+            ];
+
+            for (measure, used, limit) in measures {
+                let limit = match limit {
+                    Some(limit) => limit,
+                    None => continue,
+                };
+                let description = format!(
+                    "{} {} {} of {}",
+                    scope.name(),
+                    measure,
+                    format_amount(measure, used),
+                    format_amount(measure, limit)
+                );
+                if used >= limit {
+                    if scope == BudgetScope::Session {
+                        self.session_exceeded = true;
+                    }
+                    if check.exceeded.is_none() {
+                        check.exceeded = Some(description);
+                    }
+                } else if used >= limit * self.warn_at && !self.warned.contains(&(scope, measure)) {
+                    self.warned.push((scope, measure));
+                    check.warnings.push(description);
+                }
+            }
+        }
+
+        check
+    }
+}
+
+/// Show an amount in the unit of its measure
+fn format_amount(measure: &str, amount: f64) -> String {
+    match measure {
+        "cost" => format!("${:.2}", amount),
+        "time" => format!("{}s", amount.round()),
+        _ => format!("{}", amount.round()),
+    }
+}
@@ -1,16 +1,57 @@
-use serde_json;
+use crate::agent::attachments::{is_image_line, ImageAttachment};
+use crate::agent::backends::{without_reasoning, TokenUsage};
+use crate::agent::message_tree::{BranchPoint, MessageTree, TreeCheckpoint};
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Value};
+use thiserror::Error;
 use tracing::trace;
 
+/// Characters per token assumed until the backend has reported usage
+///
+/// Code and JSON break into more tokens than prose, so this is lower than the
+/// usual four characters per token for English.
+const DEFAULT_CHARS_PER_TOKEN: f64 = 3.5;
+
+/// Tokens added to each message by its tags and role
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// First line of the system message holding a summary of compacted turns
+const SUMMARY_HEADER: &str = "Summary of the earlier conversation:";
+
+/// Field of a tool result line holding the images the tool returned
+///
+/// Tool output only ever goes in the line's `content`, so it cannot be
+/// mistaken for an image.
+const TOOL_RESULT_IMAGES: &str = "images";
+
+/// Put before a line of user text that would otherwise read as an image line
+const TEXT_ESCAPE: char = '\\';
+
 /// Manager for maintaining conversation context
 pub struct ContextManager {
-    /// Messages in the current conversation
-    messages: Vec<Message>,
+    /// Messages in the current conversation, including edited branches
+    messages: MessageTree,
+
+    /// Tokens the backend counted for the start of the active branch
+    ///
+    /// Cleared whenever earlier messages change, since it no longer applies.
+    measured: Option<Measurement>,
+
+    /// Characters per token seen in the last request the backend measured
+    chars_per_token: f64,
+}
 
-    /// Current token count estimate
-    token_count: usize,
+/// Tokens counted by the backend for the first messages of the active branch
+struct Measurement {
+    /// Number of messages the count covers
+    messages: usize,
+
+    /// Tokens in those messages, as reported by the backend
+    tokens: usize,
 }
 
 /// Structure representing a message in the conversation
+#[derive(Serialize, Deserialize)]
 pub struct Message {
     /// Role of the message sender (user, assistant, system, tool)
     pub role: MessageRole,
@@ -20,10 +61,27 @@ pub struct Message {
 
     /// Name of the tool if role is tool
     pub tool_name: Option<String>,
+
+    /// Images attached to a user message, sent before its text
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageAttachment>,
+}
+
+/// Older turns of the conversation that can be replaced by a summary
+pub struct CompactionPlan {
+    /// Position on the active branch of the first message that is kept
+    position: usize,
+
+    /// Number of messages that would be summarized
+    pub messages: usize,
+
+    /// Plain-text transcript of those messages, for the model to summarize
+    pub transcript: String,
 }
 
 /// Enum representing the role of a message sender
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MessageRole {
     System,
     User,
@@ -31,12 +89,42 @@ pub enum MessageRole {
     Tool,
 }
 
+/// A line of a user message in the formatted context
+pub enum UserLine<'a> {
+    /// An attached image
+    Image(ImageAttachment),
+
+    /// A line of the text the user wrote, exactly as written
+    Text(&'a str),
+}
+
+/// Why a change to the conversation's messages could not be made
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ContextError {
+    /// The message is not on the active branch
+    #[error("Message {0} is not in the current conversation")]
+    NotOnBranch(u64),
+
+    /// Only user inputs can be edited
+    #[error("Message {0} is not a user input")]
+    NotUserInput(u64),
+
+    /// The edited message has fewer versions than asked for
+    #[error("Message {node} has {versions} versions, not {requested}")]
+    NoSuchVersion {
+        node: u64,
+        versions: usize,
+        requested: usize,
+    },
+}
+
 impl ContextManager {
     /// Create a new context manager
     pub fn new() -> Self {
         let mut manager = Self {
-            messages: Vec::new(),
-            token_count: 0,
+            messages: MessageTree::default(),
+            measured: None,
+            chars_per_token: DEFAULT_CHARS_PER_TOKEN,
         };
 
         // Add default system message
@@ -49,28 +137,126 @@ impl ContextManager {
         manager
     }
 
+    /// Replace the system prompt the conversation starts with
+    ///
+    /// Summaries of compressed turns follow the system prompt and are kept.
+    pub fn set_system_prompt(&mut self, prompt: &str) {
+        let message = Message {
+            role: MessageRole::System,
+            content: prompt.to_string(),
+            tool_name: None,
+            images: Vec::new(),
+        };
+        match self.messages.first_mut() {
+            Some(first) if first.role == MessageRole::System && !first.is_summary() => {
+                if first.content == prompt {
+                    return;
+                }
+                *first = message;
+            }
+            _ => self.messages.replace_prefix(0, vec![message]),
+        }
+        self.forget_measurement();
+    }
+
+    /// The system prompt as the backend sends it, followed by any summary of compressed turns
+    pub fn system_prompt(&self) -> String {
+        self.messages
+            .messages()
+            .filter(|m| m.role == MessageRole::System)
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
     /// Add a system message to the context
     pub fn add_system_message(&mut self, content: &str) {
         self.messages.push(Message {
             role: MessageRole::System,
             content: content.to_string(),
             tool_name: None,
+            images: Vec::new(),
         });
+    }
 
-        // Estimate token count (very rough estimate)
-        self.token_count += content.split_whitespace().count();
+    /// Add a user message to the context, returning its ID
+    pub fn add_user_message(&mut self, content: &str) -> u64 {
+        self.add_user_message_with_images(content, Vec::new())
     }
 
-    /// Add a user message to the context
-    pub fn add_user_message(&mut self, content: &str) {
+    /// Add a user message with images attached to it, returning its ID
+    pub fn add_user_message_with_images(
+        &mut self,
+        content: &str,
+        images: Vec<ImageAttachment>,
+    ) -> u64 {
         self.messages.push(Message {
             role: MessageRole::User,
             content: content.to_string(),
             tool_name: None,
-        });
+            images,
+        })
+    }
+
+    /// Replace an earlier user message, starting a new branch from it
+    ///
+    /// Returns the ID of the new message. The replaced message and everything
+    /// after it stay on the old branch. Images attached to the replaced
+    /// message are attached to the new one too, before the given images.
+    pub fn edit_user_message(
+        &mut self,
+        node: u64,
+        content: &str,
+        images: Vec<ImageAttachment>,
+    ) -> Result<u64, ContextError> {
+        let mut attached = self
+            .messages
+            .entries()
+            .find(|(id, _)| *id == node)
+            .map(|(_, message)| message.images.clone())
+            .unwrap_or_default();
+        attached.extend(images);
+        let id = self.messages.fork(
+            node,
+            Message {
+                role: MessageRole::User,
+                content: content.to_string(),
+                tool_name: None,
+                images: attached,
+            },
+        )?;
+        self.forget_measurement();
+        Ok(id)
+    }
+
+    /// Switch to another version of an edited user message
+    pub fn switch_branch(&mut self, node: u64, version: usize) -> Result<(), ContextError> {
+        self.messages.switch(node, version)?;
+        self.forget_measurement();
+        Ok(())
+    }
+
+    /// ID of the input that started the latest turn on the active branch
+    ///
+    /// Tool results are sent as user messages but do not start a turn.
+    pub fn last_input(&self) -> Option<u64> {
+        self.messages
+            .entries()
+            .rev()
+            .find(|(_, message)| message.role == MessageRole::User && !message.is_tool_result())
+            .map(|(id, _)| id)
+    }
 
-        // Estimate token count (very rough estimate)
-        self.token_count += content.split_whitespace().count();
+    /// Remove a user message and everything after it from the active branch
+    pub fn remove_from(&mut self, node: u64) -> Result<(), ContextError> {
+        self.messages.remove_from(node)?;
+        self.forget_measurement();
+        Ok(())
+    }
+
+    /// User messages on the active branch that have been edited
+    pub fn branch_points(&self) -> Vec<BranchPoint> {
+        self.messages.branch_points()
     }
 
     /// Add an assistant message to the context
@@ -79,10 +265,25 @@ impl ContextManager {
             role: MessageRole::Assistant,
             content: content.to_string(),
             tool_name: None,
+            images: Vec::new(),
         });
+    }
 
-        // Estimate token count (very rough estimate)
-        self.token_count += content.split_whitespace().count();
+    /// Add text the model wrote to continue its last message
+    ///
+    /// The text is appended to the last message when that is the model's,
+    /// and added as a new assistant message otherwise.
+    pub fn continue_assistant_message(&mut self, content: &str) {
+        match self.messages.last_mut() {
+            Some(message) if message.role == MessageRole::Assistant => {
+                // The request ended the message without trailing whitespace,
+                // so that is where the continuation starts
+                let length = message.content.trim_end().len();
+                message.content.truncate(length);
+                message.content.push_str(content);
+            }
+            _ => self.add_assistant_message(content),
+        }
     }
 
     /// Add tool results to the context
@@ -207,7 +408,9 @@ impl ContextManager {
                     );
 
                     // Format as pure JSON-RPC - CRITICAL: Use exactly the same tool_use_id
-                    let content = if result.tool_name == "read_file" {
+                    let content = if result.image {
+                        image_tool_result(id, &result.result)
+                    } else if result.tool_name == "read_file" {
                         trace!("CRITICAL: Formatting read_file result with special handling");
                         // For read_file, the content must be a JSON string, not an array
                         // Quote and escape the content string properly for JSON
@@ -235,9 +438,6 @@ impl ContextManager {
                     
                     // Add this content to our tool results collection
                     tool_result_contents.push(content);
-                    
-                    // Estimate token count
-                    self.token_count += result.result.split_whitespace().count();
                 } else {
                     trace!("Tool result missing tool_call_id, skipping");
                 }
@@ -253,6 +453,7 @@ impl ContextManager {
                     role: MessageRole::User,
                     content: combined_content,
                     tool_name: None,
+                    images: Vec::new(),
                 };
                 
                 // Add this message to the context
@@ -352,7 +553,9 @@ impl ContextManager {
                         })
                     };
 
-                    let content = if result.tool_name == "read_file" {
+                    let content = if result.image {
+                        image_tool_result(id, &result.result)
+                    } else if result.tool_name == "read_file" {
                         trace!("CRITICAL: Formatting read_file result with special handling");
                         // For read_file, the content must be a JSON string, not an array or broken into lines
                         // Quote and escape the content string properly for JSON
@@ -382,9 +585,8 @@ impl ContextManager {
                         role: MessageRole::Tool,
                         content,
                         tool_name: Some(result.tool_name.clone()),
+                        images: Vec::new(),
                     });
-
-                    self.token_count += result.result.split_whitespace().count();
                 } else {
                     trace!("Tool result missing tool_call_id, skipping");
                 }
@@ -396,22 +598,33 @@ impl ContextManager {
     pub fn get_context(&self) -> String {
         let mut context = String::new();
 
-        for message in &self.messages {
+        for message in self.messages.messages() {
             match message.role {
                 MessageRole::System => {
                     context.push_str(&format!("<system>\n{}\n</system>\n\n", message.content));
                 }
                 MessageRole::User => {
                     // Special case for user messages containing tool results
-                    if message.content.starts_with("{\"type\": \"tool_result\"") || 
-                       message.content.starts_with("{\"type\":\"tool_result\"") {
+                    if message.is_tool_result() {
                         // Tool results should be included directly without <user> tags
                         // This is critical for Claude's API to recognize the proper format
                         context.push_str(&format!("{}\n\n", message.content));
                         trace!("Including tool result user message directly without tags");
                     } else {
-                        // Normal user message
-                        context.push_str(&format!("<user>\n{}\n</user>\n\n", message.content));
+                        // Normal user message, with its images first
+                        context.push_str("<user>\n");
+                        for image in &message.images {
+                            context.push_str(&image.to_markup());
+                            context.push('\n');
+                        }
+                        for line in message.content.lines() {
+                            if is_escaped_line(line) {
+                                context.push(TEXT_ESCAPE);
+                            }
+                            context.push_str(line);
+                            context.push('\n');
+                        }
+                        context.push_str("</user>\n\n");
                     }
                 }
                 MessageRole::Assistant => {
@@ -430,43 +643,309 @@ impl ContextManager {
         context
     }
 
-    /// Get the current context length (rough token estimate)
+    /// Get the messages on the active branch of the conversation
+    pub fn messages(&self) -> impl DoubleEndedIterator<Item = &Message> {
+        self.messages.messages()
+    }
+
+    /// Get the whole conversation, including inactive branches
+    pub fn tree(&self) -> &MessageTree {
+        &self.messages
+    }
+
+    /// Replace the conversation with a previously saved one
+    pub fn replace_tree(&mut self, messages: MessageTree) {
+        self.messages = messages;
+        self.forget_measurement();
+    }
+
+    /// Remember the conversation so later messages can be discarded
+    pub fn checkpoint(&self) -> TreeCheckpoint {
+        self.messages.checkpoint()
+    }
+
+    /// Discard every message added since the checkpoint
+    pub fn restore(&mut self, checkpoint: TreeCheckpoint) {
+        self.messages.restore(checkpoint);
+        self.forget_measurement();
+    }
+
+    /// Remove the conversation, starting over with the default system message
+    ///
+    /// The calibration of the token estimate is kept.
+    pub fn clear(&mut self) {
+        let chars_per_token = self.chars_per_token;
+        *self = Self::new();
+        self.chars_per_token = chars_per_token;
+    }
+
+    /// Record the tokens the backend counted for a request and its response
+    ///
+    /// Call this after adding the response, with the prompt that was sent.
+    /// The count covers every message on the active branch, and the prompt
+    /// size calibrates the estimate for messages added later. Images are
+    /// counted by their size, so they are left out of the calibration.
+    pub fn record_usage(&mut self, prompt: &str, usage: TokenUsage) {
+        self.measured = Some(Measurement {
+            messages: self.messages.len(),
+            tokens: usage.total(),
+        });
+        let images: Vec<ImageAttachment> =
+            self.messages.messages().flat_map(Message::images).collect();
+        let (image_tokens, image_chars) = image_size(&images);
+        let text_tokens = usage.prompt_tokens().saturating_sub(image_tokens);
+        if text_tokens > 0 {
+            let text_chars = prompt.chars().count().saturating_sub(image_chars);
+            let ratio = text_chars as f64 / text_tokens as f64;
+            self.chars_per_token = ratio.clamp(1.0, 8.0);
+        }
+    }
+
+    /// Forget the measured token count after earlier messages changed
+    fn forget_measurement(&mut self) {
+        self.measured = None;
+    }
+
+    /// Estimate the tokens in a piece of text
+    pub fn estimate_tokens(&self, text: &str) -> usize {
+        self.text_tokens(text.chars().count())
+    }
+
+    /// Estimate the tokens in a number of characters of text
+    fn text_tokens(&self, chars: usize) -> usize {
+        (chars as f64 / self.chars_per_token).ceil() as usize
+    }
+
+    /// Estimate the tokens in a message
+    ///
+    /// Images count by their size rather than their encoded data.
+    fn estimate_message_tokens(&self, message: &Message) -> usize {
+        let images = message.images();
+        let (image_tokens, image_chars) = image_size(&images);
+        // Images a tool returned are held in the content; attached ones are not
+        let text_chars = match message.is_tool_result() {
+            true => message.content.chars().count().saturating_sub(image_chars),
+            false => message.content.chars().count(),
+        };
+        image_tokens + self.text_tokens(text_chars)
+    }
+
+    /// Get the current context length in tokens
+    ///
+    /// This is the backend's count for the last request plus an estimate for
+    /// the messages added since, or an estimate for the whole conversation if
+    /// nothing has been measured since it last changed.
     pub fn context_length(&self) -> usize {
-        self.token_count
+        let (tokens, counted) = match &self.measured {
+            Some(measured) if measured.messages <= self.messages.len() => {
+                (measured.tokens, measured.messages)
+            }
+            _ => (0, 0),
+        };
+
+        tokens
+            + self
+                .messages
+                .messages()
+                .skip(counted)
+                .map(|m| self.estimate_message_tokens(m) + MESSAGE_OVERHEAD_TOKENS)
+                .sum::<usize>()
     }
 
-    /// Replace older messages with a summary
-    pub fn replace_with_summary(&mut self, summary: &str) {
-        // Keep the system message and last few exchanges
-        let system_messages: Vec<Message> = self
-            .messages
+    /// Find the older turns to summarize, keeping the last `keep_turns` turns
+    ///
+    /// The cut is always made at the start of a turn, so the kept messages
+    /// open with a user input and every tool_use they hold keeps its
+    /// tool_result. Returns None if there are no older turns to summarize.
+    pub fn plan_compaction(&self, keep_turns: usize) -> Option<CompactionPlan> {
+        let messages: Vec<&Message> = self.messages.messages().collect();
+        let turn_starts: Vec<usize> = messages
             .iter()
-            .filter(|m| m.role == MessageRole::System)
-            .cloned()
+            .enumerate()
+            .filter(|(_, m)| m.role == MessageRole::User && !m.is_tool_result())
+            .map(|(i, _)| i)
+            .collect();
+
+        let keep_turns = keep_turns.max(1);
+        if turn_starts.len() <= keep_turns {
+            return None;
+        }
+        let position = turn_starts[turn_starts.len() - keep_turns];
+
+        // The system prompt stays as it is; an earlier summary is summarized again
+        let summarized: Vec<&Message> = messages[..position]
+            .iter()
+            .copied()
+            .filter(|m| m.role != MessageRole::System || m.is_summary())
             .collect();
+        let transcript = summarized
+            .iter()
+            .map(|m| m.transcript_entry())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        Some(CompactionPlan {
+            position,
+            messages: summarized.len(),
+            transcript,
+        })
+    }
 
-        // Keep the last 4 messages (2 exchanges) - adjust as needed
-        let recent_messages: Vec<Message> = self.messages.iter().rev().take(4).cloned().collect();
+    /// Replace the turns found by `plan_compaction` with a summary of them
+    ///
+    /// The summary goes in a system message after the system prompt, which the
+    /// backend sends as part of the request's system prompt. Returns the
+    /// estimated number of tokens saved.
+    pub fn compact(&mut self, plan: CompactionPlan, summary: &str) -> usize {
+        let before = self.context_length();
 
-        // Create a new summary message
-        let summary_message = Message {
+        let mut prefix: Vec<Message> = self
+            .messages
+            .messages()
+            .take(plan.position)
+            .filter(|m| m.role == MessageRole::System && !m.is_summary())
+            .cloned()
+            .collect();
+        prefix.push(Message {
             role: MessageRole::System,
-            content: format!("Summary of previous conversation:\n{}\n", summary),
+            content: format!("{}\n{}", SUMMARY_HEADER, summary.trim()),
             tool_name: None,
-        };
+            images: Vec::new(),
+        });
+        self.messages.replace_prefix(plan.position, prefix);
 
-        // Reset messages with system + summary + recent
-        self.messages = system_messages;
-        self.messages.push(summary_message);
-        self.messages.extend(recent_messages.into_iter().rev());
+        self.forget_measurement();
+        before.saturating_sub(self.context_length())
+    }
+}
 
-        // Recalculate token count
-        self.token_count = self
-            .messages
-            .iter()
-            .map(|m| m.content.split_whitespace().count())
-            .sum();
+impl Message {
+    /// Whether this is a user message carrying tool results rather than user input
+    pub fn is_tool_result(&self) -> bool {
+        self.role == MessageRole::User
+            && (self.content.starts_with("{\"type\": \"tool_result\"")
+                || self.content.starts_with("{\"type\":\"tool_result\""))
+    }
+
+    /// Whether this is the summary left by an earlier compaction
+    fn is_summary(&self) -> bool {
+        self.role == MessageRole::System && self.content.starts_with(SUMMARY_HEADER)
+    }
+
+    /// Images in the message: those attached by the user, or returned by tools
+    pub fn images(&self) -> Vec<ImageAttachment> {
+        if !self.is_tool_result() {
+            return self.images.clone();
+        }
+        self.content
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .flat_map(|line| tool_result_images(&line))
+            .collect()
+    }
+
+    /// The message as a line of a plain-text transcript
+    ///
+    /// Tool results are prefixed line by line so their JSON is not mistaken
+    /// for real tool results when the transcript is sent to the model.
+    /// Images are left out, with their names in their place.
+    fn transcript_entry(&self) -> String {
+        match self.role {
+            MessageRole::System => self.content.clone(),
+            MessageRole::User if self.is_tool_result() => self
+                .content
+                .lines()
+                .map(|line| format!("Tool result: {}", describe_tool_result_images(line)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            MessageRole::User => {
+                let mut entry = String::from("User: ");
+                for image in &self.images {
+                    entry.push_str(&describe_image(image));
+                    entry.push('\n');
+                }
+                entry.push_str(&self.content);
+                entry
+            }
+            MessageRole::Assistant => format!("Assistant: {}", without_reasoning(&self.content)),
+            MessageRole::Tool => format!("Tool result: {}", self.content),
+        }
+    }
+}
+
+/// Read a line of a user message in the formatted context
+///
+/// Lines of text that would read as images were escaped when the context
+/// was formatted, and come back exactly as the user wrote them.
+pub fn parse_user_line(line: &str) -> UserLine<'_> {
+    if let Some(text) = line.trim_start().strip_prefix(TEXT_ESCAPE)
+        && is_escaped_line(text)
+    {
+        return UserLine::Text(text);
     }
+    match ImageAttachment::from_markup(line) {
+        Some(image) => UserLine::Image(image),
+        None => UserLine::Text(line),
+    }
+}
+
+/// Whether a line of user text is escaped in the formatted context
+///
+/// Lines that read as images are, and so are those that already start with
+/// the escape before one, so that escaping can always be undone.
+fn is_escaped_line(line: &str) -> bool {
+    is_image_line(line.trim_start().trim_start_matches(TEXT_ESCAPE))
+}
+
+/// The images a tool result line holds
+pub fn tool_result_images(line: &Value) -> Vec<ImageAttachment> {
+    line.get(TOOL_RESULT_IMAGES)
+        .cloned()
+        .and_then(|images| serde_json::from_value(images).ok())
+        .unwrap_or_default()
+}
+
+/// A tool result line for an image a tool returned as JSON
+fn image_tool_result(id: &str, json: &str) -> String {
+    match serde_json::from_str::<ImageAttachment>(json) {
+        Ok(image) => format!(
+            "{{\"type\": \"tool_result\", \"tool_use_id\": \"{}\", \"content\": [], \"{}\": {}}}",
+            id,
+            TOOL_RESULT_IMAGES,
+            serde_json::to_string(&[image]).unwrap_or_default()
+        ),
+        Err(e) => format!(
+            "{{\"type\": \"tool_result\", \"tool_use_id\": \"{}\", \"content\": {}}}",
+            id,
+            serde_json::Value::String(format!("Error: the tool did not return an image: {}", e))
+        ),
+    }
+}
+
+/// A tool result line with each of its images replaced by its name
+fn describe_tool_result_images(line: &str) -> String {
+    let mut json = match serde_json::from_str::<Value>(line) {
+        Ok(json) => json,
+        Err(_) => return line.to_string(),
+    };
+    let images = tool_result_images(&json);
+    if images.is_empty() {
+        return line.to_string();
+    }
+    json[TOOL_RESULT_IMAGES] = images.iter().map(describe_image).collect();
+    json.to_string()
+}
+
+/// An image as it appears in a transcript
+fn describe_image(image: &ImageAttachment) -> String {
+    format!("[Image: {}]", image.name)
+}
+
+/// Tokens the model counts for some images, and the characters of their data
+fn image_size(images: &[ImageAttachment]) -> (usize, usize) {
+    images.iter().fold((0, 0), |(tokens, chars), image| {
+        (tokens + image.estimated_tokens(), chars + image.data.len())
+    })
 }
 
 impl Clone for Message {
@@ -475,6 +954,7 @@ impl Clone for Message {
             role: self.role,
             content: self.content.clone(),
             tool_name: self.tool_name.clone(),
+            images: self.images.clone(),
         }
     }
 }
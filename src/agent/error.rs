@@ -0,0 +1,72 @@
+// Failures of agent operations
+
+use crate::agent::backends::BackendError;
+use crate::agent::context::ContextError;
+use thiserror::Error;
+
+/// Why an agent operation failed
+#[derive(Debug, Clone, Error)]
+pub enum AgentError {
+    /// A turn was started before `init()`
+    #[error("Backend not initialized. Call init() first.")]
+    NotInitialized,
+
+    /// The model request of a turn failed
+    #[error("Backend error: {0}")]
+    Backend(#[from] BackendError),
+
+    /// The conversation could not be changed as asked
+    #[error(transparent)]
+    Context(#[from] ContextError),
+
+    /// There is no paused turn
+    #[error("No paused turn to continue")]
+    NothingToContinue,
+
+    /// The conversation has no turns
+    #[error("There is no turn to undo")]
+    NothingToUndo,
+
+    /// The files changed by a turn could not be restored
+    #[error("{0}")]
+    Checkpoint(String),
+
+    /// The model is not in the catalog
+    #[error("Unknown model '{name}'; the catalog has {available}")]
+    UnknownModel { name: String, available: String },
+
+    /// No profile has the given name
+    #[error("Unknown profile '{name}'; the profiles are {available}")]
+    UnknownProfile { name: String, available: String },
+
+    /// A tool call did not fit in the output limit even after it was raised
+    #[error("The model's tool call did not fit in {tokens} output tokens")]
+    ToolCallTruncated { tokens: usize },
+
+    /// The request for a session title failed
+    #[error("Title generation error: {0}")]
+    Title(BackendError),
+
+    /// The model answered the title request without text
+    #[error("Title generation returned no text")]
+    EmptyTitle,
+
+    /// The request to summarize older turns failed
+    #[error("Context compression error: {0}")]
+    Compaction(BackendError),
+}
+
+impl AgentError {
+    /// The backend failure behind this error, if there is one
+    fn backend_error(&self) -> Option<&BackendError> {
+        match self {
+            AgentError::Backend(e) | AgentError::Title(e) | AgentError::Compaction(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// What the user can do about the failure, if anything
+    pub fn hint(&self) -> Option<&'static str> {
+        self.backend_error().and_then(BackendError::hint)
+    }
+}
@@ -0,0 +1,255 @@
+use crate::agent::attachments::Thumbnail;
+use crate::agent::backends::{BackendError, ModelSelection, StopReason};
+use crate::agent::error::AgentError;
+use crate::agent::manager::{AgentMode, AgentResponse};
+use crate::agent::message_tree::BranchPoint;
+use crate::agent::tools::ToolError;
+use crate::agent::usage::UsageLedger;
+use crate::core::session::JournalEntry;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Events emitted by the agent for the UI to consume
+///
+/// Events are sent as they happen during a turn, so the UI can show model
+/// requests and tool executions with their real timing.
+pub enum AgentEvent {
+    /// The backend was initialized and the agent is ready for input
+    Ready,
+
+    /// The backend could not be initialized (retried on the next submission)
+    InitFailed(AgentError),
+
+    /// An image mentioned in the user's input could not be attached, and the
+    /// mention was left as text
+    ImageSkipped(String),
+
+    /// The user's input was added to the conversation
+    InputRecorded {
+        /// ID of the message, used to edit it later
+        node: u64,
+
+        /// Previews of the images attached to the message
+        images: Vec<Thumbnail>,
+    },
+
+    /// A request was sent to the model
+    RequestSent {
+        /// Model the request was sent to
+        model: String,
+
+        /// Whether the model thinks before it answers
+        thinking: bool,
+    },
+
+    /// A request failed with a transient error and is sent again after a wait
+    Retrying {
+        /// Retry about to be made, counting from 1
+        attempt: usize,
+
+        /// Most retries made for one request
+        max_retries: usize,
+
+        /// Wait before the request is sent again
+        delay: Duration,
+
+        /// Why the request failed
+        error: BackendError,
+    },
+
+    /// Repeated throttling moved the rest of the turn to the model's fallback
+    ModelFallback {
+        /// Name of the throttled model
+        from: String,
+
+        /// Name of the model used instead
+        to: String,
+    },
+
+    /// Reasoning the model did before the latest response
+    Reasoning(String),
+
+    /// A piece of the latest response's text, as it arrives from the model
+    TextDelta(String),
+
+    /// Text of the latest response, sent once the whole response has arrived
+    TextReceived(String),
+
+    /// A response stopped in a way the user should know about: it was
+    /// refused, reached a stop sequence, or was left cut off at the output limit
+    ResponseStopped(StopReason),
+
+    /// The model asked for a tool to be run
+    ToolRequested {
+        /// Tool use ID from the model
+        id: String,
+
+        /// Name of the requested tool
+        name: String,
+    },
+
+    /// A tool started executing
+    ToolStarted { id: String, name: String },
+
+    /// A tool finished successfully
+    ToolFinished {
+        id: String,
+        name: String,
+        duration: Duration,
+    },
+
+    /// A tool returned an error
+    ToolFailed {
+        id: String,
+        name: String,
+        error: ToolError,
+        duration: Duration,
+    },
+
+    /// The tool chain moved to a new depth
+    ChainDepthChanged { depth: usize, max_depth: usize },
+
+    /// A submitted turn completed
+    TurnDone(AgentResponse),
+
+    /// A submitted turn, or a change to the conversation, failed
+    Error(AgentError),
+
+    /// The in-flight turn was cancelled and removed from the context
+    Cancelled,
+
+    /// A turn paused at the tool round limit was stopped
+    Stopped {
+        /// Number of pending tool calls that were cancelled
+        cancelled: usize,
+    },
+
+    /// The model picked for a role was changed
+    ModelsChanged(ModelSelection),
+
+    /// A model could not be selected, as when a resumed session names one
+    /// that is no longer in the catalog
+    ModelUnavailable(String),
+
+    /// The session switched between ask and agent mode
+    ModeChanged(AgentMode),
+
+    /// Another profile was switched to, by name
+    ProfileChanged(String),
+
+    /// A profile could not be switched to, as when the configured one does not exist
+    ProfileUnavailable(String),
+
+    /// The system prompt the next request will be sent with, as asked for
+    SystemPrompt(String),
+
+    /// The conversation context was cleared and a new session started
+    Cleared,
+
+    /// A saved session was loaded and replaces the current conversation
+    SessionResumed {
+        /// Title of the resumed session
+        title: String,
+
+        /// Models the session was using
+        models: ModelSelection,
+
+        /// Working directory tools now run in
+        working_directory: String,
+
+        /// Saved journal to show in place of the current one
+        journal: Vec<JournalEntry>,
+
+        /// Tool calls still waiting to run if the session was paused
+        paused_tools: Vec<String>,
+    },
+
+    /// Edited user messages on the active branch changed
+    BranchesChanged(Vec<BranchPoint>),
+
+    /// Another branch of the conversation became active
+    BranchSwitched {
+        /// Journal rebuilt from the messages on the new branch
+        journal: Vec<JournalEntry>,
+    },
+
+    /// Older turns were replaced by a summary to keep the context within its limit
+    ContextCompacted {
+        /// Number of messages summarized
+        messages: usize,
+
+        /// Estimated tokens saved
+        tokens_saved: usize,
+    },
+
+    /// Older turns could not be summarized; the context was left as it is
+    ContextCompactionFailed(String),
+
+    /// A request's tokens were counted, or the session's usage was replaced
+    UsageChanged(UsageLedger),
+
+    /// The turn or session passed the warning level of a budget limit
+    BudgetWarning(String),
+
+    /// The last turn was removed and the files it changed were put back
+    TurnUndone {
+        /// ID of the user input that started the removed turn
+        node: u64,
+
+        /// Files restored to how they were before the turn
+        restored: Vec<String>,
+
+        /// Files that could not be restored, with the reason
+        failed: Vec<String>,
+
+        /// The turn's snapshots had been dropped for space, so no files were restored
+        evicted: bool,
+    },
+
+    /// A cancelled or failed turn was discarded and the files it changed were put back
+    DiscardedFilesRestored {
+        /// Files restored to how they were before the turn
+        restored: Vec<String>,
+
+        /// Files that could not be restored, with the reason
+        failed: Vec<String>,
+
+        /// The turn's snapshots had been dropped for space, so no files were restored
+        evicted: bool,
+    },
+
+    /// Saved sessions changed on disk, or a different session became current
+    SessionsChanged {
+        /// ID of the current session
+        current: String,
+    },
+
+    /// A session could not be saved, resumed or changed
+    SessionFailed(String),
+}
+
+/// Sending half of the agent event channel
+///
+/// Sending never fails loudly: if the receiver is gone there is nobody left
+/// to show the event to.
+#[derive(Clone, Default)]
+pub struct EventSink {
+    sender: Option<UnboundedSender<AgentEvent>>,
+}
+
+impl EventSink {
+    /// Create a sink that forwards events to the given channel
+    pub fn new(sender: UnboundedSender<AgentEvent>) -> Self {
+        Self {
+            sender: Some(sender),
+        }
+    }
+
+    /// Emit an event, returning false if nobody is listening
+    pub fn emit(&self, event: AgentEvent) -> bool {
+        match &self.sender {
+            Some(sender) => sender.send(event).is_ok(),
+            None => false,
+        }
+    }
+}
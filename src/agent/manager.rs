@@ -1,15 +1,30 @@
-use crate::agent::backends::{Backend, BackendCore, BedrockBackend, BedrockModel};
+use crate::agent::attachments::{mentioned_images, ImageAttachment};
+use crate::agent::backends::{
+    is_reasoning_line, reasoning_text, Backend, BackendError, BackendResponse, BedrockBackend,
+    BedrockConfig, ModelCatalog, ModelRole, ModelSelection, ModelSpec, StopReason, ToolUse,
+};
+use crate::agent::budget::{BudgetLimits, BudgetTracker};
 use crate::agent::context::ContextManager;
-use crate::agent::tools::ToolRegistry;
+use crate::agent::error::AgentError;
+use crate::agent::events::{AgentEvent, EventSink};
+use crate::agent::message_tree::{MessageTree, TreeCheckpoint};
+use crate::agent::prompt::{self, PromptEnvironment, DEFAULT_TEMPLATE};
+use crate::agent::retry::RetryPolicy;
+use crate::agent::tools::{ToolError, ToolRegistry};
+use crate::agent::usage::{RequestPurpose, UsageLedger};
+use crate::core::checkpoint::{CheckpointStore, RestoreReport};
 // Removed regex dependency
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
 use tracing::{error, info, trace, warn};
 
 /// Central manager for the AI agent
 pub struct AgentManager {
     /// The currently active backend for LLM processing
-    pub backend: BedrockBackend,
+    pub backend: Box<dyn Backend>,
 
     /// Tool registry for managing available tools
     pub tool_registry: ToolRegistry,
@@ -22,34 +37,160 @@ pub struct AgentManager {
 
     /// Whether the backend is initialized
     initialized: bool,
+
+    /// Destination for progress events
+    events: EventSink,
+
+    /// Tool calls left pending when the last turn reached its round limit
+    paused: Option<Vec<ToolUse>>,
+
+    /// Snapshots of files changed by tools, used to undo turns
+    checkpoints: Option<CheckpointStore>,
+
+    /// Tokens and cost of the requests made during the session
+    usage: UsageLedger,
+
+    /// Spending of the current turn and session against their limits
+    budget: BudgetTracker,
+
+    /// Names of the models picked for each role
+    models: ModelSelection,
+
+    /// Profile whose settings are in use, if one was picked
+    profile: Option<Profile>,
+
+    /// Whether tools that change files or run commands may run
+    mode: AgentMode,
 }
 
+/// Added to the system prompt in ask mode
+const ASK_MODE_NOTE: &str = "The session is in read-only ask mode: answer questions by reading \
+the workspace. Tools that change files or run commands are not available.";
+
 /// Configuration settings for the agent
 pub struct AgentConfig {
-    /// Whether to use the fast model for context management
-    pub use_fast_model_for_context: bool,
+    /// Most tokens the context may use before it is compressed, below the
+    /// model's own window (the window alone decides if not set)
+    pub max_context_length: Option<usize>,
 
-    /// Maximum context length to maintain
-    pub max_context_length: usize,
+    /// Fraction of the model's context window the context may fill before it is compressed
+    pub compress_threshold: f64,
 
     /// Whether to automatically compress older context
     pub auto_compress_context: bool,
 
+    /// Number of most recent turns kept word for word when compressing
+    pub keep_recent_turns: usize,
+
     /// AWS region to use
     pub aws_region: String,
 
     /// AWS profile to use
     pub aws_profile: Option<String>,
+
+    /// Models the agent may use
+    pub catalog: ModelCatalog,
+
+    /// Names of the models picked for each role when a session starts
+    pub models: ModelSelection,
+
+    /// Limits on the tool rounds of a single turn
+    pub tool_chain: ToolChainConfig,
+
+    /// How model requests that fail with transient errors are retried
+    pub retry: RetryPolicy,
+
+    /// Limits on what a single turn and the whole session may spend
+    pub turn_budget: BudgetLimits,
+    pub session_budget: BudgetLimits,
+
+    /// Fraction of a budget limit at which a warning is given
+    pub budget_warn_at: f64,
+
+    /// Template the system prompt is rendered from
+    pub system_prompt: String,
+
+    /// Whether project instructions files are added to the system prompt
+    pub project_instructions: bool,
+
+    /// Profiles that can be switched to during a session
+    pub profiles: Vec<Profile>,
+
+    /// Name of the profile picked when the agent is initialized
+    pub profile: Option<String>,
+}
+
+/// A named set of agent settings that can be switched to during a session
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    /// Name the profile is picked by
+    pub name: String,
+
+    /// What the profile is for, shown when profiles are listed
+    pub description: Option<String>,
+
+    /// Template the system prompt is rendered from
+    pub system_prompt: String,
+
+    /// Names of the tools the model may use
+    pub tools: Vec<String>,
+
+    /// Primary model, by catalog name
+    pub model: String,
+
+    /// Temperature used in place of the primary model's default
+    pub temperature: Option<f32>,
+
+    /// Maximum number of tool rounds in one turn
+    pub max_depth: usize,
+}
+
+/// Configuration for tool chain processing
+pub struct ToolChainConfig {
+    /// Maximum number of tool rounds in one turn
+    pub max_depth: usize,
+
+    /// Delay between API calls in milliseconds (to avoid throttling)
+    pub delay_ms: u64,
+
+    /// Most times a response cut off at the output limit is continued
+    pub max_continuations: usize,
 }
 
 impl Default for AgentConfig {
     fn default() -> Self {
         Self {
-            use_fast_model_for_context: true,
-            max_context_length: 32000,
+            max_context_length: None,
+            compress_threshold: 0.8,
             auto_compress_context: true,
+            keep_recent_turns: 2,
             aws_region: "us-east-1".to_string(),
             aws_profile: None,
+            catalog: ModelCatalog::builtin(),
+            models: ModelSelection {
+                primary: "sonnet".to_string(),
+                summarization: "haiku".to_string(),
+                title: "haiku".to_string(),
+            },
+            tool_chain: ToolChainConfig::default(),
+            retry: RetryPolicy::default(),
+            turn_budget: BudgetLimits::default(),
+            session_budget: BudgetLimits::default(),
+            budget_warn_at: 0.8,
+            system_prompt: DEFAULT_TEMPLATE.to_string(),
+            project_instructions: true,
+            profiles: Vec::new(),
+            profile: None,
+        }
+    }
+}
+
+impl Default for ToolChainConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: 5,  // Default max depth of 5: Allows for longer tool chains
+            delay_ms: 200, // Default delay of 200ms between API calls
+            max_continuations: 3,
         }
     }
 }
@@ -57,23 +198,37 @@ impl Default for AgentConfig {
 impl AgentManager {
     /// Create a new agent manager with default settings
     pub fn new() -> Self {
-        Self {
-            backend: BedrockBackend::new(),
-            tool_registry: ToolRegistry::new(),
-            context_manager: ContextManager::new(),
-            config: AgentConfig::default(),
-            initialized: false,
-        }
+        Self::with_config(AgentConfig::default())
     }
 
     /// Create a new agent manager with custom configuration
     pub fn with_config(config: AgentConfig) -> Self {
+        let backend = bedrock_backend(&config, BedrockConfig::default());
+        Self::with_backend(config, backend)
+    }
+
+    /// Create a new agent manager that uses the given backend
+    pub fn with_backend(config: AgentConfig, backend: Box<dyn Backend>) -> Self {
+        let budget = BudgetTracker::new(
+            config.turn_budget.clone(),
+            config.session_budget.clone(),
+            config.budget_warn_at,
+        );
+        let models = config.models.clone();
         Self {
-            backend: BedrockBackend::new(),
+            backend,
             tool_registry: ToolRegistry::new(),
             context_manager: ContextManager::new(),
             config,
             initialized: false,
+            events: EventSink::default(),
+            paused: None,
+            checkpoints: None,
+            usage: UsageLedger::default(),
+            budget,
+            models,
+            profile: None,
+            mode: AgentMode::default(),
         }
     }
 
@@ -85,178 +240,626 @@ impl AgentManager {
     /// Set the working directory for tool execution
     pub fn set_working_directory(&mut self, directory: &str) {
         self.tool_registry.set_working_directory(directory);
+        self.refresh_system_prompt();
     }
 
-    /// Initialize the agent manager
-    pub async fn init(&mut self) -> Result<(), String> {
-        // Initialize backend with AWS configuration
-        let mut backend_config = self.backend.config().clone();
-        backend_config.region = self.config.aws_region.clone();
-        if let Some(profile) = &self.config.aws_profile {
-            backend_config.use_profile = true;
-            backend_config.profile_name = Some(profile.clone());
-        }
+    /// Get the working directory used for tool execution
+    pub fn working_directory(&self) -> &str {
+        self.tool_registry.working_directory()
+    }
+
+    /// Snapshot files in the given store before tools change them
+    pub fn set_checkpoint_store(&mut self, checkpoints: CheckpointStore) {
+        self.checkpoints = Some(checkpoints);
+    }
 
-        // Create a new backend with updated config
-        self.backend = BedrockBackend::with_config(backend_config);
+    /// Send progress events to the given sink
+    pub fn set_event_sink(&mut self, events: EventSink) {
+        self.events = events;
+    }
+
+    /// Replace the backend configuration (takes effect on the next `init()`)
+    pub fn set_backend_config(&mut self, config: BedrockConfig) {
+        self.backend = bedrock_backend(&self.config, config);
+    }
 
+    /// Initialize the agent manager
+    pub async fn init(&mut self) -> Result<(), AgentError> {
         // Initialize the backend
         self.backend.init().await?;
+        self.backend
+            .switch_model(self.model_spec(ModelRole::Primary));
+        self.refresh_system_prompt();
+
+        // The configured profile only applies until another one is picked
+        let selected = match self.config.profile.clone() {
+            Some(name) if self.profile.is_none() => self.select_profile(&name),
+            _ => Ok(()),
+        };
+        if let Err(e) = selected {
+            warn!("Could not use the configured profile: {}", e);
+            self.events
+                .emit(AgentEvent::ProfileUnavailable(e.to_string()));
+        }
 
         self.initialized = true;
         Ok(())
     }
-    
+
+    /// The system prompt as it is sent with the next request
+    pub fn system_prompt(&mut self) -> String {
+        self.refresh_system_prompt();
+        self.context_manager.system_prompt()
+    }
+
+    /// Render the system prompt template for the current working directory, tools and date
+    fn refresh_system_prompt(&mut self) {
+        let directory = Path::new(self.working_directory());
+        let directory = directory
+            .canonicalize()
+            .unwrap_or_else(|_| directory.to_path_buf());
+        // Models without tool use are not offered any
+        let mut tools = if self.backend.current_model().capabilities.tools {
+            self.tool_registry.available_tools()
+        } else {
+            Vec::new()
+        };
+        tools.sort();
+
+        let environment =
+            PromptEnvironment::gather(&directory, tools, self.config.project_instructions);
+        let mut prompt = prompt::render(&self.config.system_prompt, &environment);
+        if self.mode == AgentMode::Ask {
+            prompt.push_str("\n\n");
+            prompt.push_str(ASK_MODE_NOTE);
+        }
+        self.context_manager.set_system_prompt(&prompt);
+    }
+
     /// Check if the agent manager is initialized
     pub fn is_initialized(&self) -> bool {
         self.initialized
     }
 
     /// Process user input and generate a response
-    pub async fn process_input(&mut self, input: &str) -> Result<AgentResponse, String> {
-        info!("Processing user input: {} chars", input.len());
+    ///
+    /// This runs the whole turn: the model is asked for a response, any tool
+    /// calls it makes are executed and their results sent back, and this
+    /// repeats until the model answers without tool calls or the configured
+    /// number of tool rounds is used up. A tool that fails does not end the
+    /// turn; its error is returned to the model as the tool result.
+    ///
+    /// A turn that runs out of rounds, or reaches a hard budget limit, is
+    /// paused rather than dropped: the pending calls are kept until
+    /// `continue_turn` or `stop_paused_turn`.
+    pub async fn process_input(&mut self, input: &str) -> Result<AgentResponse, AgentError> {
+        self.process_input_with_images(input, &[]).await
+    }
+
+    /// Process user input with images attached to it
+    ///
+    /// Image files mentioned in the input as `@path`, relative to the working
+    /// directory, are attached after the given images. A mention that cannot be
+    /// read, or leads outside the working directory, is left as text and
+    /// reported with `AgentEvent::ImageSkipped`.
+    pub async fn process_input_with_images(
+        &mut self,
+        input: &str,
+        images: &[ImageAttachment],
+    ) -> Result<AgentResponse, AgentError> {
+        info!(
+            "Processing user input: {} chars, {} images",
+            input.len(),
+            images.len()
+        );
 
         // Check if backend is initialized
         if !self.initialized {
-            return Err("Backend not initialized. Call init() first.".to_string());
+            return Err(AgentError::NotInitialized);
         }
 
+        let mut images = images.to_vec();
+        images.extend(self.mentioned_images(input));
+
+        // New input abandons a paused turn
+        self.stop_paused_turn();
+
         // First, update context with user input
-        self.context_manager.add_user_message(input);
+        let node = self
+            .context_manager
+            .add_user_message_with_images(input, images);
+        self.emit_input_recorded(node);
         info!("Context updated with user message");
 
-        // Prepare context for LLM
-        let context = self.context_manager.get_context();
-        info!("Prepared context for LLM: {} chars", context.len());
+        let max_rounds = self.config.tool_chain.max_depth;
+        self.run_turn(TurnState::Generate, max_rounds).await
+    }
+
+    /// Replace an earlier user message and run the turn again from there
+    ///
+    /// The conversation forks at the edited message: the new version and its
+    /// turn go on a new branch, and the original branch is kept so it can be
+    /// switched back to with `switch_branch`.
+    pub async fn edit_input(
+        &mut self,
+        node: u64,
+        input: &str,
+    ) -> Result<AgentResponse, AgentError> {
+        info!("Editing user message {}: {} chars", node, input.len());
+
+        if !self.initialized {
+            return Err(AgentError::NotInitialized);
+        }
+
+        let images = self.mentioned_images(input);
+
+        // Answer any paused calls so the branch being left stays valid
+        self.stop_paused_turn();
+
+        let node = self
+            .context_manager
+            .edit_user_message(node, input, images)?;
+        self.emit_input_recorded(node);
+
+        let max_rounds = self.config.tool_chain.max_depth;
+        self.run_turn(TurnState::Generate, max_rounds).await
+    }
+
+    /// Load the image files mentioned in user input
+    ///
+    /// A mention may just be prose, like an email address, so one that does
+    /// not load is reported and left as text rather than failing the turn.
+    fn mentioned_images(&self, input: &str) -> Vec<ImageAttachment> {
+        let directory = std::path::Path::new(self.working_directory());
+        mentioned_images(input)
+            .iter()
+            .filter_map(|path| match ImageAttachment::load_in(directory, path) {
+                Ok(image) => Some(image),
+                Err(e) => {
+                    warn!("Mentioned image {} not attached: {}", path, e);
+                    self.events
+                        .emit(AgentEvent::ImageSkipped(format!("{}: {}", path, e)));
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Report a recorded user message, with previews of its images
+    fn emit_input_recorded(&self, node: u64) {
+        let images = self
+            .context_manager
+            .tree()
+            .entries()
+            .find(|(id, _)| *id == node)
+            .map(|(_, message)| message.images.clone())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|image| match image.thumbnail() {
+                Ok(thumbnail) => Some(thumbnail),
+                Err(e) => {
+                    warn!("No preview for attached image: {}", e);
+                    None
+                }
+            })
+            .collect();
+        self.events.emit(AgentEvent::InputRecorded { node, images });
+    }
+
+    /// Switch to another version of an edited user message
+    pub fn switch_branch(&mut self, node: u64, version: usize) -> Result<(), AgentError> {
+        self.stop_paused_turn();
+        self.context_manager.switch_branch(node, version)?;
+        Ok(())
+    }
 
-        // Process with LLM
-        info!("Sending request to LLM backend...");
-        let backend_response = self
-            .backend
-            .generate_response(&context)
+    /// Resume a paused turn, allowing up to `rounds` more tool rounds
+    ///
+    /// Continuing confirms any budget limit the turn was paused at, so that
+    /// budget is counted again from here.
+    pub async fn continue_turn(&mut self, rounds: usize) -> Result<AgentResponse, AgentError> {
+        let pending = self.paused.clone().ok_or(AgentError::NothingToContinue)?;
+        self.budget.confirm(&self.usage);
+
+        info!(
+            "Continuing paused turn with {} pending tool calls for {} more rounds",
+            pending.len(),
+            rounds
+        );
+        self.run_turn(TurnState::RunTools(pending), rounds.max(1))
             .await
-            .map_err(|e| {
-                error!("Backend error: {}", e);
-                format!("Backend error: {}", e)
-            })?;
+    }
+
+    /// Answer the tool calls of a paused turn without running them
+    ///
+    /// Returns the number of calls that were cancelled.
+    pub fn stop_paused_turn(&mut self) -> usize {
+        let pending = match self.paused.take() {
+            Some(pending) => pending,
+            None => return 0,
+        };
+
+        // Every tool_use needs a matching tool_result, so answer the pending
+        // calls instead of leaving them in the context
+        let cancelled: Vec<ToolResult> = pending
+            .iter()
+            .map(|call| ToolResult {
+                tool_name: call.name.clone(),
+                result: "Cancelled: the user stopped the turn before this tool ran".to_string(),
+                tool_call_id: call.id.clone(),
+                image: false,
+            })
+            .collect();
+        self.add_tool_results(&cancelled);
+
+        info!(
+            "Stopped paused turn, cancelled {} tool calls",
+            pending.len()
+        );
+        pending.len()
+    }
+
+    /// Remove the last turn from the conversation and restore the files it changed
+    ///
+    /// Returns the ID of the removed user input along with which files were
+    /// put back. A paused turn is dropped along with its pending calls.
+    pub fn undo_last_turn(&mut self) -> Result<(u64, RestoreReport), AgentError> {
+        let node = self
+            .context_manager
+            .last_input()
+            .ok_or(AgentError::NothingToUndo)?;
+
+        let report = self.restore_files(node)?;
+        self.paused = None;
+        self.context_manager.remove_from(node)?;
+
         info!(
-            "Received response from LLM: {} chars",
-            backend_response.content.len()
+            "Undid turn {}, restored {} files",
+            node,
+            report.restored.len()
         );
+        Ok((node, report))
+    }
+
+    /// Put back the files changed by the turn started at user input `turn`
+    pub fn restore_files(&mut self, turn: u64) -> Result<RestoreReport, AgentError> {
+        match &mut self.checkpoints {
+            Some(checkpoints) => checkpoints.undo(turn).map_err(AgentError::Checkpoint),
+            None => Ok(RestoreReport::default()),
+        }
+    }
+
+    /// Check whether a turn is paused at its round limit or a budget limit
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// Remember the current state of the conversation
+    pub fn checkpoint(&self) -> TurnCheckpoint {
+        TurnCheckpoint {
+            context: self.context_manager.checkpoint(),
+            paused: self.paused.clone(),
+        }
+    }
+
+    /// Roll the conversation back to a checkpoint, discarding everything after it
+    pub fn restore(&mut self, checkpoint: TurnCheckpoint) {
+        self.context_manager.restore(checkpoint.context);
+        self.paused = checkpoint.paused;
+    }
 
-        // Get tool calls directly from the backend response
-        info!("Processing tool calls from response");
-        // Extract tool calls directly from the structured response
-        let tool_calls: Vec<ToolCall> = backend_response
-            .tool_calls
+    /// Tool calls waiting to run while a turn is paused
+    pub fn pending_tool_calls(&self) -> &[ToolUse] {
+        self.paused.as_deref().unwrap_or(&[])
+    }
+
+    /// Describe the pending tool calls the way they are shown to the user
+    pub fn describe_pending_tool_calls(&self) -> Vec<String> {
+        self.pending_tool_calls()
             .iter()
-            .map(|tc| {
-                let args = tc
-                    .args
-                    .iter()
-                    .map(|(k, v)| format!("{}={}", k, v))
-                    .collect();
+            .map(describe_tool_call)
+            .collect()
+    }
+
+    /// Replace the conversation with a saved one
+    ///
+    /// Calls that were pending when the conversation was saved pause the turn
+    /// again, so they can still be continued or stopped.
+    pub fn resume_conversation(&mut self, messages: MessageTree, pending: Vec<ToolUse>) {
+        self.context_manager.replace_tree(messages);
+        self.paused = if pending.is_empty() {
+            None
+        } else {
+            Some(pending)
+        };
+    }
+
+    /// Run the agent loop from the given state, counting its spending against the budget
+    async fn run_turn(
+        &mut self,
+        state: TurnState,
+        max_rounds: usize,
+    ) -> Result<AgentResponse, AgentError> {
+        self.budget.start_turn(&self.usage);
+        self.refresh_system_prompt();
+        let result = self.run_turn_steps(state, max_rounds).await;
+        self.usage.add_working_time(self.budget.turn_elapsed());
+
+        // A fallback model only stands in for the rest of the turn
+        let primary = self.model_spec(ModelRole::Primary);
+        if self.backend.current_model().name != primary.name {
+            self.backend.switch_model(primary);
+        }
+        result
+    }
+
+    /// Run the agent loop from the given state until the turn ends or pauses
+    async fn run_turn_steps(
+        &mut self,
+        mut state: TurnState,
+        max_rounds: usize,
+    ) -> Result<AgentResponse, AgentError> {
+        let mut response = AgentResponse {
+            content: String::new(),
+            tool_results: Vec::new(),
+            rounds: 0,
+            tokens_used: 0,
+            stop: TurnStop::Complete,
+        };
+
+        loop {
+            state = match state {
+                TurnState::Generate => self.generate_step(&mut response, 0, max_rounds).await?,
+                TurnState::Continue(continuation) => {
+                    self.generate_step(&mut response, continuation, max_rounds)
+                        .await?
+                }
+                TurnState::RunTools(tool_calls) => {
+                    // The pending calls are about to be answered
+                    self.paused = None;
+
+                    response.rounds += 1;
+                    trace!(
+                        "Round {}/{}: Processing {} tool calls",
+                        response.rounds,
+                        max_rounds,
+                        tool_calls.len()
+                    );
+                    self.events.emit(AgentEvent::ChainDepthChanged {
+                        depth: response.rounds,
+                        max_depth: max_rounds,
+                    });
+
+                    let tool_results = self.execute_tool_calls(&tool_calls).await;
+                    self.add_tool_results(&tool_results);
+                    response.tool_results.extend(tool_results);
+
+                    // Add a delay between API calls to avoid throttling
+                    if self.config.tool_chain.delay_ms > 0 {
+                        tokio::time::sleep(Duration::from_millis(self.config.tool_chain.delay_ms))
+                            .await;
+                    }
 
-                // Log the tool call ID to track it through the system
-                if let Some(id) = &tc.id {
-                    trace!("Received tool call with ID '{}' for tool '{}'", id, tc.name);
-                } else {
-                    warn!("Received tool call without ID for tool '{}'", tc.name);
+                    TurnState::Generate
                 }
+                TurnState::RoundLimit(tool_calls) => {
+                    info!(
+                        "Reached the limit of {} tool rounds, pausing with {} pending tool calls",
+                        max_rounds,
+                        tool_calls.len()
+                    );
 
-                ToolCall {
-                    name: tc.name.clone(),
-                    args,
-                    args_json: Some(tc.args.clone()),
-                    id: tc.id.clone(),
+                    let pending = tool_calls.iter().map(describe_tool_call).collect();
+                    self.paused = Some(tool_calls);
+
+                    TurnState::Done(TurnStop::RoundLimit { pending })
                 }
-            })
-            .collect();
+                TurnState::BudgetLimit(tool_calls, reason) => {
+                    info!(
+                        "Reached a budget limit ({}), pausing with {} pending tool calls",
+                        reason,
+                        tool_calls.len()
+                    );
+
+                    let pending = tool_calls.iter().map(describe_tool_call).collect();
+                    self.paused = Some(tool_calls);
 
-        info!("Found {} tool calls in backend response", tool_calls.len());
-        info!("Processing {} tool calls", tool_calls.len());
+                    TurnState::Done(TurnStop::BudgetLimit { pending, reason })
+                }
+                TurnState::Done(stop) => {
+                    response.stop = stop;
+                    break;
+                }
+            };
+        }
 
-        // Execute any tool calls
-        let tool_results = if !tool_calls.is_empty() {
-            info!("Executing tool calls");
-            self.execute_tool_calls(tool_calls).await?
+        // Compress context if needed, but not in the middle of a paused turn
+        let compressed = if self.config.auto_compress_context && !self.is_paused() {
+            self.maybe_compress_context().await
         } else {
-            info!("No tool calls to execute");
-            Vec::new()
+            Ok(())
         };
+        // The turn's tools have run by now, so a failed summary only leaves
+        // the context as it is rather than failing the turn
+        if let Err(e) = compressed {
+            warn!("Could not compress the context: {}", e);
+            self.events
+                .emit(AgentEvent::ContextCompactionFailed(e.to_string()));
+        }
 
-        // Add assistant response to context
-        self.context_manager
-            .add_assistant_message(&backend_response.content);
-        info!("Added assistant response to context");
-
-        // Add tool results to context if any
-        if !tool_results.is_empty() {
-            info!("Adding {} tool results to context", tool_results.len());
-            
-            // Log each tool result being added
-            for (i, result) in tool_results.iter().enumerate() {
-                trace!("Tool result {}: name={}, id={:?}, content length={}", 
-                      i, 
-                      result.tool_name, 
-                      result.tool_call_id, 
-                      result.result.len());
-                
-                // Log the beginning of the content to help debug formatting issues
-                if result.tool_name == "read_file" {
-                    trace!("read_file result first 200 chars: {}", 
-                          if result.result.len() > 200 { 
-                              &result.result[..200] 
-                          } else { 
-                              &result.result 
-                          });
-                    trace!("IMPORTANT: read_file result must be passed as raw text without JSON serialization");
-                }
+        info!(
+            "Processing complete after {} tool rounds, returning response",
+            response.rounds
+        );
+        Ok(response)
+    }
+
+    /// Ask the model for the next response and decide where the turn goes from there
+    ///
+    /// `continuation` counts the responses so far that continue a message the
+    /// output limit cut off; zero starts a new message. A message cut off
+    /// again is continued until `max_continuations` is reached.
+    async fn generate_step(
+        &mut self,
+        response: &mut AgentResponse,
+        continuation: usize,
+        max_rounds: usize,
+    ) -> Result<TurnState, AgentError> {
+        let context = self.context_manager.get_context();
+        trace!("Prepared context for LLM: {} chars", context.len());
+
+        let mut backend_response = self.generate(&context).await.map_err(|e| {
+            error!("Backend error: {}", e);
+            AgentError::Backend(e)
+        })?;
+        if is_truncated_tool_call(&backend_response) {
+            // The arguments of a tool call cut off by the output limit are
+            // incomplete, so the response is discarded and asked for again
+            // with more room
+            warn!("A tool call was cut off at the output limit, retrying with a larger limit");
+            self.record_turn_usage(&context, &backend_response, response);
+            backend_response = self
+                .generate_with_more_output(&context)
+                .await
+                .map_err(|e| {
+                    error!("Backend error: {}", e);
+                    AgentError::Backend(e)
+                })?;
+            if is_truncated_tool_call(&backend_response) {
+                self.record_turn_usage(&context, &backend_response, response);
+                return Err(AgentError::ToolCallTruncated {
+                    tokens: self.backend.current_model().max_output_tokens * 2,
+                });
+            }
+        }
+        info!(
+            "Received response from LLM: {} chars, {} tool calls",
+            backend_response.content.len(),
+            backend_response.tool_calls.len()
+        );
+
+        // Add assistant response to context before any of its tool results
+        if continuation == 0 {
+            self.context_manager
+                .add_assistant_message(&backend_response.content);
+            if !response.content.is_empty() {
+                response.content.push_str("\n\n");
             }
-            
-            self.context_manager.add_tool_results(&tool_results);
+        } else {
+            self.context_manager
+                .continue_assistant_message(&backend_response.content);
+            let length = response.content.trim_end().len();
+            response.content.truncate(length);
         }
+        response.content.push_str(&backend_response.content);
+        self.record_turn_usage(&context, &backend_response, response);
 
-        // Compress context if needed
-        if self.config.auto_compress_context {
-            self.maybe_compress_context().await?;
+        let budget = self.budget.check(&self.usage);
+        for warning in budget.warnings {
+            warn!("Budget warning: {}", warning);
+            self.events.emit(AgentEvent::BudgetWarning(warning));
         }
 
-        info!("Processing complete, returning response");
-        Ok(AgentResponse {
-            content: backend_response.content,
-            tool_results,
-        })
+        let stop_reason = backend_response.stop_reason;
+        if matches!(
+            stop_reason,
+            StopReason::Refusal | StopReason::StopSequence(_)
+        ) {
+            info!("Response stopped early: {:?}", stop_reason);
+            self.events
+                .emit(AgentEvent::ResponseStopped(stop_reason.clone()));
+        }
+
+        let state = if !backend_response.tool_calls.is_empty() {
+            if response.rounds >= max_rounds {
+                TurnState::RoundLimit(backend_response.tool_calls)
+            } else if let Some(reason) = budget.exceeded {
+                TurnState::BudgetLimit(backend_response.tool_calls, reason)
+            } else {
+                TurnState::RunTools(backend_response.tool_calls)
+            }
+        } else if stop_reason != StopReason::MaxTokens {
+            TurnState::Done(TurnStop::Complete)
+        } else if continuation < self.config.tool_chain.max_continuations
+            && budget.exceeded.is_none()
+        {
+            info!(
+                "Response reached the output limit, continuing it ({}/{})",
+                continuation + 1,
+                self.config.tool_chain.max_continuations
+            );
+            TurnState::Continue(continuation + 1)
+        } else {
+            // Left as it is, but the user is told it is incomplete
+            self.events
+                .emit(AgentEvent::ResponseStopped(StopReason::MaxTokens));
+            TurnState::Done(TurnStop::Complete)
+        };
+        Ok(state)
     }
 
-    // Removed parse_tool_calls - Using structured tool calls directly
+    /// Send a prompt with twice the current model's output token limit
+    async fn generate_with_more_output(
+        &mut self,
+        prompt: &str,
+    ) -> Result<BackendResponse, BackendError> {
+        let original = self.backend.current_model().clone();
+        let mut larger = original.clone();
+        larger.max_output_tokens *= 2;
+        self.backend.switch_model(larger);
 
-    /// Execute any tool calls found in the response
-    async fn execute_tool_calls(
-        &self,
-        tool_calls: Vec<ToolCall>,
-    ) -> Result<Vec<ToolResult>, String> {
+        let result = self.generate(prompt).await;
+
+        // Stay on a fallback model the request may have moved to
+        if self.backend.current_model().name == original.name {
+            self.backend.switch_model(original);
+        }
+        result
+    }
+
+    /// Count the tokens of a response to a turn request
+    fn record_turn_usage(
+        &mut self,
+        prompt: &str,
+        backend_response: &BackendResponse,
+        response: &mut AgentResponse,
+    ) {
+        if let Some(usage) = backend_response.usage {
+            response.tokens_used += usage.total();
+            self.context_manager.record_usage(prompt, usage);
+        }
+        self.record_usage(RequestPurpose::Turn, backend_response);
+    }
+
+    /// Execute the tool calls from one response, in order
+    ///
+    /// Every call produces a result; failures are reported as error text.
+    async fn execute_tool_calls(&mut self, tool_calls: &[ToolUse]) -> Vec<ToolResult> {
         let mut results = Vec::new();
 
         for tool_call in tool_calls {
-            let result = self
-                .tool_registry
-                .execute_tool(&tool_call.name, &tool_call.args)
-                .await
-                .map_err(|e| format!("Tool execution error: {}", e))?;
+            let args = tool_args(&tool_call.args);
+            let event_id = tool_call.id.clone().unwrap_or_default();
+            let result = match self.snapshot_files(&tool_call.name, &args) {
+                Ok(()) => self.run_tool(&event_id, &tool_call.name, &args).await,
+                Err(e) => Err(ToolError::Snapshot(e)),
+            };
+            let (result, image) = match result {
+                Ok(result) => (result, self.tool_registry.returns_image(&tool_call.name)),
+                Err(e) => {
+                    warn!("Tool {} failed: {}", tool_call.name, e);
+                    (format!("Error: {}", e), false)
+                }
+            };
 
             // CRITICAL: Make sure we're preserving the original ID from Claude's tool_use block
             // This ID must match EXACTLY for Claude's API validation - even a single character difference will fail
-            let tool_call_id = tool_call.id.clone();
-            if let Some(id) = &tool_call_id {
+            if let Some(id) = &tool_call.id {
                 trace!(
                     "USING EXACT Claude-provided tool_use_id: '{}' for result of tool '{}'",
                     id,
                     tool_call.name
                 );
-                trace!(
-                    "ID MUST NOT be modified in any way - even a single character difference will cause validation to fail"
-                );
             } else {
                 // This should never happen with Claude tool calls, and will cause validation to fail
                 warn!(
@@ -269,84 +872,1699 @@ impl AgentManager {
             results.push(ToolResult {
                 tool_name: tool_call.name.clone(),
                 result,
-                tool_call_id: tool_call_id, // This must be passed unmodified to context.rs
+                tool_call_id: tool_call.id.clone(), // This must be passed unmodified to context.rs
+                image,
             });
         }
 
-        Ok(results)
+        results
     }
 
-    /// Compress context if it gets too large
-    async fn maybe_compress_context(&mut self) -> Result<(), String> {
-        if self.context_manager.context_length() > self.config.max_context_length {
-            // Store the original model
-            let original_model = self.backend.current_model();
-
-            // Use the fast model (haiku) for context compression
-            if self.config.use_fast_model_for_context {
-                // Switch to Haiku for summarization
-                self.backend.switch_model(BedrockModel::Haiku);
-            }
+    /// Snapshot the files a tool is about to change, so the turn can be undone
+    fn snapshot_files(&mut self, name: &str, args: &[String]) -> Result<(), String> {
+        let checkpoints = match &mut self.checkpoints {
+            Some(checkpoints) => checkpoints,
+            None => return Ok(()),
+        };
+        let turn = match self.context_manager.last_input() {
+            Some(turn) => turn,
+            None => return Ok(()),
+        };
+
+        for path in self.tool_registry.modified_files(name, args) {
+            checkpoints.snapshot(turn, &path)?;
+        }
+        Ok(())
+    }
 
-            // Get the current context
-            let context = self.context_manager.get_context();
+    /// Add tool results to the context
+    fn add_tool_results(&mut self, tool_results: &[ToolResult]) {
+        info!("Adding {} tool results to context", tool_results.len());
 
-            // Ask LLM to summarize older parts of context
-            let summarization_prompt = format!(
-                "Please summarize the following conversation concisely while preserving all important information:\n{}\n",
-                context
+        // Log each tool result being added
+        for (i, result) in tool_results.iter().enumerate() {
+            trace!(
+                "Tool result {}: name={}, id={:?}, content length={}",
+                i,
+                result.tool_name,
+                result.tool_call_id,
+                result.result.len()
             );
+        }
 
-            let summary_response = self
-                .backend
-                .generate_response(&summarization_prompt)
-                .await
-                .map_err(|e| format!("Context compression error: {}", e))?;
+        self.context_manager.add_tool_results(tool_results);
+    }
 
-            // Replace older context with summary
-            self.context_manager
-                .replace_with_summary(&summary_response.content);
+    /// Send a prompt to the backend, reporting the request and its results as events
+    ///
+    /// Transient failures are retried as the retry policy allows. Once the
+    /// request has been throttled often enough, it moves to the current
+    /// model's fallback, which is then used for the rest of the turn.
+    pub async fn generate(&mut self, prompt: &str) -> Result<BackendResponse, BackendError> {
+        let policy = self.config.retry.clone();
+        let mut retries = 0;
+        let mut throttled = 0;
+
+        let mut response = loop {
+            self.events.emit(AgentEvent::RequestSent {
+                model: self.backend.current_model_name().to_string(),
+                thinking: self.backend.current_model().thinking().is_some(),
+            });
+
+            let events = &self.events;
+            let on_text = |text: &str| {
+                events.emit(AgentEvent::TextDelta(text.to_string()));
+            };
+            let error = match self.backend.stream_response(prompt, &on_text).await {
+                Ok(response) => break response,
+                Err(e) => e,
+            };
+            if !error.is_retryable() || retries >= policy.max_retries {
+                return Err(error);
+            }
+            retries += 1;
 
-            // Switch back to original model if we changed it
-            if self.config.use_fast_model_for_context {
-                self.backend.switch_model(original_model);
+            if matches!(error, BackendError::Throttled(_)) {
+                throttled += 1;
+                if policy.should_fall_back(throttled) {
+                    self.fall_back();
+                }
             }
+
+            let delay = policy.delay(retries);
+            warn!(
+                "Request failed ({}), retry {}/{} in {:?}",
+                error, retries, policy.max_retries, delay
+            );
+            self.events.emit(AgentEvent::Retrying {
+                attempt: retries,
+                max_retries: policy.max_retries,
+                delay,
+                error,
+            });
+            tokio::time::sleep(delay).await;
+        };
+
+        let reasoning = reasoning_text(&response.content);
+        if !reasoning.is_empty() {
+            self.events.emit(AgentEvent::Reasoning(reasoning));
+        }
+        let text = display_text(&response.content);
+        if !text.is_empty() {
+            self.events.emit(AgentEvent::TextReceived(text));
         }
 
-        Ok(())
+        for tool_call in &mut response.tool_calls {
+            // Every call needs an ID so its progress events can be matched up
+            let id = tool_call
+                .id
+                .get_or_insert_with(|| format!("tool-{}", uuid::Uuid::new_v4()))
+                .clone();
+            self.events.emit(AgentEvent::ToolRequested {
+                id,
+                name: tool_call.name.clone(),
+            });
+        }
+
+        Ok(response)
     }
-}
 
-/// Structure representing a tool call extracted from LLM response
-pub struct ToolCall {
-    /// Name of the tool to call
-    pub name: String,
+    /// Switch to the current model's fallback, if it has one in the catalog
+    fn fall_back(&mut self) {
+        let current = self.backend.current_model();
+        let fallback = match current
+            .fallback
+            .as_deref()
+            .and_then(|name| self.config.catalog.get(name))
+        {
+            Some(fallback) if fallback.name != current.name => fallback.clone(),
+            _ => return,
+        };
+
+        info!(
+            "Falling back from {} to {} after repeated throttling",
+            current.name, fallback.name
+        );
+        self.events.emit(AgentEvent::ModelFallback {
+            from: current.name.clone(),
+            to: fallback.name.clone(),
+        });
+        self.backend.switch_model(fallback);
+    }
 
-    /// Arguments as strings (for backward compatibility)
-    pub args: Vec<String>,
+    /// Execute a single tool, reporting its start and outcome as events
+    pub async fn run_tool(
+        &self,
+        event_id: &str,
+        name: &str,
+        args: &[String],
+    ) -> Result<String, ToolError> {
+        self.events.emit(AgentEvent::ToolStarted {
+            id: event_id.to_string(),
+            name: name.to_string(),
+        });
 
-    /// Arguments as JSON (if available)
-    pub args_json: Option<HashMap<String, Value>>,
+        let started = Instant::now();
+        let result = self.tool_registry.execute_tool(name, args).await;
+        let duration = started.elapsed();
 
-    /// Tool call ID (if available)
-    pub id: Option<String>,
-}
+        match &result {
+            Ok(_) => self.events.emit(AgentEvent::ToolFinished {
+                id: event_id.to_string(),
+                name: name.to_string(),
+                duration,
+            }),
+            Err(e) => self.events.emit(AgentEvent::ToolFailed {
+                id: event_id.to_string(),
+                name: name.to_string(),
+                error: e.clone(),
+                duration,
+            }),
+        };
 
-/// Structure representing the result of a tool execution
-pub struct ToolResult {
-    /// Name of the tool that was executed
-    pub tool_name: String,
+        result
+    }
 
-    /// Result of the tool execution
-    pub result: String,
+    /// Ask the model for a short title for a conversation that starts with `input`
+    ///
+    /// The request goes to the model picked for titles, since a title does
+    /// not need the primary model.
+    pub async fn generate_title(&mut self, input: &str) -> Result<String, AgentError> {
+        let original_model = self.backend.current_model().clone();
+        self.backend.switch_model(self.model_spec(ModelRole::Title));
 
-    /// Tool use ID (if available) - IMPORTANT: This must match exactly the ID from the original tool_use message
-    /// Internally we call it tool_call_id but when sending to Claude it must be sent as tool_use_id
-    pub tool_call_id: Option<String>,
-}
+        let prompt = format!(
+            "Write a title of at most six words for a conversation that starts with the \
+            message below. Reply with the title only, without quotes.\n\n{}\n",
+            input
+        );
+        let result = self.backend.generate_text(&prompt).await;
+        if let Ok(response) = &result {
+            self.record_usage(RequestPurpose::Title, response);
+        }
 
-/// Structure representing a complete response from the agent
-pub struct AgentResponse {
-    pub content: String,
-    pub tool_results: Vec<ToolResult>,
+        // Switch back even if the request failed
+        self.backend.switch_model(original_model);
+
+        let response = result.map_err(AgentError::Title)?;
+        let title = display_text(&response.content)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .trim_matches(|c| c == '"' || c == '\'')
+            .to_string();
+        if title.is_empty() {
+            return Err(AgentError::EmptyTitle);
+        }
+        Ok(title)
+    }
+
+    /// Names of the models picked for each role
+    pub fn models(&self) -> &ModelSelection {
+        &self.models
+    }
+
+    /// Pick the model used for a role by its catalog name
+    ///
+    /// A new primary model takes effect from the next request.
+    pub fn select_model(&mut self, role: ModelRole, name: &str) -> Result<(), AgentError> {
+        let model = match self.config.catalog.get(name) {
+            Some(model) => model.clone(),
+            None => {
+                return Err(AgentError::UnknownModel {
+                    name: name.to_string(),
+                    available: self.config.catalog.names().join(", "),
+                });
+            }
+        };
+
+        self.models.set(role, model.name.clone());
+        if role == ModelRole::Primary {
+            self.backend.switch_model(self.model_spec(role));
+        }
+        self.events
+            .emit(AgentEvent::ModelsChanged(self.models.clone()));
+        Ok(())
+    }
+
+    /// Catalog entry of the model picked for a role
+    ///
+    /// Roles without a model of their own use the primary model. The primary
+    /// model uses the active profile's temperature if it sets one.
+    fn model_spec(&self, role: ModelRole) -> ModelSpec {
+        let catalog = &self.config.catalog;
+        let mut model = catalog
+            .get(self.models.get(role))
+            .or_else(|| catalog.get(&self.models.primary))
+            .unwrap_or_else(|| catalog.default_model())
+            .clone();
+        let temperature = match role {
+            ModelRole::Primary => self.profile.as_ref().and_then(|p| p.temperature),
+            _ => None,
+        };
+        if let Some(temperature) = temperature {
+            model.temperature = temperature;
+        }
+        model
+    }
+
+    /// Name of the profile in use, if one was picked
+    pub fn profile_name(&self) -> Option<&str> {
+        self.profile.as_ref().map(|profile| profile.name.as_str())
+    }
+
+    /// Whether tools that change files or run commands may run
+    pub fn mode(&self) -> AgentMode {
+        self.mode
+    }
+
+    /// Switch between ask and agent mode
+    ///
+    /// Ask mode offers the model only the tools that read and refuses any
+    /// other call, including ones already waiting in a paused turn.
+    pub fn set_mode(&mut self, mode: AgentMode) {
+        self.mode = mode;
+        self.tool_registry.set_read_only(mode == AgentMode::Ask);
+        self.backend
+            .set_enabled_tools(self.tool_registry.available_tools());
+        self.refresh_system_prompt();
+
+        info!("Switched to {} mode", mode.label());
+        self.events.emit(AgentEvent::ModeChanged(mode));
+    }
+
+    /// Switch to a profile by name
+    ///
+    /// The profile's system prompt, tools, primary model, temperature and
+    /// tool round limit take effect from the next request.
+    pub fn select_profile(&mut self, name: &str) -> Result<(), AgentError> {
+        let profile = match self
+            .config
+            .profiles
+            .iter()
+            .find(|p| p.name.eq_ignore_ascii_case(name))
+        {
+            Some(profile) => profile.clone(),
+            None => {
+                let names: Vec<&str> = self
+                    .config
+                    .profiles
+                    .iter()
+                    .map(|p| p.name.as_str())
+                    .collect();
+                return Err(AgentError::UnknownProfile {
+                    name: name.to_string(),
+                    available: names.join(", "),
+                });
+            }
+        };
+        if self.config.catalog.get(&profile.model).is_none() {
+            return Err(AgentError::UnknownModel {
+                name: profile.model,
+                available: self.config.catalog.names().join(", "),
+            });
+        }
+
+        self.config.system_prompt = profile.system_prompt.clone();
+        self.config.tool_chain.max_depth = profile.max_depth;
+        self.tool_registry
+            .set_enabled_tools(Some(profile.tools.clone()));
+        self.backend
+            .set_enabled_tools(self.tool_registry.available_tools());
+        self.profile = Some(profile.clone());
+        self.select_model(ModelRole::Primary, &profile.model)?;
+        self.refresh_system_prompt();
+
+        info!("Switched to profile {}", profile.name);
+        self.events.emit(AgentEvent::ProfileChanged(profile.name));
+        Ok(())
+    }
+
+    /// Tokens and cost of the requests made during the session
+    pub fn usage(&self) -> &UsageLedger {
+        &self.usage
+    }
+
+    /// Replace the session's usage, as when a saved session is resumed
+    ///
+    /// The session budget is counted from the new usage.
+    pub fn replace_usage(&mut self, usage: UsageLedger) {
+        self.usage = usage;
+        self.budget.reset_session();
+        self.events
+            .emit(AgentEvent::UsageChanged(self.usage.clone()));
+    }
+
+    /// Price the tokens of a response from the active model and add them to the session's usage
+    fn record_usage(&mut self, purpose: RequestPurpose, response: &BackendResponse) {
+        let usage = match response.usage {
+            Some(usage) => usage,
+            None => return,
+        };
+        let model = self.backend.current_model();
+        self.usage.record(&model.name, purpose, usage, &model.price);
+        self.events
+            .emit(AgentEvent::UsageChanged(self.usage.clone()));
+    }
+
+    /// Tokens the context may use before it is compressed
+    ///
+    /// This is a share of the active model's context window, leaving room
+    /// for the response, and never more than the configured maximum.
+    pub fn context_limit(&self) -> usize {
+        let window = self.backend.context_window();
+        let limit = (window as f64 * self.config.compress_threshold) as usize;
+        match self.config.max_context_length {
+            Some(max) => limit.min(max),
+            None => limit,
+        }
+    }
+
+    /// Compress context if it gets too large
+    async fn maybe_compress_context(&mut self) -> Result<(), AgentError> {
+        let length = self.context_manager.context_length();
+        let limit = self.context_limit();
+        if length <= limit {
+            return Ok(());
+        }
+        info!(
+            "Context has about {} tokens, over the limit of {}",
+            length, limit
+        );
+
+        // Only whole turns before the most recent ones are summarized
+        let plan = match self
+            .context_manager
+            .plan_compaction(self.config.keep_recent_turns)
+        {
+            Some(plan) => plan,
+            None => {
+                trace!("Context is over its limit but has no older turns to summarize");
+                return Ok(());
+            }
+        };
+
+        // Use the summarization model for context compression
+        let original_model = self.backend.current_model().clone();
+        self.backend
+            .switch_model(self.model_spec(ModelRole::Summarization));
+
+        // The transcript goes in a single user message so its turns are not
+        // read as the conversation itself
+        let summarization_prompt = format!(
+            "<user>\nSummarize this earlier part of a conversation between a user and an AI assistant that uses tools. \
+            Keep the user's goals, the decisions made, the files and commands involved and anything left to do. \
+            Reply with the summary only.\n\n{}\n</user>\n",
+            plan.transcript
+        );
+        let result = self.backend.generate_text(&summarization_prompt).await;
+        if let Ok(response) = &result {
+            self.record_usage(RequestPurpose::Compaction, response);
+        }
+
+        // Switch back to original model even if the summary failed
+        self.backend.switch_model(original_model);
+        let summary_response = result.map_err(AgentError::Compaction)?;
+
+        let messages = plan.messages;
+        let tokens_saved = self
+            .context_manager
+            .compact(plan, &display_text(&summary_response.content));
+        info!(
+            "Compacted {} messages into a summary, saving about {} tokens",
+            messages, tokens_saved
+        );
+        self.events.emit(AgentEvent::ContextCompacted {
+            messages,
+            tokens_saved,
+        });
+
+        Ok(())
+    }
+}
+
+/// Build a Bedrock backend, applying the AWS settings from the agent configuration
+fn bedrock_backend(config: &AgentConfig, mut backend_config: BedrockConfig) -> Box<dyn Backend> {
+    backend_config.region = config.aws_region.clone();
+    if let Some(profile) = &config.aws_profile {
+        backend_config.use_profile = true;
+        backend_config.profile_name = Some(profile.clone());
+    }
+
+    Box::new(BedrockBackend::with_config(backend_config))
+}
+
+/// Whether a response was cut off at the output limit in the middle of its tool calls
+fn is_truncated_tool_call(response: &BackendResponse) -> bool {
+    response.stop_reason == StopReason::MaxTokens && !response.tool_calls.is_empty()
+}
+
+/// Convert JSON tool arguments to the `key=value` strings tools expect
+///
+/// String values are passed as-is, anything else as its JSON text. Arguments
+/// are sorted by key so tools see them in a stable order.
+fn tool_args(args: &HashMap<String, Value>) -> Vec<String> {
+    let mut args: Vec<String> = args
+        .iter()
+        .map(|(key, value)| match value.as_str() {
+            Some(text) => format!("{}={}", key, text),
+            None => format!("{}={}", key, value),
+        })
+        .collect();
+    args.sort();
+    args
+}
+
+/// Short description of a tool call, such as `read_file(path=src/main.rs)`
+fn describe_tool_call(tool_call: &ToolUse) -> String {
+    format!(
+        "{}({})",
+        tool_call.name,
+        tool_args(&tool_call.args).join(", ")
+    )
+}
+
+/// Text of a response with the `<tool>` call markup and reasoning removed, for display
+pub fn display_text(content: &str) -> String {
+    let mut text = String::new();
+    let mut in_tool = false;
+
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("<tool name=") {
+            in_tool = true;
+        } else if in_tool {
+            if trimmed == "</tool>" {
+                in_tool = false;
+            }
+        } else if !is_reasoning_line(line) {
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+
+    text.trim().to_string()
+}
+
+/// Structure representing the result of a tool execution
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ToolResult {
+    /// Name of the tool that was executed
+    pub tool_name: String,
+
+    /// Result of the tool execution
+    pub result: String,
+
+    /// Tool use ID (if available) - IMPORTANT: This must match exactly the ID from the original tool_use message
+    /// Internally we call it tool_call_id but when sending to Claude it must be sent as tool_use_id
+    pub tool_call_id: Option<String>,
+
+    /// Whether the result is an image, as the JSON of an `ImageAttachment`
+    #[serde(default)]
+    pub image: bool,
+}
+
+/// Structure representing a complete response from the agent
+pub struct AgentResponse {
+    pub content: String,
+    pub tool_results: Vec<ToolResult>,
+
+    /// Number of tool rounds run during the turn
+    pub rounds: usize,
+
+    /// Tokens used by the model requests in the turn, as reported by the backend
+    pub tokens_used: usize,
+
+    /// Why the turn ended
+    pub stop: TurnStop,
+}
+
+/// What the agent may do with its tools
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentMode {
+    /// Every enabled tool may run
+    #[default]
+    Agent,
+
+    /// Only tools that read may run; file writes and commands are refused
+    Ask,
+}
+
+impl AgentMode {
+    /// Every mode, in the order they are offered
+    pub const ALL: [AgentMode; 2] = [AgentMode::Agent, AgentMode::Ask];
+
+    /// Name of the mode shown to the user
+    pub fn label(&self) -> &'static str {
+        match self {
+            AgentMode::Agent => "Agent",
+            AgentMode::Ask => "Ask",
+        }
+    }
+}
+
+/// Reason a turn ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TurnStop {
+    /// The model answered without asking for more tools
+    Complete,
+
+    /// The tool round limit was reached while the model still wanted tools;
+    /// the turn is paused until it is continued or stopped
+    RoundLimit {
+        /// Descriptions of the tool calls waiting to run
+        pending: Vec<String>,
+    },
+
+    /// A hard budget limit was reached while the model still wanted tools;
+    /// the turn is paused until it is continued or stopped
+    BudgetLimit {
+        /// Descriptions of the tool calls waiting to run
+        pending: Vec<String>,
+
+        /// The limit that was reached
+        reason: String,
+    },
+}
+
+/// Saved position in the conversation, used to roll back a cancelled turn
+pub struct TurnCheckpoint {
+    /// Conversation at the checkpoint
+    context: TreeCheckpoint,
+
+    /// Tool calls that were pending at the checkpoint
+    paused: Option<Vec<ToolUse>>,
+}
+
+/// Steps of the agent loop within a turn
+enum TurnState {
+    /// Ask the model for the next response
+    Generate,
+
+    /// Ask the model to continue a message cut off at the output limit,
+    /// counting the continuations so far
+    Continue(usize),
+
+    /// Run the tool calls from the latest response
+    RunTools(Vec<ToolUse>),
+
+    /// Pause with tool calls that exceed the round limit
+    RoundLimit(Vec<ToolUse>),
+
+    /// Pause with tool calls that would go past a hard budget limit
+    BudgetLimit(Vec<ToolUse>, String),
+
+    /// The turn is over
+    Done(TurnStop),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::backends::{reasoning_line, tool_call, MockBackend};
+    use crate::agent::context::{Message, MessageRole};
+    use crate::agent::tools::EchoTool;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+
+    /// Retry policy that does not wait between attempts
+    fn no_wait_retries() -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Build an initialized agent with the echo tool and no delays
+    async fn agent(backend: MockBackend, max_depth: usize) -> AgentManager {
+        let config = AgentConfig {
+            auto_compress_context: false,
+            tool_chain: ToolChainConfig {
+                max_depth,
+                delay_ms: 0,
+                ..ToolChainConfig::default()
+            },
+            retry: no_wait_retries(),
+            ..AgentConfig::default()
+        };
+        let mut agent = AgentManager::with_backend(config, Box::new(backend));
+        agent.register_tool(Box::new(EchoTool));
+        agent.init().await.unwrap();
+        agent
+    }
+
+    fn prompt(prompts: &Arc<Mutex<Vec<String>>>, index: usize) -> String {
+        prompts.lock().unwrap()[index].clone()
+    }
+
+    #[tokio::test]
+    async fn text_response_completes_without_tools() {
+        let backend = MockBackend::new().respond_with_text("Hello there");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+
+        let response = agent.process_input("Hi").await.unwrap();
+
+        assert_eq!(response.content.trim(), "Hello there");
+        assert_eq!(response.rounds, 0);
+        assert_eq!(response.stop, TurnStop::Complete);
+        assert!(response.tool_results.is_empty());
+        assert_eq!(prompts.lock().unwrap().len(), 1);
+        assert!(prompt(&prompts, 0).contains("<user>\nHi\n</user>"));
+    }
+
+    #[tokio::test]
+    async fn tool_results_are_sent_back_until_the_model_is_done() {
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "Echoing",
+                vec![tool_call("toolu_1", "echo", &[("text", json!("hello"))])],
+            )
+            .respond_with_text("All done");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+
+        let response = agent.process_input("Echo hello").await.unwrap();
+
+        assert_eq!(response.rounds, 1);
+        assert_eq!(response.stop, TurnStop::Complete);
+        assert_eq!(response.tool_results.len(), 1);
+        assert_eq!(response.tool_results[0].result, "text=hello");
+        assert_eq!(
+            response.tool_results[0].tool_call_id.as_deref(),
+            Some("toolu_1")
+        );
+        assert!(response.content.contains("Echoing"));
+        assert!(response.content.contains("All done"));
+
+        let follow_up = prompt(&prompts, 1);
+        assert!(follow_up.contains("\"tool_use_id\": \"toolu_1\""));
+        assert!(follow_up.contains("text=hello"));
+    }
+
+    #[tokio::test]
+    async fn failed_tools_are_reported_to_the_model() {
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "",
+                vec![
+                    tool_call("toolu_1", "missing_tool", &[]),
+                    tool_call("toolu_2", "echo", &[("text", json!("still runs"))]),
+                ],
+            )
+            .respond_with_text("Recovered");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+
+        let response = agent.process_input("Try tools").await.unwrap();
+
+        assert_eq!(response.stop, TurnStop::Complete);
+        assert_eq!(response.tool_results.len(), 2);
+        assert!(response.tool_results[0]
+            .result
+            .starts_with("Error: Tool 'missing_tool' not found"));
+        assert_eq!(response.tool_results[1].result, "text=still runs");
+        assert!(prompt(&prompts, 1).contains("missing_tool"));
+    }
+
+    /// Script two rounds of echo calls, so a limit of one round pauses the turn
+    fn two_tool_rounds() -> MockBackend {
+        MockBackend::new()
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_1", "echo", &[("text", json!("one"))])],
+            )
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_2", "echo", &[("text", json!("two"))])],
+            )
+    }
+
+    #[tokio::test]
+    async fn round_limit_pauses_with_pending_calls() {
+        let backend = two_tool_rounds();
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 1).await;
+
+        let response = agent.process_input("Keep going").await.unwrap();
+
+        assert_eq!(response.rounds, 1);
+        assert_eq!(
+            response.stop,
+            TurnStop::RoundLimit {
+                pending: vec!["echo(text=two)".to_string()]
+            }
+        );
+        assert_eq!(response.tool_results.len(), 1);
+        assert_eq!(prompts.lock().unwrap().len(), 2);
+        assert!(agent.is_paused());
+    }
+
+    #[tokio::test]
+    async fn continuing_runs_the_pending_calls() {
+        let backend = two_tool_rounds().respond_with_text("Finished");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 1).await;
+        agent.process_input("Keep going").await.unwrap();
+
+        let response = agent.continue_turn(3).await.unwrap();
+
+        assert_eq!(response.rounds, 1);
+        assert_eq!(response.stop, TurnStop::Complete);
+        assert_eq!(response.tool_results[0].result, "text=two");
+        assert!(response.content.contains("Finished"));
+        assert!(prompt(&prompts, 2).contains("\"tool_use_id\": \"toolu_2\""));
+        assert!(!agent.is_paused());
+    }
+
+    #[tokio::test]
+    async fn budget_limits_warn_and_pause_until_confirmed() {
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_1", "echo", &[("text", json!("one"))])],
+            )
+            .with_usage(600, 0)
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_2", "echo", &[("text", json!("two"))])],
+            )
+            .with_usage(600, 0)
+            .respond_with_text("Finished")
+            .with_usage(600, 0);
+        let config = AgentConfig {
+            auto_compress_context: false,
+            turn_budget: BudgetLimits {
+                max_requests: Some(1),
+                ..BudgetLimits::default()
+            },
+            session_budget: BudgetLimits {
+                max_tokens: Some(1000),
+                ..BudgetLimits::default()
+            },
+            budget_warn_at: 0.5,
+            tool_chain: ToolChainConfig {
+                max_depth: 5,
+                delay_ms: 0,
+                ..ToolChainConfig::default()
+            },
+            ..AgentConfig::default()
+        };
+        let mut agent = AgentManager::with_backend(config, Box::new(backend));
+        agent.register_tool(Box::new(EchoTool));
+        agent.init().await.unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+
+        let response = agent.process_input("Keep going").await.unwrap();
+        assert_eq!(
+            response.stop,
+            TurnStop::BudgetLimit {
+                pending: vec!["echo(text=one)".to_string()],
+                reason: "turn requests 1 of 1".to_string(),
+            }
+        );
+        assert!(agent.is_paused());
+
+        // Each continuation gets a new turn budget, and this time the session
+        // limit is reached as well
+        let response = agent.continue_turn(5).await.unwrap();
+        assert_eq!(
+            response.stop,
+            TurnStop::BudgetLimit {
+                pending: vec!["echo(text=two)".to_string()],
+                reason: "turn requests 1 of 1".to_string(),
+            }
+        );
+        let response = agent.continue_turn(5).await.unwrap();
+        assert_eq!(response.stop, TurnStop::Complete);
+
+        let mut warnings = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            if let AgentEvent::BudgetWarning(warning) = event {
+                warnings.push(warning);
+            }
+        }
+        // The confirmed session budget counts from the second request, so it warns again
+        assert_eq!(warnings, vec!["session tokens 600 of 1000".to_string(); 2]);
+    }
+
+    #[test]
+    fn text_requests_offer_no_tools() {
+        let bedrock = BedrockBackend::new();
+        let prompt = "<user>\nName this conversation\n</user>\n";
+
+        let request = bedrock.request_body(prompt).unwrap();
+        let text_request = bedrock.text_request_body(prompt).unwrap();
+
+        assert!(request.get("tools").is_some());
+        assert!(text_request.get("tools").is_none());
+        assert!(text_request.get("tool_choice").is_none());
+        assert_eq!(text_request["messages"], request["messages"]);
+    }
+
+    #[tokio::test]
+    async fn each_role_uses_its_selected_model() {
+        let backend = MockBackend::new()
+            .respond_with_text("Build notes")
+            .with_usage(100, 10)
+            .respond_with_text("Done")
+            .with_usage(100, 10);
+        let tools_offered = backend.tools_offered();
+        let mut agent = agent(backend, 5).await;
+
+        // Titles go to the title model, without tools, and switch back afterwards
+        let title = agent.generate_title("Explain the build").await.unwrap();
+        assert_eq!(title, "Build notes");
+        assert_eq!(agent.backend.current_model().name, "sonnet");
+        assert_eq!(*tools_offered.lock().unwrap(), [false]);
+
+        assert!(agent.select_model(ModelRole::Primary, "opus").is_err());
+        agent.select_model(ModelRole::Primary, "Haiku").unwrap();
+        assert_eq!(agent.models().primary, "haiku");
+        assert_eq!(agent.backend.current_model().name, "haiku");
+        agent.process_input("Hi").await.unwrap();
+
+        let requests = agent.usage().requests();
+        assert_eq!(requests[0].model, "haiku");
+        assert_eq!(requests[0].purpose, RequestPurpose::Title);
+        assert_eq!(requests[1].model, "haiku");
+        let haiku = agent.backend.current_model().price;
+        assert!((requests[1].cost - haiku.cost(&requests[1].usage)).abs() < 1e-12);
+    }
+
+    #[tokio::test]
+    async fn stopping_answers_the_pending_calls() {
+        let backend = two_tool_rounds().respond_with_text("Next answer");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 1).await;
+        agent.process_input("Keep going").await.unwrap();
+
+        assert_eq!(agent.stop_paused_turn(), 1);
+        assert!(!agent.is_paused());
+        assert!(agent.continue_turn(1).await.is_err());
+
+        // The cancelled call is answered before the next user message
+        agent.process_input("Something else").await.unwrap();
+        let next = prompt(&prompts, 2);
+        let result = next.find("\"tool_use_id\": \"toolu_2\"").unwrap();
+        assert!(next[result..].contains("Cancelled"));
+        assert!(result < next.find("Something else").unwrap());
+    }
+
+    #[tokio::test]
+    async fn restoring_a_checkpoint_discards_the_turn() {
+        let backend = MockBackend::new().respond_with_text("Reply");
+        let mut agent = agent(backend, 5).await;
+        let before = agent.context_manager.get_context();
+
+        let checkpoint = agent.checkpoint();
+        agent.process_input("Hi").await.unwrap();
+        agent.restore(checkpoint);
+
+        assert_eq!(agent.context_manager.get_context(), before);
+    }
+
+    #[tokio::test]
+    async fn undoing_a_turn_restores_the_files_it_wrote() {
+        let dir = std::env::temp_dir().join(format!("gamecode-undo-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), "original").unwrap();
+
+        let write = |id, content: &str| {
+            tool_call(
+                id,
+                "write_file",
+                &[("path", json!("notes.txt")), ("content", json!(content))],
+            )
+        };
+        let backend = MockBackend::new()
+            .respond_with_tools("", vec![write("toolu_1", "first")])
+            .respond_with_tools("", vec![write("toolu_2", "second")])
+            .respond_with_text("Done");
+        let mut agent = agent(backend, 5).await;
+        agent.register_tool(Box::new(crate::agent::tools::WriteFileTool));
+        agent.set_working_directory(dir.to_str().unwrap());
+        agent.set_checkpoint_store(CheckpointStore::open(
+            dir.join("checkpoints"),
+            &crate::core::config::SessionSettings::default(),
+        ));
+        let before = agent.context_manager.get_context();
+
+        agent.process_input("Rewrite the notes").await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("notes.txt")).unwrap(),
+            "second"
+        );
+
+        let (_, report) = agent.undo_last_turn().unwrap();
+        assert_eq!(report.restored.len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("notes.txt")).unwrap(),
+            "original"
+        );
+        assert_eq!(agent.context_manager.get_context(), before);
+        assert!(agent.undo_last_turn().is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ask_mode_refuses_tools_that_change_files() {
+        let dir = std::env::temp_dir().join(format!("gamecode-ask-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "",
+                vec![tool_call(
+                    "toolu_1",
+                    "write_file",
+                    &[("path", json!("notes.txt")), ("content", json!("hi"))],
+                )],
+            )
+            .respond_with_text("Done");
+        let enabled_tools = backend.enabled_tools();
+        let mut agent = agent(backend, 5).await;
+        agent.register_tool(Box::new(crate::agent::tools::WriteFileTool));
+        agent.set_working_directory(dir.to_str().unwrap());
+
+        agent.set_mode(AgentMode::Ask);
+
+        assert_eq!(*enabled_tools.lock().unwrap(), ["echo"]);
+        assert!(agent.system_prompt().ends_with(ASK_MODE_NOTE));
+        let response = agent.process_input("Write some notes").await.unwrap();
+        assert!(response.tool_results[0]
+            .result
+            .starts_with("Error: Tool 'write_file' can change files or run commands"));
+        assert!(!dir.join("notes.txt").exists());
+
+        agent.set_mode(AgentMode::Agent);
+        let mut tools = enabled_tools.lock().unwrap().clone();
+        tools.sort();
+        assert_eq!(tools, ["echo", "write_file"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn context_length_follows_reported_usage() {
+        let backend = MockBackend::new()
+            .respond_with_text("Reply")
+            .with_usage(1000, 20);
+        let mut agent = agent(backend, 5).await;
+
+        let response = agent.process_input("Hi").await.unwrap();
+        assert_eq!(response.tokens_used, 1020);
+        assert_eq!(agent.context_manager.context_length(), 1020);
+
+        // The request is priced for the session's usage
+        let requests = agent.usage().requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].purpose, RequestPurpose::Turn);
+        assert!((requests[0].cost - 0.0033).abs() < 1e-9);
+
+        // Later messages are estimated on top of the measured count
+        agent.context_manager.add_user_message(&"x".repeat(400));
+        let length = agent.context_manager.context_length();
+        let estimate = agent.context_manager.estimate_tokens(&"x".repeat(400));
+        assert!(estimate > 0);
+        assert!(length > 1020 + estimate && length < 1040 + estimate);
+
+        // Rolling back earlier messages falls back to an estimate
+        agent.undo_last_turn().unwrap();
+        assert!(agent.context_manager.context_length() < 1000);
+    }
+
+    #[tokio::test]
+    async fn compression_summarizes_whole_turns() {
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_1", "echo", &[("text", json!("hi"))])],
+            )
+            .respond_with_text("First done")
+            .respond_with_text("Second done")
+            .respond_with_text("They echoed hi");
+        let prompts = backend.prompts();
+        let tools_offered = backend.tools_offered();
+        let config = AgentConfig {
+            max_context_length: Some(1),
+            keep_recent_turns: 1,
+            tool_chain: ToolChainConfig {
+                max_depth: 5,
+                delay_ms: 0,
+                ..ToolChainConfig::default()
+            },
+            ..AgentConfig::default()
+        };
+        let mut agent = AgentManager::with_backend(config, Box::new(backend));
+        agent.register_tool(Box::new(EchoTool));
+        agent.init().await.unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+
+        // A single turn has nothing older to summarize
+        agent.process_input("Echo hi").await.unwrap();
+        agent.process_input("Second").await.unwrap();
+
+        // The first turn, tool call and result included, was summarized
+        let summary_prompt = prompt(&prompts, 3);
+        assert!(summary_prompt.contains("User: Echo hi"));
+        assert!(summary_prompt.contains("Tool result: {"));
+        assert!(!summary_prompt.contains("User: Second"));
+        // The summary has to come back as text, so no tools are offered for it
+        assert_eq!(*tools_offered.lock().unwrap(), [true, true, true, false]);
+
+        let context = agent.context_manager.get_context();
+        assert!(context.contains("Summary of the earlier conversation:\nThey echoed hi"));
+        assert!(!context.contains("Echo hi"));
+        assert!(!context.contains("tool_result"));
+        assert!(context.contains("<user>\nSecond\n</user>"));
+
+        let mut compacted = None;
+        while let Ok(event) = receiver.try_recv() {
+            if let AgentEvent::ContextCompacted { messages, .. } = event {
+                compacted = Some(messages);
+            }
+        }
+        assert_eq!(compacted, Some(4));
+    }
+
+    #[tokio::test]
+    async fn failed_compression_keeps_the_finished_turn() {
+        let backend = MockBackend::new()
+            .respond_with_text("First done")
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_1", "echo", &[("text", json!("hi"))])],
+            )
+            .respond_with_text("Second done")
+            .fail_with(BackendError::Throttled("slow down".to_string()));
+        let config = AgentConfig {
+            max_context_length: Some(1),
+            keep_recent_turns: 1,
+            tool_chain: ToolChainConfig {
+                max_depth: 5,
+                delay_ms: 0,
+                ..ToolChainConfig::default()
+            },
+            ..AgentConfig::default()
+        };
+        let mut agent = AgentManager::with_backend(config, Box::new(backend));
+        agent.register_tool(Box::new(EchoTool));
+        agent.init().await.unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+        agent.process_input("First").await.unwrap();
+
+        // The summary request fails after the tool round and the answer
+        let response = agent.process_input("Echo hi").await.unwrap();
+
+        assert_eq!(response.tool_results.len(), 1);
+        assert!(response.content.trim().ends_with("Second done"));
+        let context = agent.context_manager.get_context();
+        assert!(context.contains("<user>\nFirst\n</user>"));
+        assert!(context.contains("Second done"));
+        let mut failed = false;
+        while let Ok(event) = receiver.try_recv() {
+            failed |= matches!(event, AgentEvent::ContextCompactionFailed(_));
+        }
+        assert!(failed);
+    }
+
+    #[tokio::test]
+    async fn backend_errors_end_the_turn() {
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_1", "echo", &[("text", json!("x"))])],
+            )
+            .fail_with(BackendError::AccessDenied("no access".to_string()));
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+
+        let error = agent.process_input("Hi").await.err().unwrap();
+
+        // Access will not be granted by asking again
+        assert!(matches!(
+            &error,
+            AgentError::Backend(BackendError::AccessDenied(message)) if message == "no access"
+        ));
+        assert_eq!(error.to_string(), "Backend error: Access denied: no access");
+        assert!(error.hint().is_some());
+        assert_eq!(prompts.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        let backend = MockBackend::new()
+            .fail_with(BackendError::Timeout("slow".to_string()))
+            .fail_with(BackendError::Service("unavailable".to_string()))
+            .respond_with_text("Hello");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+
+        let response = agent.process_input("Hi").await.unwrap();
+
+        assert_eq!(response.content.trim(), "Hello");
+        assert_eq!(prompts.lock().unwrap().len(), 3);
+        let mut retries = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            if let AgentEvent::Retrying {
+                attempt,
+                max_retries,
+                ..
+            } = event
+            {
+                retries.push((attempt, max_retries));
+            }
+        }
+        assert_eq!(retries, vec![(1, 3), (2, 3)]);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_at_the_policy_limit() {
+        let mut backend = MockBackend::new();
+        for _ in 0..5 {
+            backend = backend.fail_with(BackendError::Throttled("slow down".to_string()));
+        }
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+
+        let error = agent.process_input("Hi").await.err().unwrap();
+
+        assert!(matches!(
+            error,
+            AgentError::Backend(BackendError::Throttled(_))
+        ));
+        assert_eq!(prompts.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn repeated_throttling_falls_back_for_the_rest_of_the_turn() {
+        let backend = MockBackend::new()
+            .fail_with(BackendError::Throttled("slow down".to_string()))
+            .fail_with(BackendError::Throttled("slow down".to_string()))
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_1", "echo", &[("text", json!("x"))])],
+            )
+            .respond_with_text("Done");
+        let mut catalog = ModelCatalog::builtin();
+        let mut sonnet = catalog.get("sonnet").unwrap().clone();
+        sonnet.fallback = Some("haiku".to_string());
+        catalog.insert(sonnet);
+        let config = AgentConfig {
+            auto_compress_context: false,
+            catalog: catalog.clone(),
+            tool_chain: ToolChainConfig {
+                max_depth: 5,
+                delay_ms: 0,
+                ..ToolChainConfig::default()
+            },
+            retry: no_wait_retries(),
+            ..AgentConfig::default()
+        };
+        let mut agent = AgentManager::with_backend(config, Box::new(backend));
+        agent.register_tool(Box::new(EchoTool));
+        agent.init().await.unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+
+        agent.process_input("Hi").await.unwrap();
+
+        let sonnet_id = catalog.get("sonnet").unwrap().id.clone();
+        let haiku_id = catalog.get("haiku").unwrap().id.clone();
+        let mut requests = Vec::new();
+        let mut fallback = None;
+        while let Ok(event) = receiver.try_recv() {
+            match event {
+                AgentEvent::RequestSent { model, .. } => requests.push(model),
+                AgentEvent::ModelFallback { from, to } => fallback = Some((from, to)),
+                _ => {}
+            }
+        }
+        assert_eq!(
+            requests,
+            vec![sonnet_id.clone(), sonnet_id, haiku_id.clone(), haiku_id]
+        );
+        assert_eq!(fallback, Some(("sonnet".to_string(), "haiku".to_string())));
+
+        // The next turn starts on the primary model again
+        assert_eq!(agent.backend.current_model().name, "sonnet");
+    }
+
+    #[tokio::test]
+    async fn responses_cut_off_at_the_output_limit_are_continued() {
+        let backend = MockBackend::new()
+            .respond_with_text("Once upon")
+            .stopped_by(StopReason::MaxTokens)
+            .respond_with_text(" a time")
+            .stopped_by(StopReason::MaxTokens)
+            .respond_with_text(", the end");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+
+        let response = agent.process_input("Tell a story").await.unwrap();
+
+        assert_eq!(response.content.trim(), "Once upon a time, the end");
+        assert_eq!(prompts.lock().unwrap().len(), 3);
+
+        // The continuation request ends with the message being continued
+        assert!(
+            prompt(&prompts, 1)
+                .trim_end()
+                .ends_with("<assistant>\nOnce upon\n\n</assistant>")
+        );
+        let replies: Vec<&Message> = agent
+            .context_manager
+            .messages()
+            .filter(|m| m.role == MessageRole::Assistant)
+            .collect();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].content.trim(), "Once upon a time, the end");
+    }
+
+    #[tokio::test]
+    async fn continuations_stop_at_the_limit() {
+        let mut backend = MockBackend::new();
+        for _ in 0..5 {
+            backend = backend
+                .respond_with_text("more")
+                .stopped_by(StopReason::MaxTokens);
+        }
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+
+        let response = agent.process_input("Go on").await.unwrap();
+
+        // The first response and three continuations
+        assert_eq!(prompts.lock().unwrap().len(), 4);
+        assert_eq!(response.stop, TurnStop::Complete);
+        let mut stopped = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            if let AgentEvent::ResponseStopped(reason) = event {
+                stopped.push(reason);
+            }
+        }
+        assert_eq!(stopped, vec![StopReason::MaxTokens]);
+    }
+
+    #[tokio::test]
+    async fn cut_off_tool_calls_are_asked_for_again_with_a_larger_limit() {
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_1", "echo", &[("text", json!("par"))])],
+            )
+            .stopped_by(StopReason::MaxTokens)
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_2", "echo", &[("text", json!("full"))])],
+            )
+            .respond_with_text("Done");
+        let output_limits = backend.output_limits();
+        let mut agent = agent(backend, 5).await;
+
+        let response = agent.process_input("Echo").await.unwrap();
+
+        // Only the complete call was run
+        assert_eq!(response.tool_results.len(), 1);
+        assert_eq!(response.tool_results[0].result, "text=full");
+        assert!(!agent.context_manager.get_context().contains("toolu_1"));
+        assert_eq!(*output_limits.lock().unwrap(), vec![4096, 8192, 4096]);
+    }
+
+    #[tokio::test]
+    async fn refusals_are_reported() {
+        let backend = MockBackend::new()
+            .respond_with_text("I can't help with that")
+            .stopped_by(StopReason::Refusal);
+        let mut agent = agent(backend, 5).await;
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+
+        let response = agent.process_input("Hi").await.unwrap();
+
+        assert_eq!(response.stop, TurnStop::Complete);
+        let mut stopped = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            if let AgentEvent::ResponseStopped(reason) = event {
+                stopped.push(reason);
+            }
+        }
+        assert_eq!(stopped, vec![StopReason::Refusal]);
+    }
+
+    #[tokio::test]
+    async fn non_string_arguments_use_their_json_text() {
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "",
+                vec![tool_call(
+                    "toolu_1",
+                    "echo",
+                    &[("text", json!("n")), ("count", json!(3))],
+                )],
+            )
+            .respond_with_text("Done");
+        let mut agent = agent(backend, 5).await;
+
+        let response = agent.process_input("Count").await.unwrap();
+
+        assert_eq!(response.tool_results[0].result, "count=3 text=n");
+    }
+
+    #[tokio::test]
+    async fn reasoning_is_reported_and_sent_back_with_tool_results() {
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "Echoing",
+                vec![tool_call("toolu_1", "echo", &[("text", json!("hi"))])],
+            )
+            .with_reasoning("The user wants an echo.")
+            .respond_with_text("Done")
+            .with_reasoning("The echo worked.");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+
+        agent.process_input("Echo").await.unwrap();
+
+        let mut reasoning = Vec::new();
+        let mut texts = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            match event {
+                AgentEvent::Reasoning(text) => reasoning.push(text),
+                AgentEvent::TextReceived(text) => texts.push(text),
+                _ => {}
+            }
+        }
+        assert_eq!(reasoning, ["The user wants an echo.", "The echo worked."]);
+        assert_eq!(texts, ["Echoing", "Done"]);
+
+        // The tool round goes back to the model with the reasoning that asked for it
+        let second = prompt(&prompts, 1);
+        assert!(second.contains("<thinking>{"), "{}", second);
+        assert!(second.contains("The user wants an echo."), "{}", second);
+    }
+
+    #[test]
+    fn display_text_leaves_out_reasoning() {
+        let content = format!(
+            "{}\nThe answer.\n<tool name=\"echo\" id=\"toolu_1\">\n{{}}\n</tool>",
+            reasoning_line(r#"{"type":"thinking","thinking":"Hmm.","signature":"s"}"#)
+        );
+
+        assert_eq!(display_text(&content), "The answer.");
+        assert_eq!(reasoning_text(&content), "Hmm.");
+    }
+
+    #[tokio::test]
+    async fn image_markup_in_text_and_tool_output_stays_text() {
+        let markup = ImageAttachment {
+            name: "fake.png".to_string(),
+            media_type: "image/png".to_string(),
+            width: 1,
+            height: 1,
+            data: "AAAA".to_string(),
+        }
+        .to_markup();
+        let dir = std::env::temp_dir().join(format!("gamecode-markup-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = format!("<rss>\n{}\n\\{}\n</rss>\n", markup, markup);
+        std::fs::write(dir.join("feed.xml"), &file).unwrap();
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_1", "read_file", &[("path", json!("feed.xml"))])],
+            )
+            .respond_with_text("Done");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+        agent.register_tool(Box::new(crate::agent::tools::ReadFileTool));
+        agent.set_working_directory(dir.to_str().unwrap());
+
+        let pasted = format!("Look at this:\n{}\n\\{}", markup, markup);
+        let response = agent.process_input(&pasted).await.unwrap();
+
+        // Text and tool output are kept exactly as they were
+        let input = agent
+            .context_manager
+            .messages()
+            .find(|m| m.role == MessageRole::User)
+            .unwrap();
+        assert_eq!(input.content, pasted);
+        assert!(input.images.is_empty());
+        assert_eq!(response.tool_results[0].result, file);
+        assert!(!response.tool_results[0].image);
+
+        // and reach the model as text, not images
+        let request = crate::agent::backends::BedrockBackend::new()
+            .request_body(&prompt(&prompts, 1))
+            .unwrap();
+        assert!(!request.to_string().contains(r#""type":"image""#), "{}", request);
+        assert_eq!(request["messages"][0]["content"][0]["text"], json!(pasted));
+        assert_eq!(request["messages"][2]["content"][0]["content"], json!(file));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mentioned_images_are_attached_to_the_input() {
+        let dir = std::env::temp_dir().join(format!("gamecode-image-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        image::RgbaImage::new(8, 6).save(dir.join("shot.png")).unwrap();
+        let backend = MockBackend::new()
+            .respond_with_text("A blank image")
+            .respond_with_text("Sure");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+        agent.set_working_directory(dir.to_str().unwrap());
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+
+        agent.process_input("What is in @shot.png?").await.unwrap();
+
+        let first = prompt(&prompts, 0);
+        assert!(first.contains("<image>{"), "{}", first);
+        assert!(first.contains("\"width\":8,\"height\":6"), "{}", first);
+        let mut previews = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            if let AgentEvent::InputRecorded { images, .. } = event {
+                previews.extend(images.into_iter().map(|image| image.name));
+            }
+        }
+        assert_eq!(previews, ["shot.png"]);
+
+        // A mention that cannot be attached stays text and is reported
+        agent.process_input("Ask @logo.png about it").await.unwrap();
+
+        let second = prompt(&prompts, 1);
+        assert_eq!(second.matches("<image>{").count(), 1, "{}", second);
+        assert!(second.contains("Ask @logo.png about it"), "{}", second);
+        let mut skipped = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            if let AgentEvent::ImageSkipped(warning) = event {
+                skipped.push(warning);
+            }
+        }
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].starts_with("logo.png: "), "{}", skipped[0]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mentioned_images_outside_the_workspace_are_not_attached() {
+        let dir = std::env::temp_dir().join(format!("gamecode-image-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("workspace")).unwrap();
+        image::RgbaImage::new(8, 6).save(dir.join("secret.png")).unwrap();
+        let backend = MockBackend::new().respond_with_text("I cannot see it");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+        agent.set_working_directory(dir.join("workspace").to_str().unwrap());
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+
+        agent.process_input("What is in @../secret.png?").await.unwrap();
+
+        assert!(!prompt(&prompts, 0).contains("<image>{"));
+        let mut skipped = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            if let AgentEvent::ImageSkipped(warning) = event {
+                skipped.push(warning);
+            }
+        }
+        assert_eq!(skipped.len(), 1);
+        assert!(
+            skipped[0].ends_with("is outside the working directory"),
+            "{}",
+            skipped[0]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn system_prompt_describes_the_workspace_and_its_instructions() {
+        let dir = std::env::temp_dir().join(format!("gamecode-prompt-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join(".gamecode")).unwrap();
+        std::fs::write(dir.join(".gamecode/instructions.md"), "Use tabs.").unwrap();
+        let backend = MockBackend::new().respond_with_text("Hello");
+        let prompts = backend.prompts();
+        let mut agent = agent(backend, 5).await;
+        agent.set_working_directory(dir.to_str().unwrap());
+
+        agent.process_input("Hi").await.unwrap();
+
+        let directory = dir.canonicalize().unwrap();
+        let system = agent.system_prompt();
+        assert!(
+            system.contains(&format!("Working directory: {}", directory.display())),
+            "{}",
+            system
+        );
+        assert!(system.contains("Available tools: echo"), "{}", system);
+        assert!(
+            system.ends_with("instructions.md:\n\nUse tabs."),
+            "{}",
+            system
+        );
+        assert!(prompt(&prompts, 0).contains(&system));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn profiles_switch_prompt_tools_model_and_depth() {
+        let profile = |name: &str, tools: Vec<String>, model: &str| Profile {
+            name: name.to_string(),
+            description: None,
+            system_prompt: format!("You are the {} profile.", name),
+            tools,
+            model: model.to_string(),
+            temperature: None,
+            max_depth: 5,
+        };
+        let config = AgentConfig {
+            auto_compress_context: false,
+            tool_chain: ToolChainConfig {
+                delay_ms: 0,
+                ..ToolChainConfig::default()
+            },
+            profiles: vec![
+                profile("default", vec!["echo".to_string()], "sonnet"),
+                Profile {
+                    temperature: Some(0.1),
+                    max_depth: 1,
+                    ..profile("reviewer", Vec::new(), "haiku")
+                },
+            ],
+            profile: Some("default".to_string()),
+            ..AgentConfig::default()
+        };
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_1", "echo", &[("text", json!("hi"))])],
+            )
+            .respond_with_text("Done");
+        let enabled_tools = backend.enabled_tools();
+        let mut agent = AgentManager::with_backend(config, Box::new(backend));
+        agent.register_tool(Box::new(EchoTool));
+        agent.init().await.unwrap();
+        assert_eq!(*enabled_tools.lock().unwrap(), ["echo"]);
+        assert_eq!(agent.system_prompt(), "You are the default profile.");
+
+        agent.select_profile("Reviewer").unwrap();
+
+        assert_eq!(agent.profile.as_ref().unwrap().name, "reviewer");
+        assert_eq!(agent.models().primary, "haiku");
+        assert_eq!(agent.backend.current_model().temperature, 0.1);
+        assert!(enabled_tools.lock().unwrap().is_empty());
+        assert_eq!(agent.system_prompt(), "You are the reviewer profile.");
+        // Tools the profile leaves out cannot be run even if the model asks for them
+        let response = agent.process_input("Echo hi").await.unwrap();
+        assert!(response.tool_results[0]
+            .result
+            .starts_with("Error: Tool 'echo' not found"));
+        assert!(matches!(
+            agent.select_profile("builder"),
+            Err(AgentError::UnknownProfile { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn tool_progress_is_reported_as_events() {
+        let backend = MockBackend::new()
+            .respond_with_tools(
+                "",
+                vec![tool_call("toolu_1", "echo", &[("text", json!("hi"))])],
+            )
+            .respond_with_text("Done");
+        let mut agent = agent(backend, 5).await;
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+
+        agent.process_input("Echo").await.unwrap();
+
+        let mut names = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            names.push(match event {
+                AgentEvent::InputRecorded { .. } => "input",
+                AgentEvent::RequestSent { .. } => "request",
+                AgentEvent::TextDelta(_) => "delta",
+                AgentEvent::TextReceived(_) => "text",
+                AgentEvent::ToolRequested { .. } => "requested",
+                AgentEvent::ChainDepthChanged { .. } => "depth",
+                AgentEvent::ToolStarted { .. } => "started",
+                AgentEvent::ToolFinished { .. } => "finished",
+                _ => "other",
+            });
+        }
+        assert_eq!(
+            names,
+            [
+                "input",
+                "request",
+                "requested",
+                "depth",
+                "started",
+                "finished",
+                "request",
+                "delta",
+                "text"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn response_text_is_streamed_before_it_is_complete() {
+        let backend = MockBackend::new().respond_with_text("Hello there, world");
+        let mut agent = agent(backend, 5).await;
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        agent.set_event_sink(EventSink::new(sender));
+
+        agent.process_input("Hi").await.unwrap();
+
+        let mut deltas = Vec::new();
+        let mut received = None;
+        while let Ok(event) = receiver.try_recv() {
+            match event {
+                AgentEvent::TextDelta(text) => {
+                    assert!(received.is_none(), "delta after the whole text");
+                    deltas.push(text);
+                }
+                AgentEvent::TextReceived(text) => received = Some(text),
+                _ => {}
+            }
+        }
+        assert_eq!(deltas, ["Hello ", "there, ", "world"]);
+        assert_eq!(received.as_deref(), Some("Hello there, world"));
+    }
 }
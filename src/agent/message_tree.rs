@@ -0,0 +1,369 @@
+use crate::agent::context::{ContextError, Message, MessageRole};
+use serde::{Deserialize, Serialize};
+
+/// Messages of a conversation, kept as a tree so edited inputs can branch
+///
+/// Each message points at the message it follows. The active branch is the
+/// path from the first message to the current one; editing an earlier input
+/// starts a new branch next to it, and the old branch stays in the tree so
+/// it can be switched back to.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct MessageTree {
+    /// Every message in the conversation, in the order they were added
+    nodes: Vec<MessageNode>,
+
+    /// Positions in `nodes` of the messages on the active branch
+    branch: Vec<usize>,
+
+    /// ID for the next message (IDs are never reused)
+    next_id: u64,
+}
+
+/// A message and the message it follows
+#[derive(Clone, Serialize, Deserialize)]
+struct MessageNode {
+    /// Stable ID of the message
+    id: u64,
+
+    /// ID of the previous message, if any
+    parent: Option<u64>,
+
+    /// The message itself
+    message: Message,
+}
+
+/// A user input on the active branch that has alternative versions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BranchPoint {
+    /// ID of the input on the active branch
+    pub node: u64,
+
+    /// Position of that input among its versions
+    pub position: usize,
+
+    /// Number of versions of the input
+    pub count: usize,
+}
+
+/// Snapshot of a tree that can be rolled back to
+#[derive(Clone)]
+pub struct TreeCheckpoint {
+    /// Number of messages in the tree
+    node_count: usize,
+
+    /// Active branch
+    branch: Vec<usize>,
+}
+
+impl MessageTree {
+    /// Add a message to the end of the active branch, returning its ID
+    pub fn push(&mut self, message: Message) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.nodes.push(MessageNode {
+            id,
+            parent: self.branch.last().map(|&index| self.nodes[index].id),
+            message,
+        });
+        self.branch.push(self.nodes.len() - 1);
+        id
+    }
+
+    /// Messages on the active branch, in order
+    pub fn messages(&self) -> impl DoubleEndedIterator<Item = &Message> {
+        self.branch.iter().map(|&index| &self.nodes[index].message)
+    }
+
+    /// IDs and messages on the active branch, in order
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = (u64, &Message)> {
+        self.branch
+            .iter()
+            .map(|&index| (self.nodes[index].id, &self.nodes[index].message))
+    }
+
+    /// Number of messages on the active branch
+    pub(crate) fn len(&self) -> usize {
+        self.branch.len()
+    }
+
+    /// First message on the active branch, to be changed in place
+    pub fn first_mut(&mut self) -> Option<&mut Message> {
+        let index = *self.branch.first()?;
+        Some(&mut self.nodes[index].message)
+    }
+
+    /// Last message on the active branch
+    pub fn last(&self) -> Option<&Message> {
+        self.messages().next_back()
+    }
+
+    /// Last message on the active branch, to be changed in place
+    pub fn last_mut(&mut self) -> Option<&mut Message> {
+        let index = *self.branch.last()?;
+        Some(&mut self.nodes[index].message)
+    }
+
+    /// Replace the messages before `position` on the active branch with `messages`
+    ///
+    /// The messages from `position` on keep their IDs and now follow the new
+    /// ones. The replaced messages stay in the tree for any other branches
+    /// that start from them.
+    pub fn replace_prefix(&mut self, position: usize, messages: Vec<Message>) {
+        let kept = self.branch.split_off(position.min(self.branch.len()));
+        self.branch.clear();
+        for message in messages {
+            self.push(message);
+        }
+
+        if let Some(&first) = kept.first() {
+            self.nodes[first].parent = self.branch.last().map(|&index| self.nodes[index].id);
+        }
+        self.branch.extend(kept);
+    }
+
+    /// Start a new branch in which the user input `node` is replaced by `message`
+    ///
+    /// Everything after the input on the active branch is left on the old branch.
+    pub fn fork(&mut self, node: u64, message: Message) -> Result<u64, ContextError> {
+        let position = self.branch_position(node)?;
+        if self.nodes[self.branch[position]].message.role != MessageRole::User {
+            return Err(ContextError::NotUserInput(node));
+        }
+
+        self.branch.truncate(position);
+        Ok(self.push(message))
+    }
+
+    /// Switch to another version of the input `node` on the active branch
+    ///
+    /// The branch continues with the latest messages that followed that version.
+    pub fn switch(&mut self, node: u64, version: usize) -> Result<(), ContextError> {
+        let position = self.branch_position(node)?;
+        let versions = self.versions(self.branch[position]);
+        let target = *versions.get(version).ok_or(ContextError::NoSuchVersion {
+            node,
+            versions: versions.len(),
+            requested: version + 1,
+        })?;
+
+        self.branch.truncate(position);
+        self.branch.push(target);
+        while let Some(child) = self.children(*self.branch.last().unwrap()).last() {
+            self.branch.push(*child);
+        }
+        Ok(())
+    }
+
+    /// Remove the message `node` and everything after it from the active branch
+    ///
+    /// Other branches that started after the message are removed with it.
+    pub fn remove_from(&mut self, node: u64) -> Result<(), ContextError> {
+        let position = self.branch_position(node)?;
+
+        // Messages always come after the message they follow, so one pass
+        // finds everything below the removed one
+        let mut removed = vec![node];
+        for message in &self.nodes {
+            if message
+                .parent
+                .is_some_and(|parent| removed.contains(&parent))
+            {
+                removed.push(message.id);
+            }
+        }
+
+        let kept: Vec<u64> = self.branch[..position]
+            .iter()
+            .map(|&index| self.nodes[index].id)
+            .collect();
+        self.nodes.retain(|message| !removed.contains(&message.id));
+        self.branch = kept
+            .iter()
+            .filter_map(|id| self.nodes.iter().position(|message| message.id == *id))
+            .collect();
+        Ok(())
+    }
+
+    /// User inputs on the active branch that have more than one version
+    pub fn branch_points(&self) -> Vec<BranchPoint> {
+        self.branch
+            .iter()
+            .filter(|&&index| self.nodes[index].message.role == MessageRole::User)
+            .filter_map(|&index| {
+                let versions = self.versions(index);
+                (versions.len() > 1).then(|| BranchPoint {
+                    node: self.nodes[index].id,
+                    position: versions
+                        .iter()
+                        .position(|&v| v == index)
+                        .unwrap_or_default(),
+                    count: versions.len(),
+                })
+            })
+            .collect()
+    }
+
+    /// Remember the tree so a turn can be rolled back
+    pub fn checkpoint(&self) -> TreeCheckpoint {
+        TreeCheckpoint {
+            node_count: self.nodes.len(),
+            branch: self.branch.clone(),
+        }
+    }
+
+    /// Remove everything added since the checkpoint and restore its active branch
+    pub fn restore(&mut self, checkpoint: TreeCheckpoint) {
+        self.nodes.truncate(checkpoint.node_count);
+        self.branch = checkpoint.branch;
+    }
+
+    /// Position of a message on the active branch
+    fn branch_position(&self, node: u64) -> Result<usize, ContextError> {
+        self.branch
+            .iter()
+            .position(|&index| self.nodes[index].id == node)
+            .ok_or(ContextError::NotOnBranch(node))
+    }
+
+    /// Positions of the messages that follow the message at `index`
+    fn children(&self, index: usize) -> Vec<usize> {
+        let id = self.nodes[index].id;
+        (0..self.nodes.len())
+            .filter(|&child| self.nodes[child].parent == Some(id))
+            .collect()
+    }
+
+    /// Positions of the messages sharing a parent with the message at `index`,
+    /// which are the versions of a user input
+    fn versions(&self, index: usize) -> Vec<usize> {
+        let parent = self.nodes[index].parent;
+        (0..self.nodes.len())
+            .filter(|&sibling| self.nodes[sibling].parent == parent)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: MessageRole, content: &str) -> Message {
+        Message {
+            role,
+            content: content.to_string(),
+            tool_name: None,
+            images: Vec::new(),
+        }
+    }
+
+    fn contents(tree: &MessageTree) -> Vec<&str> {
+        tree.messages().map(|m| m.content.as_str()).collect()
+    }
+
+    fn two_turns() -> (MessageTree, u64) {
+        let mut tree = MessageTree::default();
+        tree.push(message(MessageRole::System, "system"));
+        tree.push(message(MessageRole::User, "one"));
+        tree.push(message(MessageRole::Assistant, "reply one"));
+        let second = tree.push(message(MessageRole::User, "two"));
+        tree.push(message(MessageRole::Assistant, "reply two"));
+        (tree, second)
+    }
+
+    #[test]
+    fn forking_keeps_the_old_branch() {
+        let (mut tree, second) = two_turns();
+
+        let edited = tree
+            .fork(second, message(MessageRole::User, "two, edited"))
+            .unwrap();
+        tree.push(message(MessageRole::Assistant, "reply to edit"));
+
+        assert_eq!(
+            contents(&tree),
+            ["system", "one", "reply one", "two, edited", "reply to edit"]
+        );
+        assert_eq!(
+            tree.branch_points(),
+            [BranchPoint {
+                node: edited,
+                position: 1,
+                count: 2
+            }]
+        );
+
+        tree.switch(edited, 0).unwrap();
+        assert_eq!(
+            contents(&tree),
+            ["system", "one", "reply one", "two", "reply two"]
+        );
+    }
+
+    #[test]
+    fn only_user_inputs_on_the_branch_can_be_edited() {
+        let (mut tree, second) = two_turns();
+
+        assert_eq!(
+            tree.fork(second + 1, message(MessageRole::User, "x")),
+            Err(ContextError::NotUserInput(second + 1))
+        );
+        tree.fork(second, message(MessageRole::User, "edit"))
+            .unwrap();
+        assert_eq!(
+            tree.fork(second, message(MessageRole::User, "x")),
+            Err(ContextError::NotOnBranch(second))
+        );
+    }
+
+    #[test]
+    fn restoring_a_checkpoint_removes_the_new_branch() {
+        let (mut tree, second) = two_turns();
+        let checkpoint = tree.checkpoint();
+
+        tree.fork(second, message(MessageRole::User, "edit"))
+            .unwrap();
+        tree.restore(checkpoint);
+
+        assert_eq!(
+            contents(&tree),
+            ["system", "one", "reply one", "two", "reply two"]
+        );
+        assert!(tree.branch_points().is_empty());
+    }
+
+    #[test]
+    fn replacing_a_prefix_keeps_the_later_messages() {
+        let (mut tree, second) = two_turns();
+
+        tree.replace_prefix(3, vec![message(MessageRole::System, "summary")]);
+
+        assert_eq!(contents(&tree), ["summary", "two", "reply two"]);
+        tree.fork(second, message(MessageRole::User, "edit"))
+            .unwrap();
+        assert_eq!(contents(&tree), ["summary", "edit"]);
+    }
+
+    #[test]
+    fn removing_a_turn_drops_its_branches() {
+        let (mut tree, second) = two_turns();
+        let edited = tree
+            .fork(second, message(MessageRole::User, "edit"))
+            .unwrap();
+        tree.push(message(MessageRole::Assistant, "reply to edit"));
+
+        tree.remove_from(edited).unwrap();
+        let third = tree.push(message(MessageRole::User, "three"));
+
+        assert_eq!(contents(&tree), ["system", "one", "reply one", "three"]);
+        tree.switch(third, 0).unwrap();
+        assert_eq!(
+            contents(&tree),
+            ["system", "one", "reply one", "two", "reply two"]
+        );
+
+        tree.remove_from(second).unwrap();
+        assert_eq!(contents(&tree), ["system", "one", "reply one"]);
+        assert!(tree.branch_points().is_empty());
+    }
+}
@@ -1,8 +1,16 @@
+pub mod attachments;
 pub mod backends;
+pub mod budget;
 pub mod context;
+pub mod error;
+pub mod events;
 pub mod manager;
+pub mod message_tree;
+pub mod prompt;
+pub mod retry;
 pub mod tools;
-pub mod app_recursive_processor;
+pub mod usage;
+pub mod worker;
 
 pub use context::*;
 pub use manager::*;
@@ -0,0 +1,308 @@
+// System prompt template and project instructions
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Template used when the configuration does not set one
+pub const DEFAULT_TEMPLATE: &str = "\
+You are a helpful assistant with access to tools that can run on the user's computer. \
+Respond to the user's queries directly when possible, and use tools when appropriate to complete tasks. \
+When using tools, prefer paths relative to the working directory over absolute paths, \
+and help with tasks efficiently and securely.
+
+Working directory: {{cwd}}
+Operating system: {{os}}
+Date: {{date}}
+Git branch: {{git_branch}}
+Available tools: {{tools}}";
+
+/// Variables a template can use, written as `{{name}}`
+///
+/// Project instructions are added after the prompt unless the template places
+/// them itself with `{{instructions}}`.
+pub const TEMPLATE_VARIABLES: &[&str] =
+    &["cwd", "os", "date", "git_branch", "tools", "instructions"];
+
+/// Project instructions file, looked for in the working directory and every directory above it
+pub const INSTRUCTIONS_FILE: &str = ".gamecode/instructions.md";
+
+/// Instructions read from a project's instructions file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectInstructions {
+    /// File the instructions were read from
+    pub path: PathBuf,
+
+    /// Text of the instructions
+    pub content: String,
+}
+
+/// Values the variables of a template are replaced with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptEnvironment {
+    /// Working directory of the tools
+    pub cwd: String,
+
+    /// Operating system the tools run on
+    pub os: String,
+
+    /// Today's date, as YYYY-MM-DD in UTC
+    pub date: String,
+
+    /// Checked-out git branch of the working directory, if it is in a repository
+    pub git_branch: Option<String>,
+
+    /// Names of the tools the model may call
+    pub tools: Vec<String>,
+
+    /// Project instructions, outermost directory first
+    pub instructions: Vec<ProjectInstructions>,
+}
+
+impl PromptEnvironment {
+    /// Look up the environment of the given working directory
+    pub fn gather(working_dir: &Path, tools: Vec<String>, include_instructions: bool) -> Self {
+        Self {
+            cwd: working_dir.display().to_string(),
+            os: std::env::consts::OS.to_string(),
+            date: today(),
+            git_branch: git_branch(working_dir),
+            tools,
+            instructions: if include_instructions {
+                find_instructions(working_dir)
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    /// The value of a template variable, if it is one
+    fn value(&self, name: &str) -> Option<String> {
+        match name {
+            "cwd" => Some(self.cwd.clone()),
+            "os" => Some(self.os.clone()),
+            "date" => Some(self.date.clone()),
+            "git_branch" => Some(
+                self.git_branch
+                    .clone()
+                    .unwrap_or_else(|| "none (not a git repository)".to_string()),
+            ),
+            "tools" if self.tools.is_empty() => Some("none".to_string()),
+            "tools" => Some(self.tools.join(", ")),
+            "instructions" => Some(self.instructions_section()),
+            _ => None,
+        }
+    }
+
+    /// The project instructions as a section of the prompt, or nothing if there are none
+    fn instructions_section(&self) -> String {
+        self.instructions
+            .iter()
+            .map(|file| {
+                format!(
+                    "Project instructions from {}:\n\n{}",
+                    file.path.display(),
+                    file.content
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+/// Fill in a template's variables
+///
+/// Unknown variables are left as written. Project instructions go after the
+/// prompt unless the template places them.
+pub fn render(template: &str, environment: &PromptEnvironment) -> String {
+    let mut prompt = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        prompt.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = match after.find("}}") {
+            Some(end) => end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        match environment.value(after[..end].trim()) {
+            Some(value) => prompt.push_str(&value),
+            None => prompt.push_str(&rest[start..start + end + 4]),
+        }
+        rest = &after[end + 2..];
+    }
+    prompt.push_str(rest);
+
+    let instructions = environment.instructions_section();
+    if !instructions.is_empty() && !variables(template).contains(&"instructions") {
+        prompt.push_str("\n\n");
+        prompt.push_str(&instructions);
+    }
+    prompt.trim().to_string()
+}
+
+/// Names of the variables used in a template
+pub fn variables(template: &str) -> Vec<&str> {
+    template
+        .split("{{")
+        .skip(1)
+        .filter_map(|part| part.split_once("}}"))
+        .map(|(name, _)| name.trim())
+        .collect()
+}
+
+/// Project instructions files in the working directory and above, outermost first
+pub fn find_instructions(working_dir: &Path) -> Vec<ProjectInstructions> {
+    let mut instructions: Vec<ProjectInstructions> = working_dir
+        .ancestors()
+        .map(|dir| dir.join(INSTRUCTIONS_FILE))
+        .filter(|path| path.is_file())
+        .filter_map(|path| match fs::read_to_string(&path) {
+            Ok(content) if !content.trim().is_empty() => Some(ProjectInstructions {
+                content: content.trim().to_string(),
+                path,
+            }),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Could not read instructions {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect();
+    instructions.reverse();
+    instructions
+}
+
+/// The checked-out branch of the repository the directory is in
+///
+/// A detached HEAD is described by its commit.
+pub fn git_branch(working_dir: &Path) -> Option<String> {
+    let dot_git = working_dir
+        .ancestors()
+        .map(|dir| dir.join(".git"))
+        .find(|path| path.exists())?;
+
+    // Worktrees and submodules have a file pointing at the real git directory
+    let git_dir = match fs::read_to_string(&dot_git) {
+        Ok(link) => {
+            let target = link.strip_prefix("gitdir:")?.trim();
+            dot_git.parent()?.join(target)
+        }
+        Err(_) => dot_git,
+    };
+
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+    match head.strip_prefix("ref: refs/heads/") {
+        Some(branch) => Some(branch.to_string()),
+        None => Some(format!("detached at {}", head.get(..7).unwrap_or(head))),
+    }
+}
+
+/// Today's date in UTC, as YYYY-MM-DD
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() / 86_400)
+        .unwrap_or(0);
+    let (year, month, day) = civil_date(days as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// The calendar date a number of days after 1970-01-01 falls on
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's days-to-civil algorithm, counting in 400-year eras from March 0000
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environment() -> PromptEnvironment {
+        PromptEnvironment {
+            cwd: "/work/game".to_string(),
+            os: "linux".to_string(),
+            date: "2025-03-14".to_string(),
+            git_branch: Some("main".to_string()),
+            tools: vec!["read_file".to_string(), "view_image".to_string()],
+            instructions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn variables_are_filled_in() {
+        let prompt = render(
+            "In {{cwd}} on {{ os }} ({{date}}, {{git_branch}}) with {{tools}}; {{unknown}} stays",
+            &environment(),
+        );
+
+        assert_eq!(
+            prompt,
+            "In /work/game on linux (2025-03-14, main) with read_file, view_image; {{unknown}} stays"
+        );
+    }
+
+    #[test]
+    fn instructions_follow_the_prompt_unless_placed() {
+        let mut environment = environment();
+        environment.instructions = vec![ProjectInstructions {
+            path: PathBuf::from("/work/.gamecode/instructions.md"),
+            content: "Use tabs.".to_string(),
+        }];
+
+        assert_eq!(
+            render("Be brief.", &environment),
+            "Be brief.\n\nProject instructions from /work/.gamecode/instructions.md:\n\nUse tabs."
+        );
+        assert!(
+            render("{{instructions}}\n\nBe brief.", &environment)
+                .ends_with("Use tabs.\n\nBe brief.")
+        );
+    }
+
+    #[test]
+    fn instructions_are_found_above_the_working_directory() {
+        let root = std::env::temp_dir().join(format!("gamecode-prompt-{}", uuid::Uuid::new_v4()));
+        let project = root.join("game");
+        let nested = project.join("src");
+        fs::create_dir_all(&nested).unwrap();
+        fs::create_dir_all(root.join(".gamecode")).unwrap();
+        fs::create_dir_all(project.join(".gamecode")).unwrap();
+        fs::write(root.join(INSTRUCTIONS_FILE), "Outer rules.").unwrap();
+        fs::write(project.join(INSTRUCTIONS_FILE), "Project rules.\n").unwrap();
+        fs::create_dir_all(project.join(".git")).unwrap();
+        fs::write(project.join(".git/HEAD"), "ref: refs/heads/feature/maps\n").unwrap();
+
+        let instructions = find_instructions(&nested);
+
+        let contents: Vec<&str> = instructions.iter().map(|i| i.content.as_str()).collect();
+        assert_eq!(contents, ["Outer rules.", "Project rules."]);
+        assert_eq!(git_branch(&nested).as_deref(), Some("feature/maps"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn dates_are_counted_from_the_epoch() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert_eq!(civil_date(20_161), (2025, 3, 14));
+    }
+}
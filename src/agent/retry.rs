@@ -0,0 +1,114 @@
+// When and how long to wait before sending a failed model request again
+
+use rand::Rng;
+use std::time::Duration;
+
+/// How failed model requests are retried
+///
+/// Only transient failures are retried (see `BackendError::is_retryable`).
+/// The wait doubles with every retry up to `max_delay`, and a random part of
+/// it is dropped so that clients throttled together do not retry together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Most times one request is sent again
+    pub max_retries: usize,
+
+    /// Wait before the first retry
+    pub base_delay: Duration,
+
+    /// Longest wait before any retry
+    pub max_delay: Duration,
+
+    /// Throttled attempts after which the request moves to the model's
+    /// fallback, if it has one (zero never falls back)
+    pub fallback_after: usize,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(20),
+            fallback_after: 2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Longest wait before the given retry, counting from 1, before jitter
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let doublings = retry.saturating_sub(1).min(31) as u32;
+        self.base_delay
+            .saturating_mul(2u32.pow(doublings))
+            .min(self.max_delay)
+    }
+
+    /// Wait before the given retry, counting from 1
+    ///
+    /// This is between half of the backoff and all of it.
+    pub fn delay(&self, retry: usize) -> Duration {
+        let backoff = self.backoff(retry);
+        let half = backoff / 2;
+        let jitter_ms = rand::rng().random_range(0..=(backoff - half).as_millis() as u64);
+        half + Duration::from_millis(jitter_ms)
+    }
+
+    /// Whether a request throttled this many times should move to a fallback model
+    pub fn should_fall_back(&self, throttled: usize) -> bool {
+        self.fallback_after > 0 && throttled == self.fallback_after
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(400),
+            max_delay: Duration::from_millis(3000),
+            fallback_after: 2,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = policy();
+
+        let backoffs: Vec<u64> = (1..=5)
+            .map(|retry| policy.backoff(retry).as_millis() as u64)
+            .collect();
+
+        assert_eq!(backoffs, vec![400, 800, 1600, 3000, 3000]);
+        assert_eq!(policy.backoff(1000), Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn delays_are_between_half_and_all_of_the_backoff() {
+        let policy = policy();
+
+        for retry in 1..=5 {
+            let backoff = policy.backoff(retry);
+            for _ in 0..50 {
+                let delay = policy.delay(retry);
+                assert!(delay >= backoff / 2 && delay <= backoff, "{:?}", delay);
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_once_after_the_configured_throttles() {
+        let policy = policy();
+        let never = RetryPolicy {
+            fallback_after: 0,
+            ..policy.clone()
+        };
+
+        assert!(!policy.should_fall_back(1));
+        assert!(policy.should_fall_back(2));
+        assert!(!policy.should_fall_back(3));
+        assert!(!never.should_fall_back(0));
+    }
+}
@@ -0,0 +1,76 @@
+// Failures of tool calls
+
+use thiserror::Error;
+
+/// Why a tool call did not produce a result
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ToolError {
+    /// No tool with the name is registered
+    #[error("Tool '{0}' not found")]
+    NotFound(String),
+
+    /// The arguments do not fit the tool
+    #[error("Invalid arguments for tool '{tool}': {message}")]
+    InvalidArguments { tool: String, message: String },
+
+    /// A policy forbids the call
+    #[error(transparent)]
+    Denied(#[from] PolicyError),
+
+    /// The tool ran past the time limit
+    #[error("Tool '{tool}' timed out after {ms}ms")]
+    Timeout { tool: String, ms: u64 },
+
+    /// The files the tool would change could not be snapshotted, so it was not run
+    #[error("Not run, the files could not be snapshotted: {0}")]
+    Snapshot(String),
+
+    /// The tool ran and failed
+    #[error("{0}")]
+    Failed(String),
+}
+
+/// A tool call that a policy does not allow
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PolicyError {
+    /// The command is not in the allowed list
+    #[error(
+        "Command '{command}' is not allowed for security reasons. Allowed commands are: {allowed}"
+    )]
+    CommandNotAllowed { command: String, allowed: String },
+
+    /// An argument could chain or redirect commands
+    #[error("Argument '{0}' contains potentially unsafe characters")]
+    UnsafeArgument(String),
+
+    /// The tool can change files or run commands, and the session is in ask mode
+    #[error("Tool '{0}' can change files or run commands, which ask mode does not allow")]
+    ReadOnly(String),
+
+    /// The path leads outside the working directory
+    #[error("'{0}' is outside the working directory")]
+    OutsideWorkspace(String),
+}
+
+impl ToolError {
+    /// Invalid arguments for a tool, described by `message`
+    pub fn invalid_arguments(tool: &str, message: &str) -> Self {
+        ToolError::InvalidArguments {
+            tool: tool.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// What the user can do about the failure, if anything
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            ToolError::Denied(PolicyError::CommandNotAllowed { .. }) => {
+                Some("Add the command to tools.allowed_commands to let the agent run it.")
+            }
+            ToolError::Denied(PolicyError::ReadOnly(_)) => {
+                Some("Switch to agent mode to let the agent change files or run commands.")
+            }
+            _ => None,
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::agent::tools::error::ToolError;
 use crate::agent::tools::types::Tool;
 
 /// Environment for executing tools
@@ -28,14 +29,46 @@ impl ToolExecutor {
         self.working_directory = directory.to_string();
     }
 
+    /// Get the working directory
+    pub fn working_directory(&self) -> &str {
+        &self.working_directory
+    }
+
     /// Execute a tool with the given arguments
-    pub async fn execute(&self, tool: &dyn Tool, args: &[String]) -> Result<String, String> {
-        // TODO: Implement timeout mechanism
+    pub async fn execute(&self, tool: &dyn Tool, args: &[String]) -> Result<String, ToolError> {
         // TODO: Setup proper sandboxing
 
-        // Execute the tool
-        let result = tool.execute(args, &self.working_directory).await?;
+        // Execute the tool, giving up once the time limit is reached
+        let limit = std::time::Duration::from_millis(self.max_execution_time);
+        match tokio::time::timeout(limit, tool.execute(args, &self.working_directory)).await {
+            Ok(result) => result,
+            Err(_) => Err(ToolError::Timeout {
+                tool: tool.name().to_string(),
+                ms: self.max_execution_time,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::tools::ExecuteCommandTool;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn commands_are_stopped_at_the_time_limit() {
+        let tool = ExecuteCommandTool::with_allowed_commands(vec!["sleep".to_string()]);
+        let mut executor = ToolExecutor::new();
+        executor.set_max_execution_time(100);
+        executor.set_working_directory(std::env::temp_dir().to_str().unwrap());
+
+        let started = Instant::now();
+        let result = executor
+            .execute(&tool, &["command=sleep 5".to_string()])
+            .await;
 
-        Ok(result)
+        assert!(matches!(result, Err(ToolError::Timeout { ms: 100, .. })));
+        assert!(started.elapsed() < Duration::from_secs(2));
     }
 }
@@ -1,8 +1,10 @@
+use crate::agent::attachments::{ImageAttachment, ImageError};
+use crate::agent::tools::error::{PolicyError, ToolError};
 use crate::agent::tools::types::{Tool, ToolArgument, ToolArgumentType};
 use async_trait::async_trait;
-use std::fs;
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::process::Command;
 use tracing::{error, trace};
 
 /// Tool for reading files from the filesystem
@@ -27,9 +29,12 @@ impl Tool for ReadFileTool {
         }]
     }
 
-    async fn execute(&self, args: &[String], working_dir: &str) -> Result<String, String> {
+    async fn execute(&self, args: &[String], working_dir: &str) -> Result<String, ToolError> {
         if args.is_empty() {
-            return Err("No file path provided".to_string());
+            return Err(ToolError::invalid_arguments(
+                self.name(),
+                "No file path provided",
+            ));
         }
 
         let arg = args[0].clone();
@@ -50,15 +55,80 @@ impl Tool for ReadFileTool {
         };
 
         // Read the file
-        match fs::read_to_string(&path) {
+        match fs::read_to_string(&path).await {
             Ok(content) => Ok(content),
             Err(e) => {
                 error!("Error reading file: {}", e);
-                Err(format!("Error reading file: {}", e))
+                Err(ToolError::Failed(format!("Error reading file: {}", e)))
             }
         }
     }
 
+    fn is_mutating(&self) -> bool {
+        false
+    }
+
+    fn visualization_type(&self) -> &'static str {
+        "file_read"
+    }
+}
+
+/// Tool for looking at image files, returned to the model as images
+pub struct ViewImageTool;
+
+#[async_trait]
+impl Tool for ViewImageTool {
+    fn name(&self) -> &'static str {
+        "view_image"
+    }
+
+    fn description(&self) -> &'static str {
+        "Look at a PNG, JPEG, WebP or GIF image file, such as a screenshot, chart or test snapshot"
+    }
+
+    fn required_args(&self) -> Vec<ToolArgument> {
+        vec![ToolArgument {
+            name: "path".to_string(),
+            description: "Path to the image file to view".to_string(),
+            required: true,
+            arg_type: ToolArgumentType::FilePath,
+        }]
+    }
+
+    async fn execute(&self, args: &[String], working_dir: &str) -> Result<String, ToolError> {
+        let path_value = named_arg(args, "path", 0)
+            .ok_or_else(|| ToolError::invalid_arguments(self.name(), "No file path provided"))?;
+
+        // The image is decoded and scaled off the async worker, then returned as
+        // JSON that the backend sends as an image block in the tool result
+        let (workspace, path) = (PathBuf::from(working_dir), path_value.to_string());
+        let load = move || ImageAttachment::load_in(&workspace, &path);
+        let image = tokio::task::spawn_blocking(load)
+            .await
+            .map_err(|e| ToolError::Failed(e.to_string()))?
+            .map_err(|e| match e {
+                ImageError::OutsideWorkspace { path } => {
+                    ToolError::Denied(PolicyError::OutsideWorkspace(path))
+                }
+                e => ToolError::Failed(e.to_string()),
+            })?;
+        trace!(
+            "Viewing image {} ({}x{})",
+            image.name,
+            image.width,
+            image.height
+        );
+        serde_json::to_string(&image).map_err(|e| ToolError::Failed(e.to_string()))
+    }
+
+    fn returns_image(&self) -> bool {
+        true
+    }
+
+    fn is_mutating(&self) -> bool {
+        false
+    }
+
     fn visualization_type(&self) -> &'static str {
         "file_read"
     }
@@ -94,58 +164,54 @@ impl Tool for WriteFileTool {
         ]
     }
 
-    fn validate_args(&self, args: &[String]) -> Result<(), String> {
+    fn validate_args(&self, args: &[String]) -> Result<(), ToolError> {
         if args.len() < 2 {
-            return Err("Both file path and content are required".to_string());
+            return Err(ToolError::invalid_arguments(
+                self.name(),
+                "Both file path and content are required",
+            ));
         }
         Ok(())
     }
 
-    async fn execute(&self, args: &[String], working_dir: &str) -> Result<String, String> {
+    fn modified_files(&self, args: &[String], working_dir: &str) -> Vec<PathBuf> {
+        named_arg(args, "path", 0)
+            .map(|path| PathBuf::from(resolve_path(path, working_dir)))
+            .into_iter()
+            .collect()
+    }
+
+    async fn execute(&self, args: &[String], working_dir: &str) -> Result<String, ToolError> {
         if args.len() < 2 {
-            return Err("Both file path and content are required".to_string());
+            return Err(ToolError::invalid_arguments(
+                self.name(),
+                "Both file path and content are required",
+            ));
         }
 
-        // Extract path parameter
-        let arg_path = args[0].clone();
-        let path_value = if let Some(stripped) = arg_path.strip_prefix("path=") {
-            stripped.to_string()
-        } else {
-            arg_path
-        };
-
-        let path = if path_value.starts_with('/') {
-            // Absolute path
-            path_value
-        } else {
-            // Relative path - prepend working directory
-            format!("{}/{}", working_dir.trim_end_matches('/'), path_value)
-        };
-
-        // Extract content parameter
-        let arg_content = args[1].clone();
-        let content = if let Some(stripped) = arg_content.strip_prefix("content=") {
-            stripped.to_string()
-        } else {
-            arg_content
-        };
+        // Extract the parameters; named ones arrive sorted, so content comes first
+        let path = resolve_path(named_arg(args, "path", 0).unwrap_or_default(), working_dir);
+        let content = named_arg(args, "content", 1).unwrap_or_default();
 
         // Create parent directories if they don't exist
         if let Some(parent) = Path::new(&path).parent() {
             if !parent.exists() {
-                if let Err(e) = fs::create_dir_all(parent) {
+                if let Err(e) = fs::create_dir_all(parent).await {
                     error!("Error creating directories: {}", e);
-                    return Err(format!("Error creating directories: {}", e));
+                    return Err(ToolError::Failed(format!(
+                        "Error creating directories: {}",
+                        e
+                    )));
                 }
             }
         }
 
         // Write to the file
-        match fs::write(&path, content) {
+        match fs::write(&path, content).await {
             Ok(_) => Ok(format!("Successfully wrote to file: {}", path)),
             Err(e) => {
                 error!("Error writing to file: {}", e);
-                Err(format!("Error writing to file: {}", e))
+                Err(ToolError::Failed(format!("Error writing to file: {}", e)))
             }
         }
     }
@@ -155,6 +221,25 @@ impl Tool for WriteFileTool {
     }
 }
 
+/// Value of the argument given as "name=value", or else the argument at `position`
+fn named_arg<'a>(args: &'a [String], name: &str, position: usize) -> Option<&'a str> {
+    let prefix = format!("{}=", name);
+    args.iter()
+        .find_map(|arg| arg.strip_prefix(&prefix))
+        .or_else(|| args.get(position).map(String::as_str))
+}
+
+/// Resolve a path against the working directory
+fn resolve_path(path_value: &str, working_dir: &str) -> String {
+    if path_value.starts_with('/') {
+        // Absolute path
+        path_value.to_string()
+    } else {
+        // Relative path - prepend working directory
+        format!("{}/{}", working_dir.trim_end_matches('/'), path_value)
+    }
+}
+
 /// Tool for listing files in a directory
 pub struct ListDirectoryTool;
 
@@ -177,7 +262,7 @@ impl Tool for ListDirectoryTool {
         }]
     }
 
-    async fn execute(&self, args: &[String], working_dir: &str) -> Result<String, String> {
+    async fn execute(&self, args: &[String], working_dir: &str) -> Result<String, ToolError> {
         trace!(
             "ListDirectoryTool called with args: {:?}, working_dir: {}",
             args,
@@ -245,26 +330,29 @@ impl Tool for ListDirectoryTool {
         let path_obj = Path::new(&path);
         if !path_obj.exists() {
             error!("Directory does not exist: '{}'", path);
-            return Err(format!("Directory does not exist: {}", path));
+            return Err(ToolError::Failed(format!(
+                "Directory does not exist: {}",
+                path
+            )));
         }
         if !path_obj.is_dir() {
             error!("Not a directory: '{}'", path);
-            return Err(format!("Not a directory: {}", path));
+            return Err(ToolError::Failed(format!("Not a directory: {}", path)));
         }
 
         // Read directory entries
-        match fs::read_dir(&path) {
-            Ok(entries) => {
+        match fs::read_dir(&path).await {
+            Ok(mut entries) => {
                 let mut result = format!("Contents of {}:\n", path);
 
                 // Process entries
-                for entry_result in entries {
-                    match entry_result {
-                        Ok(entry) => {
+                loop {
+                    match entries.next_entry().await {
+                        Ok(Some(entry)) => {
                             let file_name = entry.file_name();
                             let file_name_str = file_name.to_string_lossy();
 
-                            let file_type = if let Ok(metadata) = entry.metadata() {
+                            let file_type = if let Ok(metadata) = entry.metadata().await {
                                 if metadata.is_dir() {
                                     "dir"
                                 } else if metadata.is_file() {
@@ -278,9 +366,11 @@ impl Tool for ListDirectoryTool {
 
                             result.push_str(&format!("{} ({})\n", file_name_str, file_type));
                         }
+                        Ok(None) => break,
                         Err(e) => {
                             error!("Error reading directory entry: {}", e);
                             result.push_str(&format!("Error reading entry: {}\n", e));
+                            break;
                         }
                     }
                 }
@@ -289,26 +379,54 @@ impl Tool for ListDirectoryTool {
             }
             Err(e) => {
                 error!("Error reading directory: {}", e);
-                Err(format!("Error reading directory: {}", e))
+                Err(ToolError::Failed(format!("Error reading directory: {}", e)))
             }
         }
     }
 
+    fn is_mutating(&self) -> bool {
+        false
+    }
+
     fn visualization_type(&self) -> &'static str {
         "file_list"
     }
 }
 
 /// Tool for executing shell commands
-pub struct ExecuteCommandTool;
+pub struct ExecuteCommandTool {
+    /// Commands this tool is allowed to run
+    allowed_commands: Vec<String>,
+}
 
 impl ExecuteCommandTool {
-    /// List of allowed commands for security
-    pub fn allowed_commands() -> Vec<&'static str> {
+    /// Default list of allowed commands for security
+    pub fn default_allowed_commands() -> Vec<&'static str> {
         vec![
             "ls", "dir", "find", "grep", "cat", "head", "tail", "echo", "pwd",
         ]
     }
+
+    /// Create the tool with a custom list of allowed commands
+    pub fn with_allowed_commands(allowed_commands: Vec<String>) -> Self {
+        Self { allowed_commands }
+    }
+
+    /// List of allowed commands for security
+    pub fn allowed_commands(&self) -> &[String] {
+        &self.allowed_commands
+    }
+}
+
+impl Default for ExecuteCommandTool {
+    fn default() -> Self {
+        Self::with_allowed_commands(
+            Self::default_allowed_commands()
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect(),
+        )
+    }
 }
 
 #[async_trait]
@@ -330,9 +448,12 @@ impl Tool for ExecuteCommandTool {
         }]
     }
 
-    async fn execute(&self, args: &[String], working_dir: &str) -> Result<String, String> {
+    async fn execute(&self, args: &[String], working_dir: &str) -> Result<String, ToolError> {
         if args.is_empty() {
-            return Err("No command provided".to_string());
+            return Err(ToolError::invalid_arguments(
+                self.name(),
+                "No command provided",
+            ));
         }
 
         // Extract command parameter
@@ -407,14 +528,14 @@ impl Tool for ExecuteCommandTool {
         trace!("Parsed command parts: {:?}", cmd_parts);
 
         if cmd_parts.is_empty() {
-            return Err("Empty command".to_string());
+            return Err(ToolError::invalid_arguments(self.name(), "Empty command"));
         }
 
         // Security check for allowed commands
         let base_command = &cmd_parts[0];
-        let allowed_commands = Self::allowed_commands();
+        let allowed_commands = self.allowed_commands();
 
-        if allowed_commands.iter().any(|&cmd| cmd == base_command) {
+        if allowed_commands.iter().any(|cmd| cmd == base_command) {
             // Command is allowed, now check the arguments
 
             // Additional validation for command arguments
@@ -430,26 +551,25 @@ impl Tool for ExecuteCommandTool {
                     || arg.contains("`")
                     || arg.contains("${")
                 {
-                    return Err(format!(
-                        "Argument '{}' contains potentially unsafe characters",
-                        arg
-                    ));
+                    return Err(PolicyError::UnsafeArgument(arg.clone()).into());
                 }
             }
         } else {
-            return Err(format!(
-                "Command '{}' is not allowed for security reasons. Allowed commands are: {}",
-                base_command,
-                allowed_commands.join(", ")
-            ));
+            return Err(PolicyError::CommandNotAllowed {
+                command: base_command.clone(),
+                allowed: allowed_commands.join(", "),
+            }
+            .into());
         }
 
-        // Execute the command
+        // Execute the command; it is killed if the call times out or the turn is cancelled
         let output = Command::new(&cmd_parts[0])
             .args(&cmd_parts[1..])
             .current_dir(working_dir)
+            .kill_on_drop(true)
             .output()
-            .map_err(|e| format!("Failed to execute command: {}", e))?;
+            .await
+            .map_err(|e| ToolError::Failed(format!("Failed to execute command: {}", e)))?;
 
         let stdout = String::from_utf8_lossy(&output.stdout).to_string();
         let stderr = String::from_utf8_lossy(&output.stderr).to_string();
@@ -1,8 +1,10 @@
+mod error;
 mod executor;
 mod filesystem;
 mod registry;
 mod types;
 
+pub use error::*;
 pub use executor::*;
 pub use filesystem::*;
 pub use registry::*;
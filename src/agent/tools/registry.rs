@@ -1,6 +1,8 @@
+use crate::agent::tools::error::{PolicyError, ToolError};
 use crate::agent::tools::executor::ToolExecutor;
 use crate::agent::tools::types::Tool;
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 /// Registry for managing available tools
 pub struct ToolRegistry {
@@ -9,6 +11,12 @@ pub struct ToolRegistry {
 
     /// Tool execution environment
     executor: ToolExecutor,
+
+    /// Names of the registered tools that may be used, or all of them if not set
+    enabled: Option<Vec<String>>,
+
+    /// Whether tools that change files or run commands are refused
+    read_only: bool,
 }
 
 impl ToolRegistry {
@@ -17,6 +25,8 @@ impl ToolRegistry {
         Self {
             tools: HashMap::new(),
             executor: ToolExecutor::new(),
+            enabled: None,
+            read_only: false,
         }
     }
 
@@ -31,9 +41,41 @@ impl ToolRegistry {
         self.executor.set_working_directory(directory);
     }
 
+    /// Get the working directory for tool execution
+    pub fn working_directory(&self) -> &str {
+        self.executor.working_directory()
+    }
+
+    /// Set the maximum execution time for tools
+    pub fn set_max_execution_time(&mut self, milliseconds: u64) {
+        self.executor.set_max_execution_time(milliseconds);
+    }
+
+    /// Limit the tools that may be used to the named ones (all registered tools if `None`)
+    pub fn set_enabled_tools(&mut self, names: Option<Vec<String>>) {
+        self.enabled = names;
+    }
+
+    /// Refuse tools that change files or run commands, or allow them again
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Whether a registered tool may be used
+    fn is_enabled(&self, name: &str) -> bool {
+        match &self.enabled {
+            Some(enabled) => enabled.iter().any(|n| n == name),
+            None => true,
+        }
+    }
+
     /// Get a list of all available tool names
     pub fn available_tools(&self) -> Vec<String> {
-        self.tools.keys().cloned().collect()
+        self.tools
+            .iter()
+            .filter(|(name, tool)| self.is_enabled(name) && !(self.read_only && tool.is_mutating()))
+            .map(|(name, _)| name.clone())
+            .collect()
     }
 
     /// Get information about all registered tools
@@ -44,16 +86,34 @@ impl ToolRegistry {
             .collect()
     }
 
+    /// Files a tool would change if run with the given arguments
+    pub fn modified_files(&self, name: &str, args: &[String]) -> Vec<PathBuf> {
+        self.tools
+            .get(name)
+            .map(|tool| tool.modified_files(args, self.executor.working_directory()))
+            .unwrap_or_default()
+    }
+
+    /// Whether a tool's output is an image rather than text
+    pub fn returns_image(&self, name: &str) -> bool {
+        self.tools
+            .get(name)
+            .is_some_and(|tool| tool.returns_image())
+    }
+
     /// Execute a tool by name with the given arguments
-    pub async fn execute_tool(&self, name: &str, args: &[String]) -> Result<String, String> {
+    pub async fn execute_tool(&self, name: &str, args: &[String]) -> Result<String, ToolError> {
         let tool = self
             .tools
             .get(name)
-            .ok_or_else(|| format!("Tool '{}' not found", name))?;
+            .filter(|_| self.is_enabled(name))
+            .ok_or_else(|| ToolError::NotFound(name.to_string()))?;
+        if self.read_only && tool.is_mutating() {
+            return Err(PolicyError::ReadOnly(name.to_string()).into());
+        }
 
         // Validate arguments
-        tool.validate_args(args)
-            .map_err(|e| format!("Invalid arguments for tool '{}': {}", name, e))?;
+        tool.validate_args(args)?;
 
         // Execute the tool
         self.executor.execute(tool.as_ref(), args).await
@@ -1,4 +1,6 @@
+use crate::agent::tools::error::ToolError;
 use async_trait::async_trait;
+use std::path::PathBuf;
 
 /// Trait defining a tool that can be executed by the agent
 #[async_trait]
@@ -13,12 +15,15 @@ pub trait Tool: Send + Sync {
     fn required_args(&self) -> Vec<ToolArgument>;
 
     /// Validate that the provided arguments are correct
-    fn validate_args(&self, args: &[String]) -> Result<(), String> {
+    fn validate_args(&self, args: &[String]) -> Result<(), ToolError> {
         let required = self.required_args();
 
         // Check if we have at least the required number of arguments
         if args.len() < required.iter().filter(|arg| arg.required).count() {
-            return Err("Not enough arguments provided".to_string());
+            return Err(ToolError::invalid_arguments(
+                self.name(),
+                "Not enough arguments provided",
+            ));
         }
 
         // TODO: Add more sophisticated validation based on argument types
@@ -26,8 +31,30 @@ pub trait Tool: Send + Sync {
         Ok(())
     }
 
+    /// Files the tool would change if run with the given arguments
+    ///
+    /// Tools that edit files list them here so they can be snapshotted before
+    /// the tool runs and restored if the turn is undone.
+    fn modified_files(&self, _args: &[String], _working_dir: &str) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    /// Whether the tool can change files or run commands
+    ///
+    /// Ask mode refuses the tools that can; tools that only read say so here.
+    fn is_mutating(&self) -> bool {
+        true
+    }
+
+    /// Whether the tool's output is an image, as the JSON of an `ImageAttachment`
+    ///
+    /// The image is sent back to the model as an image block rather than text.
+    fn returns_image(&self) -> bool {
+        false
+    }
+
     /// Execute the tool with the given arguments
-    async fn execute(&self, args: &[String], working_dir: &str) -> Result<String, String>;
+    async fn execute(&self, args: &[String], working_dir: &str) -> Result<String, ToolError>;
 
     /// Get visualization details for this tool
     fn visualization_type(&self) -> &'static str {
@@ -93,14 +120,21 @@ impl Tool for EchoTool {
         }]
     }
 
-    async fn execute(&self, args: &[String], _working_dir: &str) -> Result<String, String> {
+    async fn execute(&self, args: &[String], _working_dir: &str) -> Result<String, ToolError> {
         if args.is_empty() {
-            return Err("No text provided to echo".to_string());
+            return Err(ToolError::invalid_arguments(
+                self.name(),
+                "No text provided to echo",
+            ));
         }
 
         Ok(args.join(" "))
     }
 
+    fn is_mutating(&self) -> bool {
+        false
+    }
+
     fn visualization_type(&self) -> &'static str {
         "echo"
     }
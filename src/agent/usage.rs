@@ -0,0 +1,267 @@
+// Tokens and cost of the requests made during a session
+
+use crate::agent::backends::TokenUsage;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Number of tokens a price is quoted for
+const TOKENS_PER_PRICE: f64 = 1_000_000.0;
+
+/// Price of a model's tokens, in US dollars per million tokens
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    /// Request tokens that do not touch the prompt cache
+    pub input: f64,
+
+    /// Generated tokens
+    pub output: f64,
+
+    /// Request tokens read from the prompt cache
+    pub cache_read: f64,
+
+    /// Request tokens written to the prompt cache
+    pub cache_write: f64,
+}
+
+/// What a request to the model was made for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestPurpose {
+    /// Answering the user, including tool rounds
+    Turn,
+
+    /// Naming the session
+    Title,
+
+    /// Summarizing older turns to compact the context
+    Compaction,
+}
+
+/// Tokens and cost of one request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestUsage {
+    /// Catalog name of the model the request was sent to
+    pub model: String,
+
+    /// What the request was for
+    pub purpose: RequestPurpose,
+
+    /// Tokens the backend counted
+    pub usage: TokenUsage,
+
+    /// Cost in US dollars, at the prices configured when the request was made
+    pub cost: f64,
+}
+
+/// Sum of the usage of several requests
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UsageTotals {
+    /// Number of requests
+    pub requests: usize,
+
+    /// Tokens of every request together
+    pub usage: TokenUsage,
+
+    /// Cost in US dollars
+    pub cost: f64,
+}
+
+/// Every request made during a session, in order
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct UsageLedger {
+    /// Usage of each request, oldest first
+    requests: Vec<RequestUsage>,
+
+    /// Milliseconds the agent spent working on turns, including tool runs
+    #[serde(default)]
+    working_ms: u64,
+}
+
+impl ModelPrice {
+    /// Cost of the tokens of one request, in US dollars
+    pub fn cost(&self, usage: &TokenUsage) -> f64 {
+        (usage.input_tokens as f64 * self.input
+            + usage.output_tokens as f64 * self.output
+            + usage.cache_read_tokens as f64 * self.cache_read
+            + usage.cache_write_tokens as f64 * self.cache_write)
+            / TOKENS_PER_PRICE
+    }
+}
+
+impl RequestPurpose {
+    /// Name shown in the usage breakdown
+    pub fn label(&self) -> &'static str {
+        match self {
+            RequestPurpose::Turn => "Turns",
+            RequestPurpose::Title => "Titles",
+            RequestPurpose::Compaction => "Compaction",
+        }
+    }
+}
+
+impl UsageTotals {
+    /// Add one request to the totals
+    fn add(&mut self, request: &RequestUsage) {
+        self.requests += 1;
+        self.usage += request.usage;
+        self.cost += request.cost;
+    }
+}
+
+impl UsageLedger {
+    /// Record a request, pricing its tokens with `price`
+    pub fn record(
+        &mut self,
+        model: &str,
+        purpose: RequestPurpose,
+        usage: TokenUsage,
+        price: &ModelPrice,
+    ) {
+        self.requests.push(RequestUsage {
+            model: model.to_string(),
+            purpose,
+            usage,
+            cost: price.cost(&usage),
+        });
+    }
+
+    /// Usage of each request, oldest first
+    pub fn requests(&self) -> &[RequestUsage] {
+        &self.requests
+    }
+
+    /// Add the time the agent spent on a turn
+    pub fn add_working_time(&mut self, time: Duration) {
+        self.working_ms += time.as_millis() as u64;
+    }
+
+    /// Time the agent spent working on turns
+    pub fn working_time(&self) -> Duration {
+        Duration::from_millis(self.working_ms)
+    }
+
+    /// Usage of every request together
+    pub fn totals(&self) -> UsageTotals {
+        let mut totals = UsageTotals::default();
+        for request in &self.requests {
+            totals.add(request);
+        }
+        totals
+    }
+
+    /// Usage of each model, in the order the models were first used
+    pub fn by_model(&self) -> Vec<(String, UsageTotals)> {
+        self.group_by(|request| request.model.clone())
+    }
+
+    /// Usage of each kind of request, in the order they were first made
+    pub fn by_purpose(&self) -> Vec<(RequestPurpose, UsageTotals)> {
+        self.group_by(|request| request.purpose)
+    }
+
+    /// Sum the requests that share a key
+    fn group_by<K, F>(&self, key: F) -> Vec<(K, UsageTotals)>
+    where
+        K: PartialEq,
+        F: Fn(&RequestUsage) -> K,
+    {
+        let mut groups: Vec<(K, UsageTotals)> = Vec::new();
+        for request in &self.requests {
+            let request_key = key(request);
+            match groups.iter_mut().find(|(k, _)| *k == request_key) {
+                Some((_, totals)) => totals.add(request),
+                None => {
+                    let mut totals = UsageTotals::default();
+                    totals.add(request);
+                    groups.push((request_key, totals));
+                }
+            }
+        }
+        groups
+    }
+}
+
+/// Dollar amount with enough places to show the cost of a single request
+pub fn format_cost(cost: f64) -> String {
+    if cost >= 1.0 {
+        format!("${:.2}", cost)
+    } else {
+        format!("${:.4}", cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRICE: ModelPrice = ModelPrice {
+        input: 3.0,
+        output: 15.0,
+        cache_read: 0.3,
+        cache_write: 3.75,
+    };
+
+    fn usage(input_tokens: usize, output_tokens: usize, cache_read_tokens: usize) -> TokenUsage {
+        TokenUsage {
+            input_tokens,
+            output_tokens,
+            cache_read_tokens,
+            cache_write_tokens: 0,
+        }
+    }
+
+    #[test]
+    fn requests_are_priced_and_grouped() {
+        let mut ledger = UsageLedger::default();
+        ledger.record(
+            "sonnet",
+            RequestPurpose::Turn,
+            usage(1_000_000, 0, 0),
+            &PRICE,
+        );
+        ledger.record("haiku", RequestPurpose::Title, usage(0, 100_000, 0), &PRICE);
+        ledger.record(
+            "sonnet",
+            RequestPurpose::Turn,
+            usage(0, 0, 1_000_000),
+            &PRICE,
+        );
+
+        let totals = ledger.totals();
+        assert_eq!(totals.requests, 3);
+        assert_eq!(totals.usage.total(), 2_100_000);
+        assert!((totals.cost - 4.8).abs() < 1e-9);
+
+        let by_model = ledger.by_model();
+        assert_eq!(by_model.len(), 2);
+        assert_eq!(by_model[0].0, "sonnet");
+        assert_eq!(by_model[0].1.requests, 2);
+        assert!((by_model[0].1.cost - 3.3).abs() < 1e-9);
+        assert_eq!(by_model[1].0, "haiku");
+        assert!((by_model[1].1.cost - 1.5).abs() < 1e-9);
+
+        let by_purpose = ledger.by_purpose();
+        assert_eq!(by_purpose[0].0, RequestPurpose::Turn);
+        assert_eq!(by_purpose[1].0, RequestPurpose::Title);
+    }
+
+    #[test]
+    fn cache_hits_are_counted_against_the_whole_prompt() {
+        let mut ledger = UsageLedger::default();
+        assert_eq!(ledger.totals().usage.cache_hit_rate(), None);
+
+        ledger.record("sonnet", RequestPurpose::Turn, usage(1_000, 50, 0), &PRICE);
+        ledger.record(
+            "sonnet",
+            RequestPurpose::Turn,
+            TokenUsage {
+                cache_write_tokens: 1_000,
+                ..usage(200, 50, 1_800)
+            },
+            &PRICE,
+        );
+
+        let rate = ledger.totals().usage.cache_hit_rate().unwrap();
+        assert!((rate - 0.45).abs() < 1e-9, "{}", rate);
+    }
+}
@@ -0,0 +1,825 @@
+use crate::agent::attachments::ImageAttachment;
+use crate::agent::backends::{ModelRole, ReplayBackend, reasoning_text};
+use crate::agent::context::MessageRole;
+use crate::agent::events::{AgentEvent, EventSink};
+use crate::agent::manager::{AgentManager, AgentMode, TurnCheckpoint, display_text};
+use crate::agent::usage::UsageLedger;
+use crate::core::checkpoint::CheckpointStore;
+use crate::core::config::{Config, SessionSettings};
+use crate::core::session::{JournalEntry, JournalSender, Session, SessionStore};
+use std::collections::VecDeque;
+use std::path::Path;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::{error, info, trace, warn};
+
+/// Commands sent from the UI to the agent worker
+pub enum AgentCommand {
+    /// Process a user message with the images attached to it
+    Submit {
+        input: String,
+        images: Vec<ImageAttachment>,
+    },
+
+    /// Abort the turn that is currently being processed
+    Cancel,
+
+    /// Resume a turn paused at the tool round limit for up to N more rounds
+    Continue(usize),
+
+    /// End a turn paused at the tool round limit without running its pending tools
+    Stop,
+
+    /// Replace an earlier user message and run the turn again from there
+    EditInput { node: u64, text: String },
+
+    /// Switch to another version of an edited user message
+    SwitchBranch { node: u64, version: usize },
+
+    /// Remove the last turn and restore the files it changed
+    UndoTurn,
+
+    /// Pick the model used for a role by its catalog name
+    SelectModel { role: ModelRole, name: String },
+
+    /// Switch to a profile by name
+    SelectProfile(String),
+
+    /// Switch between ask and agent mode
+    SetMode(AgentMode),
+
+    /// Report the system prompt the next request will be sent with
+    ShowSystemPrompt,
+
+    /// Clear the conversation context and start a new session
+    Clear,
+
+    /// Save the current session along with the journal shown in the UI
+    SaveSession(Vec<JournalEntry>),
+
+    /// Replace the conversation with a saved session (by ID or unique ID prefix)
+    ResumeSession(String),
+
+    /// Change the title of a saved session
+    RenameSession { id: String, title: String },
+
+    /// Save a copy of a session under a new ID
+    DuplicateSession(String),
+
+    /// Delete a saved session other than the current one
+    DeleteSession(String),
+}
+
+/// Handle to the long-lived task that owns the `AgentManager`
+///
+/// The worker initializes the backend and tools once and then processes
+/// commands in order. Submissions that arrive while a turn is running are
+/// queued behind it rather than racing for the agent.
+pub struct AgentWorker {
+    /// Channel for sending commands to the worker
+    commands: UnboundedSender<AgentCommand>,
+
+    /// Channel for receiving events from the worker
+    events: UnboundedReceiver<AgentEvent>,
+}
+
+impl AgentWorker {
+    /// Spawn the worker task on the given runtime
+    pub fn spawn(runtime: &Handle, config: Config) -> Self {
+        let (command_sender, command_receiver) = mpsc::unbounded_channel();
+        let (event_sender, event_receiver) = mpsc::unbounded_channel();
+
+        runtime.spawn(run_worker(config, command_receiver, event_sender));
+
+        Self {
+            commands: command_sender,
+            events: event_receiver,
+        }
+    }
+
+    /// Send a command to the worker, returning false if the worker has stopped
+    pub fn send(&self, command: AgentCommand) -> bool {
+        self.commands.send(command).is_ok()
+    }
+
+    /// Get the next pending event without blocking
+    pub fn try_recv(&mut self) -> Option<AgentEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+/// Main loop of the worker task
+async fn run_worker(
+    config: Config,
+    mut commands: UnboundedReceiver<AgentCommand>,
+    events: UnboundedSender<AgentEvent>,
+) {
+    // Use the configured limits; TOOL_CHAIN_MAX_DEPTH still overrides max_depth,
+    // including every profile's
+    let mut agent_config = config.agent_config();
+    if let Some(max_depth) = std::env::var("TOOL_CHAIN_MAX_DEPTH")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+    {
+        agent_config.tool_chain.max_depth = max_depth;
+        for profile in &mut agent_config.profiles {
+            profile.max_depth = max_depth;
+        }
+    }
+
+    let mut agent_manager = match config.replay_cassette() {
+        Some(path) => {
+            let backend = ReplayBackend::new(path, config.bedrock_config());
+            AgentManager::with_backend(agent_config, Box::new(backend))
+        }
+        None => {
+            let mut agent_manager = AgentManager::with_config(agent_config);
+            agent_manager.set_backend_config(config.bedrock_config());
+            agent_manager
+        }
+    };
+    agent_manager.set_event_sink(EventSink::new(events.clone()));
+
+    // Register the tools enabled by configuration or by any profile
+    for tool in config.enabled_tools() {
+        agent_manager.register_tool(tool);
+    }
+    agent_manager
+        .tool_registry
+        .set_max_execution_time(config.tools.max_execution_ms);
+
+    // Set working directory
+    let current_dir = std::env::current_dir()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_else(|_| ".".to_string());
+    agent_manager.set_working_directory(&current_dir);
+
+    initialize(&mut agent_manager, &events).await;
+
+    // Sessions are saved under the data directory; without one they are not saved
+    let store = match SessionStore::open_default() {
+        Ok(store) => Some(store),
+        Err(e) => {
+            warn!("Sessions will not be saved: {}", e);
+            None
+        }
+    };
+    let mut session = new_session(&agent_manager);
+    let auto_title = config.sessions.auto_title;
+    use_checkpoints(
+        &mut agent_manager,
+        store.as_ref(),
+        &session,
+        &config.sessions,
+    );
+    sessions_changed(&session, &events);
+
+    // Commands received while a turn was running
+    let mut queued = VecDeque::new();
+
+    loop {
+        let command = match queued.pop_front() {
+            Some(command) => command,
+            None => match commands.recv().await {
+                Some(command) => command,
+                None => break,
+            },
+        };
+
+        match command {
+            AgentCommand::Submit { input, images } => {
+                if !agent_manager.is_initialized() && !initialize(&mut agent_manager, &events).await
+                {
+                    continue;
+                }
+
+                // New input abandons a paused turn
+                let cancelled = agent_manager.stop_paused_turn();
+                if cancelled > 0 {
+                    let _ = events.send(AgentEvent::Stopped { cancelled });
+                }
+
+                let request = TurnRequest::Input { input, images };
+                if !run_turn(
+                    &mut agent_manager,
+                    request,
+                    &mut session,
+                    &mut commands,
+                    &mut queued,
+                    &events,
+                )
+                .await
+                {
+                    break;
+                }
+            }
+            AgentCommand::Continue(rounds) => {
+                let request = TurnRequest::Continue(rounds);
+                if !run_turn(
+                    &mut agent_manager,
+                    request,
+                    &mut session,
+                    &mut commands,
+                    &mut queued,
+                    &events,
+                )
+                .await
+                {
+                    break;
+                }
+            }
+            AgentCommand::EditInput { node, text } => {
+                let request = TurnRequest::Edit { node, input: text };
+                if !run_turn(
+                    &mut agent_manager,
+                    request,
+                    &mut session,
+                    &mut commands,
+                    &mut queued,
+                    &events,
+                )
+                .await
+                {
+                    break;
+                }
+            }
+            AgentCommand::SwitchBranch { node, version } => {
+                match agent_manager.switch_branch(node, version) {
+                    Ok(()) => {
+                        let _ = events.send(AgentEvent::BranchSwitched {
+                            journal: branch_journal(&agent_manager),
+                        });
+                        branches_changed(&agent_manager, &events);
+                    }
+                    Err(e) => {
+                        error!("Failed to switch branch: {}", e);
+                        let _ = events.send(AgentEvent::Error(e));
+                    }
+                }
+            }
+            AgentCommand::UndoTurn => match agent_manager.undo_last_turn() {
+                Ok((node, report)) => {
+                    let _ = events.send(AgentEvent::TurnUndone {
+                        node,
+                        restored: report.restored,
+                        failed: report.failed,
+                        evicted: report.evicted,
+                    });
+                    branches_changed(&agent_manager, &events);
+                }
+                Err(e) => {
+                    error!("Failed to undo turn: {}", e);
+                    let _ = events.send(AgentEvent::Error(e));
+                }
+            },
+            AgentCommand::Stop => {
+                let cancelled = agent_manager.stop_paused_turn();
+                let _ = events.send(AgentEvent::Stopped { cancelled });
+            }
+            AgentCommand::Cancel => {
+                trace!("Cancel requested with no turn in progress");
+            }
+            AgentCommand::SelectModel { role, name } => {
+                if let Err(e) = agent_manager.select_model(role, &name) {
+                    let _ = events.send(AgentEvent::ModelUnavailable(e.to_string()));
+                }
+            }
+            AgentCommand::SelectProfile(name) => {
+                if let Err(e) = agent_manager.select_profile(&name) {
+                    let _ = events.send(AgentEvent::ProfileUnavailable(e.to_string()));
+                }
+            }
+            AgentCommand::SetMode(mode) => agent_manager.set_mode(mode),
+            AgentCommand::ShowSystemPrompt => {
+                let _ = events.send(AgentEvent::SystemPrompt(agent_manager.system_prompt()));
+            }
+            AgentCommand::Clear => {
+                agent_manager.context_manager.clear();
+                agent_manager.replace_usage(UsageLedger::default());
+                session = new_session(&agent_manager);
+                use_checkpoints(
+                    &mut agent_manager,
+                    store.as_ref(),
+                    &session,
+                    &config.sessions,
+                );
+                let _ = events.send(AgentEvent::Cleared);
+                branches_changed(&agent_manager, &events);
+                sessions_changed(&session, &events);
+            }
+            AgentCommand::SaveSession(journal) => {
+                if auto_title && session.title.is_none() {
+                    session.title = Some(generate_title(&mut agent_manager, &session).await);
+                }
+                match save_session(&agent_manager, store.as_ref(), &mut session, journal) {
+                    Ok(()) => sessions_changed(&session, &events),
+                    Err(e) => {
+                        error!("Failed to save session: {}", e);
+                        let _ = events.send(AgentEvent::SessionFailed(format!(
+                            "Failed to save session: {}",
+                            e
+                        )));
+                    }
+                }
+            }
+            AgentCommand::ResumeSession(id) => {
+                match resume_session(&mut agent_manager, store.as_ref(), &id, &events) {
+                    Ok(resumed) => {
+                        let _ = events.send(AgentEvent::SessionResumed {
+                            title: resumed.title(),
+                            models: resumed.models.clone(),
+                            working_directory: resumed.working_directory.clone(),
+                            journal: resumed.journal.clone(),
+                            paused_tools: agent_manager.describe_pending_tool_calls(),
+                        });
+                        session = resumed;
+                        use_checkpoints(
+                            &mut agent_manager,
+                            store.as_ref(),
+                            &session,
+                            &config.sessions,
+                        );
+                        sessions_changed(&session, &events);
+                        branches_changed(&agent_manager, &events);
+                    }
+                    Err(e) => {
+                        error!("Failed to resume session {}: {}", id, e);
+                        let _ = events.send(AgentEvent::SessionFailed(format!(
+                            "Failed to resume session: {}",
+                            e
+                        )));
+                    }
+                }
+            }
+            AgentCommand::RenameSession { id, title } => {
+                // The current session may not have been saved yet
+                let result = if id == session.id {
+                    session.title = Some(title.trim().to_string());
+                    match store.as_ref() {
+                        Some(store) if store.is_saved(&id) => store.rename(&id, &title),
+                        _ => Ok(()),
+                    }
+                } else {
+                    with_store(store.as_ref(), |store| store.rename(&id, &title))
+                };
+                report_session_change(result, "rename", &session, &events);
+            }
+            AgentCommand::DuplicateSession(id) => {
+                let result = with_store(store.as_ref(), |store| store.duplicate(&id).map(|_| ()));
+                report_session_change(result, "duplicate", &session, &events);
+            }
+            AgentCommand::DeleteSession(id) => {
+                let result = if id == session.id {
+                    Err(
+                        "the current session cannot be deleted; clear the conversation first"
+                            .to_string(),
+                    )
+                } else {
+                    with_store(store.as_ref(), |store| store.delete(&id))
+                };
+                report_session_change(result, "delete", &session, &events);
+            }
+        }
+    }
+
+    trace!("Agent worker stopped");
+}
+
+/// Kind of turn to run
+enum TurnRequest {
+    /// Start a turn for a user message
+    Input {
+        input: String,
+        images: Vec<ImageAttachment>,
+    },
+
+    /// Resume a paused turn for up to N more tool rounds
+    Continue(usize),
+
+    /// Start a turn for an edited earlier user message
+    Edit { node: u64, input: String },
+}
+
+/// Run a turn while still listening for commands so it can be cancelled
+///
+/// Returns false if the UI has gone away and the worker should stop.
+async fn run_turn(
+    agent_manager: &mut AgentManager,
+    request: TurnRequest,
+    session: &mut Session,
+    commands: &mut UnboundedReceiver<AgentCommand>,
+    queued: &mut VecDeque<AgentCommand>,
+    events: &UnboundedSender<AgentEvent>,
+) -> bool {
+    // Remember where the turn started so a cancelled or failed turn can be removed
+    let checkpoint = agent_manager.checkpoint();
+
+    let outcome = {
+        let turn = async {
+            match &request {
+                TurnRequest::Input { input, images } => {
+                    agent_manager.process_input_with_images(input, images).await
+                }
+                TurnRequest::Continue(rounds) => agent_manager.continue_turn(*rounds).await,
+                TurnRequest::Edit { node, input } => agent_manager.edit_input(*node, input).await,
+            }
+        };
+        tokio::pin!(turn);
+
+        loop {
+            tokio::select! {
+                result = &mut turn => break Some(result),
+                command = commands.recv() => match command {
+                    Some(AgentCommand::Cancel) => break None,
+                    Some(other) => queued.push_back(other),
+                    None => return false,
+                },
+            }
+        }
+    };
+
+    let event = match outcome {
+        Some(Ok(response)) => {
+            session
+                .tool_results
+                .extend(response.tool_results.iter().cloned());
+            session.tokens_used += response.tokens_used;
+            AgentEvent::TurnDone(response)
+        }
+        Some(Err(e)) => {
+            error!("Error processing input: {}", e);
+            discard_turn(agent_manager, checkpoint, events);
+            AgentEvent::Error(e)
+        }
+        None => {
+            info!("Turn cancelled, discarding its context");
+            discard_turn(agent_manager, checkpoint, events);
+            AgentEvent::Cancelled
+        }
+    };
+    // A discarded turn takes its input (or an edit's new branch) out of the
+    // context, so the journal has to follow it
+    if !matches!(event, AgentEvent::TurnDone(_)) {
+        let _ = events.send(AgentEvent::BranchSwitched {
+            journal: branch_journal(agent_manager),
+        });
+    }
+    let sent = events.send(event).is_ok();
+    branches_changed(agent_manager, events);
+    sent
+}
+
+/// Remove a cancelled or failed turn from the context
+///
+/// A continued turn rolls back to its pause, and the calls that were pending
+/// there are then cancelled so no tool_use is left unanswered. When the
+/// turn's input is removed, the files it changed are put back as well.
+fn discard_turn(
+    agent_manager: &mut AgentManager,
+    checkpoint: TurnCheckpoint,
+    events: &UnboundedSender<AgentEvent>,
+) {
+    let started = agent_manager.context_manager.last_input();
+    agent_manager.restore(checkpoint);
+    agent_manager.stop_paused_turn();
+
+    let turn = match started {
+        Some(turn) if agent_manager.context_manager.last_input() != Some(turn) => turn,
+        _ => return,
+    };
+    match agent_manager.restore_files(turn) {
+        Ok(report) => {
+            if !report.restored.is_empty() || !report.failed.is_empty() || report.evicted {
+                let _ = events.send(AgentEvent::DiscardedFilesRestored {
+                    restored: report.restored,
+                    failed: report.failed,
+                    evicted: report.evicted,
+                });
+            }
+        }
+        Err(e) => {
+            error!("Failed to restore the files of the discarded turn: {}", e);
+            let _ = events.send(AgentEvent::Error(e));
+        }
+    }
+}
+
+/// Tell the UI which user messages on the active branch have been edited
+fn branches_changed(agent_manager: &AgentManager, events: &UnboundedSender<AgentEvent>) {
+    let _ = events.send(AgentEvent::BranchesChanged(
+        agent_manager.context_manager.branch_points(),
+    ));
+}
+
+/// Rebuild the journal from the messages on the active branch
+///
+/// Only inputs and replies are kept; the tool progress shown while the turns
+/// ran is summarized as the results that were returned to the model.
+fn branch_journal(agent_manager: &AgentManager) -> Vec<JournalEntry> {
+    let mut journal = Vec::new();
+    for (node, message) in agent_manager.context_manager.tree().entries() {
+        let (sender, content, node) = match message.role {
+            MessageRole::User if message.is_tool_result() => {
+                let count = message
+                    .content
+                    .lines()
+                    .filter(|line| line.trim_start().starts_with('{'))
+                    .count();
+                (
+                    JournalSender::System,
+                    format!("Returned {} tool results to the model", count),
+                    None,
+                )
+            }
+            MessageRole::User => (
+                JournalSender::User,
+                message.content.clone(),
+                Some(node),
+            ),
+            MessageRole::Assistant => {
+                let reasoning = reasoning_text(&message.content);
+                if !reasoning.is_empty() {
+                    journal.push(JournalEntry {
+                        sender: JournalSender::Reasoning,
+                        content: reasoning,
+                        node: None,
+                        images: Vec::new(),
+                    });
+                }
+                let text = display_text(&message.content);
+                if text.is_empty() {
+                    continue;
+                }
+                (JournalSender::Assistant, text, None)
+            }
+            MessageRole::System | MessageRole::Tool => continue,
+        };
+        let images = match message.role {
+            MessageRole::User if !message.is_tool_result() => message
+                .images
+                .iter()
+                .filter_map(|image| image.thumbnail().ok())
+                .collect(),
+            _ => Vec::new(),
+        };
+        journal.push(JournalEntry {
+            sender,
+            content,
+            node,
+            images,
+        });
+    }
+    journal
+}
+
+/// Start a session for a new conversation
+fn new_session(agent_manager: &AgentManager) -> Session {
+    Session::new(
+        agent_manager.models().clone(),
+        agent_manager.working_directory(),
+    )
+}
+
+/// Snapshot files for the session in its checkpoint directory, so its turns can be undone
+fn use_checkpoints(
+    agent_manager: &mut AgentManager,
+    store: Option<&SessionStore>,
+    session: &Session,
+    settings: &SessionSettings,
+) {
+    if let Some(store) = store {
+        let dir = store.checkpoint_dir(&session.id);
+        agent_manager.set_checkpoint_store(CheckpointStore::open(dir, settings));
+    }
+}
+
+/// Tell the UI which session is current and that the saved sessions may have changed
+fn sessions_changed(session: &Session, events: &UnboundedSender<AgentEvent>) {
+    let _ = events.send(AgentEvent::SessionsChanged {
+        current: session.id.clone(),
+    });
+}
+
+/// Run an operation on the session store, failing if there is none
+fn with_store<F>(store: Option<&SessionStore>, operation: F) -> Result<(), String>
+where
+    F: FnOnce(&SessionStore) -> Result<(), String>,
+{
+    store
+        .ok_or_else(|| "no session directory is available".to_string())
+        .and_then(operation)
+}
+
+/// Report the outcome of renaming, duplicating or deleting a session
+fn report_session_change(
+    result: Result<(), String>,
+    action: &str,
+    session: &Session,
+    events: &UnboundedSender<AgentEvent>,
+) {
+    match result {
+        Ok(()) => sessions_changed(session, events),
+        Err(e) => {
+            error!("Failed to {} session: {}", action, e);
+            let _ = events.send(AgentEvent::SessionFailed(format!(
+                "Failed to {} session: {}",
+                action, e
+            )));
+        }
+    }
+}
+
+/// Name a session after its first user message
+///
+/// Falls back to the start of that message if the model cannot be asked, so
+/// a failed request is not repeated on every save.
+async fn generate_title(agent_manager: &mut AgentManager, session: &Session) -> String {
+    let first_input = agent_manager
+        .context_manager
+        .messages()
+        .find(|m| m.role == MessageRole::User)
+        .map(|m| m.content.clone());
+
+    let first_input = match first_input {
+        Some(input) => input,
+        None => return session.title(),
+    };
+    match agent_manager.generate_title(&first_input).await {
+        Ok(title) => {
+            trace!("Generated session title: {}", title);
+            title
+        }
+        Err(e) => {
+            warn!("Could not generate a session title: {}", e);
+            session.title()
+        }
+    }
+}
+
+/// Record the current conversation in the session and write it to disk
+fn save_session(
+    agent_manager: &AgentManager,
+    store: Option<&SessionStore>,
+    session: &mut Session,
+    journal: Vec<JournalEntry>,
+) -> Result<(), String> {
+    let store = store.ok_or_else(|| "no session directory is available".to_string())?;
+
+    session.models = agent_manager.models().clone();
+    session.mode = agent_manager.mode();
+    session.profile = agent_manager.profile_name().map(str::to_string);
+    session.working_directory = agent_manager.working_directory().to_string();
+    session.conversation = agent_manager.context_manager.tree().clone();
+    session.pending_tools = agent_manager.pending_tool_calls().to_vec();
+    session.usage = agent_manager.usage().clone();
+    session.journal = journal;
+
+    store.save(session).map(|_| ())
+}
+
+/// Load a saved session and make its conversation the current one
+///
+/// Models the session names that are no longer in the catalog are reported
+/// and the current ones kept in their place.
+fn resume_session(
+    agent_manager: &mut AgentManager,
+    store: Option<&SessionStore>,
+    id: &str,
+    events: &UnboundedSender<AgentEvent>,
+) -> Result<Session, String> {
+    let store = store.ok_or_else(|| "no session directory is available".to_string())?;
+    let mut session = store.load(id)?;
+
+    agent_manager.resume_conversation(session.conversation.clone(), session.pending_tools.clone());
+    agent_manager.replace_usage(session.usage.clone());
+    // The profile picks a primary model, so it goes first and the session's
+    // own models are restored over it
+    let selected = match &session.profile {
+        Some(profile) => agent_manager.select_profile(profile),
+        None => Ok(()),
+    };
+    if let Err(e) = selected {
+        warn!("Session {} uses an unavailable profile: {}", session.id, e);
+        let _ = events.send(AgentEvent::ProfileUnavailable(e.to_string()));
+    }
+    for role in ModelRole::ALL {
+        let name = session.models.get(role);
+        if name.is_empty() {
+            continue;
+        }
+        if let Err(e) = agent_manager.select_model(role, name) {
+            warn!("Session {} uses an unavailable model: {}", session.id, e);
+            let _ = events.send(AgentEvent::ModelUnavailable(e.to_string()));
+        }
+    }
+    session.models = agent_manager.models().clone();
+    session.profile = agent_manager.profile_name().map(str::to_string);
+    agent_manager.set_mode(session.mode);
+
+    // Keep the current directory if the saved one is gone
+    if Path::new(&session.working_directory).is_dir() {
+        agent_manager.set_working_directory(&session.working_directory);
+    } else {
+        warn!(
+            "Session working directory {} no longer exists, keeping {}",
+            session.working_directory,
+            agent_manager.working_directory()
+        );
+        session.working_directory = agent_manager.working_directory().to_string();
+    }
+
+    info!(
+        "Resumed session {} with {} messages",
+        session.id,
+        session.conversation.len()
+    );
+    Ok(session)
+}
+
+/// Initialize the backend, reporting the outcome to the UI
+async fn initialize(
+    agent_manager: &mut AgentManager,
+    events: &UnboundedSender<AgentEvent>,
+) -> bool {
+    match agent_manager.init().await {
+        Ok(()) => {
+            trace!("Agent manager initialized successfully");
+            let _ = events.send(AgentEvent::Ready);
+            true
+        }
+        Err(e) => {
+            error!("Failed to initialize agent: {}", e);
+            let _ = events.send(AgentEvent::InitFailed(e));
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::backends::{MockBackend, tool_call};
+    use crate::agent::manager::{AgentConfig, ToolChainConfig};
+    use crate::agent::tools::EchoTool;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn cancelled_inputs_leave_the_journal() {
+        let backend = MockBackend::new().respond_with_tools(
+            "Echoing",
+            vec![tool_call("toolu_1", "echo", &[("text", json!("hello"))])],
+        );
+        // The delay before the next round keeps the turn running until it is cancelled
+        let config = AgentConfig {
+            auto_compress_context: false,
+            tool_chain: ToolChainConfig {
+                delay_ms: 60_000,
+                ..ToolChainConfig::default()
+            },
+            ..AgentConfig::default()
+        };
+        let mut agent_manager = AgentManager::with_backend(config, Box::new(backend));
+        agent_manager.register_tool(Box::new(EchoTool));
+        agent_manager.init().await.unwrap();
+        let mut session = new_session(&agent_manager);
+
+        let (command_tx, mut commands) = mpsc::unbounded_channel();
+        let (events, mut event_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let _ = command_tx.send(AgentCommand::Cancel);
+        });
+        let request = TurnRequest::Input {
+            input: "Echo hello".to_string(),
+            images: Vec::new(),
+        };
+        let mut queued = VecDeque::new();
+        assert!(
+            run_turn(
+                &mut agent_manager,
+                request,
+                &mut session,
+                &mut commands,
+                &mut queued,
+                &events,
+            )
+            .await
+        );
+
+        let mut journal = None;
+        let mut cancelled = false;
+        while let Ok(event) = event_rx.try_recv() {
+            match event {
+                AgentEvent::BranchSwitched { journal: entries } => journal = Some(entries),
+                AgentEvent::Cancelled => cancelled = true,
+                _ => {}
+            }
+        }
+        assert!(cancelled);
+        // Only the system prompt is left, and the journal no longer shows the input
+        let tree = agent_manager.context_manager.tree();
+        assert!(tree.messages().all(|m| m.role != MessageRole::User));
+        assert!(journal.unwrap().is_empty());
+    }
+}
@@ -1,22 +1,23 @@
 use bevy::prelude::*;
+use bevy::window::FileDragAndDrop;
 use bevy_egui::egui;
 use bevy_egui::egui::{Align, Frame, Layout};
 
 use crate::agent;
-use crate::agent::backends::Backend; // Import the Backend trait
-use crate::agent::app_recursive_processor::{
-    process_single_tool_round, 
-    process_limited_tool_chain, 
-    process_tool_chain_with_config,
-    ToolChainConfig
-};
-use crate::agent::manager::{AgentConfig, AgentManager, AgentResponse};
+use crate::agent::attachments::{IMAGE_EXTENSIONS, ImageAttachment, Thumbnail};
+use crate::agent::backends::{ModelRole, ModelSelection, StopReason};
+use crate::agent::events::AgentEvent;
+use crate::agent::manager::{AgentMode, TurnStop};
+use crate::agent::message_tree::BranchPoint;
+use crate::agent::usage::{UsageLedger, UsageTotals, format_cost};
+use crate::agent::worker::{AgentCommand, AgentWorker};
 use crate::core;
+use crate::core::config::{Config, DEFAULT_PROFILE, LoadedConfig};
+use crate::core::session::{JournalEntry, JournalSender, SessionStore, SessionSummary};
 use crate::ui;
 use crate::visualization::{self, ToolStatus, VisualizationPlugin, VisualizationState};
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::{error, trace};
+use std::path::PathBuf;
+use tracing::trace;
 
 // Define resources for our application
 #[derive(Resource)]
@@ -24,16 +25,96 @@ pub struct AppState {
     // Input and journal state
     input_text: String,
     journal_messages: Vec<JournalMessage>,
-    tool_id_counter: usize,
 
     // UI state
     show_settings: bool,
     dark_mode: bool,
 
+    // Keybindings loaded from configuration
+    submit_key: KeyChord,
+    toggle_settings_key: KeyChord,
+    toggle_sessions_key: KeyChord,
+
     // Agent state
-    agent_manager: Option<Arc<Mutex<AgentManager>>>,
-    agent_initialized: bool,
+    models: ModelSelection,
+
+    // Catalog models offered in the settings, as (name, display name)
+    model_choices: Vec<(String, String)>,
+
+    // Active profile and the profiles that can be switched to, as (name, description)
+    profile: String,
+    profile_choices: Vec<(String, Option<String>)>,
+
+    // Whether only tools that read may run, shown in the input pane, settings and scene
+    mode: AgentMode,
     processing_input: bool,
+
+    // What the agent is doing right now, shown under the input box
+    agent_status: String,
+
+    // Visualization of the model thinking through the request in flight, and
+    // how many have been shown so each gets its own ID
+    thinking: Option<String>,
+    thinking_count: usize,
+
+    // Tool calls waiting to run while a turn is paused at the round limit
+    paused_tools: Vec<String>,
+
+    // Tokens and cost of the session's requests, and whether their breakdown is shown
+    usage: UsageLedger,
+    show_usage: bool,
+
+    // Effective system prompt, shown in its own window once the worker reports it
+    system_prompt: Option<String>,
+
+    // Number of extra tool rounds granted when a paused turn is continued
+    continue_rounds: usize,
+
+    // Session list state
+    show_sessions: bool,
+    sessions: Vec<SessionSummary>,
+    session_query: String,
+    sessions_stale: bool,
+    current_session: String,
+
+    // Journal index of the submitted input still waiting for its context message ID
+    awaiting_node: Option<usize>,
+
+    // Journal index of the response whose text is still streaming in
+    streaming_text: Option<usize>,
+
+    // Edited inputs on the active branch and which of their versions is shown
+    branch_points: Vec<BranchPoint>,
+
+    // Earlier input being edited (journal index, context message ID and new text)
+    editing_input: Option<(usize, u64, String)>,
+
+    // Session being renamed (ID and the title being edited)
+    renaming_session: Option<(String, String)>,
+
+    // Session waiting for its delete to be confirmed
+    confirm_delete: Option<String>,
+
+    // Images to send with the next input
+    attachments: Vec<ImageAttachment>,
+}
+
+// Something the user asked to do with an input in the journal
+enum JournalAction {
+    Edit(usize, u64, String),
+    CancelEdit,
+    Rerun(usize, u64, String),
+    Switch(u64, usize),
+}
+
+// Something the user asked to do from the session list
+enum SessionAction {
+    New,
+    Resume(String),
+    Rename(String, String),
+    CancelRename,
+    Duplicate(String),
+    Delete(String),
 }
 
 // A message in the journal with styling information
@@ -41,17 +122,141 @@ pub struct JournalMessage {
     content: String,
     sender: MessageSender,
     timestamp: f64,
+    // Context message of a user input, used to edit it and switch between its versions
+    node: Option<u64>,
+    // Images attached to a user input
+    images: Vec<JournalImage>,
+}
+
+// Preview of an image attached to an input, uploaded to egui when first drawn
+pub struct JournalImage {
+    thumbnail: Thumbnail,
+    texture: Option<egui::TextureHandle>,
+}
+
+impl JournalImage {
+    fn new(thumbnail: Thumbnail) -> Self {
+        Self {
+            thumbnail,
+            texture: None,
+        }
+    }
 }
 
 // Who sent the message
 pub enum MessageSender {
     User,
     Assistant,
+    Reasoning, // The assistant's thinking before it answered
     System,
     Tool(String), // Tool type
 }
 
-pub fn run() {
+impl JournalMessage {
+    // Convert to the form saved in a session
+    fn to_entry(&self) -> JournalEntry {
+        let sender = match &self.sender {
+            MessageSender::User => JournalSender::User,
+            MessageSender::Assistant => JournalSender::Assistant,
+            MessageSender::Reasoning => JournalSender::Reasoning,
+            MessageSender::System => JournalSender::System,
+            MessageSender::Tool(tool_type) => JournalSender::Tool(tool_type.clone()),
+        };
+        JournalEntry {
+            sender,
+            content: self.content.clone(),
+            node: self.node,
+            images: self
+                .images
+                .iter()
+                .map(|image| image.thumbnail.clone())
+                .collect(),
+        }
+    }
+
+    // Restore a message saved in a session
+    fn from_entry(entry: JournalEntry, timestamp: f64) -> Self {
+        let sender = match entry.sender {
+            JournalSender::User => MessageSender::User,
+            JournalSender::Assistant => MessageSender::Assistant,
+            JournalSender::Reasoning => MessageSender::Reasoning,
+            JournalSender::System => MessageSender::System,
+            JournalSender::Tool(tool_type) => MessageSender::Tool(tool_type),
+        };
+        Self {
+            content: entry.content,
+            sender,
+            timestamp,
+            node: None,
+            images: entry.images.into_iter().map(JournalImage::new).collect(),
+        }
+    }
+}
+
+// A keyboard shortcut parsed from the keybindings configuration
+#[derive(Clone, Copy)]
+struct KeyChord {
+    key: egui::Key,
+    modifiers: egui::Modifiers,
+}
+
+impl KeyChord {
+    // Parse a chord such as "Enter", "Shift+Enter" or "Ctrl+Comma"
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut modifiers = egui::Modifiers::NONE;
+        let mut key = None;
+
+        for part in text.split('+').map(str::trim) {
+            match part.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => modifiers |= egui::Modifiers::CTRL,
+                "cmd" | "command" => modifiers |= egui::Modifiers::COMMAND,
+                "alt" | "option" => modifiers |= egui::Modifiers::ALT,
+                "shift" => modifiers |= egui::Modifiers::SHIFT,
+                _ => {
+                    if key.is_some() {
+                        return Err(format!("'{}' has more than one key", text));
+                    }
+                    key = Some(
+                        egui::Key::from_name(part)
+                            .ok_or_else(|| format!("unknown key '{}' in '{}'", part, text))?,
+                    );
+                }
+            }
+        }
+
+        key.map(|key| Self { key, modifiers })
+            .ok_or_else(|| format!("'{}' does not name a key", text))
+    }
+
+    // Parse a configured chord, falling back to a default and reporting problems
+    fn from_config(
+        name: &str,
+        text: &str,
+        fallback: &str,
+        journal_messages: &mut Vec<JournalMessage>,
+    ) -> Self {
+        Self::parse(text).unwrap_or_else(|e| {
+            journal_messages.push(JournalMessage {
+                content: format!(
+                    "Configuration: `keybindings.{}`: {}, using '{}'",
+                    name, e, fallback
+                ),
+                sender: MessageSender::System,
+                timestamp: 0.0,
+                node: None,
+                images: Vec::new(),
+            });
+            Self::parse(fallback).expect("default keybinding must parse")
+        })
+    }
+
+    // Whether this chord was pressed during the current frame
+    fn pressed(&self, input: &egui::InputState) -> bool {
+        input.key_pressed(self.key) && input.modifiers.matches_exact(self.modifiers)
+    }
+}
+
+pub fn run(resume_session: Option<String>, models: Vec<(ModelRole, String)>) {
     // Initialize core systems
     core::init();
 
@@ -64,6 +269,26 @@ pub fn run() {
     // Initialize agent and tools
     agent::init();
 
+    // Load user and project configuration
+    let current_dir = std::env::current_dir().unwrap_or_else(|_| ".".into());
+    let loaded_config = Config::load(&current_dir);
+
+    // Start the agent worker on the runtime that is driving main()
+    let worker = AgentWorker::spawn(
+        &tokio::runtime::Handle::current(),
+        loaded_config.config.clone(),
+    );
+
+    // Resume the session picked on the command line once the worker is ready
+    if let Some(id) = resume_session {
+        worker.send(AgentCommand::ResumeSession(id));
+    }
+
+    // Models picked on the command line win over the configuration and the resumed session
+    for (role, name) in models {
+        worker.send(AgentCommand::SelectModel { role, name });
+    }
+
     // Create Bevy app
     App::new()
         // Add default Bevy plugins without the LogPlugin
@@ -86,44 +311,153 @@ pub fn run() {
         // Add visualization plugin
         .add_plugins(VisualizationPlugin)
         // Add app resources
-        .init_resource::<AppState>()
-        .init_resource::<AgentTask>()
+        .insert_resource(AppState::from_config(loaded_config))
+        .insert_resource(AgentTask { worker })
         // Add our systems
         .add_systems(Startup, setup_system)
         // In Bevy 0.15, we need to chain system configurations
         .add_systems(Update, ui_system)
-        .add_systems(Update, initialize_agent_system) // Initialize the agent on startup
-        .add_systems(Update, poll_agent_task) // Poll agent tasks
+        .add_systems(Update, poll_agent_task) // Poll agent worker events
+        .add_systems(Update, attach_dropped_files) // Attach images dropped on the window
         .add_systems(Update, update_camera_viewport) // Update camera viewport to match UI layout
+        .add_systems(Update, show_agent_mode) // Tint the scene in ask mode
         .run();
 }
 
 // Initialize resources
-impl Default for AppState {
-    fn default() -> Self {
+impl AppState {
+    fn from_config(loaded: LoadedConfig) -> Self {
+        let LoadedConfig {
+            config,
+            sources,
+            diagnostics,
+        } = loaded;
+
+        let mut journal_messages = vec![
+            JournalMessage {
+                content: "Welcome to GameCode!".to_string(),
+                sender: MessageSender::System,
+                timestamp: 0.0,
+                node: None,
+                images: Vec::new(),
+            },
+            JournalMessage {
+                content: "Type in the input box below to interact with the AI agent.".to_string(),
+                sender: MessageSender::System,
+                timestamp: 0.0,
+                node: None,
+                images: Vec::new(),
+            },
+        ];
+
+        // Report where settings came from and anything wrong with them
+        for source in &sources {
+            journal_messages.push(JournalMessage {
+                content: format!("Loaded configuration from {}", source.display()),
+                sender: MessageSender::System,
+                timestamp: 0.0,
+                node: None,
+                images: Vec::new(),
+            });
+        }
+        for diagnostic in &diagnostics {
+            journal_messages.push(JournalMessage {
+                content: format!("Configuration: {}", diagnostic),
+                sender: MessageSender::System,
+                timestamp: 0.0,
+                node: None,
+                images: Vec::new(),
+            });
+        }
+
+        let defaults = Config::default();
+        let submit_key = KeyChord::from_config(
+            "submit",
+            &config.keybindings.submit,
+            &defaults.keybindings.submit,
+            &mut journal_messages,
+        );
+        let toggle_settings_key = KeyChord::from_config(
+            "toggle_settings",
+            &config.keybindings.toggle_settings,
+            &defaults.keybindings.toggle_settings,
+            &mut journal_messages,
+        );
+        let toggle_sessions_key = KeyChord::from_config(
+            "toggle_sessions",
+            &config.keybindings.toggle_sessions,
+            &defaults.keybindings.toggle_sessions,
+            &mut journal_messages,
+        );
+
         Self {
             input_text: String::new(),
-            journal_messages: vec![
-                JournalMessage {
-                    content: "Welcome to GameCode!".to_string(),
-                    sender: MessageSender::System,
-                    timestamp: 0.0,
-                },
-                JournalMessage {
-                    content: "Type in the input box below to interact with the AI agent."
-                        .to_string(),
-                    sender: MessageSender::System,
-                    timestamp: 0.0,
-                },
-            ],
-            tool_id_counter: 0,
+            journal_messages,
             show_settings: false,
-            dark_mode: true,
-            agent_manager: None,
-            agent_initialized: false,
+            dark_mode: config.dark_mode(),
+            models: config.model_selection(),
+            model_choices: config
+                .model_catalog()
+                .models()
+                .iter()
+                .map(|model| (model.name.clone(), model.display_name.clone()))
+                .collect(),
+            profile: config
+                .profile
+                .clone()
+                .unwrap_or_else(|| DEFAULT_PROFILE.to_string()),
+            profile_choices: config
+                .profiles()
+                .into_iter()
+                .map(|profile| (profile.name, profile.description))
+                .collect(),
+            mode: AgentMode::default(),
+            submit_key,
+            toggle_settings_key,
+            toggle_sessions_key,
             processing_input: false,
+            agent_status: String::new(),
+            thinking: None,
+            thinking_count: 0,
+            paused_tools: Vec::new(),
+            usage: UsageLedger::default(),
+            show_usage: false,
+            system_prompt: None,
+            continue_rounds: config.tool_chain_config().max_depth,
+            show_sessions: false,
+            sessions: Vec::new(),
+            session_query: String::new(),
+            sessions_stale: true,
+            current_session: String::new(),
+            renaming_session: None,
+            confirm_delete: None,
+            attachments: Vec::new(),
+            awaiting_node: None,
+            streaming_text: None,
+            branch_points: Vec::new(),
+            editing_input: None,
         }
     }
+
+    // Name shown for a catalog model, falling back to the name itself
+    fn model_display_name(&self, name: &str) -> String {
+        self.model_choices
+            .iter()
+            .find(|(model, _)| model.eq_ignore_ascii_case(name))
+            .map(|(_, display_name)| display_name.clone())
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    // Add a message from the application to the journal
+    fn push_system_message(&mut self, content: impl Into<String>, timestamp: f64) {
+        self.journal_messages.push(JournalMessage {
+            content: content.into(),
+            sender: MessageSender::System,
+            timestamp,
+            node: None,
+            images: Vec::new(),
+        });
+    }
 }
 
 // Setup function runs once at startup
@@ -154,138 +488,65 @@ fn setup_system(mut commands: Commands, windows: Query<&Window>) {
     commands.spawn(Camera2d);
 }
 
-// Generate a unique tool ID
-fn generate_tool_id(app_state: &mut AppState) -> String {
-    let id = format!("tool_{}", app_state.tool_id_counter);
-    app_state.tool_id_counter += 1;
-    id
-}
-
-// Tracking struct for demo tools
-#[derive(Clone)]
-struct DemoTool {
-    id: String,
-    tool_type: String,
-    start_time: f64,
-    completed: bool,
-}
-
-// System to initialize the agent
-fn initialize_agent_system(mut app_state: ResMut<AppState>) {
-    // Only initialize once
-    if app_state.agent_initialized {
-        return;
-    }
-
-    // Create agent manager if it doesn't exist
-    if app_state.agent_manager.is_none() {
-        // Create config
-        let config = AgentConfig {
-            use_fast_model_for_context: true,
-            max_context_length: 32000,
-            auto_compress_context: true,
-            aws_region: "us-west-2".to_string(),
-            aws_profile: None,
-        };
-
-        // Create agent manager with config
-        let agent_manager = AgentManager::with_config(config);
-        app_state.agent_manager = Some(Arc::new(Mutex::new(agent_manager)));
-
-        // Add a system message to the journal
-        app_state.journal_messages.push(JournalMessage {
-            content: "AI Assistant initialized and ready".to_string(),
-            sender: MessageSender::System,
-            timestamp: 0.0,
-        });
+// Map a tool name to its visualization type
+fn tool_type_for(tool_name: &str) -> &'static str {
+    match tool_name {
+        "read_file" | "write_file" | "list_directory" | "view_image" => "file",
+        "execute_command" => "process",
+        _ => "process", // Default
     }
-
-    // Mark as initialized - we will do the actual backend initialization in the
-    // first message since it's async and needs to be handled in a task
-    app_state.agent_initialized = true;
 }
 
-// Process agent response and update UI
-fn process_agent_response(
+// Settle the visualization of the model's thinking once its response is in
+fn finish_thinking(
     commands: &mut Commands,
     app_state: &mut AppState,
     vis_state: &mut VisualizationState,
     tool_query: &mut Query<(&mut visualization::ToolEntity, &mut Sprite)>,
-    response: AgentResponse,
-    current_time: f64,
+    status: ToolStatus,
 ) {
-    // Add assistant response to journal
-    if !response.content.is_empty() {
-        app_state.journal_messages.push(JournalMessage {
-            content: response.content,
-            sender: MessageSender::Assistant,
-            timestamp: current_time,
-        });
+    if let Some(id) = app_state.thinking.take() {
+        visualization::update_tool_status_public(commands, vis_state, &id, status, tool_query);
     }
+}
 
-    // Process any tool results
-    for tool_result in &response.tool_results {
-        // Generate a tool ID if needed
-        let tool_id = generate_tool_id(app_state);
-
-        // Map tool name to tool type for visualization (simple mapping for now)
-        let tool_type = match tool_result.tool_name.as_str() {
-            "read_file" => "file",
-            "write_file" => "file",
-            "list_directory" => "file",
-            "execute_command" => "process",
-            _ => "process", // Default
-        };
-
-        // Start a new tool visualization
-        visualization::start_tool_visualization(commands, vis_state, &tool_id, tool_type);
-
-        // Update the status to running
-        visualization::update_tool_status_public(
-            commands,
-            vis_state,
-            &tool_id,
-            ToolStatus::Running,
-            tool_query,
-        );
-
-        // Add a journal message for the tool
-        app_state.journal_messages.push(JournalMessage {
-            content: format!("Started {} tool (ID: {})", tool_type, tool_id),
-            sender: MessageSender::Tool(tool_type.to_string()),
-            timestamp: current_time,
-        });
-
-        // Don't show the raw tool result in the journal, as it will be processed
-        // and displayed in a more user-friendly way in the LLM's follow-up response
+// Journal index of the response still streaming in, if it is still in the journal
+fn streaming_message(app_state: &AppState) -> Option<usize> {
+    app_state.streaming_text.filter(|&index| {
+        matches!(
+            app_state.journal_messages.get(index),
+            Some(JournalMessage {
+                sender: MessageSender::Assistant,
+                ..
+            })
+        )
+    })
+}
 
-        // Instead, add a hidden system message to track the tool execution
-        // Adding a special marker that can be filtered out in the journal display
-        app_state.journal_messages.push(JournalMessage {
-            content: format!("<!-- TOOL_TRACKER: {} -->", tool_id),
-            sender: MessageSender::System,
-            timestamp: current_time,
-        });
+// An error message followed by what the user can do about it, if anything
+fn with_hint(message: String, hint: Option<&str>) -> String {
+    match hint {
+        Some(hint) => format!("{}\n{}", message, hint),
+        None => message,
+    }
+}
 
-        // Update the status to completed
-        visualization::update_tool_status_public(
-            commands,
-            vis_state,
-            &tool_id,
-            ToolStatus::Completed,
-            tool_query,
+// Journal text for files put back after a turn was undone or discarded
+fn restore_summary(heading: &str, restored: &[String], failed: &[String], evicted: bool) -> String {
+    let mut content = heading.to_string();
+    if evicted {
+        content.push_str(
+            "\nIts file snapshots were dropped to stay within the checkpoint size limit, \
+             so the files it changed were not restored",
         );
-
-        // Add a journal message for the completion
-        app_state.journal_messages.push(JournalMessage {
-            content: format!("Completed {} tool (ID: {})", tool_type, tool_id),
-            sender: MessageSender::Tool(tool_type.to_string()),
-            timestamp: current_time,
-        });
     }
-
-    // Reset processing flag
-    app_state.processing_input = false;
+    if !restored.is_empty() {
+        content.push_str(&format!("\nRestored: {}", restored.join(", ")));
+    }
+    if !failed.is_empty() {
+        content.push_str(&format!("\nCould not restore: {}", failed.join(", ")));
+    }
+    content
 }
 
 // System to update the camera viewport to match the visualization area
@@ -297,29 +558,108 @@ fn update_camera_viewport(windows: Query<&Window>, mut cameras: Query<&mut Camer
     }
 }
 
-// Task structure to handle async agent requests
-#[derive(Resource)]
-pub struct AgentTask {
-    // Task status
-    pub processing: bool,
-    // Input that was processed
-    pub input: String,
-    // Channel for receiving responses from the async task
-    pub receiver: Option<tokio::sync::mpsc::Receiver<AgentResponse>>,
+// Tint the scene background while the session is in ask mode
+fn show_agent_mode(app_state: Res<AppState>, mut clear_color: ResMut<ClearColor>) {
+    let color = match app_state.mode {
+        AgentMode::Ask => Color::srgb_u8(24, 40, 64),
+        AgentMode::Agent => ClearColor::default().0,
+    };
+    if clear_color.0 != color {
+        clear_color.0 = color;
+    }
 }
 
-impl Default for AgentTask {
-    fn default() -> Self {
-        Self {
-            processing: false,
-            input: String::new(),
-            receiver: None,
+// Upload the previews of attached images that have not been drawn yet
+fn load_thumbnails(ctx: &egui::Context, messages: &mut [JournalMessage]) {
+    for image in messages.iter_mut().flat_map(|message| &mut message.images) {
+        if image.texture.is_some() {
+            continue;
+        }
+        image.texture = image.thumbnail.pixels().map(|(size, rgba)| {
+            ctx.load_texture(
+                format!("thumbnail-{}", image.thumbnail.name),
+                egui::ColorImage::from_rgba_unmultiplied(size, &rgba),
+                egui::TextureOptions::LINEAR,
+            )
+        });
+    }
+}
+
+// Attach image files to the next input, reporting the ones that cannot be attached
+fn attach_images(app_state: &mut AppState, paths: Vec<PathBuf>, current_time: f64) {
+    for path in paths {
+        match ImageAttachment::load(&path) {
+            Ok(image) => app_state.attachments.push(image),
+            Err(e) => app_state.push_system_message(e.to_string(), current_time),
         }
     }
 }
 
-// System to process agent tasks
-// Checks the channel for responses from the async task
+// Attach images dropped on the window to the next input
+fn attach_dropped_files(
+    mut drops: EventReader<FileDragAndDrop>,
+    mut app_state: ResMut<AppState>,
+    time: Res<Time>,
+) {
+    let paths: Vec<PathBuf> = drops
+        .read()
+        .filter_map(|drop| match drop {
+            FileDragAndDrop::DroppedFile { path_buf, .. } => Some(path_buf.clone()),
+            _ => None,
+        })
+        .collect();
+    if !paths.is_empty() {
+        attach_images(&mut app_state, paths, time.elapsed_secs_f64());
+    }
+}
+
+// Ask the worker to save the session with the current journal
+fn autosave_session(app_state: &AppState, agent_task: &AgentTask) {
+    let journal = app_state
+        .journal_messages
+        .iter()
+        .map(JournalMessage::to_entry)
+        .collect();
+    agent_task.worker.send(AgentCommand::SaveSession(journal));
+}
+
+// Read the saved sessions matching a search, reporting problems in the journal
+fn list_sessions(
+    query: &str,
+    journal_messages: &mut Vec<JournalMessage>,
+    timestamp: f64,
+) -> Vec<SessionSummary> {
+    let sessions = SessionStore::open_default().and_then(|store| {
+        if query.trim().is_empty() {
+            store.list()
+        } else {
+            store.search(query)
+        }
+    });
+    match sessions {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            journal_messages.push(JournalMessage {
+                content: format!("Failed to list sessions: {}", e),
+                sender: MessageSender::System,
+                timestamp,
+                node: None,
+                images: Vec::new(),
+            });
+            Vec::new()
+        }
+    }
+}
+
+// Resource holding the handle to the agent worker task
+#[derive(Resource)]
+pub struct AgentTask {
+    // Worker that owns the agent manager and processes commands in order
+    worker: AgentWorker,
+}
+
+// System to process agent worker events
+// Drains the event channel without blocking the game loop
 fn poll_agent_task(
     mut commands: Commands,
     mut app_state: ResMut<AppState>,
@@ -328,50 +668,498 @@ fn poll_agent_task(
     mut tool_query: Query<(&mut visualization::ToolEntity, &mut Sprite)>,
     time: Res<Time>,
 ) {
-    // If we're not processing or don't have a receiver, nothing to do
-    if !agent_task.processing || agent_task.receiver.is_none() {
-        return;
-    }
-
     let current_time = time.elapsed_secs_f64();
 
-    // Try to get a response from the channel without blocking
-    if let Some(receiver) = &mut agent_task.receiver {
-        // Use try_recv to not block the game loop
-        match receiver.try_recv() {
-            Ok(response) => {
-                trace!("Received response from async task");
-
-                // Process the response
-                process_agent_response(
+    while let Some(event) = agent_task.worker.try_recv() {
+        match event {
+            AgentEvent::Ready => {
+                app_state.push_system_message("AI Assistant initialized and ready", current_time);
+            }
+            AgentEvent::InitFailed(e) => {
+                app_state.push_system_message(
+                    with_hint(
+                        format!("Failed to initialize AI assistant: {}", e),
+                        e.hint(),
+                    ),
+                    current_time,
+                );
+                app_state.processing_input = false;
+            }
+            AgentEvent::InputRecorded { node, images } => {
+                // Remember which context message the submitted input became
+                let index = app_state.awaiting_node.take();
+                if let Some(message) = index.and_then(|i| app_state.journal_messages.get_mut(i)) {
+                    message.node = Some(node);
+                    message.images = images.into_iter().map(JournalImage::new).collect();
+                }
+            }
+            AgentEvent::RequestSent {
+                model,
+                thinking: false,
+            } => {
+                app_state.agent_status = format!("Waiting for {}...", model);
+            }
+            AgentEvent::RequestSent {
+                model,
+                thinking: true,
+            } => {
+                app_state.agent_status = format!("{} is thinking...", model);
+
+                // Retries of the same request keep the one already shown
+                if app_state.thinking.is_none() {
+                    app_state.thinking_count += 1;
+                    let id = format!("thinking-{}", app_state.thinking_count);
+                    visualization::start_tool_visualization(
+                        &mut commands,
+                        &mut vis_state,
+                        &id,
+                        "thinking",
+                    );
+                    app_state.thinking = Some(id);
+
+                    // Leave the remaining events for the next frame, once the entity exists
+                    break;
+                }
+            }
+            AgentEvent::Retrying {
+                attempt,
+                max_retries,
+                delay,
+                error,
+            } => {
+                // Text streamed before the request failed is replaced by the retry's
+                if let Some(index) = streaming_message(&app_state) {
+                    app_state.journal_messages.remove(index);
+                }
+                app_state.streaming_text = None;
+                app_state.agent_status = format!(
+                    "{}; retrying in {}s ({}/{})",
+                    error,
+                    delay.as_secs_f64().ceil(),
+                    attempt,
+                    max_retries
+                );
+            }
+            AgentEvent::ModelFallback { from, to } => {
+                app_state.push_system_message(
+                    format!(
+                        "{} is being throttled; using {} for the rest of this turn",
+                        from, to
+                    ),
+                    current_time,
+                );
+            }
+            AgentEvent::Reasoning(text) => {
+                finish_thinking(
                     &mut commands,
                     &mut app_state,
                     &mut vis_state,
                     &mut tool_query,
-                    response,
-                    current_time,
+                    ToolStatus::Completed,
                 );
-
-                // Reset state
-                agent_task.processing = false;
-                agent_task.receiver = None;
+                let message = JournalMessage {
+                    content: text,
+                    sender: MessageSender::Reasoning,
+                    timestamp: current_time,
+                    node: None,
+                    images: Vec::new(),
+                };
+                // Reasoning comes before the text it led to, even if that streamed in first
+                match streaming_message(&app_state) {
+                    Some(index) => {
+                        app_state.journal_messages.insert(index, message);
+                        app_state.streaming_text = Some(index + 1);
+                    }
+                    None => app_state.journal_messages.push(message),
+                }
             }
-            Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {
-                // No response yet, that's OK
+            AgentEvent::TextDelta(text) => match streaming_message(&app_state) {
+                Some(index) => app_state.journal_messages[index].content.push_str(&text),
+                // Leading whitespace is not worth a message of its own
+                None if text.trim().is_empty() => {}
+                None => {
+                    finish_thinking(
+                        &mut commands,
+                        &mut app_state,
+                        &mut vis_state,
+                        &mut tool_query,
+                        ToolStatus::Completed,
+                    );
+                    app_state.journal_messages.push(JournalMessage {
+                        content: text,
+                        sender: MessageSender::Assistant,
+                        timestamp: current_time,
+                        node: None,
+                        images: Vec::new(),
+                    });
+                    app_state.streaming_text = Some(app_state.journal_messages.len() - 1);
+                }
+            },
+            AgentEvent::TextReceived(text) => {
+                finish_thinking(
+                    &mut commands,
+                    &mut app_state,
+                    &mut vis_state,
+                    &mut tool_query,
+                    ToolStatus::Completed,
+                );
+                // The whole text replaces what streamed in
+                match streaming_message(&app_state) {
+                    Some(index) => app_state.journal_messages[index].content = text,
+                    None => app_state.journal_messages.push(JournalMessage {
+                        content: text,
+                        sender: MessageSender::Assistant,
+                        timestamp: current_time,
+                        node: None,
+                        images: Vec::new(),
+                    }),
+                }
+                app_state.streaming_text = None;
+            }
+            AgentEvent::ResponseStopped(reason) => {
+                let content = match reason {
+                    StopReason::MaxTokens => {
+                        "The response was cut off at the model's output limit".to_string()
+                    }
+                    StopReason::Refusal => {
+                        "The model declined to continue this response".to_string()
+                    }
+                    StopReason::StopSequence(Some(sequence)) => {
+                        format!("The response ended at the stop sequence '{}'", sequence)
+                    }
+                    StopReason::StopSequence(None) => {
+                        "The response ended at a stop sequence".to_string()
+                    }
+                    other => format!("The response stopped early: {:?}", other),
+                };
+                app_state.push_system_message(content, current_time);
             }
-            Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
-                // Channel disconnected, reset state
-                trace!("Channel disconnected, resetting agent task state");
-                agent_task.processing = false;
-                agent_task.receiver = None;
+            AgentEvent::ToolRequested { id, name } => {
+                finish_thinking(
+                    &mut commands,
+                    &mut app_state,
+                    &mut vis_state,
+                    &mut tool_query,
+                    ToolStatus::Completed,
+                );
+                visualization::start_tool_visualization(
+                    &mut commands,
+                    &mut vis_state,
+                    &id,
+                    tool_type_for(&name),
+                );
 
-                // Add error message to journal
+                // The entity is spawned when commands are applied, so leave the
+                // remaining events for the next frame when its status can be updated
+                break;
+            }
+            AgentEvent::ToolStarted { id, name } => {
+                let tool_type = tool_type_for(&name);
+                visualization::update_tool_status_public(
+                    &mut commands,
+                    &mut vis_state,
+                    &id,
+                    ToolStatus::Running,
+                    &mut tool_query,
+                );
+                app_state.agent_status = format!("Running {}...", name);
+                app_state.journal_messages.push(JournalMessage {
+                    content: format!("Started {} tool {} (ID: {})", tool_type, name, id),
+                    sender: MessageSender::Tool(tool_type.to_string()),
+                    timestamp: current_time,
+                    node: None,
+                    images: Vec::new(),
+                });
+            }
+            AgentEvent::ToolFinished { id, name, duration } => {
+                let tool_type = tool_type_for(&name);
+                visualization::update_tool_status_public(
+                    &mut commands,
+                    &mut vis_state,
+                    &id,
+                    ToolStatus::Completed,
+                    &mut tool_query,
+                );
                 app_state.journal_messages.push(JournalMessage {
-                    content: "Lost connection to AI assistant. Please try again.".to_string(),
-                    sender: MessageSender::System,
+                    content: format!(
+                        "Completed {} tool {} in {:.2}s (ID: {})",
+                        tool_type,
+                        name,
+                        duration.as_secs_f64(),
+                        id
+                    ),
+                    sender: MessageSender::Tool(tool_type.to_string()),
                     timestamp: current_time,
+                    node: None,
+                    images: Vec::new(),
                 });
             }
+            AgentEvent::ToolFailed {
+                id,
+                name,
+                error,
+                duration,
+            } => {
+                let tool_type = tool_type_for(&name);
+                visualization::update_tool_status_public(
+                    &mut commands,
+                    &mut vis_state,
+                    &id,
+                    ToolStatus::Failed,
+                    &mut tool_query,
+                );
+                app_state.journal_messages.push(JournalMessage {
+                    content: with_hint(
+                        format!(
+                            "Failed {} tool {} after {:.2}s (ID: {}): {}",
+                            tool_type,
+                            name,
+                            duration.as_secs_f64(),
+                            id,
+                            error
+                        ),
+                        error.hint(),
+                    ),
+                    sender: MessageSender::Tool(tool_type.to_string()),
+                    timestamp: current_time,
+                    node: None,
+                    images: Vec::new(),
+                });
+            }
+            AgentEvent::ChainDepthChanged { depth, max_depth } => {
+                app_state.agent_status = format!("Tool chain depth {}/{}", depth, max_depth);
+            }
+            AgentEvent::TurnDone(response) => {
+                trace!(
+                    "Turn done: {} chars and {} tool results",
+                    response.content.len(),
+                    response.tool_results.len()
+                );
+                let paused = match response.stop {
+                    TurnStop::Complete => None,
+                    TurnStop::RoundLimit { pending } => Some((
+                        format!("Paused after {} tool rounds", response.rounds),
+                        pending,
+                    )),
+                    TurnStop::BudgetLimit { pending, reason } => Some((
+                        format!(
+                            "Paused at the budget limit for {}; continue to allow more",
+                            reason
+                        ),
+                        pending,
+                    )),
+                };
+                if let Some((notice, pending)) = paused {
+                    app_state.push_system_message(
+                        format!(
+                            "{}. Waiting to run:\n{}",
+                            notice,
+                            pending
+                                .iter()
+                                .map(|call| format!("  • {}", call))
+                                .collect::<Vec<_>>()
+                                .join("\n")
+                        ),
+                        current_time,
+                    );
+                    app_state.paused_tools = pending;
+                }
+                finish_thinking(
+                    &mut commands,
+                    &mut app_state,
+                    &mut vis_state,
+                    &mut tool_query,
+                    ToolStatus::Completed,
+                );
+                app_state.agent_status.clear();
+                app_state.processing_input = false;
+                autosave_session(&app_state, &agent_task);
+            }
+            AgentEvent::Error(e) => {
+                app_state.streaming_text = None;
+                finish_thinking(
+                    &mut commands,
+                    &mut app_state,
+                    &mut vis_state,
+                    &mut tool_query,
+                    ToolStatus::Failed,
+                );
+                app_state.push_system_message(
+                    with_hint(format!("Error processing request: {}", e), e.hint()),
+                    current_time,
+                );
+                app_state.agent_status.clear();
+                app_state.paused_tools.clear();
+                app_state.processing_input = false;
+            }
+            AgentEvent::Stopped { cancelled } => {
+                app_state.push_system_message(
+                    format!(
+                        "Stopped the paused turn; cancelled {} pending tool calls",
+                        cancelled
+                    ),
+                    current_time,
+                );
+                app_state.paused_tools.clear();
+                autosave_session(&app_state, &agent_task);
+            }
+            AgentEvent::Cancelled => {
+                app_state.streaming_text = None;
+                app_state.push_system_message("Request cancelled", current_time);
+                app_state.agent_status.clear();
+                app_state.paused_tools.clear();
+                app_state.processing_input = false;
+            }
+            AgentEvent::ModelsChanged(models) => {
+                // Report only the roles whose model changed
+                for role in ModelRole::ALL {
+                    if models.get(role) != app_state.models.get(role) {
+                        let content = format!(
+                            "{} model: {}",
+                            role.label(),
+                            app_state.model_display_name(models.get(role))
+                        );
+                        app_state.push_system_message(content, current_time);
+                    }
+                }
+                app_state.models = models;
+            }
+            AgentEvent::ModeChanged(mode) => {
+                if mode != app_state.mode {
+                    let content = match mode {
+                        AgentMode::Ask => {
+                            "Ask mode: files will not be changed and commands will not run"
+                        }
+                        AgentMode::Agent => "Agent mode: tools may change files and run commands",
+                    };
+                    app_state.push_system_message(content, current_time);
+                }
+                app_state.mode = mode;
+            }
+            AgentEvent::ProfileChanged(name) => {
+                if !name.eq_ignore_ascii_case(&app_state.profile) {
+                    app_state.push_system_message(format!("Profile: {}", name), current_time);
+                }
+                app_state.profile = name;
+            }
+            AgentEvent::ModelUnavailable(message) | AgentEvent::ProfileUnavailable(message) => {
+                app_state.push_system_message(message, current_time);
+            }
+            AgentEvent::SystemPrompt(prompt) => {
+                app_state.system_prompt = Some(prompt);
+            }
+            AgentEvent::Cleared => {
+                app_state.push_system_message("Conversation cleared", current_time);
+            }
+            AgentEvent::SessionResumed {
+                title,
+                models,
+                working_directory,
+                journal,
+                paused_tools,
+            } => {
+                app_state.journal_messages = journal
+                    .into_iter()
+                    .map(|entry| JournalMessage::from_entry(entry, current_time))
+                    .collect();
+                app_state.push_system_message(
+                    format!(
+                        "Resumed session \"{}\" (working directory: {})",
+                        title, working_directory
+                    ),
+                    current_time,
+                );
+                app_state.models = models;
+                app_state.paused_tools = paused_tools;
+                app_state.awaiting_node = None;
+                app_state.editing_input = None;
+            }
+            AgentEvent::BranchesChanged(points) => {
+                app_state.branch_points = points;
+            }
+            AgentEvent::BranchSwitched { journal } => {
+                app_state.journal_messages = journal
+                    .into_iter()
+                    .map(|entry| JournalMessage::from_entry(entry, current_time))
+                    .collect();
+                app_state
+                    .push_system_message("Showing the conversation's current branch", current_time);
+                app_state.editing_input = None;
+                app_state.paused_tools.clear();
+            }
+            AgentEvent::ContextCompacted {
+                messages,
+                tokens_saved,
+            } => {
+                app_state.push_system_message(
+                    format!(
+                        "Summarized {} earlier messages to keep the context small (about {} tokens saved)",
+                        messages, tokens_saved
+                    ),
+                    current_time,
+                );
+            }
+            AgentEvent::ContextCompactionFailed(message) => {
+                app_state.push_system_message(
+                    format!("Could not summarize earlier messages: {}", message),
+                    current_time,
+                );
+            }
+            AgentEvent::UsageChanged(usage) => {
+                app_state.usage = usage;
+            }
+            AgentEvent::ImageSkipped(warning) => {
+                app_state
+                    .push_system_message(format!("Image not attached: {}", warning), current_time);
+            }
+            AgentEvent::BudgetWarning(warning) => {
+                app_state.push_system_message(format!("Budget warning: {}", warning), current_time);
+            }
+            AgentEvent::TurnUndone {
+                node,
+                restored,
+                failed,
+                evicted,
+            } => {
+                // Drop the turn from the journal, starting at its input
+                if let Some(index) = app_state
+                    .journal_messages
+                    .iter()
+                    .position(|message| message.node == Some(node))
+                {
+                    app_state.journal_messages.truncate(index);
+                }
+
+                app_state.push_system_message(
+                    restore_summary("Undid the last turn", &restored, &failed, evicted),
+                    current_time,
+                );
+                app_state.paused_tools.clear();
+                app_state.editing_input = None;
+                autosave_session(&app_state, &agent_task);
+            }
+            AgentEvent::DiscardedFilesRestored {
+                restored,
+                failed,
+                evicted,
+            } => {
+                app_state.push_system_message(
+                    restore_summary(
+                        "Discarded the turn's file changes",
+                        &restored,
+                        &failed,
+                        evicted,
+                    ),
+                    current_time,
+                );
+            }
+            AgentEvent::SessionsChanged { current } => {
+                app_state.current_session = current;
+                app_state.sessions_stale = true;
+            }
+            AgentEvent::SessionFailed(e) => {
+                app_state.push_system_message(e, current_time);
+            }
         }
     }
 }
@@ -381,19 +1169,28 @@ fn ui_system(
     mut contexts: bevy_egui::EguiContexts,
     mut app_state: ResMut<AppState>,
     time: Res<Time>,
-    mut commands: Commands,
-    mut vis_state: ResMut<VisualizationState>,
-    mut tool_query: Query<(&mut visualization::ToolEntity, &mut Sprite)>,
-    mut agent_task: ResMut<AgentTask>,
+    agent_task: Res<AgentTask>,
 ) {
     let ctx = contexts.ctx_mut();
     let current_time = time.elapsed_secs_f64();
 
     // Apply theme
     if app_state.dark_mode {
-        let mut visuals = ctx.style().visuals.clone();
-        visuals.dark_mode = true;
-        ctx.set_visuals(visuals);
+        ctx.set_visuals(egui::Visuals::dark());
+    } else {
+        ctx.set_visuals(egui::Visuals::light());
+    }
+
+    // Keyboard shortcut for the settings panel
+    let toggle_settings_key = app_state.toggle_settings_key;
+    if ctx.input(|i| toggle_settings_key.pressed(i)) {
+        app_state.show_settings = !app_state.show_settings;
+    }
+
+    // Keyboard shortcut for the session list
+    let toggle_sessions_key = app_state.toggle_sessions_key;
+    if ctx.input(|i| toggle_sessions_key.pressed(i)) {
+        app_state.show_sessions = !app_state.show_sessions;
     }
 
     // Calculate screen divisions (25% for visualization, 50% for journal, 25% for input)
@@ -402,6 +1199,9 @@ fn ui_system(
     let journal_height = available_rect.height() * 0.5;
     let input_height = available_rect.height() * 0.25;
 
+    // The session list takes a column on the left of the journal and input panes
+    let sidebar_width = if app_state.show_sessions { 300.0 } else { 0.0 };
+
     // Top pane - Visualization (handled by Bevy rendering)
     // Use the simplest approach - just a Window with an empty frame
     egui::Window::new("visualization_window")
@@ -440,15 +1240,72 @@ fn ui_system(
                 ui.heading("Display Settings");
                 ui.checkbox(&mut app_state.dark_mode, "Dark Mode");
 
+                ui.separator();
+                ui.heading("Agent");
+                ui.horizontal(|ui| {
+                    ui.label("Mode");
+                    for mode in AgentMode::ALL {
+                        let selected = app_state.mode == mode;
+                        if ui.radio(selected, mode.label()).clicked() && !selected {
+                            agent_task.worker.send(AgentCommand::SetMode(mode));
+                        }
+                    }
+                })
+                .response
+                .on_hover_text("Ask mode only runs tools that read; agent mode runs every tool");
+                for role in ModelRole::ALL {
+                    let current_model = app_state.models.get(role).to_string();
+                    egui::ComboBox::from_label(format!("{} Model", role.label()))
+                        .selected_text(app_state.model_display_name(&current_model))
+                        .show_ui(ui, |ui| {
+                            for (name, display_name) in &app_state.model_choices {
+                                let selected = current_model.eq_ignore_ascii_case(name);
+                                if ui.selectable_label(selected, display_name).clicked()
+                                    && !selected
+                                {
+                                    agent_task.worker.send(AgentCommand::SelectModel {
+                                        role,
+                                        name: name.clone(),
+                                    });
+                                }
+                            }
+                        });
+                }
+                let idle = !app_state.processing_input;
+                if ui
+                    .add_enabled(idle, egui::Button::new("Undo Last Turn"))
+                    .on_hover_text("Remove the last turn and restore the files it changed")
+                    .clicked()
+                {
+                    agent_task.worker.send(AgentCommand::UndoTurn);
+                }
+                if ui.button("Clear Conversation").clicked() {
+                    app_state.journal_messages.clear();
+                    app_state.awaiting_node = None;
+                    app_state.editing_input = None;
+                    agent_task.worker.send(AgentCommand::Clear);
+                }
+
+                ui.separator();
+                ui.heading("Sessions");
+                ui.checkbox(&mut app_state.show_sessions, "Show Session List");
+                ui.checkbox(&mut app_state.show_usage, "Show Usage and Cost");
+                if ui
+                    .button("Show System Prompt")
+                    .on_hover_text(
+                        "The prompt the next request is sent with, project instructions included",
+                    )
+                    .clicked()
+                {
+                    agent_task.worker.send(AgentCommand::ShowSystemPrompt);
+                }
+
                 ui.separator();
                 ui.heading("Tool Visualization");
                 if ui.button("Test Agent").clicked() {
                     // Add a test message
-                    app_state.journal_messages.push(JournalMessage {
-                        content: "Test agent functionality".to_string(),
-                        sender: MessageSender::System,
-                        timestamp: time.elapsed_secs_f64(),
-                    });
+                    app_state
+                        .push_system_message("Test agent functionality", time.elapsed_secs_f64());
                 }
 
                 ui.separator();
@@ -458,13 +1315,275 @@ fn ui_system(
             });
     }
 
+    // Breakdown of the session's tokens and cost if shown
+    if app_state.show_usage {
+        egui::Window::new("Usage")
+            .resizable(true)
+            .default_size([520.0, 300.0])
+            .show(ctx, |ui| {
+                let usage = &app_state.usage;
+                let totals = usage.totals();
+                ui.label(format!(
+                    "{} requests, {} tokens, {} this session ({}s of agent time)",
+                    totals.requests,
+                    totals.usage.total(),
+                    format_cost(totals.cost),
+                    usage.working_time().as_secs()
+                ));
+                ui.label(format!(
+                    "{} of prompt tokens read from the cache",
+                    format_hit_rate(totals.usage.cache_hit_rate())
+                ));
+
+                ui.separator();
+                ui.heading("By model");
+                let rows = usage
+                    .by_model()
+                    .into_iter()
+                    .map(|(model, totals)| (app_state.model_display_name(&model), totals))
+                    .collect();
+                usage_grid(ui, "usage_by_model", "Model", rows);
+
+                ui.separator();
+                ui.heading("By purpose");
+                let rows = usage
+                    .by_purpose()
+                    .into_iter()
+                    .map(|(purpose, totals)| (purpose.label().to_string(), totals))
+                    .collect();
+                usage_grid(ui, "usage_by_purpose", "Purpose", rows);
+
+                ui.separator();
+                egui::CollapsingHeader::new(format!("Requests ({})", usage.requests().len())).show(
+                    ui,
+                    |ui| {
+                        egui::ScrollArea::vertical()
+                            .max_height(200.0)
+                            .show(ui, |ui| {
+                                let rows = usage
+                                    .requests()
+                                    .iter()
+                                    .enumerate()
+                                    .map(|(index, request)| {
+                                        let label = format!(
+                                            "{}. {} ({})",
+                                            index + 1,
+                                            app_state.model_display_name(&request.model),
+                                            request.purpose.label().to_lowercase()
+                                        );
+                                        let totals = UsageTotals {
+                                            requests: 1,
+                                            usage: request.usage,
+                                            cost: request.cost,
+                                        };
+                                        (label, totals)
+                                    })
+                                    .collect();
+                                usage_grid(ui, "usage_by_request", "Request", rows);
+                            });
+                    },
+                );
+
+                ui.separator();
+                if ui.button("Close").clicked() {
+                    app_state.show_usage = false;
+                }
+            });
+    }
+
+    // The effective system prompt if asked for
+    let mut close_prompt = false;
+    if let Some(prompt) = &app_state.system_prompt {
+        egui::Window::new("System Prompt")
+            .resizable(true)
+            .default_size([560.0, 360.0])
+            .show(ctx, |ui| {
+                egui::ScrollArea::vertical()
+                    .max_height(400.0)
+                    .show(ui, |ui| {
+                        ui.add(egui::Label::new(egui::RichText::new(prompt).monospace()).wrap());
+                    });
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.button("Copy").clicked() {
+                        ui.ctx().copy_text(prompt.clone());
+                    }
+                    if ui.button("Close").clicked() {
+                        close_prompt = true;
+                    }
+                });
+            });
+    }
+    if close_prompt {
+        app_state.system_prompt = None;
+    }
+
+    // Left pane - Session list
+    if app_state.show_sessions {
+        let state = &mut *app_state;
+
+        // Reload the list when it changed on disk or the search changed
+        if state.sessions_stale {
+            state.sessions = list_sessions(
+                &state.session_query,
+                &mut state.journal_messages,
+                current_time,
+            );
+            state.sessions_stale = false;
+        }
+
+        let mut action = None;
+        egui::Window::new("Sessions")
+            .frame(Frame::NONE.stroke(egui::Stroke::new(1.0, egui::Color32::from_gray(80))))
+            .title_bar(false)
+            .resizable(false)
+            .fixed_rect(egui::Rect::from_min_max(
+                egui::pos2(0.0, visualization_height),
+                egui::pos2(sidebar_width, available_rect.height()),
+            ))
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.heading("Sessions");
+                    if ui.button("New").clicked() {
+                        action = Some(SessionAction::New);
+                    }
+                });
+
+                // Searches titles and full transcripts
+                let search = ui.add(
+                    egui::TextEdit::singleline(&mut state.session_query)
+                        .hint_text("Search sessions...")
+                        .desired_width(f32::INFINITY),
+                );
+                if search.changed() {
+                    state.sessions_stale = true;
+                }
+                ui.separator();
+
+                egui::ScrollArea::vertical()
+                    .auto_shrink([false, false])
+                    .show(ui, |ui| {
+                        if state.sessions.is_empty() {
+                            if state.session_query.trim().is_empty() {
+                                ui.label("No saved sessions yet");
+                            } else {
+                                ui.label("No sessions match");
+                            }
+                        }
+
+                        for summary in &state.sessions {
+                            let current = summary.id == state.current_session;
+
+                            // Title, or an editor while the session is being renamed
+                            match &mut state.renaming_session {
+                                Some((id, title)) if *id == summary.id => {
+                                    let edit = ui.text_edit_singleline(title);
+                                    let submitted = edit.lost_focus()
+                                        && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                                    ui.horizontal(|ui| {
+                                        if ui.button("Save").clicked() || submitted {
+                                            action = Some(SessionAction::Rename(
+                                                id.clone(),
+                                                title.clone(),
+                                            ));
+                                        }
+                                        if ui.button("Cancel").clicked() {
+                                            action = Some(SessionAction::CancelRename);
+                                        }
+                                    });
+                                }
+                                _ => {
+                                    let title = egui::RichText::new(&summary.title);
+                                    if current {
+                                        ui.label(title.strong());
+                                    } else {
+                                        ui.label(title);
+                                    }
+                                }
+                            }
+
+                            ui.small(format!(
+                                "{} · {} · {} tokens",
+                                summary.date(),
+                                summary.model,
+                                summary.tokens_used
+                            ));
+                            ui.small(&summary.working_directory);
+                            if let Some(snippet) = &summary.snippet {
+                                ui.small(egui::RichText::new(snippet).italics());
+                            }
+
+                            ui.horizontal(|ui| {
+                                // Resuming replaces the conversation, so wait for the running turn
+                                if ui
+                                    .add_enabled(
+                                        !current && !state.processing_input,
+                                        egui::Button::new("Resume"),
+                                    )
+                                    .clicked()
+                                {
+                                    action = Some(SessionAction::Resume(summary.id.clone()));
+                                }
+                                if ui.button("Rename").clicked() {
+                                    state.renaming_session =
+                                        Some((summary.id.clone(), summary.title.clone()));
+                                }
+                                if ui.button("Duplicate").clicked() {
+                                    action = Some(SessionAction::Duplicate(summary.id.clone()));
+                                }
+                                if state.confirm_delete.as_deref() == Some(summary.id.as_str()) {
+                                    if ui.button("Confirm Delete").clicked() {
+                                        action = Some(SessionAction::Delete(summary.id.clone()));
+                                    }
+                                } else if ui
+                                    .add_enabled(!current, egui::Button::new("Delete"))
+                                    .clicked()
+                                {
+                                    state.confirm_delete = Some(summary.id.clone());
+                                }
+                            });
+                            ui.separator();
+                        }
+                    });
+            });
+
+        match action {
+            Some(SessionAction::New) => {
+                state.journal_messages.clear();
+                state.awaiting_node = None;
+                state.editing_input = None;
+                agent_task.worker.send(AgentCommand::Clear);
+            }
+            Some(SessionAction::Resume(id)) => {
+                agent_task.worker.send(AgentCommand::ResumeSession(id));
+            }
+            Some(SessionAction::Rename(id, title)) => {
+                state.renaming_session = None;
+                agent_task
+                    .worker
+                    .send(AgentCommand::RenameSession { id, title });
+            }
+            Some(SessionAction::CancelRename) => {
+                state.renaming_session = None;
+            }
+            Some(SessionAction::Duplicate(id)) => {
+                agent_task.worker.send(AgentCommand::DuplicateSession(id));
+            }
+            Some(SessionAction::Delete(id)) => {
+                state.confirm_delete = None;
+                agent_task.worker.send(AgentCommand::DeleteSession(id));
+            }
+            None => {}
+        }
+    }
+
     // Middle pane - Journal
     egui::Window::new("Journal")
         .frame(Frame::NONE.stroke(egui::Stroke::new(1.0, egui::Color32::from_gray(80))))
         .title_bar(false) // Remove title bar for consistency with input pane
         .resizable(false)
         .fixed_rect(egui::Rect::from_min_max(
-            egui::pos2(0.0, visualization_height),
+            egui::pos2(sidebar_width, visualization_height),
             egui::pos2(
                 available_rect.width(),
                 visualization_height + journal_height,
@@ -472,7 +1591,7 @@ fn ui_system(
         ))
         .show(ctx, |ui| {
             // Calculate exact dimensions we want for the journal
-            let journal_width = available_rect.width() - 20.0; // Full window width minus small margin
+            let journal_width = available_rect.width() - sidebar_width - 20.0; // Pane width minus small margin
 
             // Use a vertical layout for the journal section
             ui.with_layout(Layout::top_down(Align::LEFT), |ui| {
@@ -504,7 +1623,12 @@ fn ui_system(
                             })
                             .show(ui, |ui| {
                                 ui.with_layout(Layout::top_down(Align::LEFT), |ui| {
-                                    for message in &app_state.journal_messages {
+                                    let state = &mut *app_state;
+                                    load_thumbnails(ui.ctx(), &mut state.journal_messages);
+                                    let mut journal_action = None;
+                                    for (index, message) in
+                                        state.journal_messages.iter().enumerate()
+                                    {
                                         // Skip hidden system messages (tool trackers)
                                         if let MessageSender::System = &message.sender {
                                             if message.content.contains("<!-- TOOL_TRACKER:") {
@@ -518,6 +1642,9 @@ fn ui_system(
                                             MessageSender::Assistant => {
                                                 (egui::Color32::from_rgb(100, 200, 255), "🤖 ")
                                             }
+                                            MessageSender::Reasoning => {
+                                                (egui::Color32::from_rgb(180, 150, 255), "💭 ")
+                                            }
                                             MessageSender::System => (egui::Color32::GRAY, "📋 "),
                                             MessageSender::Tool(tool_type) => {
                                                 let color = match tool_type.as_str() {
@@ -539,15 +1666,208 @@ fn ui_system(
                                             }
                                         };
 
-                                        // Draw the message with styling
-                                        ui.horizontal(|ui| {
-                                            let formatted_text =
-                                                format!("{}{}", prefix, message.content);
-                                            ui.colored_label(text_color, formatted_text);
-                                        });
+                                        match &mut state.editing_input {
+                                            // Editor for an earlier input being changed
+                                            Some((edit_index, node, text))
+                                                if *edit_index == index =>
+                                            {
+                                                ui.add(
+                                                    egui::TextEdit::multiline(text)
+                                                        .desired_width(f32::INFINITY)
+                                                        .desired_rows(2),
+                                                );
+                                                ui.horizontal(|ui| {
+                                                    let can_rerun = !state.processing_input
+                                                        && !text.trim().is_empty();
+                                                    if ui
+                                                        .add_enabled(
+                                                            can_rerun,
+                                                            egui::Button::new("Re-run from here"),
+                                                        )
+                                                        .clicked()
+                                                    {
+                                                        journal_action = Some(JournalAction::Rerun(
+                                                            index,
+                                                            *node,
+                                                            text.clone(),
+                                                        ));
+                                                    }
+                                                    if ui.button("Cancel").clicked() {
+                                                        journal_action =
+                                                            Some(JournalAction::CancelEdit);
+                                                    }
+                                                });
+                                            }
+                                            // Reasoning stays folded away unless opened
+                                            _ if matches!(
+                                                message.sender,
+                                                MessageSender::Reasoning
+                                            ) =>
+                                            {
+                                                egui::CollapsingHeader::new(format!(
+                                                    "{}Reasoning",
+                                                    prefix
+                                                ))
+                                                .id_salt(("reasoning", index))
+                                                .default_open(false)
+                                                .show(ui, |ui| {
+                                                    ui.colored_label(text_color, &message.content);
+                                                });
+                                            }
+                                            // Draw the message with styling
+                                            _ => {
+                                                ui.horizontal(|ui| {
+                                                    let formatted_text =
+                                                        format!("{}{}", prefix, message.content);
+                                                    ui.colored_label(text_color, formatted_text);
+
+                                                    // Inputs can be edited to fork the conversation
+                                                    if let Some(node) = message.node {
+                                                        let idle = !state.processing_input;
+                                                        if ui
+                                                            .add_enabled(
+                                                                idle,
+                                                                egui::Button::new("✏").small(),
+                                                            )
+                                                            .on_hover_text(
+                                                                "Edit and re-run from here",
+                                                            )
+                                                            .clicked()
+                                                        {
+                                                            journal_action =
+                                                                Some(JournalAction::Edit(
+                                                                    index,
+                                                                    node,
+                                                                    message.content.clone(),
+                                                                ));
+                                                        }
+
+                                                        // Step between versions of an edited input
+                                                        if let Some(point) = state
+                                                            .branch_points
+                                                            .iter()
+                                                            .find(|point| point.node == node)
+                                                        {
+                                                            if ui
+                                                                .add_enabled(
+                                                                    idle && point.position > 0,
+                                                                    egui::Button::new("◀").small(),
+                                                                )
+                                                                .clicked()
+                                                            {
+                                                                journal_action =
+                                                                    Some(JournalAction::Switch(
+                                                                        node,
+                                                                        point.position - 1,
+                                                                    ));
+                                                            }
+                                                            ui.small(format!(
+                                                                "{}/{}",
+                                                                point.position + 1,
+                                                                point.count
+                                                            ));
+                                                            if ui
+                                                                .add_enabled(
+                                                                    idle && point.position + 1
+                                                                        < point.count,
+                                                                    egui::Button::new("▶").small(),
+                                                                )
+                                                                .clicked()
+                                                            {
+                                                                journal_action =
+                                                                    Some(JournalAction::Switch(
+                                                                        node,
+                                                                        point.position + 1,
+                                                                    ));
+                                                            }
+                                                        }
+                                                    }
+                                                });
+                                            }
+                                        }
+
+                                        // Previews of the images attached to an input
+                                        if !message.images.is_empty() {
+                                            ui.horizontal(|ui| {
+                                                for image in &message.images {
+                                                    if let Some(texture) = &image.texture {
+                                                        ui.add(
+                                                            egui::Image::new(texture)
+                                                                .max_height(96.0),
+                                                        )
+                                                        .on_hover_text(&image.thumbnail.name);
+                                                    }
+                                                }
+                                            });
+                                        }
                                         // Add some space between messages instead of a separator
                                         ui.add_space(4.0);
                                     }
+
+                                    match journal_action {
+                                        Some(JournalAction::Edit(index, node, text)) => {
+                                            state.editing_input = Some((index, node, text));
+                                        }
+                                        Some(JournalAction::CancelEdit) => {
+                                            state.editing_input = None;
+                                        }
+                                        Some(JournalAction::Rerun(index, node, text)) => {
+                                            let command = AgentCommand::EditInput {
+                                                node,
+                                                text: text.clone(),
+                                            };
+                                            if agent_task.worker.send(command) {
+                                                // The old branch stays in the context; the
+                                                // journal follows the new one
+                                                state.journal_messages.truncate(index);
+                                                state.journal_messages.push(JournalMessage {
+                                                    content: text,
+                                                    sender: MessageSender::User,
+                                                    timestamp: current_time,
+                                                    node: None,
+                                                    images: Vec::new(),
+                                                });
+                                                state.awaiting_node = Some(index);
+                                                state.editing_input = None;
+                                                state.paused_tools.clear();
+                                                state.processing_input = true;
+                                            }
+                                        }
+                                        Some(JournalAction::Switch(node, version)) => {
+                                            agent_task
+                                                .worker
+                                                .send(AgentCommand::SwitchBranch { node, version });
+                                        }
+                                        None => {}
+                                    }
+
+                                    // Offer to resume a turn paused at the tool round limit
+                                    if !app_state.paused_tools.is_empty() {
+                                        ui.horizontal(|ui| {
+                                            let rounds = app_state.continue_rounds;
+                                            if ui
+                                                .button(format!("Continue {} more rounds", rounds))
+                                                .clicked()
+                                                && agent_task
+                                                    .worker
+                                                    .send(AgentCommand::Continue(rounds))
+                                            {
+                                                app_state.paused_tools.clear();
+                                                app_state.processing_input = true;
+                                                app_state.push_system_message(
+                                                    format!(
+                                                        "Continuing for up to {} more tool rounds...",
+                                                        rounds
+                                                    ),
+                                                    current_time,
+                                                );
+                                            }
+                                            if ui.button("Stop").clicked() {
+                                                agent_task.worker.send(AgentCommand::Stop);
+                                                app_state.paused_tools.clear();
+                                            }
+                                        });
+                                    }
                                 });
                             });
                     });
@@ -560,7 +1880,7 @@ fn ui_system(
         .title_bar(false) // Remove the title bar
         .resizable(false)
         .fixed_rect(egui::Rect::from_min_max(
-            egui::pos2(0.0, visualization_height + journal_height),
+            egui::pos2(sidebar_width, visualization_height + journal_height),
             egui::pos2(
                 available_rect.width(),
                 visualization_height + journal_height + input_height,
@@ -579,6 +1899,12 @@ fn ui_system(
                     // Calculate available width - use full width
                     let available_width = ui.available_width();
 
+                    // Frame background, tinted blue in ask mode
+                    let fill = match app_state.mode {
+                        AgentMode::Ask => egui::Color32::from_rgb(24, 32, 48),
+                        AgentMode::Agent => egui::Color32::from_rgb(30, 30, 30), // Dark background
+                    };
+
                     // Create a styled text editor with dark background
                     let text_edit = egui::TextEdit::multiline(&mut app_state.input_text)
                         .desired_width(available_width)
@@ -594,7 +1920,7 @@ fn ui_system(
 
                     // Add with custom background color frame
                     let frame = egui::Frame::NONE
-                        .fill(egui::Color32::from_rgb(30, 30, 30)) // Dark background
+                        .fill(fill)
                         .inner_margin(egui::Margin::same(8))
                         .corner_radius(egui::CornerRadius::same(4));
 
@@ -607,209 +1933,66 @@ fn ui_system(
                     }
 
                     // Handle key events:
-                    // - Enter: Submit the message
+                    // - Submit chord (Enter by default): Submit the message
                     // - Shift+Enter: Add a new line (handled automatically by TextEdit)
-                    if response.has_focus()
-                        && ui.input(|i| {
-                            i.key_pressed(egui::Key::Enter) && !i.modifiers.shift
-                            // Not holding shift
-                        })
-                    {
+                    let submit_key = app_state.submit_key;
+                    if response.has_focus() && ui.input(|i| submit_key.pressed(i)) {
                         let input_text = app_state.input_text.clone();
-                        if !input_text.is_empty() {
+
+                        // `/profile` lists the profiles and `/profile <name>` switches to one
+                        if let Some(name) = profile_command(&input_text) {
+                            run_profile_command(
+                                &mut app_state,
+                                &agent_task.worker,
+                                name,
+                                current_time,
+                            );
+                            app_state.input_text.clear();
+                            response.request_focus();
+                            return;
+                        }
+
+                        if !input_text.is_empty() || !app_state.attachments.is_empty() {
                             // Add user input to journal
+                            let input_index = app_state.journal_messages.len();
                             app_state.journal_messages.push(JournalMessage {
                                 content: input_text.clone(),
                                 sender: MessageSender::User,
                                 timestamp: current_time,
+                                node: None,
+                                images: Vec::new(),
                             });
 
                             // If already processing input, don't process again
                             if app_state.processing_input {
                                 // Add a notice that we're already processing
-                                app_state.journal_messages.push(JournalMessage {
-                                    content: "Already processing previous request, please wait...".to_string(),
-                                    sender: MessageSender::System,
-                                    timestamp: current_time,
-                                });
+                                app_state.push_system_message(
+                                    "Already processing previous request, please wait...",
+                                    current_time,
+                                );
                                 return;
                             }
 
-                            // Mark that we're processing input
-                            app_state.processing_input = true;
-
-                            // Make sure we have an agent manager
-                            if let Some(agent_manager) = app_state.agent_manager.clone() {
-                                // Create a clone of the input for the async task
-                                let input_clone = input_text.clone();
+                            // Hand the input to the agent worker; this also stops a paused turn
+                            app_state.paused_tools.clear();
+                            if agent_task.worker.send(AgentCommand::Submit {
+                                input: input_text.clone(),
+                                images: std::mem::take(&mut app_state.attachments),
+                            }) {
+                                // Mark that we're processing input
+                                app_state.processing_input = true;
+                                app_state.awaiting_node = Some(input_index);
 
                                 // Add a "processing" message
-                                app_state.journal_messages.push(JournalMessage {
-                                    content: "Processing your request...".to_string(),
-                                    sender: MessageSender::System,
-                                    timestamp: current_time,
-                                });
-
-                                // Set agent task state
-                                agent_task.processing = true;
-                                agent_task.input = input_text.clone();
-
-                                // Create a channel for communication
-                                let (sender, receiver) = tokio::sync::mpsc::channel(1);
-                                agent_task.receiver = Some(receiver);
-
-                                // Clone what we need for the tokio task
-                                let agent_manager_clone = agent_manager.clone();
-
-                                // Create a tokio runtime for this task
-                                let runtime = match tokio::runtime::Builder::new_current_thread()
-                                    .enable_all()
-                                    .build() {
-                                        Ok(rt) => rt,
-                                        Err(e) => {
-                                            error!("Failed to create tokio runtime: {}", e);
-                                            return;
-                                        }
-                                    };
-
-                                // Use the runtime to spawn the task
-                                std::thread::spawn(move || {
-                                    // Block on the async task within the runtime
-                                    runtime.block_on(async {
-                                        // Get a lock on the agent manager
-                                        let mut agent_manager = agent_manager_clone.lock().await;
-
-                                        // Initialize if not already done
-                                        if !agent_manager.is_initialized() {
-                                            trace!("Agent manager not initialized, initializing...");
-
-                                            // Register tools before initializing
-                                            // File system tools
-                                            agent_manager.register_tool(Box::new(crate::agent::tools::ReadFileTool));
-                                            agent_manager.register_tool(Box::new(crate::agent::tools::WriteFileTool));
-                                            agent_manager.register_tool(Box::new(crate::agent::tools::ListDirectoryTool));
-                                            agent_manager.register_tool(Box::new(crate::agent::tools::ExecuteCommandTool));
-
-                                            // Set working directory
-                                            let current_dir = std::env::current_dir()
-                                                .map(|p| p.to_string_lossy().to_string())
-                                                .unwrap_or_else(|_| ".".to_string());
-                                            agent_manager.set_working_directory(&current_dir);
-
-                                            // Now initialize the backend
-                                            if let Err(e) = agent_manager.init().await {
-                                                error!("Failed to initialize agent: {}", e);
-                                                return;
-                                            }
-                                            trace!("Agent manager initialized successfully");
-                                        }
-
-                                        // Process the input
-                                        match agent_manager.process_input(&input_clone).await {
-                                            Ok(mut response) => {
-                                                // Log the initial response
-                                                trace!("Initial response: got {} chars of response and {} tool results",
-                                                      response.content.len(), response.tool_results.len());
-
-                                                // If there are tool results, we need to continue the conversation
-                                                if !response.tool_results.is_empty() {
-                                                    trace!("Tool results present - continuing conversation");
-
-                                                    // We need to continue the conversation but avoid adding an empty user message
-                                                    // This requires more direct access to create a proper agent response
-
-                                                    // We don't need to add the assistant message to the context again
-                                                    // It was already added in process_input() before we get here
-                                                    // Skipping: agent_manager.context_manager.add_assistant_message(&response.content);
-
-                                                    // Generate a second response using the backend directly
-                                                    let context = agent_manager.context_manager.get_context();
-                                                    match agent_manager.backend.generate_response(&context).await {
-                                                        Ok(mut backend_response) => {
-                                                            trace!("Follow-up response after tools: {} chars", backend_response.content.len());
-
-                                                            // Add the follow-up content to the original response
-                                                            response.content = format!("{}\n\n{}",
-                                                                                      response.content,
-                                                                                      backend_response.content);
-
-                                                            // Process any additional tool calls in the follow-up response with configurable chain depth
-                                                            if !backend_response.tool_calls.is_empty() {
-                                                                trace!("Follow-up contains {} more tool calls, processing with limited chain", 
-                                                                      backend_response.tool_calls.len());
-                                                                      
-                                                                // Configure the depth of tool chaining
-                                                                // Get max_depth from environment variable if present, or use default value
-                                                                let max_depth = std::env::var("TOOL_CHAIN_MAX_DEPTH")
-                                                                    .ok()
-                                                                    .and_then(|v| v.parse::<usize>().ok())
-                                                                    .unwrap_or(5);  // Increased default to 5
-                                                                    
-                                                                let config = ToolChainConfig {
-                                                                    max_depth,  // Allow up to configured levels of tool chaining
-                                                                    delay_ms: 200, // Small delay between API calls to avoid throttling
-                                                                };
-                                                                
-                                                                trace!("Tool chain processing configured with max_depth={}, delay_ms={}", 
-                                                                       config.max_depth, config.delay_ms);
-                                                                
-                                                                // Use our tool chain processor with:
-                                                                // 1. The agent manager
-                                                                // 2. The backend_response which contains the tool calls
-                                                                // 3. Mutable reference to response.tool_results to capture any new results
-                                                                // 4. Mutable reference to response.content to append new content
-                                                                // 5. Custom configuration for tool chain depth and delay
-                                                                // Log the tool results before processing
-                                                                trace!("Tool results BEFORE processing chain: {} results", response.tool_results.len());
-                                                                for (i, res) in response.tool_results.iter().enumerate() {
-                                                                    trace!("  Result {}: Tool={}, ID={:?}", 
-                                                                          i, 
-                                                                          res.tool_name, 
-                                                                          res.tool_call_id);
-                                                                }
-                                                                
-                                                                // Process the tool chain
-                                                                process_tool_chain_with_config(
-                                                                    &mut agent_manager,
-                                                                    backend_response.clone(), // Clone so we can still access the original below
-                                                                    &mut response.tool_results,
-                                                                    &mut response.content,
-                                                                    config
-                                                                ).await;
-                                                                
-                                                                // Log the tool results after processing
-                                                                trace!("Tool results AFTER processing chain: {} results", response.tool_results.len());
-                                                                for (i, res) in response.tool_results.iter().enumerate() {
-                                                                    trace!("  Result {}: Tool={}, ID={:?}", 
-                                                                          i, 
-                                                                          res.tool_name, 
-                                                                          res.tool_call_id);
-                                                                }
-                                                            } else {
-                                                                trace!("Follow-up response contained no additional tool calls");
-                                                            }
-                                                            
-                                                            // Add the assistant's follow-up response to the context for future messages
-                                                            agent_manager.context_manager.add_assistant_message(&backend_response.content);
-                                                        }
-                                                        Err(e) => {
-                                                            error!("Failed to get follow-up after tools: {}", e);
-                                                        }
-                                                    }
-                                                }
-
-                                                // Send the combined response to the main thread
-                                                trace!("Sending final response: {} chars", response.content.len());
-                                                if let Err(e) = sender.try_send(response) {
-                                                    error!("Failed to send response to main thread: {}", e);
-                                                }
-                                            }
-                                            Err(e) => {
-                                                error!("Error processing input: {}", e);
-                                            }
-                                        }
-                                    });
-                                });
+                                app_state.push_system_message(
+                                    "Processing your request...",
+                                    current_time,
+                                );
+                            } else {
+                                app_state.push_system_message(
+                                    "Lost connection to AI assistant. Please restart.",
+                                    current_time,
+                                );
                             }
 
                             // Clear input box
@@ -821,10 +2004,171 @@ fn ui_system(
                     }
                 });
 
-                // Simple hint text at the bottom
+                // Simple hint text at the bottom, with a cancel button while a request is running
                 ui.with_layout(Layout::right_to_left(Align::RIGHT), |ui| {
                     ui.small("Press Enter to submit, Shift+Enter for new line");
+                    if app_state.processing_input && ui.button("Cancel").clicked() {
+                        agent_task.worker.send(AgentCommand::Cancel);
+                    }
+                    if ui
+                        .small_button("📎")
+                        .on_hover_text(
+                            "Attach images; you can also drop them here or mention them as @path",
+                        )
+                        .clicked()
+                    {
+                        let picked = rfd::FileDialog::new()
+                            .set_title("Attach images")
+                            .add_filter("Images", &IMAGE_EXTENSIONS)
+                            .pick_files();
+                        if let Some(paths) = picked {
+                            attach_images(&mut app_state, paths, current_time);
+                        }
+                    }
+                    // Images waiting to go with the next input, removed when clicked
+                    let mut removed = None;
+                    for (index, image) in app_state.attachments.iter().enumerate().rev() {
+                        if ui
+                            .small_button(format!(
+                                "📎 {} ({}×{}) ✕",
+                                image.name, image.width, image.height
+                            ))
+                            .on_hover_text("Remove this image")
+                            .clicked()
+                        {
+                            removed = Some(index);
+                        }
+                    }
+                    if let Some(index) = removed {
+                        app_state.attachments.remove(index);
+                    }
+                    // Ask or agent mode, switched when clicked
+                    let (label, hint, other) = match app_state.mode {
+                        AgentMode::Ask => (
+                            "🔒 Ask mode",
+                            "Only tools that read can run; click to let the agent change files and run commands",
+                            AgentMode::Agent,
+                        ),
+                        AgentMode::Agent => (
+                            "✏ Agent mode",
+                            "Tools can change files and run commands; click for read-only ask mode",
+                            AgentMode::Ask,
+                        ),
+                    };
+                    if ui.small_button(label).on_hover_text(hint).clicked() {
+                        agent_task.worker.send(AgentCommand::SetMode(other));
+                    }
+                    // Active profile, also switched with /profile <name>
+                    let current_profile = app_state.profile.clone();
+                    egui::ComboBox::from_id_salt("profile")
+                        .selected_text(format!("Profile: {}", current_profile))
+                        .show_ui(ui, |ui| {
+                            for (name, description) in &app_state.profile_choices {
+                                let selected = current_profile.eq_ignore_ascii_case(name);
+                                let mut item = ui.selectable_label(selected, name);
+                                if let Some(description) = description {
+                                    item = item.on_hover_text(description);
+                                }
+                                if item.clicked() && !selected {
+                                    agent_task
+                                        .worker
+                                        .send(AgentCommand::SelectProfile(name.clone()));
+                                }
+                            }
+                        });
+                    // Running totals for the session, opening the breakdown when clicked
+                    let totals = app_state.usage.totals();
+                    if ui
+                        .small_button(format!(
+                            "{} tokens · {}",
+                            totals.usage.total(),
+                            format_cost(totals.cost)
+                        ))
+                        .on_hover_text("Show the session's usage and cost")
+                        .clicked()
+                    {
+                        app_state.show_usage = !app_state.show_usage;
+                    }
+                    if !app_state.agent_status.is_empty() {
+                        ui.small(&app_state.agent_status);
+                    }
                 });
             });
         });
 }
+
+// The profile named by a `/profile` command, empty if it names none
+fn profile_command(input: &str) -> Option<&str> {
+    let rest = input.trim().strip_prefix("/profile")?;
+    match rest.chars().next() {
+        None => Some(""),
+        Some(c) if c.is_whitespace() => Some(rest.trim()),
+        _ => None,
+    }
+}
+
+// Switch to the named profile, or list the profiles if no name is given
+fn run_profile_command(
+    app_state: &mut AppState,
+    worker: &AgentWorker,
+    name: &str,
+    current_time: f64,
+) {
+    if !name.is_empty() {
+        worker.send(AgentCommand::SelectProfile(name.to_string()));
+        return;
+    }
+
+    let mut content = "Profiles (switch with /profile <name>):".to_string();
+    for (profile, description) in &app_state.profile_choices {
+        let active = if profile.eq_ignore_ascii_case(&app_state.profile) {
+            " (active)"
+        } else {
+            ""
+        };
+        content.push_str(&format!("\n• {}{}", profile, active));
+        if let Some(description) = description {
+            content.push_str(&format!(" — {}", description));
+        }
+    }
+    app_state.push_system_message(content, current_time);
+}
+
+// Show usage totals as a table with one labelled row each
+fn usage_grid(ui: &mut egui::Ui, id: &str, label: &str, rows: Vec<(String, UsageTotals)>) {
+    egui::Grid::new(id).striped(true).show(ui, |ui| {
+        for heading in [
+            label,
+            "Requests",
+            "Input",
+            "Output",
+            "Cache read",
+            "Cache write",
+            "Cache hits",
+            "Cost",
+        ] {
+            ui.strong(heading);
+        }
+        ui.end_row();
+
+        for (name, totals) in rows {
+            ui.label(name);
+            ui.label(totals.requests.to_string());
+            ui.label(totals.usage.input_tokens.to_string());
+            ui.label(totals.usage.output_tokens.to_string());
+            ui.label(totals.usage.cache_read_tokens.to_string());
+            ui.label(totals.usage.cache_write_tokens.to_string());
+            ui.label(format_hit_rate(totals.usage.cache_hit_rate()));
+            ui.label(format_cost(totals.cost));
+            ui.end_row();
+        }
+    });
+}
+
+// Share of prompt tokens read from the cache, as a whole percentage
+fn format_hit_rate(rate: Option<f64>) -> String {
+    match rate {
+        Some(rate) => format!("{:.0}%", rate * 100.0),
+        None => "-".to_string(),
+    }
+}
@@ -0,0 +1,378 @@
+// Snapshots of files taken before tools change them
+
+use crate::core::config::SessionSettings;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{trace, warn};
+
+/// Name of the file listing the snapshots in a checkpoint directory
+const INDEX_FILE: &str = "index.json";
+
+/// Snapshots of the files changed by a session's turns
+///
+/// Before an editing tool runs, the files it will change are copied into the
+/// session's checkpoint directory, once per file per turn. Undoing a turn
+/// puts those copies back. Files larger than the per-file limit are not
+/// copied, and the oldest turns are dropped when the session goes over its
+/// total limit.
+pub struct CheckpointStore {
+    /// Directory holding the snapshots and their index
+    dir: PathBuf,
+
+    /// Largest file that is snapshotted, in bytes
+    max_file_bytes: u64,
+
+    /// Most bytes kept for the whole session
+    max_total_bytes: u64,
+
+    /// Snapshots by turn, oldest first
+    turns: Vec<TurnSnapshots>,
+}
+
+/// Files changed during one turn
+#[derive(Serialize, Deserialize)]
+struct TurnSnapshots {
+    /// ID of the user input that started the turn
+    turn: u64,
+
+    /// The files, in the order they were first changed
+    files: Vec<FileSnapshot>,
+
+    /// The copies were dropped to stay within the size limit
+    #[serde(default)]
+    evicted: bool,
+}
+
+/// A file as it was before a turn first changed it
+#[derive(Serialize, Deserialize)]
+struct FileSnapshot {
+    /// Path the tool wrote to
+    path: PathBuf,
+
+    /// What the file held
+    state: FileState,
+}
+
+/// What a file held before it was changed
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "kind")]
+enum FileState {
+    /// The file did not exist, so undoing removes it
+    Missing,
+
+    /// The contents are saved in the checkpoint directory
+    Saved {
+        /// File name of the copy
+        copy: String,
+
+        /// Size of the copy in bytes
+        size: u64,
+    },
+
+    /// The file was over a size limit and could not be kept
+    TooLarge {
+        /// Size of the file in bytes
+        size: u64,
+    },
+}
+
+/// Outcome of restoring the files of a turn
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    /// Files put back as they were before the turn
+    pub restored: Vec<String>,
+
+    /// Files that could not be put back, with the reason
+    pub failed: Vec<String>,
+
+    /// The turn's snapshots were dropped to stay within the size limit, so
+    /// nothing could be put back
+    pub evicted: bool,
+}
+
+impl CheckpointStore {
+    /// Open the checkpoint directory `dir`, reading any snapshots already there
+    pub fn open(dir: PathBuf, settings: &SessionSettings) -> Self {
+        let index = dir.join(INDEX_FILE);
+        let turns = match fs::read_to_string(&index) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                warn!(
+                    "Ignoring unreadable checkpoint index {}: {}",
+                    index.display(),
+                    e
+                );
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+
+        Self {
+            dir,
+            max_file_bytes: settings.max_snapshot_file_bytes,
+            max_total_bytes: settings.max_snapshot_bytes,
+            turns,
+        }
+    }
+
+    /// Keep a copy of `path` as it is before `turn` changes it
+    ///
+    /// Only the first change in a turn is recorded, since that is the state
+    /// undoing the turn goes back to.
+    pub fn snapshot(&mut self, turn: u64, path: &Path) -> Result<(), String> {
+        let already_saved = self
+            .turns
+            .iter()
+            .any(|t| t.turn == turn && t.files.iter().any(|file| file.path == path));
+        if already_saved {
+            return Ok(());
+        }
+
+        let state = self.copy_file(turn, path)?;
+        trace!("Snapshotted {} for turn {}", path.display(), turn);
+
+        let file = FileSnapshot {
+            path: path.to_path_buf(),
+            state,
+        };
+        match self.turns.iter_mut().find(|t| t.turn == turn) {
+            Some(snapshots) => snapshots.files.push(file),
+            None => self.turns.push(TurnSnapshots {
+                turn,
+                files: vec![file],
+                evicted: false,
+            }),
+        }
+
+        self.evict(turn);
+        self.write_index()
+    }
+
+    /// Put back every file `turn` changed and forget its snapshots
+    ///
+    /// Files are restored even if some of them fail; the failures are listed
+    /// in the report. A turn whose snapshots were dropped for space is
+    /// reported as evicted rather than as having changed nothing.
+    pub fn undo(&mut self, turn: u64) -> Result<RestoreReport, String> {
+        let mut report = RestoreReport::default();
+        let position = match self.turns.iter().position(|t| t.turn == turn) {
+            Some(position) => position,
+            None => return Ok(report),
+        };
+        let snapshots = self.turns.remove(position);
+        if snapshots.evicted {
+            report.evicted = true;
+            self.write_index()?;
+            return Ok(report);
+        }
+
+        for file in &snapshots.files {
+            let name = file.path.display().to_string();
+            match self.restore_file(file) {
+                Ok(()) => report.restored.push(name),
+                Err(e) => report.failed.push(format!("{} ({})", name, e)),
+            }
+        }
+
+        self.remove_copies(&snapshots.files);
+        self.write_index()?;
+        Ok(report)
+    }
+
+    /// Copy a file into the checkpoint directory, unless it is missing or too large
+    fn copy_file(&self, turn: u64, path: &Path) -> Result<FileState, String> {
+        let size = match fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Ok(FileState::Missing),
+        };
+
+        let turn_size = self.turn_size(turn);
+        if size > self.max_file_bytes || turn_size + size > self.max_total_bytes {
+            warn!(
+                "Not snapshotting {} ({} bytes), it is over the checkpoint size limit",
+                path.display(),
+                size
+            );
+            return Ok(FileState::TooLarge { size });
+        }
+
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("Failed to create {}: {}", self.dir.display(), e))?;
+        let copy = uuid::Uuid::new_v4().to_string();
+        fs::copy(path, self.dir.join(&copy))
+            .map_err(|e| format!("Failed to snapshot {}: {}", path.display(), e))?;
+        Ok(FileState::Saved { copy, size })
+    }
+
+    /// Put a file back the way the snapshot found it
+    fn restore_file(&self, file: &FileSnapshot) -> Result<(), String> {
+        match &file.state {
+            FileState::Missing => match fs::remove_file(&file.path) {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.to_string()),
+            },
+            FileState::Saved { copy, .. } => fs::copy(self.dir.join(copy), &file.path)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            FileState::TooLarge { size } => {
+                Err(format!("{} bytes was too large to snapshot", size))
+            }
+        }
+    }
+
+    /// Drop the oldest turns other than `current` until the store is within its limit
+    ///
+    /// The turns stay in the index, marked as evicted, so undoing one of them
+    /// can say its files are gone.
+    fn evict(&mut self, current: u64) {
+        while self.total_size() > self.max_total_bytes {
+            let oldest = match self
+                .turns
+                .iter()
+                .position(|t| t.turn != current && !t.evicted)
+            {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            let snapshots = &mut self.turns[oldest];
+            trace!(
+                "Dropping snapshots of turn {} to stay within the checkpoint size limit",
+                snapshots.turn
+            );
+            let files = std::mem::take(&mut snapshots.files);
+            snapshots.evicted = true;
+            self.remove_copies(&files);
+        }
+    }
+
+    /// Delete the copies belonging to a turn's snapshots
+    fn remove_copies(&self, files: &[FileSnapshot]) {
+        for file in files {
+            let copy = match &file.state {
+                FileState::Saved { copy, .. } => copy,
+                _ => continue,
+            };
+            if let Err(e) = fs::remove_file(self.dir.join(copy)) {
+                warn!("Failed to remove snapshot {}: {}", copy, e);
+            }
+        }
+    }
+
+    /// Bytes of copies kept for one turn
+    fn turn_size(&self, turn: u64) -> u64 {
+        self.turns
+            .iter()
+            .filter(|t| t.turn == turn)
+            .map(TurnSnapshots::size)
+            .sum()
+    }
+
+    /// Bytes of copies kept for every turn
+    fn total_size(&self) -> u64 {
+        self.turns.iter().map(TurnSnapshots::size).sum()
+    }
+
+    /// Save the list of snapshots next to the copies
+    fn write_index(&self) -> Result<(), String> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("Failed to create {}: {}", self.dir.display(), e))?;
+        let text = serde_json::to_string_pretty(&self.turns)
+            .map_err(|e| format!("Failed to serialize checkpoints: {}", e))?;
+        let path = self.dir.join(INDEX_FILE);
+        fs::write(&path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+impl TurnSnapshots {
+    /// Bytes of copies kept for the turn
+    fn size(&self) -> u64 {
+        self.files
+            .iter()
+            .map(|file| match file.state {
+                FileState::Saved { size, .. } => size,
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("gamecode-checkpoints-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn store(dir: &Path, max_file_bytes: u64, max_snapshot_bytes: u64) -> CheckpointStore {
+        let settings = SessionSettings {
+            max_snapshot_file_bytes: max_file_bytes,
+            max_snapshot_bytes,
+            ..SessionSettings::default()
+        };
+        CheckpointStore::open(dir.join("checkpoints"), &settings)
+    }
+
+    #[test]
+    fn undo_restores_changed_and_removes_created_files() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let changed = dir.join("changed.txt");
+        let created = dir.join("created.txt");
+        fs::write(&changed, "before").unwrap();
+
+        let mut checkpoints = store(&dir, 1024, 1024);
+        checkpoints.snapshot(1, &changed).unwrap();
+        fs::write(&changed, "after").unwrap();
+        checkpoints.snapshot(1, &created).unwrap();
+        fs::write(&created, "new").unwrap();
+        // A second write in the same turn keeps the first snapshot
+        checkpoints.snapshot(1, &changed).unwrap();
+        fs::write(&changed, "after again").unwrap();
+
+        // Snapshots survive reopening the store
+        let mut checkpoints = store(&dir, 1024, 1024);
+        let report = checkpoints.undo(1).unwrap();
+
+        assert_eq!(report.restored.len(), 2);
+        assert!(report.failed.is_empty());
+        assert_eq!(fs::read_to_string(&changed).unwrap(), "before");
+        assert!(!created.exists());
+        assert!(checkpoints.undo(1).unwrap().restored.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn size_limits_drop_large_files_and_old_turns() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let small = dir.join("small.txt");
+        let large = dir.join("large.txt");
+        fs::write(&small, "12345678").unwrap();
+        fs::write(&large, "x".repeat(32)).unwrap();
+
+        let mut checkpoints = store(&dir, 16, 12);
+        checkpoints.snapshot(1, &large).unwrap();
+        checkpoints.snapshot(1, &small).unwrap();
+        checkpoints.snapshot(2, &small).unwrap();
+
+        // Turn 1 was dropped to make room for turn 2
+        let report = checkpoints.undo(1).unwrap();
+        assert!(report.evicted);
+        assert!(report.restored.is_empty());
+        assert!(!checkpoints.undo(1).unwrap().evicted);
+        let report = checkpoints.undo(2).unwrap();
+        assert!(!report.evicted);
+        assert_eq!(report.restored.len(), 1);
+
+        let mut checkpoints = store(&dir, 16, 1024);
+        checkpoints.snapshot(3, &large).unwrap();
+        let report = checkpoints.undo(3).unwrap();
+        assert!(report.restored.is_empty());
+        assert_eq!(report.failed.len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1314 @@
+// Layered configuration loaded from TOML files
+//
+// Settings are read from the per-user config file in the platform config
+// directory and then overridden by a project-level `.gamecode/config.toml`
+// found by walking up from the working directory. Problems in either file are
+// reported as diagnostics instead of aborting startup.
+
+use crate::agent::backends::{
+    BedrockConfig, ModelCapabilities, ModelCatalog, ModelSelection, ModelSpec,
+};
+use crate::agent::budget::BudgetLimits;
+use crate::agent::manager::{AgentConfig, Profile, ToolChainConfig};
+use crate::agent::prompt::{self, DEFAULT_TEMPLATE, TEMPLATE_VARIABLES};
+use crate::agent::retry::RetryPolicy;
+use crate::agent::tools::{
+    ExecuteCommandTool, ListDirectoryTool, ReadFileTool, Tool, ViewImageTool, WriteFileTool,
+};
+use crate::agent::usage::ModelPrice;
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{trace, warn};
+
+/// Name of the directory holding project-level settings
+pub const PROJECT_DIR_NAME: &str = ".gamecode";
+
+/// Name of the configuration file in both the user and project directories
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Complete application configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Profile picked at startup, by name ("default" uses the settings outside any profile)
+    pub profile: Option<String>,
+
+    /// LLM backend connection settings
+    pub backend: BackendSettings,
+
+    /// Model catalog and the models picked for each role
+    pub models: ModelSettings,
+
+    /// Conversation context management
+    pub context: ContextSettings,
+
+    /// System prompt template and project instructions
+    pub prompt: PromptSettings,
+
+    /// Named profiles that can be switched to during a session
+    pub profiles: BTreeMap<String, ProfileEntry>,
+
+    /// Tool availability and policies
+    pub tools: ToolSettings,
+
+    /// Tool chain limits
+    pub tool_chain: ToolChainSettings,
+
+    /// Saved session settings
+    pub sessions: SessionSettings,
+
+    /// Spending limits for turns and sessions
+    pub budget: BudgetSettings,
+
+    /// Display settings
+    pub ui: UiSettings,
+
+    /// Keyboard shortcuts for the input pane
+    pub keybindings: KeyBindings,
+}
+
+/// Backend connection settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackendSettings {
+    /// Backend provider ("bedrock", or "replay" to play back a recorded cassette)
+    pub provider: String,
+
+    /// AWS region to use
+    pub region: String,
+
+    /// AWS profile to use (default credential chain if not set)
+    pub profile: Option<String>,
+
+    /// Most times a request that failed with a transient error is sent again
+    pub max_retries: usize,
+
+    /// Wait before the first retry in milliseconds, doubled for every further retry
+    pub retry_base_ms: u64,
+
+    /// Longest wait before a retry in milliseconds
+    pub retry_max_ms: u64,
+
+    /// Throttled attempts after which a turn moves to the model's fallback (0 never does)
+    pub fallback_after: usize,
+
+    /// Cassette file recorded to with "bedrock", or played back with "replay"
+    pub cassette: Option<PathBuf>,
+}
+
+/// Model catalog and the models picked for each role
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelSettings {
+    /// Model used for primary interactions, by catalog name
+    pub primary: String,
+
+    /// Model used to summarize older turns when the context is compacted
+    #[serde(alias = "fast")]
+    pub summarization: String,
+
+    /// Model used to title new sessions
+    pub title: String,
+
+    /// Models added to the built-in catalog, or changes to built-in models, by name
+    pub catalog: BTreeMap<String, ModelEntry>,
+}
+
+/// A model in the configured catalog
+///
+/// Settings that are not given keep the built-in model's values; a model
+/// that is not built in needs at least an `id`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelEntry {
+    /// Name shown to the user
+    pub display_name: Option<String>,
+
+    /// Bedrock model ID or inference profile
+    pub id: Option<String>,
+
+    /// Context window, in tokens
+    pub context_window: Option<usize>,
+
+    /// Most tokens requested for a response
+    pub max_output_tokens: Option<usize>,
+
+    /// Default temperature (0-1)
+    pub temperature: Option<f32>,
+
+    /// Prices in US dollars per million tokens; all four have to be given
+    pub price: Option<ModelPrice>,
+
+    /// Features the model supports
+    pub capabilities: Option<ModelCapabilities>,
+
+    /// Model to move to when this one is repeatedly throttled, by name
+    pub fallback: Option<String>,
+
+    /// Tokens the model may spend on extended thinking before answering (0 turns it off)
+    pub thinking_budget: Option<usize>,
+}
+
+/// Conversation context settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContextSettings {
+    /// Most tokens the context may use before compressing, if lower than the model allows
+    pub max_context_length: Option<usize>,
+
+    /// Fraction of the model's context window to fill before compressing
+    pub compress_threshold: f64,
+
+    /// Whether to automatically compress older context
+    pub auto_compress: bool,
+
+    /// Number of most recent turns kept word for word when compressing
+    pub keep_recent_turns: usize,
+}
+
+/// System prompt settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PromptSettings {
+    /// Template the system prompt is rendered from, with variables like `{{cwd}}`
+    pub template: String,
+
+    /// Whether `.gamecode/instructions.md` files at or above the working directory are added
+    pub project_instructions: bool,
+}
+
+/// A named profile in the configuration
+///
+/// Settings that are not given are taken from the rest of the configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfileEntry {
+    /// What the profile is for, shown when profiles are listed
+    pub description: Option<String>,
+
+    /// System prompt template, in place of `prompt.template`
+    pub prompt: Option<String>,
+
+    /// Names of the tools the model may use, in place of `tools.enabled`
+    pub tools: Option<Vec<String>>,
+
+    /// Primary model by catalog name, in place of `models.primary`
+    pub model: Option<String>,
+
+    /// Temperature used in place of the model's default (0-1)
+    pub temperature: Option<f32>,
+
+    /// Maximum depth of sequential tool rounds, in place of `tool_chain.max_depth`
+    pub max_depth: Option<usize>,
+}
+
+/// Tool availability and policy settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToolSettings {
+    /// Names of the tools the agent may use
+    pub enabled: Vec<String>,
+
+    /// Commands `execute_command` is allowed to run
+    pub allowed_commands: Vec<String>,
+
+    /// Maximum execution time for tools in milliseconds
+    pub max_execution_ms: u64,
+}
+
+/// Tool chain settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToolChainSettings {
+    /// Maximum depth of sequential tool rounds in one turn
+    pub max_depth: usize,
+
+    /// Delay between API calls in milliseconds
+    pub delay_ms: u64,
+
+    /// Most times a response cut off at the output limit is continued (0 never does)
+    pub max_continuations: usize,
+}
+
+/// Saved session settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionSettings {
+    /// Whether to name new sessions with the title model after their first turn
+    pub auto_title: bool,
+
+    /// Largest file snapshotted before a tool changes it, in bytes
+    pub max_snapshot_file_bytes: u64,
+
+    /// Most bytes of file snapshots kept for undoing a session's turns
+    pub max_snapshot_bytes: u64,
+}
+
+/// Spending limits, enforced while the agent works
+///
+/// Passing `warn_at` of a limit adds a warning to the journal; reaching the
+/// limit pauses the turn until it is continued or stopped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BudgetSettings {
+    /// Fraction of a limit at which a warning is given
+    pub warn_at: f64,
+
+    /// Limits for a single turn
+    pub turn: BudgetLimits,
+
+    /// Limits for the whole session
+    pub session: BudgetLimits,
+}
+
+/// Display settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UiSettings {
+    /// Color theme ("dark" or "light")
+    pub theme: String,
+}
+
+/// Keyboard shortcuts, written as chords like "Enter", "Shift+Enter" or "Ctrl+Comma"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    /// Submit the input to the agent
+    pub submit: String,
+
+    /// Toggle the settings panel
+    pub toggle_settings: String,
+
+    /// Toggle the session list
+    pub toggle_sessions: String,
+}
+
+/// Names of the tools known to the application
+pub const KNOWN_TOOLS: &[&str] = &[
+    "read_file",
+    "write_file",
+    "list_directory",
+    "execute_command",
+    "view_image",
+];
+
+/// Name of the profile made of the settings outside any profile
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Names of the supported backend providers
+pub const KNOWN_PROVIDERS: &[&str] = &["bedrock", "replay"];
+
+/// Names of the supported themes
+pub const KNOWN_THEMES: &[&str] = &["dark", "light"];
+
+impl Default for BackendSettings {
+    fn default() -> Self {
+        Self {
+            provider: "bedrock".to_string(),
+            region: "us-west-2".to_string(),
+            profile: None,
+            max_retries: 3,
+            retry_base_ms: 500,
+            retry_max_ms: 20_000,
+            fallback_after: 2,
+            cassette: None,
+        }
+    }
+}
+
+impl Default for ModelSettings {
+    fn default() -> Self {
+        Self {
+            primary: "sonnet".to_string(),
+            summarization: "haiku".to_string(),
+            title: "haiku".to_string(),
+            catalog: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for ContextSettings {
+    fn default() -> Self {
+        Self {
+            max_context_length: None,
+            compress_threshold: 0.8,
+            auto_compress: true,
+            keep_recent_turns: 2,
+        }
+    }
+}
+
+impl Default for PromptSettings {
+    fn default() -> Self {
+        Self {
+            template: DEFAULT_TEMPLATE.to_string(),
+            project_instructions: true,
+        }
+    }
+}
+
+impl Default for ToolSettings {
+    fn default() -> Self {
+        Self {
+            enabled: KNOWN_TOOLS.iter().map(|s| s.to_string()).collect(),
+            allowed_commands: ExecuteCommandTool::default_allowed_commands()
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect(),
+            max_execution_ms: 30000,
+        }
+    }
+}
+
+impl Default for ToolChainSettings {
+    fn default() -> Self {
+        Self {
+            max_depth: 5,
+            delay_ms: 200,
+            max_continuations: 3,
+        }
+    }
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            auto_title: true,
+            max_snapshot_file_bytes: 2 * 1024 * 1024,
+            max_snapshot_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl ModelEntry {
+    /// Apply the settings that are given to a catalog model
+    fn apply(&self, model: &mut ModelSpec) {
+        if let Some(display_name) = &self.display_name {
+            model.display_name = display_name.clone();
+        }
+        if let Some(id) = &self.id {
+            model.id = id.clone();
+        }
+        if let Some(context_window) = self.context_window {
+            model.context_window = context_window;
+        }
+        if let Some(max_output_tokens) = self.max_output_tokens {
+            model.max_output_tokens = max_output_tokens;
+        }
+        if let Some(temperature) = self.temperature {
+            model.temperature = temperature;
+        }
+        if let Some(price) = self.price {
+            model.price = price;
+        }
+        if let Some(capabilities) = self.capabilities {
+            model.capabilities = capabilities;
+        }
+        if let Some(fallback) = &self.fallback {
+            model.fallback = Some(fallback.clone());
+        }
+        if let Some(thinking_budget) = self.thinking_budget {
+            model.thinking_budget = thinking_budget;
+        }
+    }
+}
+
+impl Default for BudgetSettings {
+    fn default() -> Self {
+        Self {
+            warn_at: 0.8,
+            turn: BudgetLimits::default(),
+            session: BudgetLimits::default(),
+        }
+    }
+}
+
+impl Default for UiSettings {
+    fn default() -> Self {
+        Self {
+            theme: "dark".to_string(),
+        }
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            submit: "Enter".to_string(),
+            toggle_settings: "Ctrl+Comma".to_string(),
+            toggle_sessions: "Ctrl+B".to_string(),
+        }
+    }
+}
+
+/// A problem found while loading configuration
+#[derive(Debug, Clone)]
+pub struct ConfigDiagnostic {
+    /// File the problem was found in
+    pub source: PathBuf,
+
+    /// Dotted key path the problem relates to (if known)
+    pub key: Option<String>,
+
+    /// Human readable description
+    pub message: String,
+}
+
+impl fmt::Display for ConfigDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "{}: `{}`: {}", self.source.display(), key, self.message),
+            None => write!(f, "{}: {}", self.source.display(), self.message),
+        }
+    }
+}
+
+/// Result of loading the layered configuration
+pub struct LoadedConfig {
+    /// The merged configuration
+    pub config: Config,
+
+    /// Files that contributed to the configuration, in merge order
+    pub sources: Vec<PathBuf>,
+
+    /// Problems found while loading
+    pub diagnostics: Vec<ConfigDiagnostic>,
+}
+
+/// Problems found by checking one configuration layer or the merged settings
+struct Validation {
+    /// File the values came from
+    source: PathBuf,
+
+    /// Problems to show the user
+    diagnostics: Vec<ConfigDiagnostic>,
+
+    /// Keys whose values cannot be used, as table paths
+    rejected: Vec<Vec<String>>,
+}
+
+impl Validation {
+    /// Note a problem with a value that is still used
+    fn report(&mut self, key: &str, message: String) {
+        self.diagnostics.push(ConfigDiagnostic {
+            source: self.source.clone(),
+            key: Some(key.to_string()),
+            message,
+        });
+    }
+
+    /// Note a value that cannot be used, so it is dropped
+    fn reject(&mut self, path: &[&str], message: String) {
+        self.report(&path.join("."), format!("{}, ignored", message));
+        self.rejected
+            .push(path.iter().map(|key| key.to_string()).collect());
+    }
+}
+
+impl Config {
+    /// Load the user and project configuration layers for the given working directory
+    pub fn load(working_dir: &Path) -> LoadedConfig {
+        let mut paths = Vec::new();
+        if let Some(user_path) = user_config_path() {
+            paths.push(user_path);
+        }
+        if let Some(project_path) = find_project_config(working_dir) {
+            paths.push(project_path);
+        }
+        Self::load_from_paths(&paths)
+    }
+
+    /// Load and merge configuration files, later files overriding earlier ones
+    ///
+    /// Invalid values are reported and dropped, so the earlier layers or the
+    /// defaults apply in their place.
+    pub fn load_from_paths(paths: &[PathBuf]) -> LoadedConfig {
+        let mut merged = toml::Table::new();
+        let mut config = Config::default();
+        let mut sources = Vec::new();
+        let mut diagnostics = Vec::new();
+
+        // Models a layer may refer to: built in or added by an earlier layer
+        let builtin_models: Vec<String> = ModelCatalog::builtin()
+            .names()
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        let mut known_models = builtin_models.clone();
+
+        for path in paths {
+            if !path.exists() {
+                trace!("No configuration file at {}", path.display());
+                continue;
+            }
+
+            let text = match fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(e) => {
+                    diagnostics.push(ConfigDiagnostic {
+                        source: path.clone(),
+                        key: None,
+                        message: format!("could not be read: {}", e),
+                    });
+                    continue;
+                }
+            };
+
+            let table = match parse_layer(path, &text, &known_models, &mut diagnostics) {
+                Some(table) => table,
+                None => continue,
+            };
+
+            // Keep the layers merged so far if this one does not combine with them
+            let names = catalog_names(&table);
+            let mut candidate = merged.clone();
+            merge_tables(&mut candidate, table);
+            match Config::deserialize(toml::Value::Table(candidate.clone())) {
+                Ok(layered) => {
+                    known_models.extend(names);
+                    merged = candidate;
+                    config = layered;
+                    sources.push(path.clone());
+                }
+                Err(e) => diagnostics.push(ConfigDiagnostic {
+                    source: path.clone(),
+                    key: None,
+                    message: format!(
+                        "does not combine with the earlier settings: {} (file ignored)",
+                        e.to_string().trim_end()
+                    ),
+                }),
+            }
+        }
+
+        // Values that are fine in each file can still clash once combined, such
+        // as a fallback naming a model only another file removed. These are
+        // put on the last file, which completed the combination.
+        if let Some(source) = sources.last() {
+            let validation = config.validate(source, &builtin_models);
+            for diagnostic in validation.diagnostics {
+                let seen = diagnostics
+                    .iter()
+                    .any(|d| d.key == diagnostic.key && d.message == diagnostic.message);
+                if !seen {
+                    diagnostics.push(diagnostic);
+                }
+            }
+            if !validation.rejected.is_empty() {
+                for key in &validation.rejected {
+                    remove_key(&mut merged, key);
+                }
+                config = match Config::deserialize(toml::Value::Table(merged)) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        diagnostics.push(ConfigDiagnostic {
+                            source: source.clone(),
+                            key: None,
+                            message: format!(
+                                "{} (using the default settings)",
+                                e.to_string().trim_end()
+                            ),
+                        });
+                        Config::default()
+                    }
+                };
+            }
+        }
+
+        for diagnostic in &diagnostics {
+            warn!("Configuration problem: {}", diagnostic);
+        }
+
+        LoadedConfig {
+            config,
+            sources,
+            diagnostics,
+        }
+    }
+
+    /// Check values that parse correctly but are not meaningful
+    ///
+    /// `known_models` are the catalog names this layer may use without
+    /// adding them itself. Values that cannot be used are listed in the
+    /// result so they can be dropped; the rest are only reported.
+    fn validate(&self, source: &Path, known_models: &[String]) -> Validation {
+        let mut validation = Validation {
+            source: source.to_path_buf(),
+            diagnostics: Vec::new(),
+            rejected: Vec::new(),
+        };
+
+        if !KNOWN_PROVIDERS.contains(&self.backend.provider.as_str()) {
+            validation.reject(
+                &["backend", "provider"],
+                format!(
+                    "unsupported provider '{}', expected one of: {}",
+                    self.backend.provider,
+                    KNOWN_PROVIDERS.join(", ")
+                ),
+            );
+        } else if self.backend.provider == "replay" && self.backend.cassette.is_none() {
+            validation.reject(
+                &["backend", "provider"],
+                "'replay' needs backend.cassette to be set".to_string(),
+            );
+        }
+        if self.backend.region.trim().is_empty() {
+            validation.reject(&["backend", "region"], "must not be empty".to_string());
+        }
+        let mut model_names = known_models.to_vec();
+        for (name, entry) in &self.models.catalog {
+            let known = known_models.iter().any(|m| m.eq_ignore_ascii_case(name));
+            if !known && entry.id.is_none() {
+                validation.reject(
+                    &["models", "catalog", name],
+                    "needs an id, since it is not a built-in model".to_string(),
+                );
+                continue;
+            }
+            model_names.push(name.clone());
+
+            match entry.temperature {
+                Some(temperature) if !(0.0..=1.0).contains(&temperature) => validation.reject(
+                    &["models", "catalog", name, "temperature"],
+                    format!("{} is outside the range 0.0 to 1.0", temperature),
+                ),
+                _ => {}
+            }
+            match entry.thinking_budget {
+                Some(budget) if budget > 0 && budget < 1024 => validation.reject(
+                    &["models", "catalog", name, "thinking_budget"],
+                    format!(
+                        "{} is below the minimum of 1024 tokens (0 turns thinking off)",
+                        budget
+                    ),
+                ),
+                _ => {}
+            }
+            for (key, value) in [
+                ("context_window", entry.context_window),
+                ("max_output_tokens", entry.max_output_tokens),
+            ] {
+                if value == Some(0) {
+                    validation.reject(
+                        &["models", "catalog", name, key],
+                        "must be greater than zero".to_string(),
+                    );
+                }
+            }
+            if let Some(price) = &entry.price {
+                for (key, value) in [
+                    ("input", price.input),
+                    ("output", price.output),
+                    ("cache_read", price.cache_read),
+                    ("cache_write", price.cache_write),
+                ] {
+                    if value.is_nan() || value < 0.0 {
+                        validation.reject(
+                            &["models", "catalog", name, "price"],
+                            format!(
+                                "{} price {} is not valid, it must be zero or more",
+                                key, value
+                            ),
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        for (key, model) in [
+            ("primary", &self.models.primary),
+            ("summarization", &self.models.summarization),
+            ("title", &self.models.title),
+        ] {
+            if !model_names.iter().any(|m| m.eq_ignore_ascii_case(model)) {
+                validation.reject(
+                    &["models", key],
+                    format!(
+                        "unknown model '{}', expected one of: {}",
+                        model,
+                        model_names.join(", ")
+                    ),
+                );
+            }
+        }
+        for (name, entry) in &self.models.catalog {
+            match &entry.fallback {
+                Some(fallback) if !model_names.iter().any(|m| m.eq_ignore_ascii_case(fallback)) => {
+                    validation.reject(
+                        &["models", "catalog", name, "fallback"],
+                        format!(
+                            "unknown model '{}', expected one of: {}",
+                            fallback,
+                            model_names.join(", ")
+                        ),
+                    )
+                }
+                _ => {}
+            }
+        }
+        if !(self.context.compress_threshold > 0.0 && self.context.compress_threshold <= 1.0) {
+            validation.reject(
+                &["context", "compress_threshold"],
+                format!(
+                    "{} is outside the range 0.0 (exclusive) to 1.0",
+                    self.context.compress_threshold
+                ),
+            );
+        }
+        let mut templates = vec![("prompt.template".to_string(), &self.prompt.template)];
+        let mut tool_lists = vec![("tools.enabled".to_string(), &self.tools.enabled)];
+        for (name, entry) in &self.profiles {
+            if let Some(template) = &entry.prompt {
+                templates.push((format!("profiles.{}.prompt", name), template));
+            }
+            if let Some(tools) = &entry.tools {
+                tool_lists.push((format!("profiles.{}.tools", name), tools));
+            }
+            match &entry.model {
+                Some(model) if !model_names.iter().any(|m| m.eq_ignore_ascii_case(model)) => {
+                    validation.reject(
+                        &["profiles", name, "model"],
+                        format!(
+                            "unknown model '{}', expected one of: {}",
+                            model,
+                            model_names.join(", ")
+                        ),
+                    )
+                }
+                _ => {}
+            }
+            match entry.temperature {
+                Some(temperature) if !(0.0..=1.0).contains(&temperature) => validation.reject(
+                    &["profiles", name, "temperature"],
+                    format!("{} is outside the range 0.0 to 1.0", temperature),
+                ),
+                _ => {}
+            }
+            if entry.max_depth == Some(0) {
+                validation.reject(
+                    &["profiles", name, "max_depth"],
+                    "must be at least 1".to_string(),
+                );
+            }
+        }
+        for (key, template) in templates {
+            for variable in prompt::variables(template) {
+                if !TEMPLATE_VARIABLES.contains(&variable) {
+                    validation.report(
+                        &key,
+                        format!(
+                            "unknown variable '{{{{{}}}}}', expected one of: {}",
+                            variable,
+                            TEMPLATE_VARIABLES.join(", ")
+                        ),
+                    );
+                }
+            }
+        }
+        for (key, tools) in tool_lists {
+            for tool in tools {
+                if !KNOWN_TOOLS.contains(&tool.as_str()) {
+                    validation.report(
+                        &key,
+                        format!(
+                            "unknown tool '{}', expected one of: {}",
+                            tool,
+                            KNOWN_TOOLS.join(", ")
+                        ),
+                    );
+                }
+            }
+        }
+        if self.tool_chain.max_depth == 0 {
+            validation.reject(
+                &["tool_chain", "max_depth"],
+                "must be at least 1".to_string(),
+            );
+        }
+        if !(self.budget.warn_at > 0.0 && self.budget.warn_at <= 1.0) {
+            validation.reject(
+                &["budget", "warn_at"],
+                format!(
+                    "{} is outside the range 0.0 (exclusive) to 1.0",
+                    self.budget.warn_at
+                ),
+            );
+        }
+        for (scope, limits) in [
+            ("turn", &self.budget.turn),
+            ("session", &self.budget.session),
+        ] {
+            match limits.max_cost {
+                Some(cost) if cost.is_nan() || cost <= 0.0 => validation.reject(
+                    &["budget", scope, "max_cost"],
+                    format!(
+                        "{} is not a valid limit, it must be greater than zero",
+                        cost
+                    ),
+                ),
+                _ => {}
+            }
+            for (name, value) in [
+                ("max_tokens", limits.max_tokens.map(|v| v as u64)),
+                ("max_requests", limits.max_requests.map(|v| v as u64)),
+                ("max_seconds", limits.max_seconds),
+            ] {
+                if value == Some(0) {
+                    validation.reject(
+                        &["budget", scope, name],
+                        "must be greater than zero".to_string(),
+                    );
+                }
+            }
+        }
+        if !KNOWN_THEMES.contains(&self.ui.theme.as_str()) {
+            validation.reject(
+                &["ui", "theme"],
+                format!(
+                    "unknown theme '{}', expected one of: {}",
+                    self.ui.theme,
+                    KNOWN_THEMES.join(", ")
+                ),
+            );
+        }
+        validation
+    }
+
+    /// Whether the dark theme is selected
+    pub fn dark_mode(&self) -> bool {
+        self.ui.theme != "light"
+    }
+
+    /// The built-in models with the configured catalog applied
+    ///
+    /// A model that is not built in and has no `id` is left out.
+    pub fn model_catalog(&self) -> ModelCatalog {
+        let mut catalog = ModelCatalog::builtin();
+        for (name, entry) in &self.models.catalog {
+            let mut model = match (catalog.get(name), &entry.id) {
+                (Some(model), _) => model.clone(),
+                (None, Some(id)) => new_model(name, id),
+                (None, None) => continue,
+            };
+            entry.apply(&mut model);
+            catalog.insert(model);
+        }
+        catalog
+    }
+
+    /// Names of the models picked for each role
+    pub fn model_selection(&self) -> ModelSelection {
+        ModelSelection {
+            primary: self.models.primary.clone(),
+            summarization: self.models.summarization.clone(),
+            title: self.models.title.clone(),
+        }
+    }
+
+    /// Agent settings derived from this configuration
+    pub fn agent_config(&self) -> AgentConfig {
+        AgentConfig {
+            max_context_length: self.context.max_context_length,
+            compress_threshold: self.context.compress_threshold,
+            auto_compress_context: self.context.auto_compress,
+            keep_recent_turns: self.context.keep_recent_turns,
+            aws_region: self.backend.region.clone(),
+            aws_profile: self.backend.profile.clone(),
+            catalog: self.model_catalog(),
+            models: self.model_selection(),
+            turn_budget: self.budget.turn.clone(),
+            session_budget: self.budget.session.clone(),
+            budget_warn_at: self.budget.warn_at,
+            tool_chain: self.tool_chain_config(),
+            retry: self.retry_policy(),
+            system_prompt: self.prompt.template.clone(),
+            project_instructions: self.prompt.project_instructions,
+            profiles: self.profiles(),
+            profile: Some(
+                self.profile
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_PROFILE.to_string()),
+            ),
+        }
+    }
+
+    /// The profiles that can be switched to, starting with the default profile
+    ///
+    /// The default profile is made of the settings outside any profile, and
+    /// can be changed with a `[profiles.default]` section like any other.
+    pub fn profiles(&self) -> Vec<Profile> {
+        let base = Profile {
+            name: DEFAULT_PROFILE.to_string(),
+            description: None,
+            system_prompt: self.prompt.template.clone(),
+            tools: self.tools.enabled.clone(),
+            model: self.models.primary.clone(),
+            temperature: None,
+            max_depth: self.tool_chain_config().max_depth,
+        };
+
+        let mut profiles = vec![base.clone()];
+        for (name, entry) in &self.profiles {
+            let profile = Profile {
+                name: name.clone(),
+                description: entry.description.clone(),
+                system_prompt: entry
+                    .prompt
+                    .clone()
+                    .unwrap_or_else(|| base.system_prompt.clone()),
+                tools: entry.tools.clone().unwrap_or_else(|| base.tools.clone()),
+                model: entry.model.clone().unwrap_or_else(|| base.model.clone()),
+                temperature: entry.temperature,
+                max_depth: entry.max_depth.unwrap_or(base.max_depth).max(1),
+            };
+            match profiles
+                .iter_mut()
+                .find(|p| p.name.eq_ignore_ascii_case(name))
+            {
+                Some(existing) => *existing = profile,
+                None => profiles.push(profile),
+            }
+        }
+        profiles
+    }
+
+    /// Bedrock backend settings derived from this configuration
+    pub fn bedrock_config(&self) -> BedrockConfig {
+        BedrockConfig {
+            region: self.backend.region.clone(),
+            use_profile: self.backend.profile.is_some(),
+            profile_name: self.backend.profile.clone(),
+            enabled_tools: self.tools.enabled.clone(),
+            allowed_commands: self.tools.allowed_commands.clone(),
+            record_cassette: match self.backend.provider.as_str() {
+                "bedrock" => self.backend.cassette.clone(),
+                _ => None,
+            },
+        }
+    }
+
+    /// Cassette to play back instead of calling Bedrock, if the replay provider is selected
+    pub fn replay_cassette(&self) -> Option<PathBuf> {
+        match self.backend.provider.as_str() {
+            "replay" => self.backend.cassette.clone(),
+            _ => None,
+        }
+    }
+
+    /// Tool chain limits derived from this configuration
+    pub fn tool_chain_config(&self) -> ToolChainConfig {
+        ToolChainConfig {
+            max_depth: self.tool_chain.max_depth.max(1),
+            delay_ms: self.tool_chain.delay_ms,
+            max_continuations: self.tool_chain.max_continuations,
+        }
+    }
+
+    /// Retry policy for failed model requests
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.backend.max_retries,
+            base_delay: Duration::from_millis(self.backend.retry_base_ms),
+            max_delay: Duration::from_millis(
+                self.backend.retry_max_ms.max(self.backend.retry_base_ms),
+            ),
+            fallback_after: self.backend.fallback_after,
+        }
+    }
+
+    /// Instantiate the tools enabled by default or by any profile, with the configured policies
+    pub fn enabled_tools(&self) -> Vec<Box<dyn Tool>> {
+        let mut names: Vec<&String> = self
+            .tools
+            .enabled
+            .iter()
+            .chain(
+                self.profiles
+                    .values()
+                    .flat_map(|p| p.tools.iter().flatten()),
+            )
+            .collect();
+        names.sort();
+        names.dedup();
+
+        let mut tools: Vec<Box<dyn Tool>> = Vec::new();
+        for name in names {
+            match name.as_str() {
+                "read_file" => tools.push(Box::new(ReadFileTool)),
+                "write_file" => tools.push(Box::new(WriteFileTool)),
+                "list_directory" => tools.push(Box::new(ListDirectoryTool)),
+                "view_image" => tools.push(Box::new(ViewImageTool)),
+                "execute_command" => tools.push(Box::new(
+                    ExecuteCommandTool::with_allowed_commands(self.tools.allowed_commands.clone()),
+                )),
+                _ => {}
+            }
+        }
+        tools
+    }
+}
+
+/// Parse one configuration layer, reporting unknown keys and type errors
+fn parse_layer(
+    path: &Path,
+    text: &str,
+    known_models: &[String],
+    diagnostics: &mut Vec<ConfigDiagnostic>,
+) -> Option<toml::Table> {
+    let table: toml::Table = match text.parse() {
+        Ok(table) => table,
+        Err(e) => {
+            diagnostics.push(ConfigDiagnostic {
+                source: path.to_path_buf(),
+                key: None,
+                message: format!("invalid TOML: {}", e.to_string().trim_end()),
+            });
+            return None;
+        }
+    };
+
+    // Deserialize the layer on its own so errors point at the file that caused them
+    let mut unknown_keys = Vec::new();
+    let result: Result<Config, _> =
+        serde_ignored::deserialize(toml::Deserializer::new(text), |key| {
+            unknown_keys.push(key.to_string())
+        });
+
+    for key in unknown_keys {
+        diagnostics.push(ConfigDiagnostic {
+            source: path.to_path_buf(),
+            key: Some(key),
+            message: "unknown key, ignored".to_string(),
+        });
+    }
+
+    match result {
+        Ok(layer) => {
+            // Drop the values that cannot be used, so earlier layers apply instead
+            let validation = layer.validate(path, known_models);
+            diagnostics.extend(validation.diagnostics);
+            let mut table = table;
+            for key in &validation.rejected {
+                remove_key(&mut table, key);
+            }
+            Some(table)
+        }
+        Err(e) => {
+            diagnostics.push(ConfigDiagnostic {
+                source: path.to_path_buf(),
+                key: None,
+                message: format!("{} (file ignored)", e.to_string().trim_end()),
+            });
+            None
+        }
+    }
+}
+
+/// Names of the models a layer adds to or changes in the catalog
+fn catalog_names(table: &toml::Table) -> Vec<String> {
+    table
+        .get("models")
+        .and_then(|models| models.get("catalog"))
+        .and_then(|catalog| catalog.as_table())
+        .map(|catalog| catalog.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// A model that is not built in, before its configured settings are applied
+///
+/// It is free and offered tools until the configuration says otherwise.
+fn new_model(name: &str, id: &str) -> ModelSpec {
+    ModelSpec {
+        name: name.to_string(),
+        display_name: name.to_string(),
+        id: id.to_string(),
+        context_window: 200_000,
+        max_output_tokens: 4096,
+        temperature: 0.7,
+        price: ModelPrice::default(),
+        capabilities: ModelCapabilities::default(),
+        fallback: None,
+        thinking_budget: 0,
+    }
+}
+
+/// Recursively merge `overlay` into `base`, overlay values taking precedence
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Remove the value at a path of keys, if it is there
+fn remove_key(table: &mut toml::Table, path: &[String]) {
+    match path {
+        [] => {}
+        [key] => {
+            table.remove(key);
+        }
+        [key, rest @ ..] => {
+            if let Some(toml::Value::Table(inner)) = table.get_mut(key) {
+                remove_key(inner, rest);
+            }
+        }
+    }
+}
+
+/// Platform directories for the application
+pub fn project_dirs() -> Option<ProjectDirs> {
+    ProjectDirs::from("com", "onextent", "gamecode")
+}
+
+/// Path of the per-user configuration file
+pub fn user_config_path() -> Option<PathBuf> {
+    project_dirs().map(|dirs| dirs.config_dir().join(CONFIG_FILE_NAME))
+}
+
+/// Find the nearest `.gamecode/config.toml` at or above the working directory
+pub fn find_project_config(working_dir: &Path) -> Option<PathBuf> {
+    working_dir
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_DIR_NAME).join(CONFIG_FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write each layer to its own file, in merge order
+    fn layers(texts: &[&str]) -> (PathBuf, Vec<PathBuf>) {
+        let dir = std::env::temp_dir().join(format!("gamecode-config-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let paths = texts
+            .iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let path = dir.join(format!("layer{}.toml", i));
+                fs::write(&path, text).unwrap();
+                path
+            })
+            .collect();
+        (dir, paths)
+    }
+
+    fn keys(loaded: &LoadedConfig) -> Vec<(PathBuf, String)> {
+        loaded
+            .diagnostics
+            .iter()
+            .map(|d| (d.source.clone(), d.key.clone().unwrap_or_default()))
+            .collect()
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let (dir, paths) = layers(&[
+            "[models]\nprimary = \"haiku\"\n[context]\ncompress_threshold = 0.5\nkeep_recent_turns = 4\n",
+            "[context]\ncompress_threshold = 0.7\n",
+        ]);
+
+        let loaded = Config::load_from_paths(&paths);
+
+        assert!(loaded.diagnostics.is_empty());
+        assert_eq!(loaded.sources, paths);
+        assert_eq!(loaded.config.models.primary, "haiku");
+        assert_eq!(loaded.config.context.compress_threshold, 0.7);
+        assert_eq!(loaded.config.context.keep_recent_turns, 4);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_file() {
+        let (dir, paths) =
+            layers(&["[context]\nkeep_recent = 3\n[colors]\nbackground = \"red\"\n"]);
+
+        let loaded = Config::load_from_paths(&paths);
+
+        let mut found = keys(&loaded);
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                (paths[0].clone(), "colors".to_string()),
+                (paths[0].clone(), "context.keep_recent".to_string()),
+            ]
+        );
+        assert!(
+            loaded
+                .diagnostics
+                .iter()
+                .all(|d| d.message == "unknown key, ignored")
+        );
+        assert_eq!(loaded.sources, paths);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_values_fall_back_to_earlier_layers_and_defaults() {
+        let (dir, paths) = layers(&[
+            "[models.catalog.sonnet]\ntemperature = 0.5\n",
+            "[models.catalog.sonnet]\ntemperature = 1.5\ndisplay_name = \"Sonnet\"\n\
+             [context]\ncompress_threshold = 2.0\n[ui]\ntheme = \"blue\"\n",
+        ]);
+
+        let loaded = Config::load_from_paths(&paths);
+
+        let mut found = keys(&loaded);
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                (paths[1].clone(), "context.compress_threshold".to_string()),
+                (
+                    paths[1].clone(),
+                    "models.catalog.sonnet.temperature".to_string()
+                ),
+                (paths[1].clone(), "ui.theme".to_string()),
+            ]
+        );
+        let sonnet = &loaded.config.models.catalog["sonnet"];
+        assert_eq!(sonnet.temperature, Some(0.5));
+        assert_eq!(sonnet.display_name.as_deref(), Some("Sonnet"));
+        let defaults = Config::default();
+        assert_eq!(
+            loaded.config.context.compress_threshold,
+            defaults.context.compress_threshold
+        );
+        assert_eq!(loaded.config.ui.theme, defaults.ui.theme);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_file_with_type_errors_is_skipped() {
+        let (dir, paths) = layers(&[
+            "[models]\nprimary = \"haiku\"\n",
+            "[context]\nkeep_recent_turns = \"many\"\n",
+        ]);
+
+        let loaded = Config::load_from_paths(&paths);
+
+        assert_eq!(loaded.sources, paths[..1]);
+        assert_eq!(loaded.diagnostics.len(), 1);
+        assert_eq!(loaded.diagnostics[0].source, paths[1]);
+        assert!(loaded.diagnostics[0].message.ends_with("(file ignored)"));
+        assert_eq!(loaded.config.models.primary, "haiku");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,4 +1,7 @@
 mod agent;
+pub mod checkpoint;
+pub mod config;
+pub mod session;
 mod state;
 mod tools;
 
@@ -0,0 +1,715 @@
+// Saved conversations
+
+use crate::agent::attachments::Thumbnail;
+use crate::agent::backends::{ModelSelection, ToolUse};
+use crate::agent::context::MessageRole;
+use crate::agent::manager::{AgentMode, ToolResult};
+use crate::agent::message_tree::MessageTree;
+use crate::agent::usage::{UsageLedger, format_cost};
+use crate::core::config::project_dirs;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{trace, warn};
+
+/// Version of the on-disk session format
+///
+/// Bump this when a change to `Session` cannot be read by older code, and
+/// teach `read_session` to upgrade the previous version.
+pub const SESSION_FORMAT_VERSION: u32 = 1;
+
+/// Maximum length of a session title derived from its first message
+const TITLE_LENGTH: usize = 60;
+
+/// Maximum length of a search result snippet
+const SNIPPET_LENGTH: usize = 80;
+
+/// A conversation saved to disk
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// Format version the session was written with
+    pub version: u32,
+
+    /// Unique session ID, also used as the file name
+    pub id: String,
+
+    /// When the session was started (seconds since the Unix epoch)
+    pub created_at: u64,
+
+    /// When the session was last saved (seconds since the Unix epoch)
+    pub updated_at: u64,
+
+    /// Title generated after the first turn or set by the user
+    #[serde(default)]
+    pub title: Option<String>,
+
+    /// Tokens used by model requests during the session
+    #[serde(default)]
+    pub tokens_used: usize,
+
+    /// Tokens and cost of each request made during the session
+    #[serde(default)]
+    pub usage: UsageLedger,
+
+    /// Catalog names of the models picked for each role
+    pub models: ModelSelection,
+
+    /// Whether the session was in ask or agent mode
+    #[serde(default)]
+    pub mode: AgentMode,
+
+    /// Profile the session was using, by name (none if it never picked one)
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// Working directory tools ran in
+    pub working_directory: String,
+
+    /// Messages in the agent context, including edited branches
+    pub conversation: MessageTree,
+
+    /// Tool calls waiting to run if the last turn was paused at its round limit
+    #[serde(default)]
+    pub pending_tools: Vec<ToolUse>,
+
+    /// Results of every tool run during the session
+    pub tool_results: Vec<ToolResult>,
+
+    /// Journal shown in the UI
+    pub journal: Vec<JournalEntry>,
+}
+
+/// A journal message as saved in a session
+#[derive(Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Who sent the message
+    pub sender: JournalSender,
+
+    /// Message text
+    pub content: String,
+
+    /// ID of the context message for a user input, used to edit it later
+    #[serde(default)]
+    pub node: Option<u64>,
+
+    /// Previews of the images attached to a user input
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<Thumbnail>,
+}
+
+/// Sender of a saved journal message
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalSender {
+    User,
+    Assistant,
+    Reasoning,
+    System,
+    Tool(String),
+}
+
+/// Overview of a saved session, for listing
+pub struct SessionSummary {
+    /// Session ID
+    pub id: String,
+
+    /// Session title
+    pub title: String,
+
+    /// When the session was last saved (seconds since the Unix epoch)
+    pub updated_at: u64,
+
+    /// Number of messages in the agent context
+    pub message_count: usize,
+
+    /// Tokens used by model requests during the session
+    pub tokens_used: usize,
+
+    /// Catalog name of the model used for primary interactions
+    pub model: String,
+
+    /// Working directory tools ran in
+    pub working_directory: String,
+
+    /// Line of the transcript that matched a search
+    pub snippet: Option<String>,
+}
+
+/// Directory of saved sessions, one JSON file per session
+pub struct SessionStore {
+    /// Directory the session files live in
+    dir: PathBuf,
+}
+
+impl Session {
+    /// Start a new, empty session
+    pub fn new(models: ModelSelection, working_directory: &str) -> Self {
+        let now = now();
+        Self {
+            version: SESSION_FORMAT_VERSION,
+            id: uuid::Uuid::new_v4().to_string(),
+            created_at: now,
+            updated_at: now,
+            title: None,
+            tokens_used: 0,
+            usage: UsageLedger::default(),
+            models,
+            mode: AgentMode::default(),
+            profile: None,
+            working_directory: working_directory.to_string(),
+            conversation: MessageTree::default(),
+            pending_tools: Vec::new(),
+            tool_results: Vec::new(),
+            journal: Vec::new(),
+        }
+    }
+
+    /// Title for the session, falling back to its first user message
+    pub fn title(&self) -> String {
+        if let Some(title) = &self.title {
+            return title.clone();
+        }
+
+        let first_input = self
+            .journal
+            .iter()
+            .find(|entry| matches!(entry.sender, JournalSender::User))
+            .map(|entry| entry.content.as_str())
+            .or_else(|| {
+                self.conversation
+                    .messages()
+                    .find(|m| m.role == MessageRole::User)
+                    .map(|m| m.content.as_str())
+            });
+
+        match first_input {
+            Some(text) => shorten(text.lines().next().unwrap_or_default().trim(), TITLE_LENGTH),
+            None => "(empty session)".to_string(),
+        }
+    }
+
+    /// First transcript line containing `query` (already lowercase), if any
+    fn find(&self, query: &str) -> Option<String> {
+        let journal = self.journal.iter().map(|entry| entry.content.as_str());
+        let messages = self.conversation.messages().map(|m| m.content.as_str());
+
+        std::iter::once(self.title().as_str())
+            .chain(journal)
+            .chain(messages)
+            .flat_map(str::lines)
+            .find(|line| line.to_lowercase().contains(query))
+            .map(|line| shorten(line.trim(), SNIPPET_LENGTH))
+    }
+
+    /// Summarize the session for listing
+    pub fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            id: self.id.clone(),
+            title: self.title(),
+            updated_at: self.updated_at,
+            message_count: self.conversation.len(),
+            tokens_used: self.tokens_used,
+            model: self.models.primary.clone(),
+            working_directory: self.working_directory.clone(),
+            snippet: None,
+        }
+    }
+
+    /// Markdown export of the transcript and the cost of each model used
+    pub fn export(&self) -> String {
+        let mut out = format!("# {}\n\n", self.title());
+        out.push_str(&format!("- Session: {}\n", self.id));
+        out.push_str(&format!("- Saved: {}\n", self.summary().date()));
+        out.push_str(&format!(
+            "- Working directory: {}\n",
+            self.working_directory
+        ));
+        out.push_str(&format!("- Model: {}\n", self.models.primary));
+
+        out.push_str("\n## Transcript\n");
+        for entry in &self.journal {
+            let sender = match &entry.sender {
+                JournalSender::User => "User".to_string(),
+                JournalSender::Assistant => "Assistant".to_string(),
+                JournalSender::Reasoning => "Reasoning".to_string(),
+                JournalSender::System => "System".to_string(),
+                JournalSender::Tool(name) => format!("Tool ({})", name),
+            };
+            out.push_str(&format!(
+                "\n**{}**\n\n{}\n",
+                sender,
+                entry.content.trim_end()
+            ));
+        }
+
+        out.push_str("\n## Usage and cost\n\n");
+        out.push_str("| Model | Requests | Input | Output | Cache read | Cache write | Cost |\n");
+        out.push_str("|---|---:|---:|---:|---:|---:|---:|\n");
+        let totals = self.usage.totals();
+        let rows = self.usage.by_model().into_iter();
+        for (model, totals) in rows.chain(std::iter::once(("Total".to_string(), totals))) {
+            out.push_str(&format!(
+                "| {} | {} | {} | {} | {} | {} | {} |\n",
+                model,
+                totals.requests,
+                totals.usage.input_tokens,
+                totals.usage.output_tokens,
+                totals.usage.cache_read_tokens,
+                totals.usage.cache_write_tokens,
+                format_cost(totals.cost)
+            ));
+        }
+        out
+    }
+}
+
+impl SessionSummary {
+    /// How long ago the session was last saved, e.g. "5 minutes ago"
+    pub fn age(&self) -> String {
+        let seconds = now().saturating_sub(self.updated_at);
+        let (count, unit) = match seconds {
+            0..=59 => return "just now".to_string(),
+            60..=3599 => (seconds / 60, "minute"),
+            3600..=86399 => (seconds / 3600, "hour"),
+            _ => (seconds / 86400, "day"),
+        };
+        if count == 1 {
+            format!("1 {} ago", unit)
+        } else {
+            format!("{} {}s ago", count, unit)
+        }
+    }
+
+    /// Date the session was last saved, as YYYY-MM-DD (UTC)
+    pub fn date(&self) -> String {
+        // Civil date from days since the epoch (Howard Hinnant's algorithm)
+        let days = (self.updated_at / 86400) as i64 + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days.rem_euclid(146097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
+
+    /// First characters of the ID, enough to pick the session with `--resume`
+    pub fn short_id(&self) -> &str {
+        &self.id[..self.id.len().min(8)]
+    }
+}
+
+impl SessionStore {
+    /// Use the given directory for session files
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Use the sessions directory under the platform data directory
+    pub fn open_default() -> Result<Self, String> {
+        project_dirs()
+            .map(|dirs| Self::new(dirs.data_dir().join("sessions")))
+            .ok_or_else(|| "Could not determine the data directory for sessions".to_string())
+    }
+
+    /// Write a session to disk, updating its save time
+    pub fn save(&self, session: &mut Session) -> Result<PathBuf, String> {
+        session.version = SESSION_FORMAT_VERSION;
+        session.updated_at = now();
+        self.write(session)
+    }
+
+    /// Write a session to disk as it is
+    ///
+    /// The file is written next to its final location and renamed into place
+    /// so a crash never leaves a half-written session behind.
+    fn write(&self, session: &Session) -> Result<PathBuf, String> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("Failed to create {}: {}", self.dir.display(), e))?;
+
+        let text = serde_json::to_string_pretty(session)
+            .map_err(|e| format!("Failed to serialize session: {}", e))?;
+        let path = self.path_for(&session.id);
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, text)
+            .map_err(|e| format!("Failed to write {}: {}", temp_path.display(), e))?;
+        fs::rename(&temp_path, &path)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+        trace!("Saved session {} to {}", session.id, path.display());
+        Ok(path)
+    }
+
+    /// Load a session by ID or by a prefix that matches exactly one session
+    pub fn load(&self, id: &str) -> Result<Session, String> {
+        let path = self.path_for(id);
+        if path.is_file() {
+            return read_session(&path);
+        }
+
+        let matches: Vec<PathBuf> = self
+            .session_files()?
+            .into_iter()
+            .filter(|path| file_id(path).is_some_and(|file_id| file_id.starts_with(id)))
+            .collect();
+        match matches.as_slice() {
+            [path] => read_session(path),
+            [] => Err(format!("No session matches '{}'", id)),
+            _ => Err(format!(
+                "'{}' matches {} sessions, use more of the ID",
+                id,
+                matches.len()
+            )),
+        }
+    }
+
+    /// List saved sessions, most recently saved first
+    ///
+    /// Files that cannot be read are skipped with a warning.
+    pub fn list(&self) -> Result<Vec<SessionSummary>, String> {
+        let mut summaries = Vec::new();
+        for path in self.session_files()? {
+            match read_session(&path) {
+                Ok(session) => summaries.push(session.summary()),
+                Err(e) => warn!("Skipping session: {}", e),
+            }
+        }
+        summaries.sort_by_key(|summary| Reverse(summary.updated_at));
+        Ok(summaries)
+    }
+
+    /// Find sessions whose title or transcript contains `query`, ignoring case
+    ///
+    /// Results are ordered like `list`, each with the first matching line.
+    pub fn search(&self, query: &str) -> Result<Vec<SessionSummary>, String> {
+        let query = query.trim().to_lowercase();
+        let mut summaries = Vec::new();
+        for path in self.session_files()? {
+            match read_session(&path) {
+                Ok(session) => {
+                    if let Some(snippet) = session.find(&query) {
+                        summaries.push(SessionSummary {
+                            snippet: Some(snippet),
+                            ..session.summary()
+                        });
+                    }
+                }
+                Err(e) => warn!("Skipping session: {}", e),
+            }
+        }
+        summaries.sort_by_key(|summary| Reverse(summary.updated_at));
+        Ok(summaries)
+    }
+
+    /// Change the title of a saved session
+    pub fn rename(&self, id: &str, title: &str) -> Result<(), String> {
+        let mut session = self.load(id)?;
+        session.title = Some(title.trim().to_string());
+        self.write(&session).map(|_| ())
+    }
+
+    /// Delete a saved session and its file snapshots
+    pub fn delete(&self, id: &str) -> Result<(), String> {
+        let session = self.load(id)?;
+        let path = self.path_for(&session.id);
+        fs::remove_file(&path)
+            .map_err(|e| format!("Failed to delete {}: {}", path.display(), e))?;
+
+        let checkpoints = self.checkpoint_dir(&session.id);
+        if checkpoints.is_dir() {
+            fs::remove_dir_all(&checkpoints)
+                .map_err(|e| format!("Failed to delete {}: {}", checkpoints.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Save a copy of a session and its file snapshots under a new ID and return it
+    pub fn duplicate(&self, id: &str) -> Result<Session, String> {
+        let original = self.load(id)?;
+        let snapshots = self.checkpoint_dir(&original.id);
+        let mut copy = Session {
+            id: uuid::Uuid::new_v4().to_string(),
+            created_at: now(),
+            title: Some(format!("{} (copy)", original.title())),
+            ..original
+        };
+
+        // The copy's turns are undone from its own snapshots
+        if snapshots.is_dir() {
+            let target = self.checkpoint_dir(&copy.id);
+            copy_files(&snapshots, &target).map_err(|e| {
+                format!(
+                    "Failed to copy {} to {}: {}",
+                    snapshots.display(),
+                    target.display(),
+                    e
+                )
+            })?;
+        }
+        self.save(&mut copy)?;
+        Ok(copy)
+    }
+
+    /// Whether the session with the full ID `id` has been saved
+    pub fn is_saved(&self, id: &str) -> bool {
+        self.path_for(id).is_file()
+    }
+
+    /// Directory for the file snapshots of a session, used to undo its turns
+    pub fn checkpoint_dir(&self, id: &str) -> PathBuf {
+        self.dir.join("checkpoints").join(id)
+    }
+
+    /// Path of the file for a session ID
+    fn path_for(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    /// Paths of all session files (none if the directory does not exist yet)
+    fn session_files(&self) -> Result<Vec<PathBuf>, String> {
+        if !self.dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|e| format!("Failed to read {}: {}", self.dir.display(), e))?;
+        Ok(entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect())
+    }
+}
+
+/// Read a session file, rejecting formats this build does not understand
+fn read_session(path: &Path) -> Result<Session, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let value: serde_json::Value = serde_json::from_str(&text)
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+
+    let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+    if version != u64::from(SESSION_FORMAT_VERSION) {
+        return Err(format!(
+            "{} uses session format {}, but this version of GameCode reads format {}",
+            path.display(),
+            version,
+            SESSION_FORMAT_VERSION
+        ));
+    }
+
+    serde_json::from_value(value).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
+
+/// Cut `text` to at most `length` characters, marking where it was cut
+fn shorten(text: &str, length: usize) -> String {
+    if text.chars().count() > length {
+        let short: String = text.chars().take(length).collect();
+        format!("{}…", short.trim_end())
+    } else {
+        text.to_string()
+    }
+}
+
+/// Copy the files in directory `from` into `to`, creating it
+fn copy_files(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Session ID stored in a file name
+fn file_id(path: &Path) -> Option<&str> {
+    path.file_stem().and_then(|stem| stem.to_str())
+}
+
+/// Current time in seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::backends::TokenUsage;
+    use crate::agent::usage::{ModelPrice, RequestPurpose};
+
+    fn temp_store() -> SessionStore {
+        SessionStore::new(
+            std::env::temp_dir().join(format!("gamecode-sessions-{}", uuid::Uuid::new_v4())),
+        )
+    }
+
+    fn session_with_input(input: &str) -> Session {
+        let models = ModelSelection {
+            primary: "sonnet".to_string(),
+            ..ModelSelection::default()
+        };
+        let mut session = Session::new(models, "/tmp/project");
+        session.journal.push(JournalEntry {
+            sender: JournalSender::User,
+            content: input.to_string(),
+            node: None,
+            images: Vec::new(),
+        });
+        session
+    }
+
+    #[test]
+    fn saved_sessions_load_and_list() {
+        let store = temp_store();
+        let mut session = session_with_input("Explain the build");
+        session.profile = Some("review".to_string());
+        store.save(&mut session).unwrap();
+
+        let loaded = store.load(&session.id[..8]).unwrap();
+        assert_eq!(loaded.id, session.id);
+        assert_eq!(loaded.working_directory, "/tmp/project");
+        assert_eq!(loaded.profile.as_deref(), Some("review"));
+
+        let summaries = store.list().unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].title, "Explain the build");
+
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn exports_include_the_transcript_and_cost_per_model() {
+        let mut session = session_with_input("Explain the build");
+        session.journal.push(JournalEntry {
+            sender: JournalSender::Assistant,
+            content: "It runs cargo.".to_string(),
+            node: None,
+            images: Vec::new(),
+        });
+        let price = ModelPrice {
+            input: 3.0,
+            output: 15.0,
+            ..ModelPrice::default()
+        };
+        let usage = TokenUsage {
+            input_tokens: 1000,
+            output_tokens: 100,
+            ..TokenUsage::default()
+        };
+        let ledger = &mut session.usage;
+        ledger.record("sonnet", RequestPurpose::Turn, usage, &price);
+        ledger.record("sonnet", RequestPurpose::Turn, usage, &price);
+        let free = ModelPrice::default();
+        ledger.record("haiku", RequestPurpose::Title, usage, &free);
+
+        let export = session.export();
+        assert!(export.starts_with("# Explain the build\n"));
+        assert!(export.contains("**User**\n\nExplain the build\n"));
+        assert!(export.contains("**Assistant**\n\nIt runs cargo.\n"));
+        assert!(export.contains("| sonnet | 2 | 2000 | 200 | 0 | 0 | $0.0090 |"));
+        assert!(export.contains("| haiku | 1 | 1000 | 100 | 0 | 0 | $0.0000 |"));
+        assert!(export.contains("| Total | 3 | 3000 | 300 | 0 | 0 | $0.0090 |"));
+    }
+
+    #[test]
+    fn newer_formats_are_rejected() {
+        let store = temp_store();
+        let mut session = session_with_input("Hello");
+        let path = store.save(&mut session).unwrap();
+
+        let text =
+            fs::read_to_string(&path)
+                .unwrap()
+                .replacen("\"version\": 1", "\"version\": 99", 1);
+        fs::write(&path, text).unwrap();
+
+        let error = store.load(&session.id).err().unwrap();
+        assert!(error.contains("session format 99"), "{}", error);
+        assert!(store.list().unwrap().is_empty());
+
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn search_finds_transcript_lines() {
+        let store = temp_store();
+        let mut first = session_with_input("Explain the build");
+        first.journal.push(JournalEntry {
+            sender: JournalSender::Assistant,
+            content: "The build uses Cargo.\nRun cargo build --release for an optimized binary."
+                .to_string(),
+            node: None,
+            images: Vec::new(),
+        });
+        store.save(&mut first).unwrap();
+        store
+            .save(&mut session_with_input("Fix the tests"))
+            .unwrap();
+
+        let results = store.search("RELEASE").unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, first.id);
+        assert_eq!(
+            results[0].snippet.as_deref(),
+            Some("Run cargo build --release for an optimized binary.")
+        );
+
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn sessions_can_be_renamed_duplicated_and_deleted() {
+        let store = temp_store();
+        let mut session = session_with_input("Explain the build");
+        store.save(&mut session).unwrap();
+
+        let snapshots = store.checkpoint_dir(&session.id);
+        fs::create_dir_all(&snapshots).unwrap();
+        fs::write(snapshots.join("index.json"), "[]").unwrap();
+
+        assert!(store.is_saved(&session.id));
+        assert!(!store.is_saved(&session.id[..8]));
+        store.rename(&session.id, "Build notes").unwrap();
+        let copy = store.duplicate(&session.id).unwrap();
+        assert_eq!(copy.title(), "Build notes (copy)");
+        assert_ne!(copy.id, session.id);
+
+        store.delete(&session.id).unwrap();
+        let summaries = store.list().unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].id, copy.id);
+        assert!(!snapshots.exists());
+        let copied = store.checkpoint_dir(&copy.id).join("index.json");
+        assert_eq!(fs::read_to_string(copied).unwrap(), "[]");
+
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn dates_are_calendar_dates() {
+        let mut summary = session_with_input("Hello").summary();
+        summary.updated_at = 1_760_745_600;
+
+        assert_eq!(summary.date(), "2025-10-18");
+    }
+
+    #[test]
+    fn long_titles_are_shortened() {
+        let session = session_with_input(&"word ".repeat(40));
+
+        assert!(session.title().ends_with('…'));
+        assert!(session.title().chars().count() <= TITLE_LENGTH + 1);
+    }
+}
@@ -1,4 +1,5 @@
 // Application state management
+// Conversations are saved and resumed through core::session
 
 pub struct AppState {
     // TODO: Application state properties
@@ -12,14 +13,4 @@ impl AppState {
     pub fn update(&mut self) {
         // TODO: Update application state
     }
-
-    pub fn save_session(&self) -> Result<(), String> {
-        // TODO: Save session state
-        Ok(())
-    }
-
-    pub fn load_session(&mut self) -> Result<(), String> {
-        // TODO: Load session state
-        Ok(())
-    }
 }
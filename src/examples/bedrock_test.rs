@@ -1,3 +1,4 @@
+use crate::agent::error::AgentError;
 use crate::agent::manager::{AgentConfig, AgentManager};
 use crate::agent::tools::{ExecuteCommandTool, ListDirectoryTool, ReadFileTool, WriteFileTool};
 use std::env;
@@ -5,17 +6,17 @@ use std::path::Path;
 use tracing::info;
 
 /// Example showing AWS Bedrock integration with Claude models
-pub async fn run_bedrock_example() -> Result<(), String> {
+pub async fn run_bedrock_example() -> Result<(), AgentError> {
     // Initialize tracing with a more verbose configuration
     info!("Starting Bedrock integration example");
 
     // Create agent configuration
     let agent_config = AgentConfig {
-        use_fast_model_for_context: true,
-        max_context_length: 32000,
+        max_context_length: None,
         auto_compress_context: true,
         aws_region: "us-east-1".to_string(),
         aws_profile: Some("default".to_string()), // Make sure this profile exists in your ~/.aws/credentials
+        ..AgentConfig::default()
     };
 
     // Create and initialize agent manager
@@ -25,7 +26,7 @@ pub async fn run_bedrock_example() -> Result<(), String> {
     agent_manager.register_tool(Box::new(ReadFileTool));
     agent_manager.register_tool(Box::new(WriteFileTool));
     agent_manager.register_tool(Box::new(ListDirectoryTool));
-    agent_manager.register_tool(Box::new(ExecuteCommandTool));
+    agent_manager.register_tool(Box::new(ExecuteCommandTool::default()));
 
     info!("Initializing agent manager");
     agent_manager.init().await?;
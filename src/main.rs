@@ -6,6 +6,10 @@ mod ui;
 mod visualization;
 
 use std::env;
+use std::process;
+
+use crate::agent::backends::ModelRole;
+use crate::core::session::SessionStore;
 
 use tracing::{debug, error, trace};
 
@@ -55,8 +59,98 @@ async fn main() {
         if let Err(e) = examples::run_bedrock_example().await {
             error!("Error in Bedrock example: {e}");
         }
+    } else if args.contains(&String::from("--list-sessions")) {
+        list_sessions();
+    } else if let Some(index) = args.iter().position(|arg| arg == "--export") {
+        export_session(args.get(index + 1).filter(|id| !id.starts_with("--")));
     } else {
-        // Run the normal application
-        app::run();
+        // Run the normal application, resuming a saved session if one was picked
+        let resume_session = args
+            .iter()
+            .position(|arg| arg == "--resume")
+            .map(|index| resolve_session(args.get(index + 1).filter(|id| !id.starts_with("--"))));
+        app::run(resume_session, model_overrides(&args));
+    }
+}
+
+// Models picked on the command line with --model, --summarization-model and --title-model
+fn model_overrides(args: &[String]) -> Vec<(ModelRole, String)> {
+    let flags = [
+        ("--model", ModelRole::Primary),
+        ("--summarization-model", ModelRole::Summarization),
+        ("--title-model", ModelRole::Title),
+    ];
+    flags
+        .into_iter()
+        .filter_map(|(flag, role)| {
+            let index = args.iter().position(|arg| arg == flag)?;
+            match args.get(index + 1).filter(|name| !name.starts_with("--")) {
+                Some(name) => Some((role, name.clone())),
+                None => {
+                    eprintln!("{} needs a model name", flag);
+                    process::exit(1);
+                }
+            }
+        })
+        .collect()
+}
+
+// Print the saved sessions, most recent first
+fn list_sessions() {
+    let sessions = match SessionStore::open_default().and_then(|store| store.list()) {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            eprintln!("Failed to list sessions: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if sessions.is_empty() {
+        println!("No saved sessions");
+        return;
     }
+    for session in sessions {
+        println!(
+            "{}  {:<16} {:>4} messages  {}",
+            session.short_id(),
+            session.age(),
+            session.message_count,
+            session.title
+        );
+    }
+    println!();
+    println!("Resume one with: gamecode --resume <id>");
+}
+
+// Print a saved session's transcript and cost as Markdown
+fn export_session(id: Option<&String>) {
+    let Some(id) = id else {
+        eprintln!("--export needs a session ID");
+        process::exit(1);
+    };
+    match SessionStore::open_default().and_then(|store| store.load(id)) {
+        Ok(session) => print!("{}", session.export()),
+        Err(e) => {
+            eprintln!("Cannot export session: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Find the session to resume: the given ID (or prefix), or the most recent one
+fn resolve_session(id: Option<&String>) -> String {
+    let resolved = SessionStore::open_default().and_then(|store| match id {
+        Some(id) => store.load(id).map(|session| session.id),
+        None => store
+            .list()?
+            .into_iter()
+            .next()
+            .map(|session| session.id)
+            .ok_or_else(|| "No saved sessions to resume".to_string()),
+    });
+
+    resolved.unwrap_or_else(|e| {
+        eprintln!("Cannot resume session: {}", e);
+        process::exit(1);
+    })
 }
@@ -7,6 +7,7 @@ use tracing::trace;
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ToolStatus {
     Idle,
+    Thinking,
     Running,
     Completed,
     Failed,
@@ -52,6 +53,10 @@ impl ToolEntity {
         self
     }
 
+    pub fn think(&mut self) {
+        self.status = ToolStatus::Thinking;
+    }
+
     pub fn start(&mut self) {
         self.status = ToolStatus::Running;
     }
@@ -28,6 +28,11 @@ pub fn update_visualization_system(
     for (_entity, tool_entity, mut transform) in query.iter_mut() {
         // Make tool entities rotate
         match tool_entity.status {
+            ToolStatus::Thinking => {
+                // Pulse slowly while the model reasons
+                let pulse = 1.0 + (time.elapsed_secs_f64() * 3.0).sin() as f32 * 0.15;
+                transform.scale = Vec3::splat(pulse);
+            }
             ToolStatus::Running => {
                 transform.rotate_z(time.delta_secs() * 2.0);
 
@@ -56,8 +61,12 @@ pub fn spawn_tool_visualization(
     tool_type: &str,
     position: Vec3,
 ) -> Entity {
-    // Create a new tool entity
-    let tool = ToolEntity::new(tool_type);
+    // Create a new tool entity; the model's reasoning has no separate start,
+    // so it is shown thinking from the outset
+    let mut tool = ToolEntity::new(tool_type);
+    if tool_type == "thinking" {
+        tool.think();
+    }
 
     // Use an extremely bright, large sprite that should be clearly visible
     // In Bevy 0.15, we use the Sprite component directly instead of SpriteBundle
@@ -68,6 +77,7 @@ pub fn spawn_tool_visualization(
                 // Use extremely bright colors that stand out
                 color: match tool.status {
                     ToolStatus::Idle => Color::srgba(0.8, 0.8, 0.8, 1.0), // Bright white
+                    ToolStatus::Thinking => Color::srgba(0.7, 0.4, 1.0, 1.0), // Bright purple
                     ToolStatus::Running => Color::srgba(1.0, 1.0, 0.0, 1.0), // Bright yellow
                     ToolStatus::Completed => Color::srgba(0.0, 1.0, 0.0, 1.0), // Bright green
                     ToolStatus::Failed => Color::srgba(1.0, 0.0, 0.0, 1.0), // Bright red
@@ -100,6 +110,7 @@ pub fn update_tool_status(
         // In Bevy 0.15, we need to use srgba instead of color constants
         sprite.color = match status {
             ToolStatus::Idle => Color::srgba(0.5, 0.5, 0.5, 1.0), // Gray
+            ToolStatus::Thinking => Color::srgba(0.7, 0.4, 1.0, 1.0), // Purple
             ToolStatus::Running => Color::srgba(1.0, 1.0, 0.0, 1.0), // Yellow
             ToolStatus::Completed => Color::srgba(0.0, 1.0, 0.0, 1.0), // Green
             ToolStatus::Failed => Color::srgba(1.0, 0.0, 0.0, 1.0), // Red